@@ -1,7 +1,23 @@
+// The protobuf sources of the crate are generated from src/*.proto and
+// checked in, so building the runtime does not pull in the code generator.
+// Build with `--features codegen` after changing a .proto file.
+
+const PROTOS: &[&str] = &[
+    "src/ttrpc.proto",
+    "src/diagnostics.proto",
+    "src/reflection.proto",
+];
+
 fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    for proto in PROTOS {
+        println!("cargo:rerun-if-changed={}", proto);
+    }
+
+    #[cfg(feature = "codegen")]
     protobuf_codegen_pure::Codegen::new()
         .out_dir("src")
-        .inputs(&["src/ttrpc.proto"])
+        .inputs(PROTOS)
         .include("src")
         .run()
         .expect("Codegen failed.");
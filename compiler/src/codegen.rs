@@ -36,18 +36,61 @@
 
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use protobuf;
 use protobuf::compiler_plugin;
 use protobuf::descriptor::*;
 use protobuf::descriptorx::*;
+use protobuf::{self, Message};
 use protobuf_codegen::code_writer::CodeWriter;
 use std::fs::File;
 use std::io::{self, Write};
 use std::path::Path;
 
-use super::util::{self, fq_grpc, to_camel_case, to_snake_case, MethodType};
+use super::util::{self, fnv1a, fq_grpc, to_camel_case, to_snake_case, MethodType};
+
+/// Options controlling how ttrpc code is generated.
+#[derive(Clone, Debug, Default)]
+pub struct Customize {
+    /// Rust module path to use for each proto package, e.g.
+    /// `containerd.services.tasks.v1` => `tasks_v1`. Packages without an
+    /// entry keep the module rust-protobuf derives from the file name.
+    pub module_mapping: HashMap<String, String>,
+    /// Embed the serialized `FileDescriptorProto` of each file as
+    /// `FILE_DESCRIPTOR`, for `Server::register_descriptor`.
+    pub embed_descriptors: bool,
+    /// Decode requests into messages kept in a `MessagePool` of this size
+    /// per method, and pass them to the service by reference, to save
+    /// allocating each request message anew.
+    pub message_pool: Option<usize>,
+    /// Generate a `#[cfg(test)]` harness for each service, serving an
+    /// implementation in process to a client connected to it. Only for
+    /// blocking servers and clients.
+    pub test_harness: bool,
+    /// Generate async service traits, served by
+    /// `ttrpc::asynchronous::Server`, in place of blocking ones. Requests
+    /// are not pooled then.
+    pub async_server: bool,
+    /// Generate clients calling through `ttrpc::asynchronous::Client` in
+    /// place of blocking ones.
+    pub async_client: bool,
+    /// Generate a `{Service}Tower` per async service, implementing
+    /// `tower::Service` over `ttrpc::asynchronous::tower::Call`s so tower
+    /// layers can wrap it. Needs `async_server` and the `tower` feature of
+    /// ttrpc.
+    pub tower_service: bool,
+}
+
+impl Customize {
+    /// Rust module holding the types generated for `file`, relative to the
+    /// parent of the generated ttrpc module.
+    fn rust_mod(&self, file: &FileDescriptorProto) -> String {
+        match self.module_mapping.get(file.get_package()) {
+            Some(m) => m.clone(),
+            None => proto_path_to_rust_mod(file.get_name()),
+        }
+    }
+}
 
 struct MethodGen<'a> {
     proto: &'a MethodDescriptorProto,
@@ -55,6 +98,7 @@ struct MethodGen<'a> {
     service_name: String,
     service_path: String,
     root_scope: &'a RootScope<'a>,
+    customize: &'a Customize,
 }
 
 impl<'a> MethodGen<'a> {
@@ -64,6 +108,7 @@ impl<'a> MethodGen<'a> {
         service_name: String,
         service_path: String,
         root_scope: &'a RootScope<'a>,
+        customize: &'a Customize,
     ) -> MethodGen<'a> {
         MethodGen {
             proto,
@@ -71,24 +116,33 @@ impl<'a> MethodGen<'a> {
             service_name,
             service_path,
             root_scope,
+            customize,
         }
     }
 
+    fn message_mod(&self, type_name: &str) -> String {
+        let message = self.root_scope.find_message(type_name);
+        self.customize
+            .rust_mod(message.get_scope().get_file_descriptor())
+    }
+
     fn input(&self) -> String {
         format!(
-            "super::{}",
+            "super::{}::{}",
+            self.message_mod(self.proto.get_input_type()),
             self.root_scope
                 .find_message(self.proto.get_input_type())
-                .rust_fq_name()
+                .rust_name()
         )
     }
 
     fn output(&self) -> String {
         format!(
-            "super::{}",
+            "super::{}::{}",
+            self.message_mod(self.proto.get_output_type()),
             self.root_scope
                 .find_message(self.proto.get_output_type())
-                .rust_fq_name()
+                .rust_name()
         )
     }
 
@@ -110,6 +164,18 @@ impl<'a> MethodGen<'a> {
         }
     }
 
+    // only unary requests are decoded into pooled messages
+    fn pooled(&self) -> bool {
+        self.customize.message_pool.is_some()
+            && !self.customize.async_server
+            && matches!(self.method_type().0, MethodType::Unary)
+    }
+
+    // the async server serves no streaming methods
+    fn served(&self) -> bool {
+        !self.customize.async_server || matches!(self.method_type().0, MethodType::Unary)
+    }
+
     fn service_name(&self) -> String {
         to_snake_case(&self.service_name)
     }
@@ -126,6 +192,32 @@ impl<'a> MethodGen<'a> {
         format!("\"{}/{}\"", self.service_path, &self.proto.get_name())
     }
 
+    /// The path the client sends and the server registers the method at.
+    fn path(&self) -> String {
+        format!(
+            "/{}.{}/{}",
+            self.package_name,
+            self.service_name,
+            self.proto.get_name()
+        )
+    }
+
+    fn const_path_name(&self) -> String {
+        format!(
+            "{}_{}_PATH",
+            to_snake_case(&self.service_name).to_uppercase(),
+            to_snake_case(self.proto.get_name()).to_uppercase()
+        )
+    }
+
+    fn const_hash_name(&self) -> String {
+        format!(
+            "{}_{}_HASH",
+            to_snake_case(&self.service_name).to_uppercase(),
+            to_snake_case(self.proto.get_name()).to_uppercase()
+        )
+    }
+
     fn const_method_name(&self) -> String {
         format!(
             "METHOD_{}_{}",
@@ -134,6 +226,51 @@ impl<'a> MethodGen<'a> {
         )
     }
 
+    /// What the hash of the method covers: its path, its streaming, and the
+    /// numbers, labels and types of the fields of its messages, nested
+    /// ones included. Renaming a field or a message leaves it alone, as it
+    /// does the wire format.
+    fn signature(&self) -> String {
+        let mut sig = format!(
+            "{} {} {}\n",
+            self.fq_name(),
+            self.proto.get_client_streaming(),
+            self.proto.get_server_streaming()
+        );
+        let mut seen = HashSet::new();
+        self.describe(self.proto.get_input_type(), &mut sig, &mut seen);
+        sig.push_str("returns\n");
+        self.describe(self.proto.get_output_type(), &mut sig, &mut seen);
+        sig
+    }
+
+    fn describe(&self, type_name: &str, sig: &mut String, seen: &mut HashSet<String>) {
+        // a message used twice, or recursively, is described once
+        if !seen.insert(type_name.to_string()) {
+            sig.push_str("seen\n");
+            return;
+        }
+        let message = self.root_scope.find_message(type_name).message;
+        let mut fields: Vec<&FieldDescriptorProto> = message.get_field().iter().collect();
+        fields.sort_by_key(|f| f.get_number());
+        sig.push_str(&format!("{} fields\n", fields.len()));
+        for f in fields {
+            sig.push_str(&format!(
+                "{} {:?} {:?}\n",
+                f.get_number(),
+                f.get_label(),
+                f.get_field_type()
+            ));
+            if f.get_field_type() == FieldDescriptorProto_Type::TYPE_MESSAGE {
+                self.describe(f.get_type_name(), sig, seen);
+            }
+        }
+    }
+
+    fn hash(&self) -> u64 {
+        fnv1a(self.signature().as_bytes())
+    }
+
     fn write_definition(&self, w: &mut CodeWriter) {
         let head = format!(
             "const {}: {}<{}, {}> = {} {{",
@@ -158,6 +295,9 @@ impl<'a> MethodGen<'a> {
     }
 
     fn write_handler(&self, w: &mut CodeWriter) {
+        if self.customize.async_server {
+            return self.write_async_handler(w);
+        }
         w.block(
             &format!("struct {}Method {{", self.struct_name()),
             "}",
@@ -166,6 +306,12 @@ impl<'a> MethodGen<'a> {
                     "service: Arc<std::boxed::Box<dyn {} + Send + Sync>>,",
                     self.service_name
                 ));
+                if self.pooled() {
+                    w.write_line(&format!(
+                        "pool: ::ttrpc::message_pool::MessagePool<{}>,",
+                        self.input()
+                    ));
+                }
             },
         );
         w.write_line("");
@@ -173,11 +319,55 @@ impl<'a> MethodGen<'a> {
         |w| {
             w.block("fn handler(&self, ctx: ::ttrpc::TtrpcContext, req: ::ttrpc::Request) -> ::ttrpc::Result<()> {", "}",
             |w| {
-                w.write_line(&format!("::ttrpc::request_handler!(self, ctx, req, {}, {}, {});",
-                                        proto_path_to_rust_mod(self.root_scope.find_message(self.proto.get_input_type()).get_scope().get_file_descriptor().get_name()),
+                match self.method_type().0 {
+                    MethodType::Unary if self.pooled() => {
+                        w.write_line(&format!("::ttrpc::pooled_request_handler!(self, ctx, req, {});", self.name()));
+                    }
+                    MethodType::Unary => {
+                        w.write_line(&format!("::ttrpc::request_handler!(self, ctx, req, {}, {}, {});",
+                                                self.message_mod(self.proto.get_input_type()),
+                                                self.root_scope.find_message(self.proto.get_input_type()).rust_name(),
+                                                self.name()));
+                    }
+                    MethodType::ClientStreaming => {
+                        w.write_line(&format!("::ttrpc::client_streaming_handler!(self, ctx, req, {});", self.name()));
+                    }
+                    MethodType::ServerStreaming => {
+                        w.write_line(&format!("::ttrpc::server_streaming_handler!(self, ctx, req, {}, {}, {});",
+                                                self.message_mod(self.proto.get_input_type()),
+                                                self.root_scope.find_message(self.proto.get_input_type()).rust_name(),
+                                                self.name()));
+                    }
+                    MethodType::Duplex => {
+                        w.write_line(&format!("::ttrpc::duplex_streaming_handler!(self, ctx, req, {});", self.name()));
+                    }
+                }
+                w.write_line("Ok(())");
+            });
+        });
+    }
+
+    fn write_async_handler(&self, w: &mut CodeWriter) {
+        w.block(
+            &format!("struct {}Method {{", self.struct_name()),
+            "}",
+            |w| {
+                w.write_line(&format!(
+                    "service: Arc<std::boxed::Box<dyn {} + Send + Sync>>,",
+                    self.service_name
+                ));
+            },
+        );
+        w.write_line("");
+        w.write_line("#[::ttrpc::asynchronous::async_trait]");
+        w.block(&format!("impl ::ttrpc::asynchronous::MethodHandler for {}Method {{", self.struct_name()), "}",
+        |w| {
+            w.block("async fn handler(&self, ctx: ::ttrpc::asynchronous::TtrpcContext, req: ::ttrpc::Request) -> ::ttrpc::Result<::ttrpc::Response> {", "}",
+            |w| {
+                w.write_line(&format!("::ttrpc::async_request_handler!(self, ctx, req, {}, {}, {});",
+                                        self.message_mod(self.proto.get_input_type()),
                                         self.root_scope.find_message(self.proto.get_input_type()).rust_name(),
                                         self.name()));
-                w.write_line("Ok(())");
             });
         });
     }
@@ -229,73 +419,33 @@ impl<'a> MethodGen<'a> {
 
     fn client_streaming(&self, method_name: &str) -> String {
         format!(
-            "{}(&self) -> {}<({}<{}>, {}<{}>)>",
+            "{}(&self, timeout_nano: i64) -> {}<{}<{}, {}>>",
             method_name,
             fq_grpc("Result"),
-            fq_grpc("ClientCStreamSender"),
+            fq_grpc("ClientStreamSender"),
             self.input(),
-            fq_grpc("ClientCStreamReceiver"),
-            self.output()
-        )
-    }
-
-    fn client_streaming_opt(&self, method_name: &str) -> String {
-        format!(
-            "{}_opt(&self, opt: {}) -> {}<({}<{}>, {}<{}>)>",
-            method_name,
-            fq_grpc("CallOption"),
-            fq_grpc("Result"),
-            fq_grpc("ClientCStreamSender"),
-            self.input(),
-            fq_grpc("ClientCStreamReceiver"),
             self.output()
         )
     }
 
     fn server_streaming(&self, method_name: &str) -> String {
         format!(
-            "{}(&self, req: &{}) -> {}<{}<{}>>",
+            "{}(&self, req: &{}, timeout_nano: i64) -> {}<{}<{}>>",
             method_name,
             self.input(),
             fq_grpc("Result"),
-            fq_grpc("ClientSStreamReceiver"),
-            self.output()
-        )
-    }
-
-    fn server_streaming_opt(&self, method_name: &str) -> String {
-        format!(
-            "{}_opt(&self, req: &{}, opt: {}) -> {}<{}<{}>>",
-            method_name,
-            self.input(),
-            fq_grpc("CallOption"),
-            fq_grpc("Result"),
-            fq_grpc("ClientSStreamReceiver"),
+            fq_grpc("ServerStreamReceiver"),
             self.output()
         )
     }
 
     fn duplex_streaming(&self, method_name: &str) -> String {
         format!(
-            "{}(&self) -> {}<({}<{}>, {}<{}>)>",
+            "{}(&self, timeout_nano: i64) -> {}<{}<{}, {}>>",
             method_name,
             fq_grpc("Result"),
-            fq_grpc("ClientDuplexSender"),
+            fq_grpc("DuplexStream"),
             self.input(),
-            fq_grpc("ClientDuplexReceiver"),
-            self.output()
-        )
-    }
-
-    fn duplex_streaming_opt(&self, method_name: &str) -> String {
-        format!(
-            "{}_opt(&self, opt: {}) -> {}<({}<{}>, {}<{}>)>",
-            method_name,
-            fq_grpc("CallOption"),
-            fq_grpc("Result"),
-            fq_grpc("ClientDuplexSender"),
-            self.input(),
-            fq_grpc("ClientDuplexReceiver"),
             self.output()
         )
     }
@@ -304,6 +454,23 @@ impl<'a> MethodGen<'a> {
         let method_name = self.name();
         match self.method_type().0 {
             // Unary
+            MethodType::Unary if self.customize.async_client => {
+                w.block(
+                    &format!("pub async fn {} {{", self.unary(&method_name)),
+                    "}",
+                    |w| {
+                        w.write_line(&format!("let mut cres = {}::new();", self.output()));
+                        w.write_line(&format!(
+                            "::ttrpc::async_client_request!(self, req, timeout_nano, \"{}.{}\", \"{}\", cres);",
+                            self.package_name,
+                            self.service_name,
+                            &self.proto.get_name(),
+                        ));
+                        w.write_line("Ok(cres)");
+                    },
+                );
+            }
+
             MethodType::Unary => {
                 w.pub_fn(&self.unary(&method_name), |w| {
                     w.write_line(&format!(
@@ -320,47 +487,116 @@ impl<'a> MethodGen<'a> {
                 });
             }
 
-            _ => {}
+            // the async client makes no streaming calls
+            _ if self.customize.async_client => {}
+
+            MethodType::ClientStreaming => {
+                w.pub_fn(&self.client_streaming(&method_name), |w| {
+                    w.write_line(&format!(
+                        "::ttrpc::client_stream_request!(self, timeout_nano, \"{}.{}\", \"{}\", client_streaming)",
+                        self.package_name,
+                        self.service_name,
+                        &self.proto.get_name(),
+                    ));
+                });
+            }
+
+            MethodType::ServerStreaming => {
+                w.pub_fn(&self.server_streaming(&method_name), |w| {
+                    w.write_line(&format!(
+                        "::ttrpc::client_stream_request!(self, req, timeout_nano, \"{}.{}\", \"{}\", server_streaming)",
+                        self.package_name,
+                        self.service_name,
+                        &self.proto.get_name(),
+                    ));
+                });
+            }
+
+            MethodType::Duplex => {
+                w.pub_fn(&self.duplex_streaming(&method_name), |w| {
+                    w.write_line(&format!(
+                        "::ttrpc::client_stream_request!(self, timeout_nano, \"{}.{}\", \"{}\", duplex_streaming)",
+                        self.package_name,
+                        self.service_name,
+                        &self.proto.get_name(),
+                    ));
+                });
+            }
         };
     }
 
     fn write_service(&self, w: &mut CodeWriter) {
         let req_stream_type = format!("{}<{}>", fq_grpc("RequestStream"), self.input());
-        let (req, req_type, _resp_type) = match self.method_type().0 {
-            MethodType::Unary => ("req", self.input(), "UnarySink"),
-            MethodType::ClientStreaming => ("stream", req_stream_type, "ClientStreamingSink"),
-            MethodType::ServerStreaming => ("req", self.input(), "ServerStreamingSink"),
-            MethodType::Duplex => ("stream", req_stream_type, "DuplexSink"),
+        let sink = format!(", _sink: {}<{}>", fq_grpc("StreamSink"), self.output());
+        let (req, req_type, sink, resp_type) = match self.method_type().0 {
+            MethodType::Unary => ("req", self.input(), String::new(), self.output()),
+            MethodType::ClientStreaming => {
+                ("stream", req_stream_type, String::new(), self.output())
+            }
+            MethodType::ServerStreaming => ("req", self.input(), sink, "()".to_string()),
+            MethodType::Duplex => ("stream", req_stream_type, sink, "()".to_string()),
+        };
+        let req_type = if self.pooled() {
+            format!("&{}", req_type)
+        } else {
+            req_type
+        };
+        let ctx_type = if self.customize.async_server {
+            fq_grpc("asynchronous::TtrpcContext")
+        } else {
+            fq_grpc("TtrpcContext")
         };
 
         let sig = format!(
-            "{}(&self, _ctx: &{}, _{}: {}) -> ::ttrpc::Result<{}>",
+            "{}(&self, _ctx: &{}, _{}: {}{}) -> ::ttrpc::Result<{}>",
             self.name(),
-            fq_grpc("TtrpcContext"),
+            ctx_type,
             req,
             req_type,
-            self.output()
+            sink,
+            resp_type
         );
 
-        w.def_fn(&sig, |w| {
+        let body = |w: &mut CodeWriter| {
             w.write_line(format!("Err(::ttrpc::Error::RpcStatus(::ttrpc::get_status(::ttrpc::Code::NOT_FOUND, \"/{}.{}/{} is not supported\".to_string())))",
             self.package_name,
             self.service_name, self.proto.get_name(),));
-        });
+        };
+        if self.customize.async_server {
+            w.block(&format!("async fn {} {{", sig), "}", body);
+        } else {
+            w.def_fn(&sig, body);
+        }
     }
 
     fn write_bind(&self, w: &mut CodeWriter) {
-        let s = format!("methods.insert(\"/{}.{}/{}\".to_string(),
-                    std::boxed::Box::new({}Method{{service: service.clone()}}) as std::boxed::Box<dyn ::ttrpc::MethodHandler + Send + Sync>);",
-                    self.package_name,
-                    self.service_name, self.proto.get_name(), self.struct_name());
+        let pool = match self.customize.message_pool {
+            Some(max) if self.pooled() => {
+                format!(", pool: ::ttrpc::message_pool::MessagePool::new({})", max)
+            }
+            _ => String::new(),
+        };
+        let s = format!("methods.insert({}.to_string(),
+                    std::boxed::Box::new({}Method{{service: service.clone(){}}}) as std::boxed::Box<dyn {} + Send + Sync>);",
+                    self.const_path_name(), self.struct_name(), pool, handler_trait(self.customize));
         w.write_line(&s);
     }
 }
 
+// the trait the generated handlers implement
+fn handler_trait(customize: &Customize) -> String {
+    if customize.async_server {
+        fq_grpc("asynchronous::MethodHandler")
+    } else {
+        fq_grpc("MethodHandler")
+    }
+}
+
 struct ServiceGen<'a> {
     proto: &'a ServiceDescriptorProto,
+    service_path: String,
     methods: Vec<MethodGen<'a>>,
+    customize: &'a Customize,
 }
 
 impl<'a> ServiceGen<'a> {
@@ -368,6 +604,7 @@ impl<'a> ServiceGen<'a> {
         proto: &'a ServiceDescriptorProto,
         file: &FileDescriptorProto,
         root_scope: &'a RootScope,
+        customize: &'a Customize,
     ) -> ServiceGen<'a> {
         let service_path = if file.get_package().is_empty() {
             format!("{}", proto.get_name())
@@ -384,11 +621,17 @@ impl<'a> ServiceGen<'a> {
                     util::to_camel_case(proto.get_name()),
                     service_path.clone(),
                     root_scope,
+                    customize,
                 )
             })
             .collect();
 
-        ServiceGen { proto, methods }
+        ServiceGen {
+            proto,
+            service_path,
+            methods,
+            customize,
+        }
     }
 
     fn service_name(&self) -> String {
@@ -399,16 +642,153 @@ impl<'a> ServiceGen<'a> {
         format!("{}Client", self.service_name())
     }
 
+    // the ::ttrpc::Service of the trait
+    fn registration_name(&self) -> String {
+        format!("{}Service", self.service_name())
+    }
+
+    fn method_enum_name(&self) -> String {
+        format!("{}Method", self.service_name())
+    }
+
+    fn const_hashes_name(&self) -> String {
+        format!(
+            "{}_METHOD_HASHES",
+            to_snake_case(&self.service_name()).to_uppercase()
+        )
+    }
+
+    /// The paths and hashes of the methods, computed here once rather
+    /// than by the stubs on each call.
+    fn write_paths(&self, w: &mut CodeWriter) {
+        for (method, h) in self.methods.iter().zip(self.hashes().iter()) {
+            w.write_line(format!("/// Path of `{}`.", method.path()));
+            w.write_line(format!(
+                "pub const {}: &str = \"{}\";",
+                method.const_path_name(),
+                method.path()
+            ));
+            w.write_line(format!("/// Hash of `{}`.", method.path()));
+            w.write_line(format!(
+                "pub const {}: u64 = 0x{:016x};",
+                method.const_hash_name(),
+                h
+            ));
+        }
+    }
+
+    fn hashes(&self) -> Vec<u64> {
+        self.methods.iter().map(|m| m.hash()).collect()
+    }
+
+    /// The hashes of the methods, for `Server::register_method_hashes` and
+    /// `Client::check_methods`, and the hash of the whole service.
+    fn write_hashes(&self, w: &mut CodeWriter) {
+        let hashes = self.hashes();
+        let mut all = Vec::new();
+        for h in &hashes {
+            all.extend_from_slice(&h.to_be_bytes());
+        }
+
+        w.write_line(format!(
+            "/// Hashes of the methods of `{}`, by method path.",
+            self.service_path
+        ));
+        w.write_line(format!(
+            "pub const {}: &[(&str, u64)] = &[",
+            self.const_hashes_name()
+        ));
+        w.indented(|w| {
+            for method in &self.methods {
+                w.write_line(format!(
+                    "(\"/{}/{}\", {}),",
+                    self.service_path,
+                    method.proto.get_name(),
+                    method.const_hash_name()
+                ));
+            }
+        });
+        w.write_line("];");
+        w.write_line("");
+        w.write_line(format!(
+            "/// Hash of `{}`, which changes with any of its methods.",
+            self.service_path
+        ));
+        w.write_line(format!(
+            "pub const {}_SERVICE_HASH: u64 = 0x{:016x};",
+            to_snake_case(&self.service_name()).to_uppercase(),
+            fnv1a(&all)
+        ));
+    }
+
+    /// An enum of the methods, so middleware can match on calls
+    /// exhaustively and fails to build once the service gains a method.
+    fn write_method_enum(&self, w: &mut CodeWriter) {
+        let name = self.method_enum_name();
+        w.write_line(format!("/// The methods of `{}`.", self.service_path));
+        w.write_line("#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]");
+        w.pub_enum(&name, |w| {
+            for method in &self.methods {
+                w.write_line(format!("{},", method.struct_name()));
+            }
+        });
+
+        w.write_line("");
+
+        w.impl_self_block(&name, |w| {
+            w.write_line(format!(
+                "/// The method at `path`, if it is one of `{}`.",
+                self.service_path
+            ));
+            w.pub_fn(
+                "from_path(path: &str) -> ::std::option::Option<Self>",
+                |w| {
+                    w.match_expr("path", |w| {
+                        for method in &self.methods {
+                            w.case_expr(
+                                method.const_path_name(),
+                                format!(
+                                    "::std::option::Option::Some({}::{})",
+                                    name,
+                                    method.struct_name()
+                                ),
+                            );
+                        }
+                        w.case_expr("_", "::std::option::Option::None");
+                    });
+                },
+            );
+            w.write_line("");
+            w.write_line("/// The path the method is called at.");
+            w.pub_fn("as_path(&self) -> &'static str", |w| {
+                w.match_expr("*self", |w| {
+                    for method in &self.methods {
+                        w.case_expr(
+                            format!("{}::{}", name, method.struct_name()),
+                            method.const_path_name(),
+                        );
+                    }
+                });
+            });
+        });
+    }
+
     fn write_client(&self, w: &mut CodeWriter) {
+        let client_type = if self.customize.async_client {
+            fq_grpc("asynchronous::Client")
+        } else {
+            fq_grpc("Client")
+        };
+
         w.write_line("#[derive(Clone)]");
         w.pub_struct(&self.client_name(), |w| {
-            w.field_decl("client", "::ttrpc::Client");
+            w.field_decl("client", &client_type);
         });
 
         w.write_line("");
 
         w.impl_self_block(&self.client_name(), |w| {
-            w.pub_fn("new(client: ::ttrpc::Client) -> Self", |w| {
+            w.pub_fn(&format!("new(client: {}) -> Self", client_type), |w| {
                 w.expr_block(&self.client_name(), |w| {
                     w.field_entry("client", "client");
                 });
@@ -418,12 +798,34 @@ impl<'a> ServiceGen<'a> {
                 w.write_line("");
                 method.write_client(w);
             }
+
+            // the async client has no check_methods
+            if self.customize.async_client {
+                return;
+            }
+
+            w.write_line("");
+            w.pub_fn(
+                "check_methods(&self, timeout: std::time::Duration) -> ::ttrpc::Result<()>",
+                |w| {
+                    w.write_line(format!(
+                        "self.client.check_methods({}, timeout)",
+                        self.const_hashes_name()
+                    ));
+                },
+            );
         });
     }
 
     fn write_server(&self, w: &mut CodeWriter) {
-        w.pub_trait(&self.service_name(), |w| {
-            for method in &self.methods {
+        let trait_name = if self.customize.async_server {
+            w.write_line("#[::ttrpc::asynchronous::async_trait]");
+            format!("{}: Sync", self.service_name())
+        } else {
+            self.service_name()
+        };
+        w.pub_trait(&trait_name, |w| {
+            for method in self.methods.iter().filter(|m| m.served()) {
                 method.write_service(w);
             }
         });
@@ -431,19 +833,213 @@ impl<'a> ServiceGen<'a> {
         w.write_line("");
 
         let s = format!(
-            "create_{}(service: Arc<std::boxed::Box<dyn {} + Send + Sync>>) -> HashMap <String, Box<dyn ::ttrpc::MethodHandler + Send + Sync>>",
-            to_snake_case(&self.service_name()), self.service_name()
+            "create_{}(service: Arc<std::boxed::Box<dyn {} + Send + Sync>>) -> HashMap <String, Box<dyn {} + Send + Sync>>",
+            to_snake_case(&self.service_name()), self.service_name(), handler_trait(self.customize)
         );
 
         w.pub_fn(&s, |w| {
             w.write_line("let mut methods = HashMap::new();");
-            for method in &self.methods[0..self.methods.len()] {
+            for method in self.methods.iter().filter(|m| m.served()) {
                 w.write_line("");
                 method.write_bind(w);
             }
             w.write_line("");
             w.write_line("methods");
         });
+
+        // middleware and ::ttrpc::Service are for blocking servers only
+        if self.customize.async_server {
+            if self.customize.tower_service {
+                w.write_line("");
+                self.write_tower(w);
+            }
+            return;
+        }
+
+        w.write_line("");
+
+        let s = format!(
+            "create_{}_with(service: Arc<std::boxed::Box<dyn {} + Send + Sync>>, prefix: &str, middleware: Option<::ttrpc::Middleware>) -> HashMap <String, Box<dyn ::ttrpc::MethodHandler + Send + Sync>>",
+            to_snake_case(&self.service_name()), self.service_name()
+        );
+
+        w.pub_fn(&s, |w| {
+            w.write_line(format!(
+                "::ttrpc::wrap_service(create_{}(service), prefix, middleware)",
+                to_snake_case(&self.service_name())
+            ));
+        });
+
+        w.write_line("");
+        self.write_registration(w);
+    }
+
+    fn tower_name(&self) -> String {
+        format!("{}Tower", self.service_name())
+    }
+
+    /// A `tower::Service` dispatching calls to the async handlers, so the
+    /// service can be wrapped in tower layers before it is served.
+    fn write_tower(&self, w: &mut CodeWriter) {
+        let service_type = format!(
+            "Arc<std::boxed::Box<dyn {} + Send + Sync>>",
+            self.service_name()
+        );
+
+        w.write_line("#[derive(Clone)]");
+        w.pub_struct(&self.tower_name(), |w| {
+            w.field_decl("methods", "Arc<::ttrpc::asynchronous::tower::Methods>");
+        });
+
+        w.write_line("");
+
+        w.impl_self_block(&self.tower_name(), |w| {
+            w.pub_fn(&format!("new(service: {}) -> Self", service_type), |w| {
+                w.expr_block(&self.tower_name(), |w| {
+                    w.field_entry(
+                        "methods",
+                        &format!(
+                            "Arc::new(create_{}(service))",
+                            to_snake_case(&self.service_name())
+                        ),
+                    );
+                });
+            });
+
+            w.write_line("");
+
+            w.write_line("/// Paths of the methods served, to register the service under.");
+            w.pub_fn("paths() -> &'static [&'static str]", |w| {
+                let paths: Vec<String> = self
+                    .methods
+                    .iter()
+                    .filter(|m| m.served())
+                    .map(|m| m.const_path_name())
+                    .collect();
+                w.write_line(format!("&[{}]", paths.join(", ")));
+            });
+        });
+
+        w.write_line("");
+
+        w.impl_for_block(
+            "::ttrpc::asynchronous::tower::Service<::ttrpc::asynchronous::tower::Call>",
+            &self.tower_name(),
+            |w| {
+                w.write_line("type Response = ::ttrpc::Response;");
+                w.write_line("type Error = ::ttrpc::Error;");
+                w.write_line("type Future = ::ttrpc::asynchronous::tower::BoxFuture;");
+                w.write_line("");
+                w.def_fn(
+                    "poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<::ttrpc::Result<()>>",
+                    |w| {
+                        w.write_line("std::task::Poll::Ready(Ok(()))");
+                    },
+                );
+                w.write_line("");
+                w.def_fn(
+                    "call(&mut self, call: ::ttrpc::asynchronous::tower::Call) -> Self::Future",
+                    |w| {
+                        w.write_line(
+                            "::ttrpc::asynchronous::tower::dispatch(self.methods.clone(), call)",
+                        );
+                    },
+                );
+            },
+        );
+    }
+
+    fn write_registration(&self, w: &mut CodeWriter) {
+        let service_type = format!(
+            "Arc<std::boxed::Box<dyn {} + Send + Sync>>",
+            self.service_name()
+        );
+
+        w.pub_struct(&self.registration_name(), |w| {
+            w.field_decl("service", &service_type);
+        });
+
+        w.write_line("");
+
+        w.impl_self_block(&self.registration_name(), |w| {
+            w.pub_fn(&format!("new(service: {}) -> Self", service_type), |w| {
+                w.expr_block(&self.registration_name(), |w| {
+                    w.field_entry("service", "service");
+                });
+            });
+        });
+
+        w.write_line("");
+
+        w.impl_for_block("::ttrpc::Service", &self.registration_name(), |w| {
+            w.def_fn("name(&self) -> &str", |w| {
+                w.write_line(format!("\"{}\"", self.service_path));
+            });
+
+            w.write_line("");
+
+            w.def_fn(
+                "methods(&self) -> HashMap<String, Box<dyn ::ttrpc::MethodHandler + Send + Sync>>",
+                |w| {
+                    w.write_line(format!(
+                        "create_{}(self.service.clone())",
+                        to_snake_case(&self.service_name())
+                    ));
+                },
+            );
+
+            w.write_line("");
+
+            w.def_fn("method_hashes(&self) -> &[(&str, u64)]", |w| {
+                w.write_line(self.const_hashes_name());
+            });
+        });
+    }
+
+    fn harness_name(&self) -> String {
+        format!("{}Harness", self.service_name())
+    }
+
+    /// A server over one end of a socketpair, serving the implementation a
+    /// test passes, and a client on the other end.
+    fn write_harness(&self, w: &mut CodeWriter) {
+        w.write_line(format!(
+            "/// `{}` served in process, for tests calling it through `client`.",
+            self.service_path
+        ));
+        w.write_line("#[cfg(test)]");
+        w.pub_struct(&self.harness_name(), |w| {
+            w.field_decl("pub client", &self.client_name());
+            w.field_decl("pub server", "::ttrpc::Server");
+        });
+
+        w.write_line("");
+
+        w.write_line("#[cfg(test)]");
+        w.impl_self_block(&self.harness_name(), |w| {
+            let sig = format!(
+                "new(service: std::boxed::Box<dyn {} + Send + Sync>) -> ::ttrpc::Result<Self>",
+                self.service_name()
+            );
+            w.pub_fn(&sig, |w| {
+                w.write_line("let (fd, client) = ::ttrpc::pair()?;");
+                w.write_line(format!(
+                    "let service = Arc::new({}::new(Arc::new(service)));",
+                    self.registration_name()
+                ));
+                w.write_line("let mut server = ::ttrpc::Server::builder()");
+                w.indented(|w| {
+                    w.write_line(".register(service)");
+                    w.write_line(".add_connection(std::os::unix::io::IntoRawFd::into_raw_fd(fd))");
+                    w.write_line(".build()?;");
+                });
+                w.write_line("server.start()?;");
+                w.block("Ok(Self {", "})", |w| {
+                    w.field_entry("client", &format!("{}::new(client)", self.client_name()));
+                    w.field_entry("server", "server");
+                });
+            });
+        });
     }
 
     fn write_method_definitions(&self, w: &mut CodeWriter) {
@@ -457,7 +1053,7 @@ impl<'a> ServiceGen<'a> {
     }
 
     fn write_method_handlers(&self, w: &mut CodeWriter) {
-        for (i, method) in self.methods.iter().enumerate() {
+        for (i, method) in self.methods.iter().filter(|m| m.served()).enumerate() {
             if i != 0 {
                 w.write_line("");
             }
@@ -467,11 +1063,24 @@ impl<'a> ServiceGen<'a> {
     }
 
     fn write(&self, w: &mut CodeWriter) {
+        self.write_paths(w);
+        w.write_line("");
+        self.write_hashes(w);
+        w.write_line("");
+        self.write_method_enum(w);
+        w.write_line("");
         self.write_client(w);
         w.write_line("");
         self.write_method_handlers(w);
         w.write_line("");
         self.write_server(w);
+        if self.customize.test_harness
+            && !self.customize.async_server
+            && !self.customize.async_client
+        {
+            w.write_line("");
+            self.write_harness(w);
+        }
     }
 }
 
@@ -510,12 +1119,14 @@ fn write_generated_common(w: &mut CodeWriter) {
 fn gen_file(
     file: &FileDescriptorProto,
     root_scope: &RootScope,
+    customize: &Customize,
 ) -> Option<compiler_plugin::GenResult> {
     if file.get_service().is_empty() {
         return None;
     }
 
-    let base = protobuf::descriptorx::proto_path_to_rust_mod(file.get_name());
+    let rust_mod = customize.rust_mod(file);
+    let base = rust_mod.rsplit("::").next().unwrap_or_default().to_string();
 
     let mut v = Vec::new();
     {
@@ -527,9 +1138,26 @@ fn gen_file(
         w.write_line("use std::collections::HashMap;");
         w.write_line("use std::sync::Arc;");
 
+        if customize.embed_descriptors {
+            let bytes = file.write_to_bytes().expect("serialize descriptor");
+            w.write_line("");
+            w.write_line(format!(
+                "/// Serialized `FileDescriptorProto` of `{}`.",
+                file.get_name()
+            ));
+            w.write_line("pub const FILE_DESCRIPTOR: &[u8] = &[");
+            w.indented(|w| {
+                for chunk in bytes.chunks(16) {
+                    let line: Vec<String> = chunk.iter().map(|b| format!("0x{:02x},", b)).collect();
+                    w.write_line(line.join(" "));
+                }
+            });
+            w.write_line("];");
+        }
+
         for service in file.get_service() {
             w.write_line("");
-            ServiceGen::new(service, file, root_scope).write(&mut w);
+            ServiceGen::new(service, file, root_scope, customize).write(&mut w);
         }
     }
 
@@ -542,6 +1170,14 @@ fn gen_file(
 pub fn gen(
     file_descriptors: &[FileDescriptorProto],
     files_to_generate: &[String],
+) -> Vec<compiler_plugin::GenResult> {
+    gen_with_customize(file_descriptors, files_to_generate, &Customize::default())
+}
+
+pub fn gen_with_customize(
+    file_descriptors: &[FileDescriptorProto],
+    files_to_generate: &[String],
+    customize: &Customize,
 ) -> Vec<compiler_plugin::GenResult> {
     let files_map: HashMap<&str, &FileDescriptorProto> =
         file_descriptors.iter().map(|f| (f.get_name(), f)).collect();
@@ -557,7 +1193,7 @@ pub fn gen(
             continue;
         }
 
-        results.extend(gen_file(file, &root_scope).into_iter());
+        results.extend(gen_file(file, &root_scope, customize).into_iter());
     }
 
     results
@@ -568,7 +1204,21 @@ pub fn gen_and_write(
     files_to_generate: &[String],
     out_dir: &Path,
 ) -> io::Result<()> {
-    let results = gen(file_descriptors, files_to_generate);
+    gen_and_write_with_customize(
+        file_descriptors,
+        files_to_generate,
+        out_dir,
+        &Customize::default(),
+    )
+}
+
+pub fn gen_and_write_with_customize(
+    file_descriptors: &[FileDescriptorProto],
+    files_to_generate: &[String],
+    out_dir: &Path,
+    customize: &Customize,
+) -> io::Result<()> {
+    let results = gen_with_customize(file_descriptors, files_to_generate, customize);
 
     for r in &results {
         let mut file_path = out_dir.to_owned();
@@ -93,6 +93,14 @@ pub fn to_camel_case(name: &str) -> String {
     camel_case_name
 }
 
+/// 64-bit FNV-1a of `bytes`. Generated hashes must not change between
+/// releases, so no `std::hash` here.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 pub fn fq_grpc(item: &str) -> String {
     format!("::ttrpc::{}", item)
 }
@@ -163,4 +171,11 @@ mod test {
             assert_eq!(res, exp);
         }
     }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(super::fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(super::fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(super::fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
 }
@@ -16,8 +16,7 @@ mod protocols;
 
 use std::env;
 use std::thread;
-
-use nix::sys::socket::*;
+use std::time::Duration;
 
 use ttrpc::client::Client;
 
@@ -29,22 +28,13 @@ fn main() {
         panic!("Usage: {} unix_addr", args[0]);
     }
 
-    let fd = socket(
-        AddressFamily::Unix,
-        SockType::Stream,
-        SockFlag::empty(),
-        None,
-    )
-    .unwrap();
-    let sockaddr = args[1].clone() + &"\x00".to_string();
-    let sockaddr = UnixAddr::new_abstract(sockaddr.as_bytes()).unwrap();
-    let sockaddr = SockAddr::Unix(sockaddr);
-    connect(fd, &sockaddr).unwrap();
-
-    let c = Client::new(fd);
+    let c = Client::connect(&format!("unix://{}", args[1])).unwrap();
     let hc = protocols::health_ttrpc::HealthClient::new(c.clone());
     let ac = protocols::agent_ttrpc::AgentServiceClient::new(c);
 
+    // fail now rather than on the first call if the server is out of date
+    hc.check_methods(Duration::from_secs(1)).unwrap();
+
     let thc = hc.clone();
     let tac = ac.clone();
     let t = thread::spawn(move || {
@@ -95,11 +95,14 @@ fn main() {
     let a = Arc::new(a);
     let aservice = protocols::agent_ttrpc::create_agent_service(a);
 
-    let mut server = Server::new()
+    let mut server = Server::builder()
         .bind("unix:///tmp/1")
-        .unwrap()
         .register_service(hservice)
-        .register_service(aservice);
+        .register_method_hashes(protocols::health_ttrpc::HEALTH_METHOD_HASHES)
+        .register_service(aservice)
+        .register_method_hashes(protocols::agent_ttrpc::AGENT_SERVICE_METHOD_HASHES)
+        .build()
+        .unwrap();
 
     server.start().unwrap();
 
@@ -0,0 +1,307 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stress and soak test: many concurrent clients calling a server in the
+//! same process for a while, then checks for leaked fds and threads,
+//! memory growth and requests which never got an answer.
+//!
+//! ```text
+//! cargo run --release --example stress -- --clients 1000 --duration 600
+//! cargo run --release --example stress -- \
+//!     --listen vsock://-1:1024 --connect vsock://1:1024
+//! ```
+//!
+//! Exits with status 1 if any check failed.
+
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ttrpc::builtin::DIAGNOSTICS_SERVICE;
+use ttrpc::diagnostics::EchoRequest;
+use ttrpc::{Client, Error, Request, Server};
+
+use protobuf::Message;
+
+struct Options {
+    clients: usize,
+    duration: Duration,
+    listen: String,
+    connect: String,
+    payload: usize,
+    reconnect_every: usize,
+    stuck: Duration,
+    max_rss_growth_kb: u64,
+    server_threads: usize,
+}
+
+impl Options {
+    fn parse() -> Options {
+        let default_addr = format!("unix://@ttrpc-stress-{}", process::id());
+        let mut o = Options {
+            clients: 100,
+            duration: Duration::from_secs(10),
+            listen: default_addr.clone(),
+            connect: default_addr,
+            payload: 64,
+            reconnect_every: 0,
+            stuck: Duration::from_secs(5),
+            max_rss_growth_kb: 64 << 10,
+            server_threads: 3,
+        };
+
+        let args: Vec<String> = env::args().skip(1).collect();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().unwrap_or_else(|| usage(flag));
+            let number = || value.parse::<u64>().unwrap_or_else(|_| usage(flag));
+            match flag.as_str() {
+                "--clients" => o.clients = number() as usize,
+                "--duration" => o.duration = Duration::from_secs(number()),
+                "--listen" => o.listen = value.clone(),
+                "--connect" => o.connect = value.clone(),
+                "--payload" => o.payload = number() as usize,
+                "--reconnect-every" => o.reconnect_every = number() as usize,
+                "--stuck-ms" => o.stuck = Duration::from_millis(number()),
+                "--max-rss-growth-kb" => o.max_rss_growth_kb = number(),
+                "--server-threads" => o.server_threads = number() as usize,
+                _ => usage(flag),
+            }
+        }
+        o
+    }
+}
+
+fn usage(flag: &str) -> ! {
+    eprintln!("bad argument: {}", flag);
+    eprintln!(
+        "usage: stress [--clients N] [--duration SECS] [--listen ADDR] [--connect ADDR]
+              [--payload BYTES] [--reconnect-every CALLS] [--stuck-ms MS]
+              [--max-rss-growth-kb KB] [--server-threads N]"
+    );
+    process::exit(2);
+}
+
+/// What the process holds, to compare before and after a run.
+#[derive(Clone, Copy, Debug)]
+struct Usage {
+    fds: usize,
+    threads: usize,
+    rss_kb: u64,
+}
+
+impl Usage {
+    fn sample() -> Usage {
+        let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let field = |name: &str| {
+            status
+                .lines()
+                .find(|l| l.starts_with(name))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        Usage {
+            fds: fs::read_dir("/proc/self/fd")
+                .map(|d| d.count())
+                .unwrap_or(0),
+            threads: field("Threads:") as usize,
+            rss_kb: field("VmRSS:"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    calls: AtomicUsize,
+    errors: AtomicUsize,
+    stuck: AtomicUsize,
+    connects: AtomicUsize,
+}
+
+fn echo_request(payload: usize) -> Request {
+    let mut q = EchoRequest::new();
+    q.set_payload(vec![0x5a; payload]);
+    let mut req = Request::new();
+    req.set_service(DIAGNOSTICS_SERVICE.to_string());
+    req.set_method("Echo".to_string());
+    req.set_payload(q.write_to_bytes().unwrap());
+    req
+}
+
+fn run_client(o: &Options, stop: &AtomicBool, counters: &Counters) {
+    let mut client: Option<Client> = None;
+    let mut calls = 0;
+    while !stop.load(Ordering::Relaxed) {
+        if o.reconnect_every > 0 && calls % o.reconnect_every == 0 {
+            client = None;
+        }
+        let c = match client.as_ref() {
+            Some(c) => c,
+            None => match Client::connect(&o.connect) {
+                Ok(c) => {
+                    counters.connects.fetch_add(1, Ordering::Relaxed);
+                    client.get_or_insert(c)
+                }
+                Err(e) => {
+                    counters.errors.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("connect: {:?}", e);
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            },
+        };
+
+        let (handle, _canceller) = c.call_cancellable(echo_request(o.payload));
+        match handle.wait_timeout(o.stuck) {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+                eprintln!("call: {:?}", e);
+                client = None;
+            }
+            Err(_) => {
+                counters.stuck.fetch_add(1, Ordering::Relaxed);
+                eprintln!("call got no answer within {:?}", o.stuck);
+                client = None;
+            }
+        }
+        counters.calls.fetch_add(1, Ordering::Relaxed);
+        calls += 1;
+    }
+}
+
+fn main() {
+    let o = Arc::new(Options::parse());
+
+    let server = Server::builder()
+        .bind(&o.listen)
+        .set_thread_count_min(1)
+        .set_thread_count_default(o.server_threads.max(2))
+        .set_thread_count_max(o.server_threads.max(2) + 2)
+        .register_diagnostics()
+        .build()
+        .and_then(|s| s.spawn())
+        .unwrap_or_else(|e: Error| {
+            eprintln!("failed to start server on {}: {:?}", o.listen, e);
+            process::exit(2);
+        });
+    thread::sleep(Duration::from_millis(100));
+    let before = Usage::sample();
+    println!(
+        "{} clients for {:?} against {}, baseline {:?}",
+        o.clients, o.duration, o.connect, before
+    );
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let counters = Arc::new(Counters::default());
+    let clients: Vec<_> = (0..o.clients)
+        .map(|i| {
+            let (o, stop, counters) = (o.clone(), stop.clone(), counters.clone());
+            thread::Builder::new()
+                .name(format!("stress-client-{}", i))
+                .stack_size(256 << 10)
+                .spawn(move || run_client(&o, &stop, &counters))
+                .unwrap()
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut last_calls = 0;
+    let mut peak_rss = 0;
+    while start.elapsed() < o.duration {
+        thread::sleep(Duration::from_secs(1));
+        let u = Usage::sample();
+        peak_rss = peak_rss.max(u.rss_kb);
+        let calls = counters.calls.load(Ordering::Relaxed);
+        println!(
+            "{:>5}s calls/s={} errors={} stuck={} connections={} fds={} threads={} rss={}kB",
+            start.elapsed().as_secs(),
+            calls - last_calls,
+            counters.errors.load(Ordering::Relaxed),
+            counters.stuck.load(Ordering::Relaxed),
+            server.connection_count(),
+            u.fds,
+            u.threads,
+            u.rss_kb
+        );
+        last_calls = calls;
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    for c in clients {
+        c.join().unwrap_or(());
+    }
+
+    // wait for the server to notice every client went away
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while server.connection_count() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    thread::sleep(Duration::from_millis(200));
+    let after = Usage::sample();
+
+    let mut failures = Vec::new();
+    if counters.stuck.load(Ordering::SeqCst) > 0 {
+        failures.push(format!(
+            "{} requests got no answer",
+            counters.stuck.load(Ordering::SeqCst)
+        ));
+    }
+    if server.connection_count() > 0 {
+        failures.push(format!(
+            "{} connections still open",
+            server.connection_count()
+        ));
+    }
+    if after.fds > before.fds {
+        failures.push(format!("{} fds leaked", after.fds - before.fds));
+    }
+    if after.threads > before.threads {
+        failures.push(format!("{} threads leaked", after.threads - before.threads));
+    }
+    let growth = after.rss_kb.saturating_sub(before.rss_kb);
+    if growth > o.max_rss_growth_kb {
+        failures.push(format!("rss grew by {}kB", growth));
+    }
+
+    match server.shutdown() {
+        Ok(report) if !report.is_clean() => {
+            failures.push(format!("unclean shutdown: {:?}", report))
+        }
+        Err(e) => failures.push(format!("shutdown failed: {:?}", e)),
+        _ => {}
+    }
+
+    println!(
+        "calls={} errors={} connects={} peak_rss={}kB after {:?}",
+        counters.calls.load(Ordering::SeqCst),
+        counters.errors.load(Ordering::SeqCst),
+        counters.connects.load(Ordering::SeqCst),
+        peak_rss,
+        after
+    );
+    if failures.is_empty() {
+        println!("ok");
+    } else {
+        for f in failures.iter() {
+            println!("FAIL: {}", f);
+        }
+        process::exit(1);
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares the server backends: the threaded one, the async one on a
+//! single-threaded tokio runtime, which waits on epoll, and the io_uring
+//! one. Prints calls per second and server threads for an increasing
+//! number of clients, each calling the diagnostics echo method in a loop.
+//!
+//! ```text
+//! cargo run --release --features uring,async --example uring_bench -- \
+//!     --clients 1,16,64,256 --duration 5 --payload 64
+//! ```
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ttrpc::asynchronous::{self, async_trait, TtrpcContext};
+use ttrpc::builtin::DIAGNOSTICS_SERVICE;
+use ttrpc::diagnostics::{EchoRequest, EchoResponse};
+use ttrpc::{get_status, Client, Code, Request, Response, Server};
+
+use protobuf::Message;
+
+struct Options {
+    clients: Vec<usize>,
+    duration: Duration,
+    payload: usize,
+    workers: usize,
+}
+
+impl Options {
+    fn parse() -> Options {
+        let mut o = Options {
+            clients: vec![1, 16, 64],
+            duration: Duration::from_secs(3),
+            payload: 64,
+            workers: 8,
+        };
+
+        let args: Vec<String> = env::args().skip(1).collect();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().unwrap_or_else(|| usage(flag));
+            let number = |v: &str| v.parse::<u64>().unwrap_or_else(|_| usage(flag));
+            match flag.as_str() {
+                "--clients" => o.clients = value.split(',').map(|v| number(v) as usize).collect(),
+                "--duration" => o.duration = Duration::from_secs(number(value)),
+                "--payload" => o.payload = number(value) as usize,
+                "--workers" => o.workers = number(value) as usize,
+                _ => usage(flag),
+            }
+        }
+        o
+    }
+}
+
+fn usage(flag: &str) -> ! {
+    eprintln!("bad argument: {}", flag);
+    eprintln!(
+        "usage: uring_bench [--clients N,N,...] [--duration SECS] [--payload BYTES]
+                   [--workers N]"
+    );
+    process::exit(2);
+}
+
+/// The server's threads, told apart from the clients' by their names.
+fn server_threads() -> usize {
+    const NAMES: &[&str] = &[
+        "client_handler",
+        "method_handler",
+        "response-",
+        "listener_loop",
+        "reaper",
+        "uring_loop",
+        "uring_worker",
+        "async_loop",
+    ];
+    let tasks = fs::read_dir("/proc/self/task").into_iter().flatten();
+    tasks
+        .filter_map(|t| fs::read_to_string(t.ok()?.path().join("comm")).ok())
+        .filter(|comm| NAMES.iter().any(|n| comm.starts_with(n)))
+        .count()
+}
+
+fn echo_request(payload: usize) -> Request {
+    let mut q = EchoRequest::new();
+    q.set_payload(vec![0x5a; payload]);
+    let mut req = Request::new();
+    req.set_service(DIAGNOSTICS_SERVICE.to_string());
+    req.set_method("Echo".to_string());
+    req.set_payload(q.write_to_bytes().unwrap());
+    req
+}
+
+/// The diagnostics echo method, for the async server, which has no
+/// built-in services.
+struct Echo;
+
+#[async_trait]
+impl asynchronous::MethodHandler for Echo {
+    async fn handler(&self, _ctx: TtrpcContext, req: Request) -> ttrpc::Result<Response> {
+        let q = EchoRequest::parse_from_bytes(&req.payload)
+            .map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+        let mut r = EchoResponse::new();
+        r.set_payload(q.payload);
+        let mut res = Response::new();
+        res.set_status(get_status(Code::OK, "".to_string()));
+        res.set_payload(r.write_to_bytes().unwrap());
+        Ok(res)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Backend {
+    Threads,
+    Epoll,
+    Uring,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Threads => "threads",
+            Backend::Epoll => "epoll",
+            Backend::Uring => "uring",
+        }
+    }
+}
+
+/// A server being benchmarked.
+enum Running {
+    Sync(Box<Server>),
+    // told to stop through the sender, then joined
+    Async(Sender<()>, JoinHandle<()>),
+}
+
+impl Running {
+    fn start(backend: Backend, o: &Options, addr: &str) -> ttrpc::Result<Running> {
+        if let Backend::Epoll = backend {
+            return start_async(addr);
+        }
+        let mut server = Server::builder()
+            .bind(addr)
+            .set_thread_count_min(1)
+            .set_thread_count_default(2)
+            .set_thread_count_max(o.workers.max(3))
+            .register_diagnostics()
+            .build()?;
+        match backend {
+            Backend::Uring => server.start_uring()?,
+            _ => server.start()?,
+        }
+        Ok(Running::Sync(Box::new(server)))
+    }
+
+    fn stop(self) {
+        match self {
+            Running::Sync(server) => {
+                server.shutdown().unwrap();
+            }
+            Running::Async(stop, handle) => {
+                stop.send(()).unwrap();
+                handle.join().unwrap();
+            }
+        }
+    }
+}
+
+/// Serve `addr` with the async server, from a thread of its own running
+/// a single-threaded runtime.
+fn start_async(addr: &str) -> ttrpc::Result<Running> {
+    let mut methods: HashMap<String, Box<dyn asynchronous::MethodHandler + Send + Sync>> =
+        HashMap::new();
+    let path = format!("/{}/Echo", DIAGNOSTICS_SERVICE);
+    methods.insert(path, Box::new(Echo));
+    let mut server = asynchronous::Server::new()
+        .bind(addr)?
+        .register_service(methods);
+
+    let (started_tx, started_rx) = channel();
+    let (stop_tx, stop_rx) = channel::<()>();
+    let handle = thread::Builder::new()
+        .name("async_loop".to_string())
+        .spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async move {
+                started_tx.send(server.start().await).unwrap();
+                // the clients run on threads of their own
+                let stop = tokio::task::spawn_blocking(move || stop_rx.recv());
+                stop.await.unwrap().unwrap_or(());
+                server.shutdown().await.unwrap();
+            });
+        })
+        .unwrap();
+    started_rx.recv().unwrap()?;
+    Ok(Running::Async(stop_tx, handle))
+}
+
+/// Run `clients` clients against `addr` for the configured time, and
+/// return the calls per second and the server's threads at the end of
+/// the run.
+fn run(o: &Options, addr: &str, clients: usize) -> (f64, usize) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..clients)
+        .map(|_| {
+            let (stop, calls) = (stop.clone(), calls.clone());
+            let (addr, req) = (addr.to_string(), echo_request(o.payload));
+            thread::spawn(move || {
+                let c = Client::connect(&addr).unwrap();
+                while !stop.load(Ordering::Relaxed) {
+                    if c.request(req.clone()).is_ok() {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    thread::sleep(o.duration);
+    let threads = server_threads();
+    stop.store(true, Ordering::SeqCst);
+    let n = calls.load(Ordering::SeqCst);
+    let rate = n as f64 / start.elapsed().as_secs_f64();
+    for h in handles {
+        h.join().unwrap();
+    }
+    (rate, threads)
+}
+
+fn main() {
+    let o = Options::parse();
+
+    println!(
+        "{:>8} {:>8} {:>12} {:>8}",
+        "backend", "clients", "calls/s", "threads"
+    );
+    for backend in [Backend::Threads, Backend::Epoll, Backend::Uring] {
+        let name = backend.name();
+        for &clients in o.clients.iter() {
+            let addr = format!("unix://@ttrpc-bench-{}-{}", process::id(), name);
+            let server = match Running::start(backend, &o, &addr) {
+                Ok(server) => server,
+                Err(e) => {
+                    eprintln!("failed to start the {} backend: {}", name, e);
+                    process::exit(2);
+                }
+            };
+            thread::sleep(Duration::from_millis(100));
+
+            let (rate, threads) = run(&o, &addr, clients);
+            println!("{:>8} {:>8} {:>12.0} {:>8}", name, clients, rate, threads);
+            server.stop();
+        }
+    }
+}
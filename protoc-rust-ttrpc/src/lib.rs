@@ -25,6 +25,8 @@ pub struct Codegen {
     rust_protobuf: bool,
     /// Customize rust-protobuf codegen
     pub rust_protobuf_customize: Customize,
+    /// Customize ttrpc codegen
+    customize: ttrpc_compiler::codegen::Customize,
 }
 
 impl Codegen {
@@ -79,6 +81,84 @@ impl Codegen {
         self
     }
 
+    /// Derive `serde::{Serialize, Deserialize}` for generated messages,
+    /// guarded by `#[cfg(<cfg>)]` when `cfg` is given. Implies
+    /// [`rust_protobuf`](Codegen::rust_protobuf).
+    ///
+    /// The crate using the generated code needs `protobuf` with the
+    /// `with-serde` feature and the derive macros in scope, e.g. via
+    /// `#[macro_use] extern crate serde_derive;`.
+    pub fn serde_derive(&mut self, cfg: Option<&str>) -> &mut Self {
+        self.rust_protobuf = true;
+        self.rust_protobuf_customize.serde_derive = Some(true);
+        self.rust_protobuf_customize.serde_derive_cfg = cfg.map(|c| c.to_owned());
+        self
+    }
+
+    /// Map the proto package `package` to the Rust module `module` in
+    /// generated code, e.g. `containerd.services.tasks.v1` to `tasks_v1`.
+    ///
+    /// The module path is relative to the parent of the generated ttrpc
+    /// modules, and the message types of `package` must live there. The
+    /// ttrpc file generated for `package` is named after the last segment
+    /// of `module`.
+    pub fn module_mapping(&mut self, package: &str, module: &str) -> &mut Self {
+        self.customize
+            .module_mapping
+            .insert(package.to_owned(), module.to_owned());
+        self
+    }
+
+    /// Embed the serialized descriptor of each file as `FILE_DESCRIPTOR` in
+    /// the generated ttrpc module, so servers can pass it to
+    /// `Server::register_descriptor` and serve it over reflection.
+    pub fn embed_descriptors(&mut self) -> &mut Self {
+        self.customize.embed_descriptors = true;
+        self
+    }
+
+    /// Decode requests into messages reused across calls, at most `max` of
+    /// them per method, and pass them to the generated service traits by
+    /// reference. See `ttrpc::message_pool`.
+    pub fn message_pool(&mut self, max: usize) -> &mut Self {
+        self.customize.message_pool = Some(max);
+        self
+    }
+
+    /// Generate a `#[cfg(test)]` harness for each service, e.g.
+    /// `GreeterHarness::new(Box::new(MyGreeter))?`, serving the
+    /// implementation in process to the client in its `client` field.
+    pub fn test_harness(&mut self) -> &mut Self {
+        self.customize.test_harness = true;
+        self
+    }
+
+    /// Generate async service traits, registered with
+    /// `ttrpc::asynchronous::Server`. The crate using them needs the
+    /// `async` feature of ttrpc.
+    pub fn async_server(&mut self) -> &mut Self {
+        self.customize.async_server = true;
+        self
+    }
+
+    /// Generate async clients, over `ttrpc::asynchronous::Client`. The
+    /// crate using them needs the `async` feature of ttrpc.
+    pub fn async_client(&mut self) -> &mut Self {
+        self.customize.async_client = true;
+        self
+    }
+
+    /// Generate a `tower::Service` per async service, e.g. `GreeterTower`,
+    /// to wrap in tower layers and serve with
+    /// `ttrpc::asynchronous::tower::methods`. Implies
+    /// [`async_server`](Codegen::async_server); the crate using it needs
+    /// the `tower` feature of ttrpc.
+    pub fn tower_service(&mut self) -> &mut Self {
+        self.customize.async_server = true;
+        self.customize.tower_service = true;
+        self
+    }
+
     /// Like `protoc --rust_out=...` but without requiring `protoc` or `protoc-gen-rust`
     /// commands in `$PATH`.
     pub fn run(&self) -> io::Result<()> {
@@ -91,6 +171,7 @@ impl Codegen {
                 .out_dir(&self.out_dir)
                 .inputs(&self.inputs)
                 .includes(&self.includes)
+                .customize(self.rust_protobuf_customize.clone())
                 .run()
                 .expect("Gen rust protobuf failed.");
         }
@@ -102,10 +183,11 @@ impl Codegen {
         //     .map(|p| p.to_string())
         //     .collect();
 
-        ttrpc_compiler::codegen::gen_and_write(
+        ttrpc_compiler::codegen::gen_and_write_with_customize(
             &p.file_descriptors,
             &p.relative_paths,
             &self.out_dir,
+            &self.customize,
         )
     }
 }
@@ -0,0 +1,331 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access control over which callers may call which methods. See
+//! [`ServerBuilder::set_acl`].
+//!
+//! [`ServerBuilder::set_acl`]: crate::ServerBuilder::set_acl
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::sys::socket::{getsockopt, sockopt};
+use std::os::unix::io::RawFd;
+use std::sync::RwLock;
+
+use crate::error::{Error, Result};
+use crate::metadata::{self, Metadata, AUTHORIZATION_KEY};
+
+/// Who a rule applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Principal {
+    /// Every caller.
+    Any,
+    /// Callers whose peer process runs as this user.
+    Uid(u32),
+    /// Callers whose peer process runs as this group.
+    Gid(u32),
+    /// Callers connected through the listener on this address, as
+    /// reported by [`Server::listen_addresses`](crate::Server::listen_addresses).
+    Listener(String),
+    /// Callers which connected with this identity, see
+    /// [`Client::connect_with_identity`](crate::Client::connect_with_identity).
+    Identity(Vec<u8>),
+    /// Calls carrying this token as [`AUTHORIZATION_KEY`] metadata.
+    Token(String),
+}
+
+/// The methods a [`Principal`] may call.
+#[derive(Clone, Debug)]
+pub struct AclRule {
+    principal: Principal,
+    patterns: Vec<String>,
+}
+
+impl AclRule {
+    pub fn new(principal: Principal) -> AclRule {
+        AclRule {
+            principal,
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Allow the methods whose path matches `pattern`, where `*` matches
+    /// any run of characters and `?` any one, e.g. `/grpc.Health/*`.
+    pub fn allow(mut self, pattern: &str) -> AclRule {
+        self.patterns.push(pattern.to_string());
+        self
+    }
+}
+
+/// The caller of a request, as far as rules are concerned.
+pub(crate) struct Caller<'a> {
+    pub fd: RawFd,
+    pub listener: Option<&'a str>,
+    pub identity: Option<&'a [u8]>,
+    pub metadata: &'a Metadata,
+}
+
+/// Rules deciding which methods callers may call. A call is allowed if
+/// one of the rules applying to its caller allows its method, and denied
+/// with `PERMISSION_DENIED` otherwise.
+///
+/// The rules may be replaced while the server runs, see [`Acl::reload`].
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: RwLock<Vec<AclRule>>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>) -> Acl {
+        Acl {
+            rules: RwLock::new(rules),
+        }
+    }
+
+    /// Parse rules, one per line: a principal followed by the patterns it
+    /// is allowed. Principals are `*`, `uid:N`, `gid:N`, `listener:ADDR`,
+    /// `identity:TEXT` and `token:TEXT`. Empty lines and those starting
+    /// with `#` are skipped.
+    ///
+    /// ```text
+    /// uid:0                   /*
+    /// listener:vsock://-1:1024 /containerd.task.v2.Task/* /grpc.Health/*
+    /// ```
+    pub fn parse(text: &str) -> Result<Vec<AclRule>> {
+        let mut rules = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let principal = words.next().unwrap_or_default();
+            let principal = parse_principal(principal)
+                .ok_or_else(|| Error::Others(format!("acl line {}: bad principal", n + 1)))?;
+            let mut rule = AclRule::new(principal);
+            for pattern in words {
+                rule = rule.allow(pattern);
+            }
+            rules.push(rule);
+        }
+        Ok(rules)
+    }
+
+    /// Replace the rules, for the calls checked from now on.
+    pub fn reload(&self, rules: Vec<AclRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    pub(crate) fn permits(&self, caller: &Caller, path: &str) -> bool {
+        let rules = self.rules.read().unwrap();
+        let mut credentials = None;
+        rules.iter().any(|rule| {
+            rule.patterns.iter().any(|p| glob_match(p, path))
+                && applies(&rule.principal, caller, &mut credentials)
+        })
+    }
+}
+
+/// The uid and gid of the process at the other end of `fd`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_ids(fd: RawFd) -> Option<(u32, u32)> {
+    let c = getsockopt(fd, sockopt::PeerCredentials).ok()?;
+    Some((c.uid(), c.gid()))
+}
+
+/// The uid and gid of the process at the other end of `fd`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_ids(fd: RawFd) -> Option<(u32, u32)> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } < 0 {
+        return None;
+    }
+    Some((uid, gid))
+}
+
+/// Whether `principal` is the caller. Credentials are looked up once, if
+/// a rule needs them.
+fn applies(
+    principal: &Principal,
+    caller: &Caller,
+    credentials: &mut Option<Option<(u32, u32)>>,
+) -> bool {
+    let mut creds = || *credentials.get_or_insert_with(|| peer_ids(caller.fd));
+    match principal {
+        Principal::Any => true,
+        Principal::Uid(uid) => creds().is_some_and(|(u, _)| u == *uid),
+        Principal::Gid(gid) => creds().is_some_and(|(_, g)| g == *gid),
+        Principal::Listener(addr) => caller.listener == Some(addr.as_str()),
+        Principal::Identity(id) => caller.identity == Some(id.as_slice()),
+        Principal::Token(token) => metadata::get(caller.metadata, AUTHORIZATION_KEY)
+            .is_some_and(|t| same_secret(t.as_bytes(), token.as_bytes())),
+    }
+}
+
+fn parse_principal(s: &str) -> Option<Principal> {
+    if s == "*" {
+        return Some(Principal::Any);
+    }
+    let (kind, value) = s.split_once(':')?;
+    match kind {
+        "uid" => value.parse().ok().map(Principal::Uid),
+        "gid" => value.parse().ok().map(Principal::Gid),
+        "listener" => Some(Principal::Listener(value.to_string())),
+        "identity" => Some(Principal::Identity(value.as_bytes().to_vec())),
+        "token" => Some(Principal::Token(value.to_string())),
+        _ => None,
+    }
+}
+
+/// Compare in time independent of where `a` and `b` differ.
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of
+/// characters and `?` any one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    // where the last `*` was, and the text it matched up to
+    let mut star = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            // let the `*` match one more character
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let cases = [
+            ("/grpc.Health/Check", "/grpc.Health/Check", true),
+            ("/grpc.Health/*", "/grpc.Health/Check", true),
+            ("/grpc.Health/*", "/grpc.Health/", true),
+            ("/grpc.Health/*", "/grpc.Health", false),
+            ("/*/Check", "/grpc.Health/Check", true),
+            ("/*/Check", "/grpc.Health/Watch", false),
+            ("/x/Get?", "/x/GetA", true),
+            ("/x/Get?", "/x/Get", false),
+            ("/x/Get?", "/x/GetAB", false),
+            ("?", "é", true),
+            // the first `*` has to give back what it took
+            ("*ab*ab", "xabyabab", true),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYcZ", false),
+            ("*", "", true),
+            ("*", "/anything/at/all", true),
+            ("**", "x", true),
+            ("", "", true),
+            ("", "/x/Get", false),
+            ("/x/Get", "", false),
+        ];
+        for (pattern, text, matched) in cases.iter() {
+            assert_eq!(glob_match(pattern, text), *matched, "{} {}", pattern, text);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules = Acl::parse(
+            "# comment\n\n  uid:0 /*\ngid:5\nlistener:vsock://-1:1024 /a/* /b/?\n\
+             identity:sandbox-1 /c/*\ntoken:s3cret /d/*\n* /grpc.Health/*\n",
+        )
+        .unwrap();
+        let principals: Vec<Principal> = rules.iter().map(|r| r.principal.clone()).collect();
+        assert_eq!(
+            principals,
+            vec![
+                Principal::Uid(0),
+                Principal::Gid(5),
+                Principal::Listener("vsock://-1:1024".to_string()),
+                Principal::Identity(b"sandbox-1".to_vec()),
+                Principal::Token("s3cret".to_string()),
+                Principal::Any,
+            ]
+        );
+        assert!(rules[1].patterns.is_empty());
+        assert_eq!(rules[2].patterns, vec!["/a/*", "/b/?"]);
+
+        for bad in ["uid:root /*", "gid:-1", "uid", "user:0", "** /*", ":x"].iter() {
+            match Acl::parse(&format!("* /*\n{}", bad)) {
+                Err(Error::Others(e)) => assert!(e.starts_with("acl line 2"), "{}", e),
+                other => panic!("{} parsed as {:?}", bad, other),
+            }
+        }
+    }
+
+    fn caller<'a>(md: &'a Metadata, listener: Option<&'a str>) -> Caller<'a> {
+        Caller {
+            fd: -1,
+            listener,
+            identity: Some(b"sandbox-1"),
+            metadata: md,
+        }
+    }
+
+    #[test]
+    fn test_permits() {
+        let md = Metadata::new();
+        let anyone = caller(&md, None);
+        // no rules, no calls
+        assert!(!Acl::default().permits(&anyone, "/x/Get"));
+        // uids and gids cannot be looked up on a bad fd
+        let acl = Acl::new(Acl::parse("uid:0 /*\ngid:0 /*").unwrap());
+        assert!(!acl.permits(&anyone, "/x/Get"));
+
+        let acl = Acl::new(
+            Acl::parse("listener:unix://@a /a/*\nidentity:sandbox-1 /b/*\ntoken:s3cret /c/*")
+                .unwrap(),
+        );
+        assert!(acl.permits(&caller(&md, Some("unix://@a")), "/a/Get"));
+        assert!(!acl.permits(&caller(&md, Some("unix://@b")), "/a/Get"));
+        assert!(acl.permits(&anyone, "/b/Get"));
+        assert!(!acl.permits(&anyone, "/c/Get"));
+        let mut with_token = Metadata::new();
+        with_token.insert(AUTHORIZATION_KEY.to_string(), vec!["s3cret".to_string()]);
+        assert!(acl.permits(&caller(&with_token, None), "/c/Get"));
+        with_token.insert(AUTHORIZATION_KEY.to_string(), vec!["s3cre".to_string()]);
+        assert!(!acl.permits(&caller(&with_token, None), "/c/Get"));
+    }
+
+    #[test]
+    fn test_reload() {
+        let md = Metadata::new();
+        let acl = Acl::new(Acl::parse("* /a/*").unwrap());
+        assert!(acl.permits(&caller(&md, None), "/a/Get"));
+        assert!(!acl.permits(&caller(&md, None), "/b/Get"));
+
+        acl.reload(Acl::parse("* /b/*").unwrap());
+        assert!(!acl.permits(&caller(&md, None), "/a/Get"));
+        assert!(acl.permits(&caller(&md, None), "/b/Get"));
+        acl.reload(Vec::new());
+        assert!(!acl.permits(&caller(&md, None), "/b/Get"));
+    }
+}
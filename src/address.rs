@@ -0,0 +1,127 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Addresses built from their parts rather than written out as strings.
+
+use std::fmt;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::io::AsRawFd;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::error::{Error, Result};
+
+/// A host as given to [`ServerBuilder::bind`](crate::ServerBuilder::bind) and
+/// [`Client::connect`](crate::Client::connect), e.g.
+/// `server.bind(Address::vsock(VsockCid::Any, 1024).as_str())`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Address(String);
+
+impl Address {
+    /// The vsock address of `port` on `cid`. Servers listen on any cid,
+    /// whichever is given.
+    pub fn vsock(cid: VsockCid, port: u32) -> Address {
+        Address(format!("vsock://{}:{}", cid, port))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for Address {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Address> for String {
+    fn from(addr: Address) -> String {
+        addr.0
+    }
+}
+
+/// The context id of a vsock peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VsockCid {
+    /// `VMADDR_CID_HYPERVISOR`, the hypervisor.
+    Hypervisor,
+    /// `VMADDR_CID_LOCAL`, this host, over the loopback transport.
+    Local,
+    /// `VMADDR_CID_HOST`, the host, as seen from a guest.
+    Host,
+    /// `VMADDR_CID_ANY`, for servers listening on every cid.
+    Any,
+    /// The cid of a guest, or any other.
+    Id(u32),
+}
+
+// IOCTL_VM_SOCKETS_GET_LOCAL_CID, missing from older libc
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const GET_LOCAL_CID: u64 = 0x7b9;
+
+impl VsockCid {
+    /// The cid of this machine, as `/dev/vsock` tells. Fails where there
+    /// is no vsock transport.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn local() -> Result<VsockCid> {
+        let dev = std::fs::File::open("/dev/vsock")
+            .map_err(|e| Error::Socket(format!("open /dev/vsock: {}", e)))?;
+        let mut cid: u32 = 0;
+        let res = unsafe { libc::ioctl(dev.as_raw_fd(), GET_LOCAL_CID as _, &mut cid) };
+        if res < 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(Error::Socket(format!("get the local vsock cid: {}", e)));
+        }
+        Ok(VsockCid::from(cid))
+    }
+
+    pub fn as_u32(self) -> u32 {
+        match self {
+            VsockCid::Hypervisor => 0,
+            VsockCid::Local => 1,
+            VsockCid::Host => 2,
+            VsockCid::Any => u32::MAX,
+            VsockCid::Id(cid) => cid,
+        }
+    }
+}
+
+impl From<u32> for VsockCid {
+    fn from(cid: u32) -> VsockCid {
+        match cid {
+            0 => VsockCid::Hypervisor,
+            1 => VsockCid::Local,
+            2 => VsockCid::Host,
+            u32::MAX => VsockCid::Any,
+            cid => VsockCid::Id(cid),
+        }
+    }
+}
+
+/// Formats the cid the way addresses spell it, with `-1` for
+/// [`VsockCid::Any`].
+impl fmt::Display for VsockCid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VsockCid::Any => f.write_str("-1"),
+            cid => write!(f, "{}", cid.as_u32()),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The async client, see [`Client`].
+
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use super::rt::{self, Either};
+use super::stream::FdStream;
+use crate::client::{DefaultDialer, Dialer};
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::{ClientConnection, ClientEvent};
+use crate::seccomp;
+use crate::ttrpc::{Code, Request, Response};
+
+// how much is read from a connection at once
+const READ_CHUNK: usize = 64 << 10;
+
+type Call = (Request, oneshot::Sender<Result<Response>>);
+
+/// A client running on the runtime it is created from. Its clones
+/// share one connection, over which any number of calls are made at once
+/// by a single task.
+#[derive(Clone)]
+pub struct Client {
+    calls: mpsc::UnboundedSender<Call>,
+}
+
+impl Client {
+    /// Initialize a new client on the connected socket `fd`, which it
+    /// takes over, from within the runtime.
+    pub fn new(fd: RawFd) -> Result<Client> {
+        seccomp::set_nosigpipe(fd).map_err(|e| Error::Socket(e.to_string()))?;
+        let stream = FdStream::new(fd)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        rt::spawn(connection_loop(stream, rx));
+        Ok(Client { calls: tx })
+    }
+
+    /// Connect to `addr`, like [`crate::Client::connect`].
+    pub async fn connect(addr: &str) -> Result<Client> {
+        let addr = addr.to_string();
+        let fd = rt::unblock(move || DefaultDialer.dial(&addr)).await??;
+        Client::new(fd)
+    }
+
+    /// Send `req` and wait for its response.
+    ///
+    /// A request with a `timeout_nano` is waited for that long, then
+    /// failed with `DEADLINE_EXCEEDED`; the server drops it as well.
+    pub async fn request(&self, req: Request) -> Result<Response> {
+        let timeout = req.timeout_nano;
+        let (tx, rx) = oneshot::channel();
+        self.calls
+            .send((req, tx))
+            .map_err(|_| Error::ConnectionClosed)?;
+
+        let response = async { rx.await.map_err(|_| Error::ConnectionClosed)? };
+        if timeout <= 0 {
+            return response.await;
+        }
+        match rt::timeout(Duration::from_nanos(timeout as u64), response).await {
+            Some(result) => result,
+            None => Err(get_rpc_status(
+                Code::DEADLINE_EXCEEDED,
+                "no response before the request deadline".to_string(),
+            )),
+        }
+    }
+}
+
+/// Write the calls of the clients and hand them their responses, until
+/// the connection closes or every client is dropped. The calls still
+/// waiting then fail with [`Error::ConnectionClosed`].
+async fn connection_loop(stream: FdStream, mut calls: mpsc::UnboundedReceiver<Call>) {
+    let fd = stream.fd();
+    let mut conn = ClientConnection::new();
+    let mut waiting: HashMap<u32, oneshot::Sender<Result<Response>>> = HashMap::new();
+    let mut buf = vec![0u8; READ_CHUNK];
+    'conn: loop {
+        match rt::race(calls.recv(), stream.read(&mut buf)).await {
+            Either::First(call) => {
+                let (req, tx) = match call {
+                    Some(call) => call,
+                    None => break 'conn,
+                };
+                let (stream_id, frame) = match conn.request(&req) {
+                    Ok(sent) => sent,
+                    Err(e) => {
+                        tx.send(Err(e)).unwrap_or(());
+                        continue 'conn;
+                    }
+                };
+                if let Err(e) = stream.write_all(&frame).await {
+                    debug!("writing to connection {} failed: {}", fd, e);
+                    tx.send(Err(Error::Socket(e.to_string()))).unwrap_or(());
+                    break 'conn;
+                }
+                waiting.insert(stream_id, tx);
+            }
+            Either::Second(read) => {
+                match read {
+                    Ok(0) => break 'conn,
+                    Ok(n) => conn.receive(&buf[..n]),
+                    Err(e) => {
+                        debug!("reading from connection {} failed: {}", fd, e);
+                        break 'conn;
+                    }
+                }
+                loop {
+                    match conn.poll_event() {
+                        Ok(Some(ClientEvent::Response(stream_id, res))) => {
+                            if let Some(tx) = waiting.remove(&stream_id) {
+                                tx.send(res).unwrap_or(());
+                            }
+                        }
+                        Ok(Some(ClientEvent::GoAway(grace, reason))) => {
+                            debug!("server going away in {:?}: {}", grace, reason);
+                        }
+                        Ok(Some(_)) => (),
+                        Ok(None) => break,
+                        Err(e) => {
+                            debug!("undecodable response on connection {}: {:?}", fd, e);
+                            break 'conn;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
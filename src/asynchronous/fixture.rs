@@ -0,0 +1,84 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A server to test against, shared by the tests of the async server,
+//! its tower layers and the gRPC gateway.
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::IntoRawFd;
+use std::time::Duration;
+
+use super::{rt, Client, MethodHandler, Server, TtrpcContext};
+use crate::error::{get_status, Result};
+use crate::metadata;
+use crate::ttrpc::{Code, Request, Response};
+
+pub(crate) type Methods = HashMap<String, Box<dyn MethodHandler + Send + Sync>>;
+
+/// Answers with the request payload and metadata, after sleeping for as
+/// many milliseconds as the first byte of the payload says.
+pub(crate) struct Echo;
+
+#[async_trait]
+impl MethodHandler for Echo {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response> {
+        let delay = req.payload.first().copied().unwrap_or(0);
+        rt::timeout(
+            Duration::from_millis(u64::from(delay)),
+            std::future::pending::<()>(),
+        )
+        .await;
+        let mut res = Response::new();
+        res.set_status(get_status(Code::OK, "".to_string()));
+        res.set_metadata(metadata::to_pairs(&ctx.metadata));
+        res.payload = req.payload;
+        Ok(res)
+    }
+}
+
+/// `/test.Echo/Echo` served by [`Echo`].
+pub(crate) fn echo() -> Methods {
+    let mut methods: Methods = HashMap::new();
+    methods.insert("/test.Echo/Echo".to_string(), Box::new(Echo));
+    methods
+}
+
+/// A call of `method` of `test.Echo` with `payload`.
+pub(crate) fn request(method: &str, payload: Vec<u8>, timeout: Duration) -> Request {
+    let mut req = Request::new();
+    req.set_service("test.Echo".to_string());
+    req.set_method(method.to_string());
+    req.payload = payload;
+    req.timeout_nano = timeout.as_nanos() as i64;
+    req
+}
+
+/// A started server of `methods` on a loopback port, and a client of it.
+pub(crate) async fn serve(methods: Methods) -> (Server, Client) {
+    serve_with(Server::new().register_service(methods)).await
+}
+
+/// Like [`serve`], for a server set up further.
+pub(crate) async fn serve_with(server: Server) -> (Server, Client) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = server.add_listener(listener.into_raw_fd()).unwrap();
+    server.start().await.unwrap();
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let client = Client::new(stream.into_raw_fd()).unwrap();
+    (server, client)
+}
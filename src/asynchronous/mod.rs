@@ -0,0 +1,91 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A server and a client running on tokio or smol, for programs with a
+//! runtime of their own. They speak the protocol through the state
+//! machines of [`proto`](crate::proto), so many calls are served at once
+//! on a few threads rather than with threads per connection. Needs the
+//! `async` feature for tokio, or the `smol` feature for smol.
+//!
+//! The compiler generates async service traits and clients for them
+//! when asked to, with `Customize::async_server` and
+//! `Customize::async_client`, and tower services of their methods with
+//! `Customize::tower_service`, see [`tower`](self::tower).
+
+pub mod client;
+#[cfg(test)]
+pub(crate) mod fixture;
+pub(crate) mod rt;
+pub mod server;
+mod stream;
+#[cfg(feature = "tower")]
+pub mod tower;
+
+pub use self::client::Client;
+pub use self::server::{BlockingMethodHandler, MethodHandler, Server, TtrpcContext};
+pub use async_trait::async_trait;
+
+/// Like `request_handler!`, for the handlers of async services.
+#[macro_export]
+macro_rules! async_request_handler {
+    ($class: ident, $ctx: ident, $req: ident, $($server: ident)::+, $req_type: ident, $req_fn: ident) => {
+        // the stream is not Send, so it must be gone before the await
+        let mut req = super::$($server)::+::$req_type::new();
+        {
+            let mut s = CodedInputStream::from_bytes(&$req.payload);
+            if let Err(e) = req.merge_from(&mut s) {
+                let status = ::ttrpc::get_status(::ttrpc::Code::INVALID_ARGUMENT, e.to_string());
+                return Err(::ttrpc::Error::RpcStatus(status));
+            }
+        }
+
+        let mut res = ::ttrpc::Response::new();
+        match $class.service.$req_fn(&$ctx, req).await {
+            Ok(rep) => {
+                res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string()));
+                res.payload.reserve(rep.compute_size() as usize);
+                let mut s = CodedOutputStream::vec(&mut res.payload);
+                rep.write_to(&mut s)
+                    .map_err(::ttrpc::Err_to_Others!(e, ""))?;
+                s.flush().map_err(::ttrpc::Err_to_Others!(e, ""))?;
+            }
+            Err(x) => res.set_status(x.to_status()),
+        }
+        return Ok(res);
+    };
+}
+
+/// Like `client_request!`, for async clients.
+#[macro_export]
+macro_rules! async_client_request {
+    ($self: ident, $req: ident, $timeout_nano: ident, $server: expr, $method: expr, $cres: ident) => {
+        let mut creq = ::ttrpc::Request::new();
+        creq.set_service($server.to_string());
+        creq.set_method($method.to_string());
+        creq.set_timeout_nano($timeout_nano);
+        creq.payload.reserve($req.compute_size() as usize);
+        {
+            let mut s = CodedOutputStream::vec(&mut creq.payload);
+            $req.write_to(&mut s)
+                .map_err(::ttrpc::Err_to_Others!(e, ""))?;
+            s.flush().map_err(::ttrpc::Err_to_Others!(e, ""))?;
+        }
+
+        let res = $self.client.request(creq).await?;
+        let mut s = CodedInputStream::from_bytes(&res.payload);
+        $cres
+            .merge_from(&mut s)
+            .map_err(::ttrpc::Err_to_Others!(e, "Unpack get error "))?;
+    };
+}
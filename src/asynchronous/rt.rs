@@ -0,0 +1,73 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What the async server and client need of their runtime: spawning
+//! tasks, timers and sockets registered with its reactor. tokio provides
+//! them with the `async` feature, smol with the `smol` feature; with
+//! both, tokio does. The channels are tokio's either way, which run on
+//! any executor.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[cfg(not(feature = "async"))]
+mod smol;
+#[cfg(feature = "async")]
+mod tokio;
+
+#[cfg(all(test, not(feature = "async")))]
+pub(crate) use self::smol::block_on;
+#[cfg(not(feature = "async"))]
+pub(crate) use self::smol::{spawn, timeout, unblock, AsyncFd, Task};
+#[cfg(all(test, feature = "async"))]
+pub(crate) use self::tokio::block_on;
+#[cfg(feature = "async")]
+pub(crate) use self::tokio::{spawn, timeout, unblock, AsyncFd, Task};
+
+/// Which of the futures given to [`race`] completed first.
+pub(crate) enum Either<A, B> {
+    First(A),
+    Second(B),
+}
+
+struct Race<A, B> {
+    first: Pin<Box<A>>,
+    second: Pin<Box<B>>,
+}
+
+impl<A: Future, B: Future> Future for Race<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(a) = self.first.as_mut().poll(cx) {
+            return Poll::Ready(Either::First(a));
+        }
+        self.second.as_mut().poll(cx).map(Either::Second)
+    }
+}
+
+/// Wait for the first of `first` and `second` to complete, dropping the
+/// other. `first` is polled first, so it wins when both are ready.
+pub(crate) async fn race<A, B>(first: A, second: B) -> Either<A::Output, B::Output>
+where
+    A: Future,
+    B: Future,
+{
+    Race {
+        first: Box::pin(first),
+        second: Box::pin(second),
+    }
+    .await
+}
@@ -0,0 +1,133 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The runtime shim on smol, see [`rt`](super). Tasks run on smol's
+//! global executor.
+
+use smol::Async;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+use super::{race, Either};
+use crate::error::{Error, Result};
+
+/// A spawned task, left running once dropped.
+pub(crate) struct Task<T> {
+    // None once aborted or joined
+    task: Option<smol::Task<T>>,
+}
+
+impl<T> Task<T> {
+    /// Stop the task at its next await.
+    pub(crate) fn abort(mut self) {
+        // a smol task is cancelled by dropping it
+        self.task.take();
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        match self.task.as_ref() {
+            Some(task) => task.is_finished(),
+            None => true,
+        }
+    }
+
+    /// Wait for the task to complete.
+    pub(crate) async fn join(mut self) -> Result<T> {
+        match self.task.take() {
+            Some(task) => Ok(task.await),
+            None => unreachable!("a task is joined once"),
+        }
+    }
+}
+
+impl<T> Drop for Task<T> {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.detach();
+        }
+    }
+}
+
+/// Run `future` on smol's global executor.
+pub(crate) fn spawn<F>(future: F) -> Task<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Task {
+        task: Some(smol::spawn(future)),
+    }
+}
+
+/// Run `f` on a thread allowed to block.
+pub(crate) async fn unblock<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Ok(smol::unblock(f).await)
+}
+
+/// Wait for `future` at most `duration`, `None` if it did not complete.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    match race(future, smol::Timer::after(duration)).await {
+        Either::First(output) => Some(output),
+        Either::Second(_) => None,
+    }
+}
+
+/// Run `future` to completion on the current thread.
+#[cfg(test)]
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    smol::block_on(future)
+}
+
+/// A non-blocking socket registered with smol's reactor, closed once
+/// dropped.
+pub(crate) struct AsyncFd {
+    inner: Async<OwnedFd>,
+}
+
+impl AsyncFd {
+    /// Take over `fd`, set it non-blocking and register it. `fd` is
+    /// closed if that fails.
+    pub(crate) fn new(fd: RawFd) -> Result<AsyncFd> {
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let inner = Async::new(fd).map_err(err_to_Others!(e, "failed to register fd: "))?;
+        Ok(AsyncFd { inner })
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+
+    /// Run `op` on the fd once it is readable, until it no longer fails
+    /// with `WouldBlock`.
+    pub(crate) async fn read_with<R>(
+        &self,
+        mut op: impl FnMut(RawFd) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.inner.read_with(|fd| op(fd.as_raw_fd())).await
+    }
+
+    /// Like [`AsyncFd::read_with`], once the fd is writable.
+    pub(crate) async fn write_with<R>(
+        &self,
+        mut op: impl FnMut(RawFd) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.inner.write_with(|fd| op(fd.as_raw_fd())).await
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The runtime shim on tokio, see [`rt`](super).
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::error::{Error, Result};
+
+/// A spawned task, left running once dropped.
+pub(crate) struct Task<T> {
+    handle: JoinHandle<T>,
+}
+
+impl<T> Task<T> {
+    /// Stop the task at its next await.
+    pub(crate) fn abort(self) {
+        self.handle.abort();
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Wait for the task to complete.
+    pub(crate) async fn join(self) -> Result<T> {
+        self.handle
+            .await
+            .map_err(err_to_Others!(e, "task failed: "))
+    }
+}
+
+/// Run `future` on the current runtime.
+pub(crate) fn spawn<F>(future: F) -> Task<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Task {
+        handle: tokio::spawn(future),
+    }
+}
+
+/// Run `f` on a thread allowed to block.
+pub(crate) async fn unblock<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(err_to_Others!(e, "blocking task failed: "))
+}
+
+/// Wait for `future` at most `duration`, `None` if it did not complete.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    tokio::time::timeout(duration, future).await.ok()
+}
+
+/// Run `future` to completion on a runtime of its own.
+#[cfg(test)]
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// A non-blocking socket registered with the reactor of the current
+/// runtime, closed once dropped.
+pub(crate) struct AsyncFd {
+    inner: tokio::io::unix::AsyncFd<OwnedFd>,
+}
+
+impl AsyncFd {
+    /// Take over `fd`, set it non-blocking and register it. `fd` is
+    /// closed if that fails.
+    pub(crate) fn new(fd: RawFd) -> Result<AsyncFd> {
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(|e| {
+            Error::Others(format!(
+                "failed to set fd: {} as non block: {}",
+                fd.as_raw_fd(),
+                e
+            ))
+        })?;
+        let inner = tokio::io::unix::AsyncFd::new(fd)
+            .map_err(err_to_Others!(e, "failed to register fd: "))?;
+        Ok(AsyncFd { inner })
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+
+    /// Run `op` on the fd once it is readable, until it no longer fails
+    /// with `WouldBlock`.
+    pub(crate) async fn read_with<R>(
+        &self,
+        mut op: impl FnMut(RawFd) -> io::Result<R>,
+    ) -> io::Result<R> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|fd| op(fd.as_raw_fd())) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Like [`AsyncFd::read_with`], once the fd is writable.
+    pub(crate) async fn write_with<R>(
+        &self,
+        mut op: impl FnMut(RawFd) -> io::Result<R>,
+    ) -> io::Result<R> {
+        loop {
+            let mut guard = self.inner.writable().await?;
+            match guard.try_io(|fd| op(fd.as_raw_fd())) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
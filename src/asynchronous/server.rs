@@ -0,0 +1,521 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The async server, see [`Server`].
+
+use async_trait::async_trait;
+use nix::sys::socket::{bind, listen};
+use nix::unistd::close;
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Semaphore};
+
+use super::rt::{self, AsyncFd, Either, Task};
+use super::stream::{accept, FdStream};
+use crate::common;
+use crate::error::{get_rpc_status, get_status, Error, Result};
+use crate::metadata::{self, Metadata};
+use crate::proto::{
+    encode_frame, encode_response, MessageHeader, ServerConnection, ServerEvent,
+    MESSAGE_LENGTH_MAX, MESSAGE_TYPE_RESPONSE,
+};
+use crate::server::{DecodeErrorKind, EVENT_TARGET};
+use crate::ttrpc::{Code, Request, Response, Status};
+
+// how much is read from a connection at once
+const READ_CHUNK: usize = 64 << 10;
+
+/// How many blocking handlers run at once unless
+/// [`Server::set_blocking_threads`] says otherwise.
+pub const DEFAULT_BLOCKING_THREADS: usize = 16;
+
+/// What a handler knows about the request it serves.
+pub struct TtrpcContext {
+    /// The connection the request arrived on.
+    pub fd: RawFd,
+    pub stream_id: u32,
+    pub metadata: Metadata,
+    /// How long the client waits for the response, 0 for no limit. The
+    /// server answers with `DEADLINE_EXCEEDED` once it passed.
+    pub timeout_nano: i64,
+    identity: Option<Arc<Vec<u8>>>,
+}
+
+impl TtrpcContext {
+    /// The identity the client presented when it connected, if any.
+    pub fn identity(&self) -> Option<&[u8]> {
+        self.identity.as_ref().map(|id| id.as_slice())
+    }
+}
+
+/// Serves the calls to one method, as generated by the compiler for the
+/// services it is asked to generate async servers for.
+#[async_trait]
+pub trait MethodHandler {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response>;
+}
+
+type Methods = HashMap<String, Box<dyn MethodHandler + Send + Sync>>;
+
+/// Serves the calls to one method by blocking, e.g. on heavy computation
+/// or syscalls which would stall the other tasks of the runtime. See
+/// [`Server::blocking_method`].
+pub trait BlockingMethodHandler {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response>;
+}
+
+impl<F> BlockingMethodHandler for F
+where
+    F: Fn(TtrpcContext, Request) -> Result<Response>,
+{
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response> {
+        self(ctx, req)
+    }
+}
+
+type BlockingHandler = Arc<dyn BlockingMethodHandler + Send + Sync>;
+
+/// Runs a blocking handler on a thread of the runtime's blocking pool,
+/// once one of the server's permits for them is free.
+struct BlockingMethod {
+    handler: BlockingHandler,
+    permits: Arc<Semaphore>,
+}
+
+#[async_trait]
+impl MethodHandler for BlockingMethod {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(err_to_Others!(e, "blocking pool closed: "))?;
+        let handler = self.handler.clone();
+        // the permit is held until the handler returns, even when the call
+        // is given up on before
+        rt::unblock(move || {
+            let _permit = permit;
+            handler.handler(ctx, req)
+        })
+        .await?
+    }
+}
+
+/// A server running on the runtime it is started from. Each
+/// connection is one task reading requests, and each request one task
+/// running its handler, so a connection costs no thread of its own.
+///
+/// It serves requests and cancellations, and answers HELLO frames, but
+/// none of the policies of [`crate::Server`] apply. Dropping it shuts it
+/// down like [`Server::shutdown`].
+#[derive(Default)]
+pub struct Server {
+    listeners: Vec<RawFd>,
+    methods: Methods,
+    blocking: HashMap<String, BlockingHandler>,
+    blocking_threads: Option<usize>,
+    quit: Option<watch::Sender<bool>>,
+    tasks: Vec<Task<()>>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Listen on `host`, like [`crate::ServerBuilder::bind`].
+    pub fn bind(mut self, host: &str) -> Result<Server> {
+        let (fd, sockaddr) = common::make_socket(host, true)?;
+
+        if let Err(e) = bind(fd, &sockaddr) {
+            close(fd).unwrap_or(());
+            if e.as_errno() == Some(nix::errno::Errno::EADDRINUSE) {
+                return Err(Error::AddressInUse(host.to_string(), None));
+            }
+            return Err(Error::Others(format!("bind {}: {}", host, e)));
+        }
+        self.listeners.push(fd);
+
+        Ok(self)
+    }
+
+    /// Accept connections on the listening socket `fd`, e.g. one passed by
+    /// systemd. The server takes it over.
+    pub fn add_listener(mut self, fd: RawFd) -> Result<Server> {
+        self.listeners.push(fd);
+
+        Ok(self)
+    }
+
+    pub fn register_service(mut self, methods: Methods) -> Server {
+        self.methods.extend(methods);
+        self
+    }
+
+    /// Serve the method at `path`, e.g. `/grpc.Health/Check`, with a
+    /// handler which blocks. It runs on the runtime's blocking threads,
+    /// at most [`set_blocking_threads`](Server::set_blocking_threads) of
+    /// them across all blocking methods, rather than on the tasks serving
+    /// connections; calls beyond that wait for one to finish.
+    pub fn blocking_method<H>(mut self, path: &str, handler: H) -> Server
+    where
+        H: BlockingMethodHandler + Send + Sync + 'static,
+    {
+        self.blocking.insert(path.to_string(), Arc::new(handler));
+        self
+    }
+
+    /// How many blocking handlers may run at once, by default
+    /// [`DEFAULT_BLOCKING_THREADS`].
+    pub fn set_blocking_threads(mut self, n: usize) -> Server {
+        self.blocking_threads = Some(n);
+        self
+    }
+
+    /// Start accepting connections, from within the runtime.
+    pub async fn start(&mut self) -> Result<()> {
+        let permits = Arc::new(Semaphore::new(
+            self.blocking_threads.unwrap_or(DEFAULT_BLOCKING_THREADS),
+        ));
+        for (path, handler) in self.blocking.drain() {
+            let method = BlockingMethod {
+                handler,
+                permits: permits.clone(),
+            };
+            self.methods.insert(path, Box::new(method));
+        }
+        let methods = Arc::new(std::mem::take(&mut self.methods));
+        let (quit_tx, quit_rx) = watch::channel(false);
+
+        for fd in self.listeners.drain(..) {
+            let listener = AsyncFd::new(fd)?;
+            listen(fd, 10).map_err(|e| Error::Socket(e.to_string()))?;
+            let task = listener_loop(listener, methods.clone(), quit_rx.clone());
+            self.tasks.push(rt::spawn(task));
+        }
+        self.quit = Some(quit_tx);
+
+        Ok(())
+    }
+
+    /// Stop accepting connections, close the listeners, and stop reading
+    /// requests from the connections. The requests being handled are
+    /// still answered, then their connections are closed.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        if let Some(quit) = self.quit.take() {
+            quit.send(true).unwrap_or(());
+        }
+        for task in self.tasks.drain(..) {
+            task.join().await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn listener_loop(listener: AsyncFd, methods: Arc<Methods>, mut quit: watch::Receiver<bool>) {
+    loop {
+        let accepted = match rt::race(quit.changed(), accept(&listener)).await {
+            Either::First(_) => break,
+            Either::Second(accepted) => accepted,
+        };
+        let fd = match accepted {
+            Ok(fd) => fd,
+            Err(e) => {
+                warn!("accept on {} failed: {}", listener.fd(), e);
+                break;
+            }
+        };
+        let stream = match FdStream::new(fd) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("failed to serve connection {}: {:?}", fd, e);
+                continue;
+            }
+        };
+        rt::spawn(serve_connection(stream, methods.clone(), quit.clone()));
+    }
+}
+
+async fn serve_connection(
+    stream: FdStream,
+    methods: Arc<Methods>,
+    mut quit: watch::Receiver<bool>,
+) {
+    let fd = stream.fd();
+    let stream = Arc::new(stream);
+    let writer = stream.clone();
+
+    // the frames to write, from this task and those of the handlers
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let writing = rt::spawn(async move {
+        while let Some(buf) = rx.recv().await {
+            if let Err(e) = writer.write_all(&buf).await {
+                debug!("writing to connection {} failed: {}", fd, e);
+                break;
+            }
+        }
+    });
+
+    let mut conn = ServerConnection::new(MESSAGE_LENGTH_MAX);
+    let mut handlers: HashMap<u32, Task<()>> = HashMap::new();
+    let mut identity = None;
+    let mut buf = vec![0u8; READ_CHUNK];
+    'reading: loop {
+        let read = match rt::race(quit.changed(), stream.read(&mut buf)).await {
+            Either::First(_) => break,
+            Either::Second(read) => read,
+        };
+        match read {
+            Ok(0) => break,
+            Ok(n) => conn.receive(&buf[..n]),
+            Err(e) => {
+                debug!("reading from connection {} failed: {}", fd, e);
+                break;
+            }
+        }
+        handlers.retain(|_, h| !h.is_finished());
+
+        loop {
+            let event = match conn.poll_event() {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(e) => {
+                    // the frame cannot be answered, its stream being unknown, so
+                    // close rather than leave its client waiting
+                    warn!(
+                        target: EVENT_TARGET,
+                        "decode_error fd={} kind={:?} stream=0 method=- error={}",
+                        fd,
+                        DecodeErrorKind::Oversized,
+                        e.to_status().message
+                    );
+                    break 'reading;
+                }
+            };
+            match event {
+                ServerEvent::Request {
+                    stream_id,
+                    request,
+                    wants_reply,
+                    streaming: true,
+                    ..
+                } => {
+                    let path = format!("/{}/{}", request.service, request.method);
+                    debug!("refusing streaming call of {} on fd {}", path, fd);
+                    if wants_reply {
+                        let message = format!(
+                            "{}: streaming calls are not served by the async server",
+                            path
+                        );
+                        let status = get_status(Code::UNIMPLEMENTED, message);
+                        reply(&tx, stream_id, status_response(status));
+                    }
+                }
+                ServerEvent::Request {
+                    stream_id,
+                    request,
+                    wants_reply,
+                    ..
+                } => {
+                    let ctx = TtrpcContext {
+                        fd,
+                        stream_id,
+                        metadata: metadata::from_pairs(request.get_metadata()),
+                        timeout_nano: request.timeout_nano,
+                        identity: identity.clone(),
+                    };
+                    let tx = if wants_reply { Some(tx.clone()) } else { None };
+                    let task = handle(methods.clone(), ctx, request, tx);
+                    handlers.insert(stream_id, rt::spawn(task));
+                }
+                ServerEvent::Undecodable {
+                    stream_id,
+                    wants_reply,
+                    message,
+                } => {
+                    if wants_reply {
+                        let status = get_status(Code::INVALID_ARGUMENT, message);
+                        reply(&tx, stream_id, status_response(status));
+                    }
+                }
+                ServerEvent::Data { stream_id, .. } => {
+                    debug!("dropping data for stream {} on fd {}", stream_id, fd);
+                }
+                ServerEvent::Cancel(stream_id) => {
+                    debug!("client cancelled stream {} on fd {}", stream_id, fd);
+                    if let Some(handler) = handlers.remove(&stream_id) {
+                        handler.abort();
+                    }
+                }
+                ServerEvent::Hello(_) => {
+                    tx.send(conn.hello()).unwrap_or(());
+                }
+                ServerEvent::Identity(id) => {
+                    if identity.is_none() {
+                        identity = Some(Arc::new(id));
+                    } else {
+                        debug!("ignoring identity sent again on fd {}", fd);
+                    }
+                }
+            }
+        }
+    }
+
+    // closed once the handlers still running have replied
+    drop(tx);
+    writing.join().await.unwrap_or(());
+}
+
+/// Run the handler of `req`, replying through `tx` unless it is `None`.
+async fn handle(
+    methods: Arc<Methods>,
+    ctx: TtrpcContext,
+    req: Request,
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+) {
+    let stream_id = ctx.stream_id;
+    let path = format!("/{}/{}", req.service, req.method);
+    let method = match methods.get(&path) {
+        Some(method) => method,
+        None => {
+            if let Some(tx) = tx.as_ref() {
+                let status = get_status(Code::INVALID_ARGUMENT, format!("{} does not exist", path));
+                reply(tx, stream_id, status_response(status));
+            }
+            return;
+        }
+    };
+
+    let timeout = req.timeout_nano;
+    let call = method.handler(ctx, req);
+    let result = if timeout > 0 {
+        match rt::timeout(Duration::from_nanos(timeout as u64), call).await {
+            Some(result) => result,
+            None => Err(get_rpc_status(
+                Code::DEADLINE_EXCEEDED,
+                format!("{} did not answer before the request deadline", path),
+            )),
+        }
+    } else {
+        call.await
+    };
+    let res = match result {
+        Ok(res) => res,
+        Err(e) => status_response(e.to_status()),
+    };
+    if let Some(tx) = tx.as_ref() {
+        reply(tx, stream_id, res);
+    }
+}
+
+fn status_response(status: Status) -> Response {
+    let mut res = Response::new();
+    res.set_status(status);
+    res
+}
+
+fn reply(tx: &mpsc::UnboundedSender<Vec<u8>>, stream_id: u32, res: Response) {
+    let buf = match encode_response(&res) {
+        Ok(buf) => buf,
+        Err(e) => {
+            warn!(
+                "failed to encode the response on stream {}: {:?}",
+                stream_id, e
+            );
+            return;
+        }
+    };
+    let mh = MessageHeader {
+        stream_id,
+        type_: MESSAGE_TYPE_RESPONSE,
+        ..Default::default()
+    };
+    tx.send(encode_frame(mh, &buf)).unwrap_or(());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asynchronous::fixture::{echo, request, serve, serve_with};
+
+    #[test]
+    fn test_async_request() {
+        rt::block_on(async {
+            let (mut server, client) = serve(echo()).await;
+            let res = client
+                .request(request("Echo", vec![0, 1, 2], Duration::from_secs(5)))
+                .await
+                .unwrap();
+            assert_eq!(res.payload, vec![0, 1, 2]);
+
+            // timed out by both the client and the server
+            let late = client
+                .request(request("Echo", vec![200], Duration::from_millis(20)))
+                .await;
+            match late {
+                Err(Error::RpcStatus(s)) => assert_eq!(s.get_code(), Code::DEADLINE_EXCEEDED),
+                r => panic!("expected the deadline to pass, got {:?}", r),
+            }
+            server.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_blocking_method() {
+        rt::block_on(async {
+            let sleep = |_ctx: TtrpcContext, req: Request| -> Result<Response> {
+                std::thread::sleep(Duration::from_millis(100));
+                let mut res = status_response(get_status(Code::OK, "".to_string()));
+                res.payload = req.payload;
+                Ok(res)
+            };
+            let server = Server::new()
+                .register_service(echo())
+                .blocking_method("/test.Echo/Sleep", sleep)
+                .set_blocking_threads(1);
+            let (mut server, client) = serve_with(server).await;
+            let client = Arc::new(client);
+            let start = std::time::Instant::now();
+            let sleeping: Vec<_> = (0..2u8)
+                .map(|i| {
+                    let client = client.clone();
+                    rt::spawn(async move {
+                        let req = request("Sleep", vec![i], Duration::from_secs(5));
+                        client.request(req).await
+                    })
+                })
+                .collect();
+
+            // served by the runtime's only thread while both sleep
+            let res = client
+                .request(request("Echo", vec![0, 1], Duration::from_secs(5)))
+                .await
+                .unwrap();
+            assert_eq!(res.payload, vec![0, 1]);
+            assert!(start.elapsed() < Duration::from_millis(100));
+
+            for (i, call) in sleeping.into_iter().enumerate() {
+                let res = call.join().await.unwrap().unwrap();
+                assert_eq!(res.payload, vec![i as u8]);
+            }
+            // one after the other, with a single blocking thread
+            assert!(start.elapsed() >= Duration::from_millis(200));
+            server.shutdown().await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sockets of any family registered with the reactor of the runtime.
+
+use nix::unistd::read;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use super::rt::AsyncFd;
+use crate::channel::send_nosignal;
+use crate::error::Result;
+use crate::seccomp::accept_cloexec;
+
+fn io_error(e: nix::Error) -> io::Error {
+    io::Error::from_raw_os_error(e.as_errno().map_or(libc::EIO, |e| e as i32))
+}
+
+/// Accept a connection on `listener`, once there is one.
+pub(crate) async fn accept(listener: &AsyncFd) -> io::Result<RawFd> {
+    listener
+        .read_with(|fd| accept_cloexec(fd).map_err(io_error))
+        .await
+}
+
+/// A connected socket, closed once dropped. Unlike tokio's `UnixStream`,
+/// it takes vsock sockets too. Reading and writing take `&self`, so one
+/// task reads while another writes.
+pub(crate) struct FdStream {
+    inner: AsyncFd,
+}
+
+impl FdStream {
+    /// Take over the connected socket `fd`.
+    pub(crate) fn new(fd: RawFd) -> Result<FdStream> {
+        Ok(FdStream {
+            inner: AsyncFd::new(fd)?,
+        })
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.inner.fd()
+    }
+
+    /// Read what is there into `buf`, once there is something; 0 once
+    /// the peer closed the connection.
+    pub(crate) async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner
+            .read_with(|fd| read(fd, buf).map_err(io_error))
+            .await
+    }
+
+    /// Write all of `buf`.
+    pub(crate) async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = self
+                .inner
+                .write_with(|fd| send_nosignal(fd, buf).map_err(io_error))
+                .await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! tower layers around the methods of async services, e.g. timeouts,
+//! rate limits or load shedding. Needs the `tower` feature.
+//!
+//! The compiler generates a `tower::Service` of [`Call`]s for each async
+//! service when asked to, with `Customize::tower_service`. Wrapped in
+//! layers, it is served again through [`methods`].
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use super::server::{MethodHandler, TtrpcContext};
+use crate::error::{get_rpc_status, Error, Result};
+use crate::ttrpc::{Code, Request, Response};
+
+pub use ::tower::Service;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What the services generated for `tower` return.
+pub type BoxFuture = Pin<Box<dyn Future<Output = Result<Response>> + Send>>;
+
+/// The method handlers of an async service, by path.
+pub type Methods = HashMap<String, Box<dyn MethodHandler + Send + Sync>>;
+
+/// A call to a method, as the services generated for `tower` take it.
+pub struct Call {
+    pub ctx: TtrpcContext,
+    pub req: Request,
+}
+
+/// Serve `call` with the handler of its method among `methods`, as the
+/// generated services do.
+pub fn dispatch(methods: Arc<Methods>, call: Call) -> BoxFuture {
+    Box::pin(async move {
+        let path = format!("/{}/{}", call.req.service, call.req.method);
+        match methods.get(&path) {
+            Some(method) => method.handler(call.ctx, call.req).await,
+            None => Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("{} does not exist", path),
+            )),
+        }
+    })
+}
+
+/// The ttrpc error matching what a layer failed a call with: a
+/// timeout is `DEADLINE_EXCEEDED`, a shed call `UNAVAILABLE`.
+fn to_error(e: BoxError) -> Error {
+    let e = match e.downcast::<Error>() {
+        Ok(e) => return *e,
+        Err(e) => e,
+    };
+    if e.is::<::tower::timeout::error::Elapsed>() {
+        get_rpc_status(Code::DEADLINE_EXCEEDED, e.to_string())
+    } else if e.is::<::tower::load_shed::error::Overloaded>() {
+        get_rpc_status(Code::UNAVAILABLE, e.to_string())
+    } else {
+        Error::Others(e.to_string())
+    }
+}
+
+struct TowerMethod<S> {
+    // cloned for each call, as calling takes it mutably
+    service: Mutex<S>,
+}
+
+#[async_trait]
+impl<S> MethodHandler for TowerMethod<S>
+where
+    S: Service<Call, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+{
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response> {
+        let mut service = self.service.lock().unwrap().clone();
+        poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .map_err(|e| to_error(e.into()))?;
+        service
+            .call(Call { ctx, req })
+            .await
+            .map_err(|e| to_error(e.into()))
+    }
+}
+
+/// Serve the methods at `paths` with `service`, for
+/// [`Server::register_service`](super::Server::register_service).
+pub fn methods<S>(paths: &[&str], service: S) -> Methods
+where
+    S: Service<Call, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+{
+    let mut methods: Methods = HashMap::new();
+    for path in paths {
+        let method = TowerMethod {
+            service: Mutex::new(service.clone()),
+        };
+        methods.insert(path.to_string(), Box::new(method));
+    }
+    methods
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asynchronous::fixture::{echo, request, serve};
+    use crate::asynchronous::rt;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    /// What the compiler generates for a service with an `Echo` method.
+    #[derive(Clone)]
+    struct EchoTower {
+        methods: Arc<Methods>,
+    }
+
+    impl Service<Call> for EchoTower {
+        type Response = Response;
+        type Error = Error;
+        type Future = BoxFuture;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, call: Call) -> BoxFuture {
+            dispatch(self.methods.clone(), call)
+        }
+    }
+
+    #[test]
+    fn test_tower_layers() {
+        rt::block_on(async {
+            let service = ::tower::ServiceBuilder::new()
+                .timeout(Duration::from_millis(20))
+                .service(EchoTower {
+                    methods: Arc::new(echo()),
+                });
+            let (mut server, client) = serve(methods(&["/test.Echo/Echo"], service)).await;
+            let timeout = Duration::from_secs(5);
+            let res = client
+                .request(request("Echo", vec![0, 1], timeout))
+                .await
+                .unwrap();
+            assert_eq!(res.payload, vec![0, 1]);
+
+            // cut short by the timeout layer
+            match client.request(request("Echo", vec![200], timeout)).await {
+                Err(Error::RpcStatus(s)) => assert_eq!(s.get_code(), Code::DEADLINE_EXCEEDED),
+                r => panic!("expected the layer to time out, got {:?}", r),
+            }
+            server.shutdown().await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Services shipped with the crate which can be registered on any [`Server`].
+//!
+//! [`Server`]: crate::Server
+
+use protobuf::descriptor::{FileDescriptorProto, MethodDescriptorProto, ServiceDescriptorProto};
+use protobuf::{CodedInputStream, CodedOutputStream, Message, RepeatedField};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::client::Client;
+use crate::diagnostics::*;
+use crate::error::{get_rpc_status, get_status, Error, Result};
+use crate::reflection::*;
+use crate::server::{DebugHandle, MethodHandler, TtrpcContext};
+use crate::ttrpc::{Code, Request, Response};
+
+pub const DIAGNOSTICS_SERVICE: &str = "ttrpc.diagnostics.Diagnostics";
+pub const REFLECTION_SERVICE: &str = "ttrpc.reflection.Reflection";
+
+/// Serialized `FileDescriptorProto`s registered with a server, see
+/// [`ServerBuilder::register_descriptor`].
+///
+/// [`ServerBuilder::register_descriptor`]: crate::ServerBuilder::register_descriptor
+pub type Descriptors = Arc<RwLock<Vec<Vec<u8>>>>;
+
+fn now_nano() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+/// Decode the payload of `req`, run `f` on it and send back its result,
+/// the same way `request_handler!` does for generated services.
+fn handle<Q, R, F>(ctx: TtrpcContext, req: Request, f: F) -> Result<()>
+where
+    Q: Message,
+    R: Message,
+    F: FnOnce(Q) -> Result<R>,
+{
+    let mut s = CodedInputStream::from_bytes(&req.payload);
+    let mut q = Q::new();
+    if let Err(e) = q.merge_from(&mut s) {
+        return ctx.undecodable(e.to_string());
+    }
+
+    let result = f(q);
+    if !ctx.sink().wants_reply() {
+        return Ok(());
+    }
+    let mut res = Response::new();
+    match result {
+        Ok(r) => {
+            res.set_status(get_status(Code::OK, "".to_string()));
+            res.payload.reserve(r.compute_size() as usize);
+            let mut s = CodedOutputStream::vec(&mut res.payload);
+            r.write_to(&mut s).map_err(err_to_Others!(e, ""))?;
+            s.flush().map_err(err_to_Others!(e, ""))?;
+        }
+        Err(x) => res.set_status(x.to_status()),
+    }
+    ctx.sink().send(res)
+}
+
+struct EchoMethod;
+
+impl MethodHandler for EchoMethod {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        handle(ctx, req, |q: EchoRequest| {
+            let mut r = EchoResponse::new();
+            r.set_payload(q.payload);
+            Ok(r)
+        })
+    }
+}
+
+struct PingMethod;
+
+impl MethodHandler for PingMethod {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        handle(ctx, req, |q: PingRequest| {
+            let mut r = PingResponse::new();
+            r.set_client_timestamp_nano(q.timestamp_nano);
+            r.set_server_timestamp_nano(now_nano());
+            Ok(r)
+        })
+    }
+}
+
+struct InfoMethod {
+    started: Instant,
+    debug: DebugHandle,
+}
+
+impl MethodHandler for InfoMethod {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        handle(ctx, req, |_: InfoRequest| {
+            let mut r = InfoResponse::new();
+            r.set_version(env!("CARGO_PKG_VERSION").to_string());
+            r.set_uptime_nano(self.started.elapsed().as_nanos() as i64);
+            r.set_connections(self.debug.connection_count() as u64);
+            r.set_in_flight(self.debug.in_flight() as u64);
+            r.set_served(self.debug.served());
+            r.set_abandoned(self.debug.abandoned() as u64);
+            r.set_torn_writes(self.debug.torn_writes() as u64);
+            Ok(r)
+        })
+    }
+}
+
+/// Build the method table of the `ttrpc.diagnostics.Diagnostics` service.
+///
+/// The counters `Info` reports are read from `debug` each time it is
+/// called. Most users want [`ServerBuilder::register_diagnostics`]
+/// instead.
+///
+/// [`ServerBuilder::register_diagnostics`]: crate::ServerBuilder::register_diagnostics
+pub fn create_diagnostics(
+    debug: DebugHandle,
+) -> HashMap<String, Box<dyn MethodHandler + Send + Sync>> {
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert(
+        format!("/{}/Echo", DIAGNOSTICS_SERVICE),
+        Box::new(EchoMethod),
+    );
+    methods.insert(
+        format!("/{}/Ping", DIAGNOSTICS_SERVICE),
+        Box::new(PingMethod),
+    );
+    methods.insert(
+        format!("/{}/Info", DIAGNOSTICS_SERVICE),
+        Box::new(InfoMethod {
+            started: Instant::now(),
+            debug,
+        }),
+    );
+    methods
+}
+
+struct ListRequestsMethod(DebugHandle);
+
+impl MethodHandler for ListRequestsMethod {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        handle(ctx, req, |_: ListRequestsRequest| {
+            let mut r = ListRequestsResponse::new();
+            for q in self.0.requests() {
+                let mut info = RequestInfo::new();
+                info.set_connection(i64::from(q.connection));
+                info.set_stream_id(q.stream_id);
+                info.set_method(q.method);
+                info.set_age_nano(q.age.as_nanos() as i64);
+                info.set_bytes(q.bytes as u64);
+                info.set_deferred(q.deferred);
+                r.mut_requests().push(info);
+            }
+            Ok(r)
+        })
+    }
+}
+
+struct CancelRequestMethod(DebugHandle);
+
+impl MethodHandler for CancelRequestMethod {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        handle(ctx, req, |q: CancelRequestRequest| {
+            self.0.cancel(q.connection as i32, q.stream_id)?;
+            Ok(CancelRequestResponse::new())
+        })
+    }
+}
+
+/// Build the `ListRequests` and `CancelRequest` methods of the
+/// `ttrpc.diagnostics.Diagnostics` service on top of `debug`.
+/// [`ServerBuilder::register_diagnostics`] adds them to the rest.
+///
+/// [`ServerBuilder::register_diagnostics`]: crate::ServerBuilder::register_diagnostics
+pub fn create_debug(debug: DebugHandle) -> HashMap<String, Box<dyn MethodHandler + Send + Sync>> {
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert(
+        format!("/{}/ListRequests", DIAGNOSTICS_SERVICE),
+        Box::new(ListRequestsMethod(debug.clone())),
+    );
+    methods.insert(
+        format!("/{}/CancelRequest", DIAGNOSTICS_SERVICE),
+        Box::new(CancelRequestMethod(debug)),
+    );
+    methods
+}
+
+struct MetricsMethod(DebugHandle);
+
+impl MethodHandler for MetricsMethod {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        handle(ctx, req, |_: MetricsRequest| {
+            let mut r = MetricsResponse::new();
+            r.set_text(self.0.openmetrics());
+            Ok(r)
+        })
+    }
+}
+
+/// Build the `Metrics` method of the `ttrpc.diagnostics.Diagnostics`
+/// service, which serves [`DebugHandle::openmetrics`], so agents in guest
+/// VMs can be scraped over the vsock they already serve ttrpc on.
+/// [`ServerBuilder::register_diagnostics`] adds it to the rest; register it alone
+/// to serve metrics without letting clients cancel requests.
+///
+/// [`ServerBuilder::register_diagnostics`]: crate::ServerBuilder::register_diagnostics
+pub fn create_metrics(debug: DebugHandle) -> HashMap<String, Box<dyn MethodHandler + Send + Sync>> {
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert(
+        format!("/{}/Metrics", DIAGNOSTICS_SERVICE),
+        Box::new(MetricsMethod(debug)),
+    );
+    methods
+}
+
+/// Serialized descriptor of a built-in service. rust-protobuf leaves
+/// services out of the descriptors it embeds, so add `service` back.
+fn builtin_descriptor(file: &FileDescriptorProto, service: &str, methods: &[&str]) -> Vec<u8> {
+    let mut file = file.clone();
+    let mut s = ServiceDescriptorProto::new();
+    s.set_name(service.to_string());
+    for m in methods {
+        let mut md = MethodDescriptorProto::new();
+        md.set_name(m.to_string());
+        md.set_input_type(format!(".{}.{}Request", file.get_package(), m));
+        md.set_output_type(format!(".{}.{}Response", file.get_package(), m));
+        s.mut_method().push(md);
+    }
+    file.mut_service().push(s);
+    file.write_to_bytes().unwrap_or_default()
+}
+
+pub(crate) fn diagnostics_descriptor() -> Vec<u8> {
+    builtin_descriptor(
+        crate::diagnostics::file_descriptor_proto(),
+        "Diagnostics",
+        &[
+            "Echo",
+            "Ping",
+            "Info",
+            "ListRequests",
+            "CancelRequest",
+            "Metrics",
+        ],
+    )
+}
+
+pub(crate) fn reflection_descriptor() -> Vec<u8> {
+    builtin_descriptor(
+        crate::reflection::file_descriptor_proto(),
+        "Reflection",
+        &["ListServices", "FileContainingService"],
+    )
+}
+
+fn parse_descriptors(descriptors: &Descriptors) -> Result<Vec<(FileDescriptorProto, Vec<u8>)>> {
+    descriptors
+        .read()
+        .unwrap()
+        .iter()
+        .map(|bytes| {
+            let file = FileDescriptorProto::parse_from_bytes(bytes)
+                .map_err(err_to_Others!(e, "bad registered descriptor: "))?;
+            Ok((file, bytes.clone()))
+        })
+        .collect()
+}
+
+fn service_names(file: &FileDescriptorProto) -> impl Iterator<Item = String> + '_ {
+    file.get_service().iter().map(move |s| {
+        if file.get_package().is_empty() {
+            s.get_name().to_string()
+        } else {
+            format!("{}.{}", file.get_package(), s.get_name())
+        }
+    })
+}
+
+struct ListServicesMethod(Descriptors);
+
+impl MethodHandler for ListServicesMethod {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        handle(ctx, req, |_: ListServicesRequest| {
+            let mut services = Vec::new();
+            for (file, _) in parse_descriptors(&self.0)? {
+                services.extend(service_names(&file));
+            }
+            let mut r = ListServicesResponse::new();
+            r.set_services(RepeatedField::from_vec(services));
+            Ok(r)
+        })
+    }
+}
+
+struct FileContainingServiceMethod(Descriptors);
+
+impl MethodHandler for FileContainingServiceMethod {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        handle(ctx, req, |q: FileContainingServiceRequest| {
+            let files = parse_descriptors(&self.0)?;
+            let start = files
+                .iter()
+                .position(|(f, _)| service_names(f).any(|s| s == q.service))
+                .ok_or_else(|| {
+                    get_rpc_status(
+                        Code::NOT_FOUND,
+                        format!("no descriptor registered for {}", q.service),
+                    )
+                })?;
+
+            // the file itself first, then what it imports
+            let mut wanted = vec![start];
+            let mut i = 0;
+            while i < wanted.len() {
+                for dep in files[wanted[i]].0.get_dependency() {
+                    if let Some(j) = files.iter().position(|(f, _)| f.get_name() == dep) {
+                        if !wanted.contains(&j) {
+                            wanted.push(j);
+                        }
+                    }
+                }
+                i += 1;
+            }
+
+            let mut r = FileContainingServiceResponse::new();
+            r.set_file_descriptor_protos(wanted.into_iter().map(|i| files[i].1.clone()).collect());
+            Ok(r)
+        })
+    }
+}
+
+/// Build the method table of the `ttrpc.reflection.Reflection` service,
+/// which serves `descriptors` so dynamic clients can decode any method.
+/// Most users want [`ServerBuilder::register_reflection`] instead.
+///
+/// [`ServerBuilder::register_reflection`]: crate::ServerBuilder::register_reflection
+pub fn create_reflection(
+    descriptors: Descriptors,
+) -> HashMap<String, Box<dyn MethodHandler + Send + Sync>> {
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert(
+        format!("/{}/ListServices", REFLECTION_SERVICE),
+        Box::new(ListServicesMethod(descriptors.clone())),
+    );
+    methods.insert(
+        format!("/{}/FileContainingService", REFLECTION_SERVICE),
+        Box::new(FileContainingServiceMethod(descriptors)),
+    );
+    methods
+}
+
+/// Client for the `ttrpc.diagnostics.Diagnostics` service.
+#[derive(Clone)]
+pub struct DiagnosticsClient {
+    client: Client,
+}
+
+impl DiagnosticsClient {
+    pub fn new(client: Client) -> Self {
+        DiagnosticsClient { client }
+    }
+
+    fn call<Q: Message, R: Message>(&self, method: &str, q: &Q, timeout_nano: i64) -> Result<R> {
+        let mut creq = Request::new();
+        creq.set_service(DIAGNOSTICS_SERVICE.to_string());
+        creq.set_method(method.to_string());
+        creq.set_timeout_nano(timeout_nano);
+        creq.payload.reserve(q.compute_size() as usize);
+        let mut s = CodedOutputStream::vec(&mut creq.payload);
+        q.write_to(&mut s).map_err(err_to_Others!(e, ""))?;
+        s.flush().map_err(err_to_Others!(e, ""))?;
+        drop(s);
+
+        let res = self.client.request(creq)?;
+        let mut s = CodedInputStream::from_bytes(&res.payload);
+        let mut r = R::new();
+        r.merge_from(&mut s)
+            .map_err(err_to_Others!(e, "Unpack get error "))?;
+        Ok(r)
+    }
+
+    /// Send `payload` to the server and return what it echoed back.
+    pub fn echo(&self, payload: Vec<u8>, timeout_nano: i64) -> Result<Vec<u8>> {
+        let mut q = EchoRequest::new();
+        q.set_payload(payload);
+        let r: EchoResponse = self.call("Echo", &q, timeout_nano)?;
+        Ok(r.payload)
+    }
+
+    /// Measure the round-trip time of a call through the server.
+    pub fn ping(&self, timeout_nano: i64) -> Result<Duration> {
+        let start = Instant::now();
+        let mut q = PingRequest::new();
+        q.set_timestamp_nano(now_nano());
+        let _: PingResponse = self.call("Ping", &q, timeout_nano)?;
+        Ok(start.elapsed())
+    }
+
+    /// Get the server version, uptime and connection count.
+    pub fn info(&self, timeout_nano: i64) -> Result<InfoResponse> {
+        self.call("Info", &InfoRequest::new(), timeout_nano)
+    }
+
+    /// List the requests the server is serving, oldest first, including
+    /// this one.
+    pub fn list_requests(&self, timeout_nano: i64) -> Result<Vec<RequestInfo>> {
+        let r: ListRequestsResponse =
+            self.call("ListRequests", &ListRequestsRequest::new(), timeout_nano)?;
+        Ok(r.requests.into_vec())
+    }
+
+    /// Have the server answer a request with `CANCELLED`, see
+    /// [`DebugHandle::cancel`].
+    ///
+    /// [`DebugHandle::cancel`]: crate::DebugHandle::cancel
+    pub fn cancel_request(&self, connection: i64, stream_id: u32, timeout_nano: i64) -> Result<()> {
+        let mut q = CancelRequestRequest::new();
+        q.set_connection(connection);
+        q.set_stream_id(stream_id);
+        let _: CancelRequestResponse = self.call("CancelRequest", &q, timeout_nano)?;
+        Ok(())
+    }
+
+    /// Get the server metrics in OpenMetrics text format.
+    pub fn metrics(&self, timeout_nano: i64) -> Result<String> {
+        let r: MetricsResponse = self.call("Metrics", &MetricsRequest::new(), timeout_nano)?;
+        Ok(r.text)
+    }
+}
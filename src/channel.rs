@@ -12,28 +12,40 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::socket::*;
+use protobuf::{CodedInputStream, Message};
+use std::io::{self, Read};
 use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
 
-use crate::error::{get_rpc_status, Error, Result};
-use crate::ttrpc::Code;
+pub use crate::proto::{
+    decode_message_header, encode_message_header, goaway_frame, hello_frame, hello_frame_limited,
+    hello_frame_with, pack_batch, parse_goaway, parse_hello, unpack_batch, MessageHeader,
+    MethodHash, PeerInfo, FLAG_BATCH_OK, FLAG_EXTENSIONS, FLAG_NO_DATA, FLAG_NO_REPLY,
+    FLAG_PROGRESS_OK, FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_HEADER_LENGTH,
+    MESSAGE_LENGTH_MAX, MESSAGE_TYPE_BATCH, MESSAGE_TYPE_CANCEL, MESSAGE_TYPE_DATA,
+    MESSAGE_TYPE_GOAWAY, MESSAGE_TYPE_HELLO, MESSAGE_TYPE_IDENTITY, MESSAGE_TYPE_PROGRESS,
+    MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE,
+};
 
-const MESSAGE_HEADER_LENGTH: usize = 10;
-const MESSAGE_LENGTH_MAX: usize = 4 << 20;
+use crate::error::{get_rpc_status, Error, Result};
+use crate::ttrpc::{Code, Request};
 
-pub const MESSAGE_TYPE_REQUEST: u8 = 0x1;
-pub const MESSAGE_TYPE_RESPONSE: u8 = 0x2;
+// how much of a message too long to accept is read at a time
+const DISCARD_CHUNK: usize = 64 << 10;
 
-#[derive(Default, Debug)]
-pub struct MessageHeader {
-    pub length: u32,
-    pub stream_id: u32,
-    pub type_: u8,
-    pub flags: u8,
-}
+// Only this many frames, each no bigger than BATCH_FRAME_MAX, are packed
+// into one batch frame. Bigger frames are written on their own.
+const BATCH_MAX_FRAMES: usize = 64;
+const BATCH_FRAME_MAX: usize = 4 << 10;
+/// How many queued frames a writer takes at once to pack into batch frames.
+pub const BATCH_QUEUE_MAX: usize = 256;
 
-const SOCK_DICONNECTED: &str = "socket disconnected";
+pub(crate) const SOCK_DICONNECTED: &str = "socket disconnected";
+pub(crate) const SOCK_READ_TIMEOUT: &str = "read timed out";
+pub(crate) const SOCK_WRITE_TIMEOUT: &str = "write timed out";
 
 fn sock_error_msg(size: usize, msg: String) -> Error {
     if size == 0 {
@@ -43,14 +55,36 @@ fn sock_error_msg(size: usize, msg: String) -> Error {
     get_rpc_status(Code::INVALID_ARGUMENT, msg)
 }
 
-fn read_count(fd: RawFd, count: usize) -> Result<Vec<u8>> {
+pub(crate) fn read_count(fd: RawFd, count: usize) -> Result<Vec<u8>> {
+    read_count_within(fd, count, None, &mut None)
+}
+
+/// Read `count` bytes like [`read_count`], failing once `deadline` passed.
+/// The deadline is set `timeout` after the first byte arrives if unset.
+fn read_count_within(
+    fd: RawFd,
+    count: usize,
+    timeout: Option<Duration>,
+    deadline: &mut Option<Instant>,
+) -> Result<Vec<u8>> {
     let mut v: Vec<u8> = vec![0; count];
     let mut len = 0;
+    // recv(2) into no room at all waits for data on unix sockets, which
+    // would hold up empty frames such as CANCEL until the next one
+    if count == 0 {
+        return Ok(v);
+    }
 
     loop {
+        if let Some(d) = *deadline {
+            wait_readable(fd, d)?;
+        }
         match recv(fd, &mut v[len..], MsgFlags::empty()) {
             Ok(l) => {
                 len += l;
+                if l > 0 && deadline.is_none() {
+                    *deadline = timeout.map(|t| Instant::now() + t);
+                }
                 // when socket peer closed, it would return 0.
                 if len == count || l == 0 {
                     break;
@@ -68,31 +102,126 @@ fn read_count(fd: RawFd, count: usize) -> Result<Vec<u8>> {
     Ok(v[0..len].to_vec())
 }
 
-fn write_count(fd: RawFd, buf: &[u8], count: usize) -> Result<usize> {
+/// Wait for `fd` to be readable, failing with [`SOCK_READ_TIMEOUT`] if it
+/// is not by `deadline`.
+fn wait_readable(fd: RawFd, deadline: Instant) -> Result<()> {
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let ms = left
+            .as_micros()
+            .div_ceil(1000)
+            .min(libc::c_int::MAX as u128);
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        match poll(&mut fds, ms as libc::c_int) {
+            Ok(0) => return Err(Error::Socket(SOCK_READ_TIMEOUT.to_string())),
+            Ok(_) => return Ok(()),
+            Err(e) if e == ::nix::Error::from_errno(Errno::EINTR) => {}
+            Err(e) => return Err(Error::Socket(e.to_string())),
+        }
+    }
+}
+
+// Writing to a socket whose peer went away must not raise SIGPIPE in
+// processes embedding us which haven't ignored it. Elsewhere the sockets
+// have `SO_NOSIGPIPE` set instead, see seccomp::set_nosigpipe().
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SEND_FLAGS: libc::c_int = 0;
+
+pub(crate) fn send_nosignal(fd: RawFd, buf: &[u8]) -> nix::Result<usize> {
+    let ret = unsafe {
+        libc::send(
+            fd,
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+            SEND_FLAGS,
+        )
+    };
+
+    Errno::result(ret).map(|r| r as usize)
+}
+
+pub(crate) fn write_count(fd: RawFd, buf: &[u8], count: usize) -> Result<usize> {
     let mut len = 0;
+    send_all(fd, &buf[..count], &mut len)?;
+    Ok(len)
+}
 
-    loop {
-        match send(fd, &buf[len..], MsgFlags::empty()) {
+/// Write all of `buf`, adding what went out to `sent` even if it fails.
+fn send_all(fd: RawFd, buf: &[u8], sent: &mut usize) -> Result<()> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match send_nosignal(fd, &buf[len..]) {
             Ok(l) => {
                 len += l;
-                if len == count {
-                    break;
-                }
+                *sent += l;
             }
 
             Err(e) => {
-                if e != ::nix::Error::from_errno(::nix::errno::Errno::EINTR) {
+                if e == ::nix::Error::from_errno(Errno::EPIPE) {
+                    return Err(Error::ConnectionClosed);
+                }
+                // what a send timeout set on the socket fails with
+                if e == ::nix::Error::from_errno(Errno::EAGAIN) {
+                    return Err(Error::Socket(SOCK_WRITE_TIMEOUT.to_string()));
+                }
+                if e != ::nix::Error::from_errno(Errno::EINTR) {
                     return Err(Error::Socket(e.to_string()));
                 }
             }
         }
     }
 
-    Ok(len)
+    Ok(())
 }
 
-fn read_message_header(fd: RawFd) -> Result<MessageHeader> {
-    let buf = read_count(fd, MESSAGE_HEADER_LENGTH)?;
+/// A write of frames which failed.
+#[derive(Debug)]
+pub(crate) struct WriteError {
+    pub error: Error,
+    /// A frame went out in part, leaving the peer in its middle: the
+    /// connection can no longer carry frames.
+    pub torn: bool,
+}
+
+impl From<WriteError> for Error {
+    fn from(e: WriteError) -> Error {
+        e.error
+    }
+}
+
+/// Write `mh` and `buf` as one frame.
+pub(crate) fn write_frame(
+    fd: RawFd,
+    mh: MessageHeader,
+    buf: &[u8],
+) -> std::result::Result<(), WriteError> {
+    let mut header = [0u8; MESSAGE_HEADER_LENGTH];
+    encode_message_header(&mh, &mut header);
+
+    let mut sent = 0;
+    send_all(fd, &header, &mut sent)
+        .and_then(|_| send_all(fd, buf, &mut sent))
+        .map_err(|error| WriteError {
+            error,
+            torn: sent > 0,
+        })
+}
+
+pub(crate) fn read_message_header(fd: RawFd) -> Result<MessageHeader> {
+    read_message_header_within(fd, None, &mut None)
+}
+
+/// Read the header of a message, like [`read_frame`] does: the time
+/// left of `timeout` to read its body in is kept in `deadline`.
+pub(crate) fn read_message_header_within(
+    fd: RawFd,
+    timeout: Option<Duration>,
+    deadline: &mut Option<Instant>,
+) -> Result<MessageHeader> {
+    let buf = read_count_within(fd, MESSAGE_HEADER_LENGTH, timeout, deadline)?;
     let size = buf.len();
     if size != MESSAGE_HEADER_LENGTH {
         return Err(sock_error_msg(
@@ -101,38 +230,159 @@ fn read_message_header(fd: RawFd) -> Result<MessageHeader> {
         ));
     }
 
-    let mut mh = MessageHeader::default();
-    let mut covbuf: &[u8] = &buf[..4];
-    mh.length =
-        covbuf
-            .read_u32::<BigEndian>()
-            .map_err(err_to_RpcStatus!(Code::INVALID_ARGUMENT, e, ""))?;
-    let mut covbuf: &[u8] = &buf[4..8];
-    mh.stream_id =
-        covbuf
-            .read_u32::<BigEndian>()
-            .map_err(err_to_RpcStatus!(Code::INVALID_ARGUMENT, e, ""))?;
-    mh.type_ = buf[8];
-    mh.flags = buf[9];
-
-    Ok(mh)
+    decode_message_header(&buf)
 }
 
 pub fn read_message(fd: RawFd) -> Result<(MessageHeader, Vec<u8>)> {
-    let mh = read_message_header(fd)?;
+    let (mh, buf) = read_frame(fd, MESSAGE_LENGTH_MAX, None)?;
+    if buf.is_err() {
+        discard(fd, mh.length as usize, None)?;
+    }
+    Ok((mh, buf?))
+}
+
+/// Read a message, failing only if the connection can no longer be read.
+/// The body of a message longer than `max` is read and dropped, so the
+/// next message can still be read, unless it is longer than the protocol
+/// allows: its header is likely corrupt, and the caller decides whether
+/// to [`discard`] the body or give up on the connection. Reading it fails
+/// with [`SOCK_READ_TIMEOUT`] if it takes longer than `timeout` from its
+/// first byte.
+pub(crate) fn read_frame(
+    fd: RawFd,
+    max: usize,
+    timeout: Option<Duration>,
+) -> Result<(MessageHeader, Result<Vec<u8>>)> {
+    let mut deadline = None;
+    let mh = read_message_header_within(fd, timeout, &mut deadline)?;
     trace!("Got Message header {:?}", mh);
 
-    if mh.length > MESSAGE_LENGTH_MAX as u32 {
-        return Err(get_rpc_status(
+    let body = read_body(fd, &mh, max, timeout, &mut deadline)?;
+    Ok((mh, body))
+}
+
+/// The body of a message read by [`read_body_decoding`].
+pub(crate) enum Body {
+    Frame(Vec<u8>),
+    /// The request of a long frame, decoded as it was read.
+    Request(Request),
+    /// Why the request of a long frame did not decode. The rest of the
+    /// frame was read and dropped.
+    Undecodable(String),
+}
+
+/// Read the body of the message of `mh` like [`read_body`], except that
+/// a request longer than `decode_above`, without extensions, is decoded
+/// from the socket as it arrives rather than read whole first. Only the
+/// request is held in memory then, not a copy of the frame as well.
+pub(crate) fn read_body_decoding(
+    fd: RawFd,
+    mh: &MessageHeader,
+    max: usize,
+    decode_above: usize,
+    timeout: Option<Duration>,
+    deadline: &mut Option<Instant>,
+) -> Result<Result<Body>> {
+    let len = mh.length as usize;
+    if len <= decode_above
+        || len > max
+        || mh.type_ != MESSAGE_TYPE_REQUEST
+        || mh.flags & FLAG_EXTENSIONS != 0
+    {
+        let body = read_body(fd, mh, max, timeout, deadline)?;
+        return Ok(body.map(Body::Frame));
+    }
+
+    let mut reader = BodyReader {
+        fd,
+        left: len,
+        deadline: *deadline,
+        error: None,
+    };
+    let mut req = Request::new();
+    let decoded = req.merge_from(&mut CodedInputStream::new(&mut reader));
+    if let Some(e) = reader.error {
+        return Err(e);
+    }
+    let body = match decoded {
+        Ok(()) => Body::Request(req),
+        Err(e) => {
+            discard_within(fd, reader.left, timeout, deadline)?;
+            Body::Undecodable(e.to_string())
+        }
+    };
+    trace!("Decoded Message body of {} bytes", len);
+
+    Ok(Ok(body))
+}
+
+/// Reads the body of a message from the socket, ending with it.
+struct BodyReader {
+    fd: RawFd,
+    left: usize,
+    deadline: Option<Instant>,
+    // why the socket could not be read
+    error: Option<Error>,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = buf.len().min(self.left);
+        if count == 0 {
+            return Ok(0);
+        }
+        match recv_some(self.fd, &mut buf[..count], self.deadline) {
+            Ok(l) => {
+                self.left -= l;
+                Ok(l)
+            }
+            Err(e) => {
+                let msg = format!("{:?}", e);
+                self.error = Some(e);
+                Err(io::Error::other(msg))
+            }
+        }
+    }
+}
+
+/// Receive at least one byte into `buf`, by `deadline` if set.
+fn recv_some(fd: RawFd, buf: &mut [u8], deadline: Option<Instant>) -> Result<usize> {
+    loop {
+        if let Some(d) = deadline {
+            wait_readable(fd, d)?;
+        }
+        match recv(fd, buf, MsgFlags::empty()) {
+            Ok(0) => return Err(sock_error_msg(0, String::new())),
+            Ok(l) => return Ok(l),
+            Err(e) if e == ::nix::Error::from_errno(Errno::EINTR) => {}
+            Err(e) => return Err(Error::Socket(e.to_string())),
+        }
+    }
+}
+
+/// Read the body of the message of `mh`, like [`read_frame`].
+fn read_body(
+    fd: RawFd,
+    mh: &MessageHeader,
+    max: usize,
+    timeout: Option<Duration>,
+    deadline: &mut Option<Instant>,
+) -> Result<Result<Vec<u8>>> {
+    if mh.length as usize > max {
+        if mh.length as usize <= MESSAGE_LENGTH_MAX {
+            discard_within(fd, mh.length as usize, timeout, deadline)?;
+        }
+        let e = get_rpc_status(
             Code::INVALID_ARGUMENT,
             format!(
                 "message length {} exceed maximum message size of {}",
-                mh.length, MESSAGE_LENGTH_MAX
+                mh.length, max
             ),
-        ));
+        );
+        return Ok(Err(e));
     }
 
-    let buf = read_count(fd, mh.length as usize)?;
+    let buf = read_count_within(fd, mh.length as usize, timeout, deadline)?;
     let size = buf.len();
     if size != mh.length as usize {
         return Err(sock_error_msg(
@@ -142,18 +392,36 @@ pub fn read_message(fd: RawFd) -> Result<(MessageHeader, Vec<u8>)> {
     }
     trace!("Got Message body {:?}", buf);
 
-    Ok((mh, buf))
+    Ok(Ok(buf))
 }
 
-fn write_message_header(fd: RawFd, mh: MessageHeader) -> Result<()> {
-    let mut buf = [0u8; MESSAGE_HEADER_LENGTH];
+/// Read and drop the `len` bytes of the body of a message, within
+/// `timeout`.
+pub(crate) fn discard(fd: RawFd, len: usize, timeout: Option<Duration>) -> Result<()> {
+    discard_within(fd, len, timeout, &mut None)
+}
 
-    let mut covbuf: &mut [u8] = &mut buf[..4];
-    BigEndian::write_u32(&mut covbuf, mh.length);
-    let mut covbuf: &mut [u8] = &mut buf[4..8];
-    BigEndian::write_u32(&mut covbuf, mh.stream_id);
-    buf[8] = mh.type_;
-    buf[9] = mh.flags;
+/// Like [`discard`], within the time left of `timeout` in `deadline`.
+pub(crate) fn discard_within(
+    fd: RawFd,
+    len: usize,
+    timeout: Option<Duration>,
+    deadline: &mut Option<Instant>,
+) -> Result<()> {
+    let mut left = len;
+    while left > 0 {
+        let buf = read_count_within(fd, left.min(DISCARD_CHUNK), timeout, deadline)?;
+        if buf.is_empty() {
+            return Err(sock_error_msg(0, String::new()));
+        }
+        left -= buf.len();
+    }
+    Ok(())
+}
+
+pub(crate) fn write_message_header(fd: RawFd, mh: MessageHeader) -> Result<()> {
+    let mut buf = [0u8; MESSAGE_HEADER_LENGTH];
+    encode_message_header(&mh, &mut buf);
 
     let size = write_count(fd, &buf, MESSAGE_HEADER_LENGTH)?;
     if size != MESSAGE_HEADER_LENGTH {
@@ -167,15 +435,50 @@ fn write_message_header(fd: RawFd, mh: MessageHeader) -> Result<()> {
 }
 
 pub fn write_message(fd: RawFd, mh: MessageHeader, buf: Vec<u8>) -> Result<()> {
-    write_message_header(fd, mh)?;
+    Ok(write_frame(fd, mh, &buf)?)
+}
 
-    let size = write_count(fd, &buf, buf.len())?;
-    if size != buf.len() {
-        return Err(sock_error_msg(
-            size,
-            format!("Send Message length size {} is not right", size),
-        ));
+fn write_batch(
+    fd: RawFd,
+    frames: &mut Vec<(MessageHeader, Vec<u8>)>,
+) -> std::result::Result<(), WriteError> {
+    if frames.len() <= 1 {
+        return match frames.pop() {
+            Some((mh, buf)) => write_frame(fd, mh, &buf),
+            None => Ok(()),
+        };
     }
 
-    Ok(())
+    let batch = pack_batch(std::mem::take(frames));
+
+    let mh = MessageHeader {
+        length: batch.len() as u32,
+        stream_id: 0,
+        type_: MESSAGE_TYPE_BATCH,
+        flags: 0,
+    };
+    write_frame(fd, mh, &batch)
+}
+
+/// Write `frames`, packing runs of small ones into batch frames. The peer
+/// must be known to unpack them.
+pub(crate) fn write_batched(
+    fd: RawFd,
+    frames: Vec<(MessageHeader, Vec<u8>)>,
+) -> std::result::Result<(), WriteError> {
+    let mut run = Vec::new();
+    for (mh, buf) in frames {
+        // the server routes the frames of streams as it reads them
+        let streaming = mh.type_ == MESSAGE_TYPE_DATA || mh.flags & FLAG_REMOTE_OPEN != 0;
+        if buf.len() > BATCH_FRAME_MAX || streaming {
+            write_batch(fd, &mut run)?;
+            write_frame(fd, mh, &buf)?;
+            continue;
+        }
+        run.push((mh, buf));
+        if run.len() == BATCH_MAX_FRAMES {
+            write_batch(fd, &mut run)?;
+        }
+    }
+    write_batch(fd, &mut run)
 }
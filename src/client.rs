@@ -14,34 +14,650 @@
 
 use nix::sys::select::*;
 use nix::sys::socket::*;
+use nix::sys::time::{TimeVal, TimeValLike};
 use nix::unistd::close;
-use protobuf::{CodedInputStream, CodedOutputStream, Message};
-use std::collections::HashMap;
+use protobuf::{CodedInputStream, Message};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::os::unix::io::RawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::builtin::DIAGNOSTICS_SERVICE;
 use crate::channel::{
-    read_message, write_message, MessageHeader, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE,
+    hello_frame, hello_frame_with, parse_goaway, parse_hello, read_message, unpack_batch,
+    write_batched, write_message, MessageHeader, MethodHash, PeerInfo, BATCH_QUEUE_MAX,
+    FLAG_BATCH_OK, FLAG_EXTENSIONS, FLAG_NO_DATA, FLAG_NO_REPLY, FLAG_PROGRESS_OK,
+    FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_TYPE_BATCH, MESSAGE_TYPE_CANCEL,
+    MESSAGE_TYPE_DATA, MESSAGE_TYPE_GOAWAY, MESSAGE_TYPE_HELLO, MESSAGE_TYPE_IDENTITY,
+    MESSAGE_TYPE_PROGRESS, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE,
 };
-use crate::error::{Error, Result};
+use crate::common::{self, MethodPaths};
+use crate::error::{get_rpc_status, Error, Result};
+use crate::extension::{self, Extensions};
+use crate::proto::{self, encode_request};
+use crate::seccomp;
+use crate::server::{report_panic, PanicHandler, ThreadPanic};
+use crate::stream::{ClientStreamSender, DuplexStream, ServerStreamReceiver};
 use crate::ttrpc::{Code, Request, Response};
 
 #[derive(Clone)]
 pub struct Client {
-    fd: RawFd,
-    sender_tx: mpsc::Sender<(Vec<u8>, mpsc::SyncSender<Result<Vec<u8>>>)>,
+    sender_tx: mpsc::Sender<Outgoing>,
     client_close: Arc<ClientClose>,
+    hedge: Option<Arc<(Client, HedgePolicy)>>,
+    batching: Arc<Batching>,
+    recver_map: Arc<Mutex<HashMap<u32, Waiter>>>,
+    stats: Arc<Stats>,
+    // the address this client dialed, when reconnecting to it is safe
+    addr: Option<Arc<String>>,
+    failover: Arc<Failover>,
+    fork: Arc<ForkState>,
+    write_closed: Arc<AtomicBool>,
+    debug: Arc<Mutex<DebugLog>>,
+    interceptors: Vec<Interceptor>,
+    validators: HashMap<String, ResponseValidator>,
+    timeouts: DefaultTimeouts,
+    paths: Arc<MethodPaths>,
+    streams: Arc<Streams>,
+    // set again on the connections this client moves on to
+    socket_options: Option<Arc<SocketOptions>>,
+    // read by the threads of the connection when they panic
+    panic_handler: Arc<Mutex<Option<PanicHandler>>>,
+}
+
+/// What keeps a connection intact across `fork()`, see
+/// [`Client::prepare_for_fork`].
+struct ForkState {
+    // the process owning the connection and its threads
+    pid: u32,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+type ProgressFn = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// Where the response to a stream goes, who gets its progress updates,
+/// and where the messages the server streams go.
+type Waiter = (
+    mpsc::SyncSender<Result<Vec<u8>>>,
+    Option<ProgressFn>,
+    Option<mpsc::Sender<Vec<u8>>>,
+);
+
+/// Spawn a thread of the connection `fd`, reporting its panics like the
+/// server's threads, to the handler set by then.
+fn spawn_guarded<F>(name: String, fd: RawFd, panic_handler: Arc<Mutex<Option<PanicHandler>>>, f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                let handler = panic_handler
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone();
+                report_panic(fd, None, &handler, e);
+            }
+        })
+        .unwrap();
+}
+
+/// Fails the calls still waiting once the receiver thread is gone,
+/// whether it finished or panicked, as nothing will answer them.
+struct Orphans {
+    recver_map: Arc<Mutex<HashMap<u32, Waiter>>>,
+    stats: Arc<Stats>,
+}
+
+impl Drop for Orphans {
+    fn drop(&mut self) {
+        let going_away = self.stats.going_away.lock();
+        let err = match going_away.unwrap_or_else(PoisonError::into_inner).clone() {
+            Some(reason) => Error::ServerShutdown(reason),
+            None => Error::ConnectionClosed,
+        };
+        let mut waiting = self
+            .recver_map
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (_, (recver_tx, _, _)) in waiting.drain() {
+            recver_tx.send(Err(err.clone())).unwrap_or(());
+        }
+    }
+}
+
+/// What the sender thread writes.
+enum Outgoing {
+    /// An encoded request, with where to deliver its response unless it
+    /// is a notification, and its state if it may be cancelled.
+    Request(
+        Vec<u8>,
+        Option<mpsc::SyncSender<Result<Vec<u8>>>>,
+        Option<Arc<Call>>,
+    ),
+    /// Cancel the request sent on a stream.
+    Cancel(u32),
+    /// A message of a streaming call, with the flags of its data frame.
+    Data(Arc<Call>, Vec<u8>, u8),
+    /// Shut down the write side of the socket once everything queued
+    /// before is written.
+    ShutdownWrite,
+    /// A HELLO frame naming methods, see [`Client::check_methods`].
+    Hello(Vec<(String, MethodHash)>),
+}
+
+/// A request made with [`Client::call_cancellable`] or
+/// [`Client::request_with_progress`].
+#[derive(Default)]
+struct Call {
+    // 0 until the sender thread assigns the stream
+    stream_id: AtomicU32,
+    cancelled: AtomicBool,
+    progress: Option<ProgressFn>,
+    // the encoded extension block to send with the request
+    extensions: Option<Vec<u8>>,
+    // FLAG_REMOTE_OPEN or FLAG_REMOTE_CLOSED for streaming calls
+    stream_flags: u8,
+    // where the messages the server streams go, until the call is sent
+    data: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+}
+
+/// Where calls go once this connection is no longer usable, see
+/// [`Client::with_failover`].
+#[derive(Default)]
+struct Failover {
+    addrs: Vec<String>,
+    current: Mutex<Option<Client>>,
+}
+
+/// How long the stream of a call given up on is remembered, to drop the
+/// response should it still arrive.
+const ABANDONED_TTL: Duration = Duration::from_secs(60);
+
+/// How many calls a connection carries: stream ids are odd and never
+/// reused, so a connection runs out of them after 2^31 calls.
+pub const MAX_STREAM_IDS: usize = 1 << 31;
+
+/// A snapshot of the state of a [`Client`] connection, see
+/// [`Client::stats`].
+#[derive(Clone, Debug, Default)]
+pub struct ClientStats {
+    /// Calls written to the connection and waiting for their response.
+    pub in_flight: usize,
+    /// Calls and notifications queued but not yet written.
+    pub queued_writes: usize,
+    /// The last error hit reading from or writing to the connection.
+    pub last_error: Option<Error>,
+    /// Smoothed round-trip time of the [`Client::ready`] probes answered
+    /// so far. Other calls are left out, as their time includes the time
+    /// the server spent handling them.
+    pub rtt: Option<Duration>,
+    /// The reason the server gave when it announced it is shutting down.
+    pub going_away: Option<String>,
+    /// How many stream ids this connection used up, out of its limit.
+    pub stream_ids_used: usize,
+    /// Calls given up on, cancelled or past their deadline, whose response
+    /// may still arrive.
+    pub abandoned: usize,
+    /// Responses which arrived after their call was given up on, and were
+    /// dropped.
+    pub late_responses: usize,
+    /// Calls given up on which were forgotten without their response ever
+    /// arriving.
+    pub abandoned_expired: usize,
+    /// How many times calls moved on to a new connection: to a failover
+    /// address, after running out of stream ids, or after a fork.
+    pub reconnects: usize,
+}
+
+#[derive(Default)]
+struct Stats {
+    queued_writes: AtomicUsize,
+    last_error: Mutex<Option<Error>>,
+    rtt: Mutex<Option<Duration>>,
+    going_away: Mutex<Option<String>>,
+    server_info: Mutex<Option<PeerInfo>>,
+    // the server's answers to the methods named in HELLO frames
+    method_hashes: Mutex<HashMap<String, MethodHash>>,
+    hello_answered: Condvar,
+    stream_ids_used: AtomicUsize,
+    stream_id_limit: AtomicUsize,
+    abandoned: Mutex<Abandoned>,
+    late_responses: AtomicUsize,
+    abandoned_expired: AtomicUsize,
+    reconnects: AtomicUsize,
+}
+
+/// The streams of calls given up on, see [`ABANDONED_TTL`].
+#[derive(Default)]
+struct Abandoned {
+    streams: HashMap<u32, Instant>,
+    // in the order they were given up on
+    order: VecDeque<(u32, Instant)>,
+}
+
+impl Stats {
+    fn error(&self, e: &Error) {
+        *self.last_error.lock().unwrap() = Some(e.clone());
+    }
+
+    /// Whether the server said it takes extensions, see
+    /// [`Client::request_with_extensions`].
+    fn takes_extensions(&self) -> bool {
+        self.server_info
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|info| info.has("extensions"))
+    }
+
+    /// Remember that nobody waits for the response on `stream_id` anymore,
+    /// and forget the streams given up on too long ago.
+    fn abandon(&self, stream_id: u32) {
+        let now = Instant::now();
+        let mut abandoned = self.abandoned.lock().unwrap();
+        self.expire(&mut abandoned, now);
+        abandoned.streams.insert(stream_id, now);
+        abandoned.order.push_back((stream_id, now));
+    }
+
+    /// Forget the streams given up on [`ABANDONED_TTL`] before `now`.
+    fn expire(&self, abandoned: &mut Abandoned, now: Instant) {
+        while let Some(&(id, at)) = abandoned.order.front() {
+            if now.duration_since(at) < ABANDONED_TTL {
+                break;
+            }
+            abandoned.order.pop_front();
+            // unless its response arrived meanwhile
+            if abandoned.streams.get(&id) == Some(&at) {
+                abandoned.streams.remove(&id);
+                self.abandoned_expired.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Whether `mh` belongs to a call given up on, and should be dropped.
+    /// Streams given up on too long ago are forgotten first, so they do
+    /// not pile up on connections where calls are rarely given up on.
+    fn abandoned(&self, mh: &MessageHeader) -> bool {
+        let mut abandoned = self.abandoned.lock().unwrap();
+        self.expire(&mut abandoned, Instant::now());
+        if !abandoned.streams.contains_key(&mh.stream_id) {
+            return false;
+        }
+        // progress updates may come before it
+        if mh.type_ == MESSAGE_TYPE_RESPONSE {
+            abandoned.streams.remove(&mh.stream_id);
+            self.late_responses.fetch_add(1, Ordering::SeqCst);
+        }
+        true
+    }
+
+    fn sample_rtt(&self, sample: Duration) {
+        let mut rtt = self.rtt.lock().unwrap();
+        // the same smoothing as TCP's SRTT
+        *rtt = Some(match *rtt {
+            Some(r) => (r * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+}
+
+#[derive(Default)]
+struct Batching {
+    wanted: AtomicBool,
+    peer_ok: AtomicBool,
+}
+
+impl Batching {
+    fn enabled(&self) -> bool {
+        self.wanted.load(Ordering::SeqCst) && self.peer_ok.load(Ordering::SeqCst)
+    }
+}
+
+/// Establishes the connection a [`Client`] talks over.
+///
+/// Implement this to reach a server through something other than a plain
+/// `connect(2)`, such as a proxy process or the firecracker hybrid vsock
+/// handshake. The returned fd must be a connected stream socket and is
+/// owned by the client afterwards.
+pub trait Dialer {
+    fn dial(&self, addr: &str) -> Result<RawFd>;
+}
+
+impl<F> Dialer for F
+where
+    F: Fn(&str) -> Result<RawFd>,
+{
+    fn dial(&self, addr: &str) -> Result<RawFd> {
+        self(addr)
+    }
+}
+
+/// Dials `unix://` (abstract) and `vsock://cid:port` addresses, the same
+/// schemes [`ServerBuilder::bind`] accepts.
+///
+/// [`ServerBuilder::bind`]: crate::ServerBuilder::bind
+pub struct DefaultDialer;
+
+impl Dialer for DefaultDialer {
+    fn dial(&self, addr: &str) -> Result<RawFd> {
+        let (fd, sockaddr) = common::make_socket(addr, false)?;
+        if let Err(e) = connect(fd, &sockaddr) {
+            close(fd).unwrap_or(());
+            return Err(Error::Socket(e.to_string()));
+        }
+
+        Ok(fd)
+    }
+}
+
+/// Options set on the socket of a [`Client`] once it is connected, see
+/// [`Client::connect_with_options`].
+///
+/// Only `TCP_NODELAY` is set by default, and only on TCP sockets, which
+/// otherwise hold back small requests waiting for earlier ones to be
+/// acknowledged.
+#[derive(Clone, Debug)]
+pub struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<bool>,
+    send_timeout: Option<Duration>,
+    recv_timeout: Option<Duration>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            send_timeout: None,
+            recv_timeout: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub fn new() -> SocketOptions {
+        SocketOptions::default()
+    }
+
+    /// Set `TCP_NODELAY` on TCP sockets. Other sockets have no such delay.
+    pub fn nodelay(mut self, nodelay: bool) -> SocketOptions {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Set `SO_KEEPALIVE`.
+    pub fn keepalive(mut self, keepalive: bool) -> SocketOptions {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Set `SO_SNDTIMEO`: a request which cannot be written for this long
+    /// fails, and so does the connection.
+    pub fn send_timeout(mut self, timeout: Duration) -> SocketOptions {
+        self.send_timeout = Some(timeout);
+        self
+    }
+
+    /// Set `SO_RCVTIMEO`: the connection fails once nothing arrived on it
+    /// for this long, even while no call is waiting.
+    pub fn recv_timeout(mut self, timeout: Duration) -> SocketOptions {
+        self.recv_timeout = Some(timeout);
+        self
+    }
+
+    /// Set `SO_SNDBUF`.
+    pub fn send_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Set `SO_RCVBUF`.
+    pub fn recv_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Set the options on the connected socket `fd`, e.g. one returned by
+    /// a [`Dialer`].
+    pub fn apply(&self, fd: RawFd) -> Result<()> {
+        let err = |e: nix::Error| Error::Socket(e.to_string());
+        if self.nodelay {
+            if let Ok(SockAddr::Inet(_)) = getsockname(fd) {
+                setsockopt(fd, sockopt::TcpNoDelay, &true).map_err(err)?;
+            }
+        }
+        if let Some(keepalive) = self.keepalive {
+            setsockopt(fd, sockopt::KeepAlive, &keepalive).map_err(err)?;
+        }
+        if let Some(t) = self.send_timeout {
+            let tv = TimeVal::microseconds(t.as_micros() as i64);
+            setsockopt(fd, sockopt::SendTimeout, &tv).map_err(err)?;
+        }
+        if let Some(t) = self.recv_timeout {
+            let tv = TimeVal::microseconds(t.as_micros() as i64);
+            setsockopt(fd, sockopt::ReceiveTimeout, &tv).map_err(err)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            setsockopt(fd, sockopt::SndBuf, &size).map_err(err)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            setsockopt(fd, sockopt::RcvBuf, &size).map_err(err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decides which calls a [`Client`] hedges and when.
+///
+/// A hedged call is sent on the primary connection first. If no response
+/// arrived after `delay`, the same request is also sent on the secondary
+/// connection and whichever response arrives first is returned. Only
+/// methods registered with [`HedgePolicy::idempotent`] are hedged, since
+/// the server may end up executing the call twice.
+#[derive(Clone, Debug)]
+pub struct HedgePolicy {
+    delay: Duration,
+    methods: HashSet<String>,
+}
+
+impl HedgePolicy {
+    pub fn new(delay: Duration) -> HedgePolicy {
+        HedgePolicy {
+            delay,
+            methods: HashSet::new(),
+        }
+    }
+
+    /// Mark the method `path` (e.g. `/grpc.Health/Check`) safe to hedge.
+    pub fn idempotent(mut self, path: &str) -> HedgePolicy {
+        self.methods.insert(path.to_string());
+        self
+    }
+}
+
+/// What a [`Client`] does with calls beyond the number of concurrent
+/// requests the server advertised, see [`Client::with_stream_limit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StreamLimit {
+    /// Send them, and let the server reject those it has no room for.
+    #[default]
+    Ignore,
+    /// Hold them until an earlier call finished, or their deadline passed.
+    Queue,
+    /// Fail them with `RESOURCE_EXHAUSTED` without sending them.
+    FailFast,
+}
+
+/// The calls in flight on a connection, see [`Client::with_stream_limit`].
+#[derive(Default)]
+struct Streams {
+    policy: Mutex<StreamLimit>,
+    active: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A call counted against the server's limit until dropped.
+struct StreamSlot(Arc<Streams>);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        *self.0.active.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// How much a [`Client`] logs about each call, see [`Client::set_debug`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DebugLevel {
+    #[default]
+    Off,
+    /// The method, the payload sizes, how long the call took and how it
+    /// ended.
+    Calls,
+    /// As `Calls`, plus the payloads.
+    Payloads,
+}
+
+/// A payload about to be logged, see [`Redactor`].
+pub enum DebugPayload<'a> {
+    Request(&'a [u8]),
+    Response(&'a [u8]),
+}
+
+/// Renders the payload of a call to the method at the given path for the
+/// log, e.g. by decoding it, masking secrets such as the environment of a
+/// `CreateContainer` request, and pretty-printing the result.
+pub type Redactor = Arc<dyn Fn(&str, DebugPayload) -> String + Send + Sync>;
+
+/// Amends every request before it is sent, e.g. to add metadata, see
+/// [`Client::with_interceptor`] and
+/// [`MetadataInjector`](crate::metadata::MetadataInjector).
+pub type Interceptor = Arc<dyn Fn(&mut Request) + Send + Sync>;
+
+// checks the payload of a response, see Client::with_response_validator
+type ResponseValidator = Arc<dyn Fn(&[u8]) -> Result<()> + Send + Sync>;
+
+/// Payloads rendered without a [`Redactor`] are cut at this many bytes.
+const DEBUG_PAYLOAD_MAX: usize = 512;
+
+/// `buf` as escaped bytes for the log, cut at [`DEBUG_PAYLOAD_MAX`] bytes.
+pub(crate) fn escape_payload(buf: &[u8]) -> String {
+    let shown = &buf[..buf.len().min(DEBUG_PAYLOAD_MAX)];
+    let mut text: String = shown
+        .iter()
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect();
+    if shown.len() < buf.len() {
+        text.push_str(&format!("... ({} more bytes)", buf.len() - shown.len()));
+    }
+    text
+}
+
+/// The timeouts of calls made without one, see
+/// [`Client::with_default_timeout`].
+#[derive(Clone, Default)]
+struct DefaultTimeouts {
+    client: Option<Duration>,
+    services: HashMap<String, Duration>,
+    // by method path
+    methods: HashMap<String, Duration>,
+}
+
+impl DefaultTimeouts {
+    /// The timeout of calls to `path`, a method of `service`: that of the
+    /// method, else of the service, else of the client.
+    fn get(&self, service: &str, path: &str) -> Option<Duration> {
+        self.methods
+            .get(path)
+            .or_else(|| self.services.get(service))
+            .copied()
+            .or(self.client)
+    }
+}
+
+#[derive(Clone, Default)]
+struct DebugLog {
+    level: DebugLevel,
+    redactor: Option<Redactor>,
+}
+
+/// A call being logged, see [`Client::set_debug`].
+struct CallLog {
+    debug: DebugLog,
+    path: Arc<str>,
+    start: Instant,
+}
+
+impl CallLog {
+    fn payload(&self, payload: DebugPayload) -> String {
+        if self.debug.level < DebugLevel::Payloads {
+            return String::new();
+        }
+        if let Some(redactor) = self.debug.redactor.as_ref() {
+            return format!(": {}", redactor(&self.path, payload));
+        }
+        let (DebugPayload::Request(buf) | DebugPayload::Response(buf)) = payload;
+        format!(": \"{}\"", escape_payload(buf))
+    }
+
+    fn sent(&self, req: &Request, kind: &str) {
+        info!(
+            "{} {} sent, {} bytes{}",
+            kind,
+            self.path,
+            req.payload.len(),
+            self.payload(DebugPayload::Request(&req.payload))
+        );
+    }
+
+    fn finished(&self, res: &Result<Response>) {
+        match res {
+            Ok(res) => info!(
+                "call {} answered in {:?}, {} bytes{}",
+                self.path,
+                self.start.elapsed(),
+                res.payload.len(),
+                self.payload(DebugPayload::Response(&res.payload))
+            ),
+            Err(Error::RpcStatus(status)) => info!(
+                "call {} failed in {:?}: {:?} {}",
+                self.path,
+                self.start.elapsed(),
+                status.code(),
+                status.get_message()
+            ),
+            Err(e) => info!(
+                "call {} failed in {:?}: {:?}",
+                self.path,
+                self.start.elapsed(),
+                e
+            ),
+        }
+    }
 }
 
 impl Client {
     /// Initialize a new [`Client`].
     pub fn new(fd: RawFd) -> Client {
-        let (sender_tx, rx): (
-            mpsc::Sender<(Vec<u8>, mpsc::SyncSender<Result<Vec<u8>>>)>,
-            mpsc::Receiver<(Vec<u8>, mpsc::SyncSender<Result<Vec<u8>>>)>,
-        ) = mpsc::channel();
+        if let Err(e) = seccomp::set_nosigpipe(fd) {
+            warn!("failed to set SO_NOSIGPIPE on {}: {}", fd, e);
+        }
+        let (sender_tx, rx): (mpsc::Sender<Outgoing>, mpsc::Receiver<Outgoing>) = mpsc::channel();
 
         let (recver_fd, close_fd) = socketpair(
             AddressFamily::Unix,
@@ -50,153 +666,1586 @@ impl Client {
             SockFlag::empty(),
         )
         .unwrap();
-        let client_close = Arc::new(ClientClose { fd, close_fd });
+        let client_close = Arc::new(ClientClose {
+            fd,
+            close_fd,
+            recver_fd,
+            pid: process::id(),
+        });
+        let fork = Arc::new(ForkState {
+            pid: process::id(),
+            paused: Mutex::new(false),
+            resumed: Condvar::new(),
+        });
+        // closed once both threads are done with it
+        let socket = Arc::new(OwnedSocket(fd));
 
         let recver_map_orig = Arc::new(Mutex::new(HashMap::new()));
+        let batching = Arc::new(Batching::default());
+        let stats = Arc::new(Stats::default());
+        stats
+            .stream_id_limit
+            .store(MAX_STREAM_IDS, Ordering::SeqCst);
 
         //Sender
         let recver_map = recver_map_orig.clone();
-        thread::spawn(move || {
-            let mut stream_id: u32 = 1;
-            for (buf, recver_tx) in rx.iter() {
-                let current_stream_id = stream_id;
-                stream_id += 2;
-                //Put current_stream_id and recver_tx to recver_map
-                {
-                    let mut map = recver_map.lock().unwrap();
-                    map.insert(current_stream_id, recver_tx.clone());
-                }
-                let mh = MessageHeader {
-                    length: buf.len() as u32,
-                    stream_id: current_stream_id,
-                    type_: MESSAGE_TYPE_REQUEST,
-                    flags: 0,
-                };
+        let sender_batching = batching.clone();
+        let sender_stats = stats.clone();
+        let sender_socket = socket.clone();
+        let sender_fork = fork.clone();
+        let panic_handler = Arc::new(Mutex::new(None));
+        spawn_guarded(
+            format!("client_sender-{}", fd),
+            fd,
+            panic_handler.clone(),
+            move || {
+                let _socket = sender_socket;
+                // older servers ignore it
+                let (mh, buf) = hello_frame();
                 if let Err(e) = write_message(fd, mh, buf) {
-                    //Remove current_stream_id and recver_tx to recver_map
-                    {
+                    debug!("write hello failed: {:?}", e);
+                }
+                let mut stream_id: u32 = 1;
+                let mut write_closed = false;
+                for first in rx.iter() {
+                    let mut queued = vec![first];
+                    if sender_batching.enabled() {
+                        queued.extend(rx.try_iter().take(BATCH_QUEUE_MAX));
+                    }
+                    sender_stats
+                        .queued_writes
+                        .fetch_sub(queued.len(), Ordering::SeqCst);
+
+                    let mut frames = Vec::with_capacity(queued.len());
+                    let mut waiters = Vec::with_capacity(queued.len());
+                    for item in queued {
+                        let (buf, recver_tx, call) = match item {
+                            // queued while shutdown_write() was called
+                            Outgoing::Request(_, Some(recver_tx), _) if write_closed => {
+                                recver_tx.send(Err(write_closed_error())).unwrap_or(());
+                                continue;
+                            }
+                            Outgoing::Request(..)
+                            | Outgoing::Cancel(_)
+                            | Outgoing::Data(..)
+                            | Outgoing::Hello(_)
+                                if write_closed =>
+                            {
+                                continue
+                            }
+                            Outgoing::ShutdownWrite => {
+                                write_closed = true;
+                                continue;
+                            }
+                            Outgoing::Request(buf, recver_tx, call) => (buf, recver_tx, call),
+                            Outgoing::Cancel(id) => {
+                                let mh = MessageHeader {
+                                    length: 0,
+                                    stream_id: id,
+                                    type_: MESSAGE_TYPE_CANCEL,
+                                    flags: 0,
+                                };
+                                frames.push((mh, Vec::new()));
+                                continue;
+                            }
+                            Outgoing::Data(call, buf, flags) => {
+                                let id = call.stream_id.load(Ordering::SeqCst);
+                                // its request was never sent
+                                if id == 0 || call.cancelled.load(Ordering::SeqCst) {
+                                    continue;
+                                }
+                                let mh = MessageHeader {
+                                    length: buf.len() as u32,
+                                    stream_id: id,
+                                    type_: MESSAGE_TYPE_DATA,
+                                    flags,
+                                };
+                                frames.push((mh, buf));
+                                continue;
+                            }
+                            Outgoing::Hello(methods) => {
+                                frames.push(hello_frame_with(&methods));
+                                continue;
+                            }
+                        };
+                        // queue() stops before ids run out
+                        let current_stream_id = stream_id;
+                        stream_id = stream_id.wrapping_add(2);
+                        let extensions = call.as_ref().and_then(|c| c.extensions.clone());
+                        let stream_flags = call.as_ref().map_or(0, |c| c.stream_flags);
+                        let mut flags = match recver_tx {
+                            Some(recver_tx) => {
+                                //Put current_stream_id and recver_tx to recver_map
+                                let progress = call.as_ref().and_then(|c| c.progress.clone());
+                                let data =
+                                    call.as_ref().and_then(|c| c.data.lock().unwrap().take());
+                                {
+                                    let mut map = recver_map.lock().unwrap();
+                                    if let Some(call) = call {
+                                        // cancelled before it was sent
+                                        if call.cancelled.load(Ordering::SeqCst) {
+                                            continue;
+                                        }
+                                        call.stream_id.store(current_stream_id, Ordering::SeqCst);
+                                    }
+                                    map.insert(
+                                        current_stream_id,
+                                        (recver_tx.clone(), progress.clone(), data),
+                                    );
+                                }
+                                waiters.push((current_stream_id, recver_tx));
+                                if progress.is_some() {
+                                    FLAG_PROGRESS_OK
+                                } else {
+                                    0
+                                }
+                            }
+                            None => FLAG_NO_REPLY,
+                        } | stream_flags;
+                        let buf = match extensions {
+                            Some(mut block) if sender_stats.takes_extensions() => {
+                                flags |= FLAG_EXTENSIONS;
+                                block.extend_from_slice(&buf);
+                                block
+                            }
+                            Some(_) => {
+                                debug!("server takes no extensions, leaving them out");
+                                buf
+                            }
+                            None => buf,
+                        };
+                        let mh = MessageHeader {
+                            length: buf.len() as u32,
+                            stream_id: current_stream_id,
+                            type_: MESSAGE_TYPE_REQUEST,
+                            flags,
+                        };
+                        frames.push((mh, buf));
+                    }
+
+                    // hold off while the process forks, and keep it from
+                    // forking mid-write
+                    let paused = sender_fork.paused.lock().unwrap();
+                    let _paused = sender_fork.resumed.wait_while(paused, |p| *p).unwrap();
+                    // a single frame is written as is
+                    if let Err(e) = write_batched(fd, frames).map_err(Error::from) {
+                        debug!("write requests failed: {:?}", e);
+                        sender_stats.error(&e);
+                        //Remove current_stream_id and recver_tx to recver_map
                         let mut map = recver_map.lock().unwrap();
-                        map.remove(&current_stream_id);
+                        for (current_stream_id, recver_tx) in waiters {
+                            map.remove(&current_stream_id);
+                            // the caller may have given up waiting already
+                            recver_tx.send(Err(e.clone())).unwrap_or(());
+                        }
+                    }
+                    if write_closed {
+                        shutdown(fd, Shutdown::Write).unwrap_or(());
                     }
-                    recver_tx.send(Err(e)).unwrap();
                 }
-            }
-            trace!("Sender quit");
-        });
+                trace!("Sender quit");
+            },
+        );
 
         //Recver
         let recver_map = recver_map_orig.clone();
-        thread::spawn(move || {
-            let bigfd = {
-                if fd > recver_fd {
-                    fd + 1
-                } else {
-                    recver_fd + 1
-                }
-            };
-            loop {
-                let mut rs = FdSet::new();
-                rs.insert(recver_fd);
-                rs.insert(fd);
-                select(bigfd, Some(&mut rs), None, None, None).unwrap();
-                if rs.contains(recver_fd) {
-                    break;
-                } else if !rs.contains(fd) {
-                    continue;
-                }
-
-                let mh;
-                let buf;
-                match read_message(fd) {
-                    Ok((x, y)) => {
-                        mh = x;
-                        buf = y;
+        let recver_batching = batching.clone();
+        let recver_stats = stats.clone();
+        spawn_guarded(
+            format!("client_recver-{}", fd),
+            fd,
+            panic_handler.clone(),
+            move || {
+                let _socket = socket;
+                let _orphans = Orphans {
+                    recver_map: recver_map.clone(),
+                    stats: recver_stats.clone(),
+                };
+                let bigfd = {
+                    if fd > recver_fd {
+                        fd + 1
+                    } else {
+                        recver_fd + 1
+                    }
+                };
+                loop {
+                    let mut rs = FdSet::new();
+                    rs.insert(recver_fd);
+                    rs.insert(fd);
+                    select(bigfd, Some(&mut rs), None, None, None).unwrap();
+                    if rs.contains(recver_fd) {
+                        break;
+                    } else if !rs.contains(fd) {
+                        continue;
                     }
-                    Err(x) => match x {
-                        Error::Socket(y) => {
-                            trace!("Socket error {}", y);
-                            break;
+
+                    let mh;
+                    let buf;
+                    match read_message(fd) {
+                        Ok((x, y)) => {
+                            mh = x;
+                            buf = y;
+                        }
+                        Err(x) => {
+                            recver_stats.error(&x);
+                            match x {
+                                Error::Socket(y) => {
+                                    trace!("Socket error {}", y);
+                                    break;
+                                }
+                                _ => {
+                                    trace!("Others error {:?}", x);
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    let frames = if mh.type_ == MESSAGE_TYPE_BATCH {
+                        match unpack_batch(&buf) {
+                            Ok(frames) => frames,
+                            Err(x) => {
+                                trace!("Others error {:?}", x);
+                                recver_stats.error(&x);
+                                continue;
+                            }
                         }
-                        _ => {
-                            trace!("Others error {:?}", x);
+                    } else {
+                        vec![(mh, buf)]
+                    };
+
+                    for (mh, buf) in frames {
+                        let mut map = recver_map.lock().unwrap();
+                        if mh.flags & FLAG_BATCH_OK != 0 {
+                            recver_batching.peer_ok.store(true, Ordering::SeqCst);
+                        }
+                        if mh.type_ == MESSAGE_TYPE_GOAWAY {
+                            match parse_goaway(&buf) {
+                                Ok((grace, reason)) => {
+                                    debug!("server going away in {:?}: {}", grace, reason);
+                                    *recver_stats.going_away.lock().unwrap() = Some(reason);
+                                }
+                                Err(x) => recver_stats.error(&x),
+                            }
                             continue;
                         }
-                    },
-                };
-                let mut map = recver_map.lock().unwrap();
-                let recver_tx = match map.get(&mh.stream_id) {
-                    Some(tx) => tx,
-                    None => {
-                        debug!("Recver got unknown packet {:?} {:?}", mh, buf);
-                        continue;
+                        if mh.type_ == MESSAGE_TYPE_HELLO && mh.stream_id == 0 {
+                            let info = parse_hello(&buf);
+                            debug!("server is {} with {:?}", info.version, info.capabilities);
+                            if info.has("batch") {
+                                recver_batching.peer_ok.store(true, Ordering::SeqCst);
+                            }
+                            let methods = info.methods.clone();
+                            *recver_stats.server_info.lock().unwrap() = Some(info);
+                            recver_stats.method_hashes.lock().unwrap().extend(methods);
+                            recver_stats.hello_answered.notify_all();
+                            continue;
+                        }
+                        let (recver_tx, progress, data) = match map.get(&mh.stream_id) {
+                            Some(x) => x,
+                            None if recver_stats.abandoned(&mh) => {
+                                trace!("Recver dropped {:?} of a call given up on", mh);
+                                continue;
+                            }
+                            None => {
+                                debug!("Recver got unknown packet {:?} {:?}", mh, buf);
+                                continue;
+                            }
+                        };
+                        if mh.type_ == MESSAGE_TYPE_PROGRESS && progress.is_some() {
+                            // the callback may call into the client
+                            let progress = progress.clone().unwrap();
+                            drop(map);
+                            progress(&buf);
+                            continue;
+                        }
+                        if mh.type_ == MESSAGE_TYPE_DATA && data.is_some() {
+                            if mh.flags & FLAG_NO_DATA == 0 {
+                                // the caller may have stopped reading
+                                data.as_ref().unwrap().send(buf).unwrap_or(());
+                            }
+                            continue;
+                        }
+                        if mh.type_ != MESSAGE_TYPE_RESPONSE {
+                            recver_tx
+                                .send(Err(Error::Others(format!(
+                                    "Recver got malformed packet {:?} {:?}",
+                                    mh, buf
+                                ))))
+                                .unwrap_or(());
+                            continue;
+                        }
+
+                        let mut mh = mh;
+                        let res = extension::split(&mut mh, buf).map(|(_, buf)| buf);
+                        // the caller is gone if it lost a hedged race
+                        recver_tx.send(res).unwrap_or(());
+
+                        map.remove(&mh.stream_id);
                     }
-                };
-                if mh.type_ != MESSAGE_TYPE_RESPONSE {
-                    recver_tx
-                        .send(Err(Error::Others(format!(
-                            "Recver got malformed packet {:?} {:?}",
-                            mh, buf
-                        ))))
-                        .unwrap();
-                    continue;
                 }
+                close(recver_fd).unwrap_or(());
+                trace!("Recver quit");
+            },
+        );
 
-                recver_tx.send(Ok(buf)).unwrap();
+        Client {
+            sender_tx,
+            client_close,
+            hedge: None,
+            batching,
+            recver_map: recver_map_orig,
+            stats,
+            addr: None,
+            failover: Arc::new(Failover::default()),
+            fork,
+            write_closed: Arc::new(AtomicBool::new(false)),
+            debug: Arc::default(),
+            interceptors: Vec::new(),
+            validators: HashMap::new(),
+            timeouts: DefaultTimeouts::default(),
+            paths: Arc::default(),
+            streams: Arc::default(),
+            socket_options: None,
+            panic_handler,
+        }
+    }
+
+    /// Connect to `addr` and initialize a new [`Client`] on the connection.
+    ///
+    /// Once the connection ran out of stream ids, new calls transparently
+    /// go to a new connection to `addr`.
+    pub fn connect(addr: &str) -> Result<Client> {
+        let mut client = Client::connect_with_dialer(addr, Box::new(DefaultDialer))?;
+        client.addr = Some(Arc::new(addr.to_string()));
+        Ok(client)
+    }
+
+    /// Connect to `addr` like [`Client::connect`] and set `options` on the
+    /// socket, and on those of the connections the client moves on to.
+    pub fn connect_with_options(addr: &str, options: SocketOptions) -> Result<Client> {
+        let fd = DefaultDialer.dial(addr)?;
+        if let Err(e) = options.apply(fd) {
+            close(fd).unwrap_or(());
+            return Err(e);
+        }
+        let mut client = Client::new(fd);
+        client.addr = Some(Arc::new(addr.to_string()));
+        client.socket_options = Some(Arc::new(options));
+        Ok(client)
+    }
+
+    /// Initialize a new [`Client`] on the connection established by `dialer`.
+    pub fn connect_with_dialer(addr: &str, dialer: Box<dyn Dialer>) -> Result<Client> {
+        let fd = dialer.dial(addr)?;
+        Ok(Client::new(fd))
+    }
+
+    /// Connect to `addr` and present `identity`, such as a sandbox id or a
+    /// token, to the server. Handlers get it from
+    /// [`TtrpcContext::identity`] for every call on the connection.
+    ///
+    /// [`TtrpcContext::identity`]: crate::TtrpcContext::identity
+    pub fn connect_with_identity(addr: &str, identity: &[u8]) -> Result<Client> {
+        let fd = DefaultDialer.dial(addr)?;
+        let mh = MessageHeader {
+            length: identity.len() as u32,
+            stream_id: 0,
+            type_: MESSAGE_TYPE_IDENTITY,
+            flags: 0,
+        };
+        if let Err(e) = write_message(fd, mh, identity.to_vec()) {
+            close(fd).unwrap_or(());
+            return Err(e);
+        }
+        Ok(Client::new(fd))
+    }
 
-                map.remove(&mh.stream_id);
+    /// Connect to `addr`, retrying until the server answers or `timeout`
+    /// passes, for callers which want to fail fast when the server is
+    /// unreachable rather than on their first call.
+    pub fn wait_for_ready(addr: &str, timeout: Duration) -> Result<Client> {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_millis(10);
+        loop {
+            let err = match Client::connect(addr) {
+                Ok(c) => match c.ready(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(()) => return Ok(c),
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(err);
             }
-            trace!("Recver quit");
+            trace!("{} not ready: {:?}", addr, err);
+            thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(Duration::from_millis(500));
+        }
+    }
+
+    /// Check that the server answers on this connection within `timeout`.
+    ///
+    /// The probe is a call to the built-in diagnostics `Ping`. Servers
+    /// which did not register it still answer, with an error status, and
+    /// count as ready. The time the answer took is sampled into
+    /// [`ClientStats::rtt`].
+    pub fn ready(&self, timeout: Duration) -> Result<()> {
+        if let Some(c) = self.redirect()? {
+            return c.ready(timeout);
+        }
+        let mut req = Request::new();
+        req.set_service(DIAGNOSTICS_SERVICE.to_string());
+        req.set_method("Ping".to_string());
+        req.set_timeout_nano(timeout.as_nanos() as i64);
+        let buf = encode_request(&req)?;
+
+        let (tx, rx) = mpsc::sync_channel(1);
+        let sent = Instant::now();
+        self.dispatch(buf, tx)?;
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result.map(|_| self.stats.sample_rtt(sent.elapsed())),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(get_rpc_status(
+                Code::DEADLINE_EXCEEDED,
+                "server did not answer in time".to_string(),
+            )),
+            Err(e) => Err(Error::Others(format!(
+                "Recive packet from recver error {}",
+                e
+            ))),
+        }
+    }
+
+    /// Set a callback invoked when one of the threads of the connection
+    /// panics, e.g. in a progress callback, to abort the process or record
+    /// a metric. It is shared by the clones of this client. The calls
+    /// still waiting on the connection fail once its receiver panicked.
+    pub fn with_panic_handler<F>(self, f: F) -> Client
+    where
+        F: Fn(&ThreadPanic) + Send + Sync + 'static,
+    {
+        *self.panic_handler.lock().unwrap() = Some(Arc::new(f));
+        self
+    }
+
+    /// Hedge the calls selected by `policy` onto `secondary`.
+    pub fn with_hedging(mut self, secondary: Client, policy: HedgePolicy) -> Client {
+        self.hedge = Some(Arc::new((secondary, policy)));
+        self
+    }
+
+    /// Pack requests queued while the connection is busy into batch frames,
+    /// once the server showed it can unpack them. This saves syscalls and
+    /// wakeups for callers issuing many small calls concurrently.
+    ///
+    /// Applies to all clones of this client.
+    pub fn with_batching(self) -> Client {
+        self.batching.wanted.store(true, Ordering::SeqCst);
+        self
+    }
+
+    /// Once the server announced it is going away, send new calls to the
+    /// first of `addrs` accepting a connection instead of failing them
+    /// with [`Error::ServerShutdown`].
+    pub fn with_failover(mut self, addrs: &[&str]) -> Client {
+        self.failover = Arc::new(Failover {
+            addrs: addrs.iter().map(|a| a.to_string()).collect(),
+            current: Mutex::new(None),
         });
+        self
+    }
 
-        Client {
-            fd,
-            sender_tx,
-            client_close,
+    /// Move on to a new connection after `max` calls instead of after
+    /// [`MAX_STREAM_IDS`], e.g. to recycle long-lived connections.
+    ///
+    /// Applies to all clones of this client.
+    pub fn with_stream_id_limit(self, max: usize) -> Client {
+        self.stats
+            .stream_id_limit
+            .store(max.min(MAX_STREAM_IDS), Ordering::SeqCst);
+        self
+    }
+
+    /// Hold calls to the limit on concurrent requests the server advertised
+    /// in its answer to the handshake, as `limit` says, rather than have the
+    /// server reject those over it. Calls are not held before the first
+    /// one returned, nor by servers advertising no limit.
+    ///
+    /// Applies to all clones of this client.
+    pub fn with_stream_limit(self, limit: StreamLimit) -> Client {
+        *self.streams.policy.lock().unwrap() = limit;
+        self
+    }
+
+    /// Count a call of `req` against the server's limit, waiting for an
+    /// earlier call to finish if the client queues calls.
+    fn stream_slot(&self, req: &Request) -> Result<Option<StreamSlot>> {
+        let policy = *self.streams.policy.lock().unwrap();
+        let max = self
+            .stats
+            .server_info
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|info| info.max_streams);
+        let max = match max {
+            Some(max) if policy != StreamLimit::Ignore => max,
+            _ => return Ok(None),
+        };
+        let mut active = self.streams.active.lock().unwrap();
+        if *active >= max {
+            if policy == StreamLimit::FailFast {
+                return Err(get_rpc_status(
+                    Code::RESOURCE_EXHAUSTED,
+                    format!("server handles at most {} requests at once", max),
+                ));
+            }
+            let full = |active: &mut usize| *active >= max;
+            active = if req.timeout_nano > 0 {
+                let timeout = Duration::from_nanos(req.timeout_nano as u64);
+                let (active, waited) = self
+                    .streams
+                    .freed
+                    .wait_timeout_while(active, timeout, full)
+                    .unwrap();
+                if waited.timed_out() {
+                    return Err(get_rpc_status(
+                        Code::DEADLINE_EXCEEDED,
+                        "timed out waiting for the server to take the call".to_string(),
+                    ));
+                }
+                active
+            } else {
+                self.streams.freed.wait_while(active, full).unwrap()
+            };
+        }
+        *active += 1;
+        Ok(Some(StreamSlot(self.streams.clone())))
+    }
+
+    /// Run every request through `interceptor` before sending it, after
+    /// the interceptors added before.
+    pub fn with_interceptor(mut self, interceptor: Interceptor) -> Client {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    fn intercept(&self, mut req: Request) -> Request {
+        for interceptor in self.interceptors.iter() {
+            interceptor(&mut req);
+        }
+        req
+    }
+
+    /// Give calls made without a timeout `timeout`, rather than waiting
+    /// for their response forever, so a wedged server cannot hang the
+    /// caller. Notifications are left alone.
+    ///
+    /// The timeouts of [`Client::with_service_timeout`] and
+    /// [`Client::with_method_timeout`] take precedence.
+    pub fn with_default_timeout(mut self, timeout: Duration) -> Client {
+        self.timeouts.client = Some(timeout);
+        self
+    }
+
+    /// Like [`Client::with_default_timeout`], for the calls to `service`,
+    /// e.g. `grpc.Health`.
+    pub fn with_service_timeout(mut self, service: &str, timeout: Duration) -> Client {
+        self.timeouts.services.insert(service.to_string(), timeout);
+        self
+    }
+
+    /// Like [`Client::with_default_timeout`], for the calls to the method
+    /// `path`, e.g. `/grpc.Health/Check`.
+    pub fn with_method_timeout(mut self, path: &str, timeout: Duration) -> Client {
+        self.timeouts.methods.insert(path.to_string(), timeout);
+        self
+    }
+
+    /// [`Client::intercept`] a call, then give it its default timeout if it
+    /// has none.
+    fn intercept_call(&self, req: Request) -> Request {
+        let mut req = self.intercept(req);
+        if req.timeout_nano <= 0 {
+            let path = self.paths.get(&req.service, &req.method);
+            if let Some(timeout) = self.timeouts.get(&req.service, &path) {
+                req.set_timeout_nano(timeout.as_nanos() as i64);
+            }
+        }
+        req
+    }
+
+    /// Check the successful responses of method `path` with `validate`,
+    /// e.g. for invariants the schema cannot express, so a server bug is
+    /// caught before the response reaches the caller. Calls whose response
+    /// `validate` fails, or which does not decode as `M`, fail with
+    /// [`Error::InvalidResponse`].
+    ///
+    /// Responses to [`Client::call_cancellable`] are not checked. The
+    /// payload is decoded once more by the caller itself.
+    pub fn with_response_validator<M, F>(mut self, path: &str, validate: F) -> Client
+    where
+        M: Message,
+        F: Fn(&M) -> Result<()> + Send + Sync + 'static,
+    {
+        let path = path.to_string();
+        let method = path.clone();
+        let validator: ResponseValidator = Arc::new(move |payload: &[u8]| {
+            let mut s = CodedInputStream::from_bytes(payload);
+            let mut m = M::new();
+            m.merge_from(&mut s).map_err(|e| {
+                Error::InvalidResponse(format!("{} does not decode: {}", method, e))
+            })?;
+            validate(&m).map_err(|e| {
+                let reason = match e {
+                    Error::InvalidResponse(r) => r,
+                    e => e.to_status().message,
+                };
+                Error::InvalidResponse(format!("{}: {}", method, reason))
+            })
+        });
+        self.validators.insert(path, validator);
+        self
+    }
+
+    /// Run the validator of `path`, if any, on the response `res`.
+    fn validate(&self, path: &str, res: Result<Response>) -> Result<Response> {
+        let res = res?;
+        match self.validators.get(path) {
+            Some(validate) if res.get_status().code == Code::OK => {
+                if let Err(e) = validate(&res.payload) {
+                    warn!("rejected response: {:?}", e);
+                    return Err(e);
+                }
+                Ok(res)
+            }
+            _ => Ok(res),
+        }
+    }
+
+    /// Log each call at `level`, with payloads rendered by `redactor` if
+    /// given, or as escaped bytes otherwise. Takes effect at once, for all
+    /// clones of this client.
+    ///
+    /// Calls are logged at the `info` level.
+    pub fn set_debug(&self, level: DebugLevel, redactor: Option<Redactor>) {
+        *self.debug.lock().unwrap() = DebugLog { level, redactor };
+    }
+
+    /// Start logging a call of `req` to `path`, if calls are logged.
+    fn log_call(&self, req: &Request, path: &Arc<str>, kind: &str) -> Option<CallLog> {
+        let debug = self.debug.lock().unwrap().clone();
+        if debug.level == DebugLevel::Off {
+            return None;
+        }
+        let log = CallLog {
+            debug,
+            path: path.clone(),
+            start: Instant::now(),
+        };
+        log.sent(req, kind);
+        Some(log)
+    }
+
+    fn stream_ids_exhausted(&self) -> bool {
+        self.stats.stream_ids_used.load(Ordering::SeqCst)
+            >= self.stats.stream_id_limit.load(Ordering::SeqCst)
+    }
+
+    fn usable(&self) -> bool {
+        self.stats.going_away.lock().unwrap().is_none()
+            && !self.stream_ids_exhausted()
+            && !self.forked()
+    }
+
+    /// Whether this client was inherited from the parent of a `fork()`.
+    /// Its connection belongs to the parent, and its threads did not
+    /// survive the fork.
+    fn forked(&self) -> bool {
+        self.fork.pid != process::id()
+    }
+
+    /// Stop writing to the connection, after any write in progress, so
+    /// the process can fork without the child inheriting a half-written
+    /// frame. Calls are queued meanwhile. Follow with
+    /// [`resume_after_fork`] in the parent and [`reset_after_fork`] in the
+    /// child.
+    ///
+    /// [`resume_after_fork`]: Client::resume_after_fork
+    /// [`reset_after_fork`]: Client::reset_after_fork
+    pub fn prepare_for_fork(&self) {
+        *self.fork.paused.lock().unwrap() = true;
+    }
+
+    /// Start writing again in the parent after a fork.
+    pub fn resume_after_fork(&self) {
+        *self.fork.paused.lock().unwrap() = false;
+        self.fork.resumed.notify_all();
+    }
+
+    /// Make an inherited client usable in the child of a fork by
+    /// connecting again to the address it was connected to. The parent's
+    /// connection is left alone, including when the client is dropped.
+    ///
+    /// Calls made in the child do this on their own; it fails for clients
+    /// not made by [`Client::connect`], which cannot be used in the child.
+    pub fn reset_after_fork(&self) -> Result<()> {
+        if !self.forked() {
+            return Err(Error::Others(
+                "reset_after_fork() must be called in the child".to_string(),
+            ));
+        }
+        self.redirect().map(|_| ())
+    }
+
+    /// Tell the server no more calls will be sent, once the calls already
+    /// made are written; the server sees end of file. Responses to those
+    /// calls are still received, and new calls fail.
+    pub fn shutdown_write(&self) -> Result<()> {
+        if self.write_closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.stats.queued_writes.fetch_add(1, Ordering::SeqCst);
+        self.sender_tx.send(Outgoing::ShutdownWrite).map_err(|_| {
+            self.stats.queued_writes.fetch_sub(1, Ordering::SeqCst);
+            Error::ConnectionClosed
+        })
+    }
+
+    /// Stop receiving responses. Calls still waiting for one fail with
+    /// [`Error::ConnectionClosed`].
+    pub fn shutdown_read(&self) -> Result<()> {
+        shutdown(self.client_close.fd, Shutdown::Read).map_err(|e| Error::Socket(e.to_string()))
+    }
+
+    /// The library version and protocol capabilities the server sent in
+    /// answer to the handshake, see
+    /// [`MESSAGE_TYPE_HELLO`](crate::MESSAGE_TYPE_HELLO). Known once the
+    /// first call on the connection returned; `None` until then, and for
+    /// servers predating the handshake.
+    pub fn server_info(&self) -> Option<PeerInfo> {
+        self.stats.server_info.lock().unwrap().clone()
+    }
+
+    /// Check that the server was built from the same definitions of
+    /// `methods` as this client, by the hashes ttrpc-compiler generates for
+    /// them, e.g. `GREETER_METHOD_HASHES`. The methods the server does not
+    /// serve or defines differently fail the check with FAILED_PRECONDITION
+    /// naming them, rather than calls failing with UNIMPLEMENTED or garbled
+    /// messages later on. Methods the server has no hash for pass.
+    ///
+    /// Fails with UNIMPLEMENTED if the server predates the check, and with
+    /// DEADLINE_EXCEEDED if it did not answer within `timeout`.
+    pub fn check_methods(&self, methods: &[(&str, u64)], timeout: Duration) -> Result<()> {
+        if self.write_closed.load(Ordering::SeqCst) {
+            return Err(write_closed_error());
+        }
+        let named: Vec<(String, MethodHash)> = methods
+            .iter()
+            .map(|(path, hash)| (path.to_string(), MethodHash::Hash(*hash)))
+            .collect();
+        {
+            let mut answers = self.stats.method_hashes.lock().unwrap();
+            for (path, _) in named.iter() {
+                answers.remove(path);
+            }
+        }
+        self.stats.queued_writes.fetch_add(1, Ordering::SeqCst);
+        if self.sender_tx.send(Outgoing::Hello(named)).is_err() {
+            self.stats.queued_writes.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::ConnectionClosed);
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut answers = self.stats.method_hashes.lock().unwrap();
+        while !methods.iter().all(|(path, _)| answers.contains_key(*path)) {
+            // the answer to the HELLO frame sent on connecting tells
+            if let Some(info) = self.server_info() {
+                if !info.has("method_hash") {
+                    return Err(get_rpc_status(
+                        Code::UNIMPLEMENTED,
+                        format!("server {} cannot check methods", info.version),
+                    ));
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(get_rpc_status(
+                    Code::DEADLINE_EXCEEDED,
+                    format!("no answer to the method check within {:?}", timeout),
+                ));
+            }
+            answers = self
+                .stats
+                .hello_answered
+                .wait_timeout(answers, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        let mut mismatched = Vec::new();
+        for (path, hash) in methods {
+            match answers[*path] {
+                MethodHash::Hash(h) if h != *hash => mismatched.push(format!(
+                    "{} is defined differently (client {:016x}, server {:016x})",
+                    path, hash, h
+                )),
+                MethodHash::Missing => mismatched.push(format!("{} is not served", path)),
+                _ => {}
+            }
+        }
+        if mismatched.is_empty() {
+            return Ok(());
+        }
+        Err(get_rpc_status(
+            Code::FAILED_PRECONDITION,
+            format!("server does not match: {}", mismatched.join("; ")),
+        ))
+    }
+
+    /// Report the state of the connection, e.g. to decide on backing off
+    /// rather than retrying blindly. Once calls moved on to a new
+    /// connection, see [`ClientStats::reconnects`], that one is reported.
+    pub fn stats(&self) -> ClientStats {
+        let current = self.failover.current.lock().unwrap().clone();
+        if let Some(c) = current {
+            return ClientStats {
+                reconnects: self.stats.reconnects.load(Ordering::SeqCst),
+                ..c.stats()
+            };
+        }
+        ClientStats {
+            in_flight: self.recver_map.lock().unwrap().len(),
+            queued_writes: self.stats.queued_writes.load(Ordering::SeqCst),
+            last_error: self.stats.last_error.lock().unwrap().clone(),
+            rtt: *self.stats.rtt.lock().unwrap(),
+            going_away: self.stats.going_away.lock().unwrap().clone(),
+            stream_ids_used: self.stats.stream_ids_used.load(Ordering::SeqCst),
+            abandoned: self.stats.abandoned.lock().unwrap().streams.len(),
+            late_responses: self.stats.late_responses.load(Ordering::SeqCst),
+            abandoned_expired: self.stats.abandoned_expired.load(Ordering::SeqCst),
+            reconnects: self.stats.reconnects.load(Ordering::SeqCst),
+        }
+    }
+
+    /// The client new calls go to: `None` for this one, or another one if
+    /// the server went away or this connection ran out of stream ids.
+    fn redirect(&self) -> Result<Option<Client>> {
+        let going_away = self.stats.going_away.lock().unwrap().clone();
+        let (reason, own) = match going_away {
+            // the connection is the parent's, so connect again
+            _ if self.forked() => ("inherited across fork".to_string(), self.addr.as_ref()),
+            Some(reason) => (reason, None),
+            // the server is still there, so a new connection will do
+            None if self.stream_ids_exhausted() => {
+                ("stream ids exhausted".to_string(), self.addr.as_ref())
+            }
+            None => return Ok(None),
+        };
+
+        let mut current = self.failover.current.lock().unwrap();
+        if let Some(c) = current.as_ref() {
+            if c.usable() {
+                return Ok(Some(c.clone()));
+            }
+        }
+        let addrs = own.map(|a| a.as_str()).into_iter();
+        for addr in addrs.chain(self.failover.addrs.iter().map(|a| a.as_str())) {
+            let c = match self.socket_options.as_ref() {
+                Some(o) => Client::connect_with_options(addr, (**o).clone()),
+                None => Client::connect(addr),
+            };
+            match c {
+                Ok(c) => {
+                    debug!("moving on to {}: {}", addr, reason);
+                    c.batching.wanted.store(
+                        self.batching.wanted.load(Ordering::SeqCst),
+                        Ordering::SeqCst,
+                    );
+                    let limit = self.stats.stream_id_limit.load(Ordering::SeqCst);
+                    let policy = *self.streams.policy.lock().unwrap();
+                    let c = c.with_stream_id_limit(limit).with_stream_limit(policy);
+                    *c.panic_handler.lock().unwrap() = self.panic_handler.lock().unwrap().clone();
+                    *current = Some(c.clone());
+                    self.stats.reconnects.fetch_add(1, Ordering::SeqCst);
+                    return Ok(Some(c));
+                }
+                Err(e) => trace!("connecting to {} failed: {:?}", addr, e),
+            }
+        }
+        if self.forked() {
+            Err(Error::Others(format!(
+                "client {}, and cannot connect again",
+                reason
+            )))
+        } else if self.stats.going_away.lock().unwrap().is_some() {
+            Err(Error::ServerShutdown(reason))
+        } else {
+            Err(get_rpc_status(Code::RESOURCE_EXHAUSTED, reason))
+        }
+    }
+
+    fn queue(&self, item: Outgoing) -> Result<()> {
+        if self.write_closed.load(Ordering::SeqCst) {
+            return Err(write_closed_error());
+        }
+        // every queued frame takes the next stream id
+        let limit = self.stats.stream_id_limit.load(Ordering::SeqCst);
+        if self.stats.stream_ids_used.fetch_add(1, Ordering::SeqCst) >= limit {
+            self.stats.stream_ids_used.fetch_sub(1, Ordering::SeqCst);
+            return Err(get_rpc_status(
+                Code::RESOURCE_EXHAUSTED,
+                "stream ids exhausted".to_string(),
+            ));
+        }
+        self.stats.queued_writes.fetch_add(1, Ordering::SeqCst);
+        self.sender_tx.send(item).map_err(|e| {
+            self.stats.queued_writes.fetch_sub(1, Ordering::SeqCst);
+            Error::Others(format!("Send packet to sender error {}", e))
+        })
+    }
+
+    fn dispatch(&self, buf: Vec<u8>, tx: mpsc::SyncSender<Result<Vec<u8>>>) -> Result<()> {
+        self.queue(Outgoing::Request(buf, Some(tx), None))
+    }
+
+    /// Send `req` without waiting for, or getting, a response.
+    ///
+    /// The request is flagged so the server runs its handler but sends
+    /// nothing back. Returns once the request is queued; write errors are
+    /// only logged.
+    pub fn notify(&self, req: Request) -> Result<()> {
+        self.send_notification(self.intercept(req))
+    }
+
+    fn send_notification(&self, req: Request) -> Result<()> {
+        if let Some(c) = self.redirect()? {
+            return c.notify(req);
+        }
+        let path = self.paths.get(&req.service, &req.method);
+        self.log_call(&req, &path, "notification");
+        let buf = encode_request(&req)?;
+        match self.queue(Outgoing::Request(buf, None, None)) {
+            // lost the race for the last stream id
+            Err(_) if self.stream_ids_exhausted() => self.send_notification(req),
+            r => r,
         }
     }
+
+    /// Send `req` and wait for its response.
+    ///
+    /// A request with a `timeout_nano` is waited for that long, then
+    /// cancelled and failed with `DEADLINE_EXCEEDED`.
     pub fn request(&self, req: Request) -> Result<Response> {
-        let mut buf = Vec::with_capacity(req.compute_size() as usize);
-        let mut s = CodedOutputStream::vec(&mut buf);
-        req.write_to(&mut s).map_err(err_to_Others!(e, ""))?;
-        s.flush().map_err(err_to_Others!(e, ""))?;
+        let req = self.intercept_call(req);
+        let path = self.paths.get(&req.service, &req.method);
+        if let Some(c) = self.redirect()? {
+            return self.validate(&path, c.request(req));
+        }
+        let _slot = self.stream_slot(&req)?;
+        let log = self.log_call(&req, &path, "call");
+        let res = self.validate(&path, self.send_request(req, &path));
+        if let Some(log) = log {
+            log.finished(&res);
+        }
+        res
+    }
+
+    fn send_request(&self, req: Request, path: &str) -> Result<Response> {
+        if let Some(hedge) = self.hedge.as_ref() {
+            if hedge.1.methods.contains(path) {
+                let buf = encode_request(&req)?;
+                let deadline = (req.timeout_nano > 0)
+                    .then(|| Instant::now() + Duration::from_nanos(req.timeout_nano as u64));
+                return self.request_hedged(buf, &hedge.0, hedge.1.delay, deadline);
+            }
+        }
 
-        let (tx, rx) = mpsc::sync_channel(0);
+        if req.timeout_nano > 0 {
+            return self.call_and_wait(&req, Arc::default());
+        }
 
-        self.sender_tx
-            .send((buf, tx))
-            .map_err(err_to_Others!(e, "Send packet to sender error "))?;
+        let buf = encode_request(&req)?;
+        let (tx, rx) = mpsc::sync_channel(1);
+        if let Err(e) = self.dispatch(buf, tx) {
+            // lost the race for the last stream id
+            if self.stream_ids_exhausted() {
+                return self.send_request(req, path);
+            }
+            return Err(e);
+        }
         let result = rx
             .recv()
             .map_err(err_to_Others!(e, "Recive packet from recver error "))?;
 
-        let buf = result?;
-        let mut s = CodedInputStream::from_bytes(&buf);
-        let mut res = Response::new();
-        res.merge_from(&mut s)
-            .map_err(err_to_Others!(e, "Unpack response error "))?;
+        decode_response(result?)
+    }
+
+    /// Send `req` and wait for its response, calling `on_progress` with
+    /// the payload of each progress update the server sends meanwhile,
+    /// see [`MESSAGE_TYPE_PROGRESS`](crate::MESSAGE_TYPE_PROGRESS).
+    ///
+    /// `on_progress` runs on the thread receiving the responses of the
+    /// connection, so it should be quick.
+    pub fn request_with_progress<F>(&self, req: Request, on_progress: F) -> Result<Response>
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        let req = self.intercept_call(req);
+        let call = Arc::new(Call {
+            progress: Some(Arc::new(on_progress)),
+            ..Default::default()
+        });
+        let _slot = self.stream_slot(&req)?;
+        let path = self.paths.get(&req.service, &req.method);
+        let log = self.log_call(&req, &path, "call");
+        let res = self.validate(&path, self.call_and_wait(&req, call));
+        if let Some(log) = log {
+            log.finished(&res);
+        }
+        res
+    }
 
-        let status = res.get_status();
-        if status.get_code() != Code::OK {
-            return Err(Error::RpcStatus((*status).clone()));
+    /// Send `req` with `extensions` and wait for its response, see
+    /// [`extension`](crate::extension).
+    ///
+    /// Extensions are only sent once the server's answer to the handshake
+    /// shows it takes them, so they are left out of calls to servers
+    /// predating them, and of the calls made before the first one
+    /// returned.
+    pub fn request_with_extensions(
+        &self,
+        req: Request,
+        extensions: &Extensions,
+    ) -> Result<Response> {
+        let req = self.intercept_call(req);
+        let block = if extensions.is_empty() {
+            None
+        } else {
+            Some(extensions.encode()?)
+        };
+        let call = Arc::new(Call {
+            extensions: block,
+            ..Default::default()
+        });
+        let _slot = self.stream_slot(&req)?;
+        let path = self.paths.get(&req.service, &req.method);
+        let log = self.log_call(&req, &path, "call");
+        let res = self.validate(&path, self.call_and_wait(&req, call));
+        if let Some(log) = log {
+            log.finished(&res);
         }
+        res
+    }
 
-        Ok(res)
+    /// Send `req` as `call` and wait for its response, until the deadline
+    /// of `req` if it has one. A call timing out is cancelled, so nothing
+    /// is left waiting for its response.
+    fn call_and_wait(&self, req: &Request, call: Arc<Call>) -> Result<Response> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let client = self.dispatch_cancellable(req, tx, &call)?;
+        let result = if req.timeout_nano > 0 {
+            match rx.recv_timeout(Duration::from_nanos(req.timeout_nano as u64)) {
+                Ok(result) => result,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    client.cancel(&call);
+                    return Err(deadline_exceeded());
+                }
+                Err(e) => {
+                    return Err(Error::Others(format!(
+                        "Recive packet from recver error {}",
+                        e
+                    )))
+                }
+            }
+        } else {
+            rx.recv()
+                .map_err(err_to_Others!(e, "Recive packet from recver error "))?
+        };
+
+        decode_response(result?)
+    }
+
+    /// Send `req` and return at once with a handle to wait for its
+    /// response and a canceller. Triggering or dropping the canceller
+    /// makes the wait end with [`Error::Cancelled`] and tells the server
+    /// to drop the request, see
+    /// [`MESSAGE_TYPE_CANCEL`](crate::MESSAGE_TYPE_CANCEL).
+    ///
+    /// Errors queueing the request are returned by the handle. The
+    /// canceller keeps the connection open until it is dropped.
+    pub fn call_cancellable(&self, req: Request) -> (ResultHandle, Canceller) {
+        // room for both the response and the cancellation, so neither
+        // the receiver thread nor the canceller ever blocks
+        let (tx, rx) = mpsc::sync_channel(2);
+        let req = self.intercept_call(req);
+        let call = Arc::new(Call::default());
+        let path = self.paths.get(&req.service, &req.method);
+        let log = self.log_call(&req, &path, "call");
+        let dispatched = self.stream_slot(&req).and_then(|slot| {
+            let client = self.dispatch_cancellable(&req, tx.clone(), &call)?;
+            Ok((slot, client))
+        });
+        let (slot, client) = match dispatched {
+            Ok((slot, client)) => (slot, Some(client)),
+            Err(e) => {
+                call.cancelled.store(true, Ordering::SeqCst);
+                tx.send(Err(e)).unwrap_or(());
+                (None, None)
+            }
+        };
+        let handle = ResultHandle {
+            rx,
+            log,
+            _slot: slot,
+        };
+        (handle, Canceller { tx, call, client })
     }
+
+    /// Start a call of `req` streaming messages to the method, which
+    /// answers once the stream is closed, see [`stream`](crate::stream).
+    /// The payload of `req` is left empty.
+    pub fn client_streaming<Q, P>(&self, req: Request) -> Result<ClientStreamSender<Q, P>>
+    where
+        Q: Message,
+        P: Message,
+    {
+        let stream = self.open_stream(req, FLAG_REMOTE_OPEN)?;
+        Ok(ClientStreamSender::new(stream))
+    }
+
+    /// Start a call of `req`, whose payload is the request message, to a
+    /// method streaming messages back ahead of its response.
+    pub fn server_streaming<P: Message>(&self, req: Request) -> Result<ServerStreamReceiver<P>> {
+        let stream = self.open_stream(req, FLAG_REMOTE_CLOSED)?;
+        Ok(ServerStreamReceiver::new(stream))
+    }
+
+    /// Start a call of `req` where the client and the method stream
+    /// messages to each other. The payload of `req` is left empty.
+    pub fn duplex_streaming<Q, P>(&self, req: Request) -> Result<DuplexStream<Q, P>>
+    where
+        Q: Message,
+        P: Message,
+    {
+        let stream = self.open_stream(req, FLAG_REMOTE_OPEN)?;
+        Ok(DuplexStream::new(stream))
+    }
+
+    /// Send `req` flagged with `flags` as a streaming call. Its deadline is
+    /// left to the server to enforce.
+    fn open_stream(&self, req: Request, flags: u8) -> Result<ClientStream> {
+        let req = self.intercept_call(req);
+        let (data_tx, data) = mpsc::channel();
+        let call = Arc::new(Call {
+            stream_flags: flags,
+            data: Mutex::new(Some(data_tx)),
+            ..Default::default()
+        });
+        let slot = self.stream_slot(&req)?;
+        let path = self.paths.get(&req.service, &req.method);
+        let log = self.log_call(&req, &path, "stream");
+        let (tx, res) = mpsc::sync_channel(1);
+        let client = self.dispatch_cancellable(&req, tx, &call)?;
+        Ok(ClientStream {
+            client,
+            call,
+            data,
+            res,
+            ended: None,
+            send_closed: false,
+            log,
+            _slot: slot,
+        })
+    }
+
+    /// Queue a message of the streaming `call`, after its request.
+    fn send_data(&self, call: &Arc<Call>, buf: Vec<u8>, flags: u8) -> Result<()> {
+        if self.write_closed.load(Ordering::SeqCst) {
+            return Err(write_closed_error());
+        }
+        self.stats.queued_writes.fetch_add(1, Ordering::SeqCst);
+        if self
+            .sender_tx
+            .send(Outgoing::Data(call.clone(), buf, flags))
+            .is_err()
+        {
+            self.stats.queued_writes.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::ConnectionClosed);
+        }
+        Ok(())
+    }
+
+    /// Queue `req`, returning the client it went through.
+    fn dispatch_cancellable(
+        &self,
+        req: &Request,
+        tx: mpsc::SyncSender<Result<Vec<u8>>>,
+        call: &Arc<Call>,
+    ) -> Result<Client> {
+        if let Some(c) = self.redirect()? {
+            return c.dispatch_cancellable(req, tx, call);
+        }
+        let buf = encode_request(req)?;
+        match self.queue(Outgoing::Request(buf, Some(tx.clone()), Some(call.clone()))) {
+            Ok(()) => Ok(self.clone()),
+            // lost the race for the last stream id
+            Err(_) if self.stream_ids_exhausted() => self.dispatch_cancellable(req, tx, call),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stop waiting for `call`, and have the server drop it if it was sent.
+    fn cancel(&self, call: &Call) {
+        call.cancelled.store(true, Ordering::SeqCst);
+        let mut map = self.recver_map.lock().unwrap();
+        let stream_id = call.stream_id.load(Ordering::SeqCst);
+        // not sent yet, or already answered
+        if stream_id == 0 || map.remove(&stream_id).is_none() {
+            return;
+        }
+        // before the response can arrive
+        self.stats.abandon(stream_id);
+        drop(map);
+        self.stats.queued_writes.fetch_add(1, Ordering::SeqCst);
+        if self.sender_tx.send(Outgoing::Cancel(stream_id)).is_err() {
+            self.stats.queued_writes.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn request_hedged(
+        &self,
+        buf: Vec<u8>,
+        secondary: &Client,
+        delay: Duration,
+        deadline: Option<Instant>,
+    ) -> Result<Response> {
+        // Room for both responses, so the losing one never blocks a
+        // receiver thread after we stopped listening.
+        let (tx, rx) = mpsc::sync_channel(2);
+        let primary = Arc::new(Call::default());
+        let hedged = Arc::new(Call::default());
+        self.queue(Outgoing::Request(
+            buf.clone(),
+            Some(tx.clone()),
+            Some(primary.clone()),
+        ))?;
+        let expired = || deadline.is_some_and(|d| Instant::now() >= d);
+        // the next response, until the deadline if there is one
+        let next = || match deadline {
+            Some(d) => rx.recv_timeout(d.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        let hedge_at = Instant::now() + delay;
+        let first_wait = deadline.map_or(hedge_at, |d| d.min(hedge_at));
+        let mut pending = 1;
+        let first = match rx.recv_timeout(first_wait.saturating_duration_since(Instant::now())) {
+            Ok(result) => Some(result),
+            Err(mpsc::RecvTimeoutError::Timeout) if expired() => {
+                self.cancel(&primary);
+                return Err(deadline_exceeded());
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                trace!("hedging request after {:?}", delay);
+                let call = Outgoing::Request(buf, Some(tx), Some(hedged.clone()));
+                if secondary.queue(call).is_ok() {
+                    pending += 1;
+                }
+                None
+            }
+            Err(e) => {
+                return Err(Error::Others(format!(
+                    "Recive packet from recver error {}",
+                    e
+                )))
+            }
+        };
+
+        let mut result = match first {
+            Some(result) => return decode_response(result?),
+            None => match next() {
+                Ok(result) => result,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    self.cancel(&primary);
+                    secondary.cancel(&hedged);
+                    return Err(deadline_exceeded());
+                }
+                Err(e) => Err(Error::Others(format!(
+                    "Recive packet from recver error {}",
+                    e
+                ))),
+            },
+        };
+        let res = loop {
+            pending -= 1;
+            let res = result.and_then(decode_response);
+            if res.is_ok() || pending == 0 {
+                break res;
+            }
+            result = match next() {
+                Ok(r) => r,
+                Err(mpsc::RecvTimeoutError::Timeout) => break Err(deadline_exceeded()),
+                Err(_) => break res,
+            };
+        };
+        // the server drops the call still running, if any; the answered
+        // one is no longer waited for, so cancelling it does nothing
+        self.cancel(&primary);
+        secondary.cancel(&hedged);
+        res
+    }
+}
+
+/// The pending response of a [`Client::call_cancellable`] call.
+pub struct ResultHandle {
+    rx: mpsc::Receiver<Result<Vec<u8>>>,
+    log: Option<CallLog>,
+    // held until the handle is dropped
+    _slot: Option<StreamSlot>,
+}
+
+impl ResultHandle {
+    /// Wait for the response, or for the call to be cancelled.
+    pub fn wait(self) -> Result<Response> {
+        let res = self
+            .rx
+            .recv()
+            .map_err(err_to_Others!(e, "Recive packet from recver error "))
+            .and_then(|result| result.and_then(decode_response));
+        self.finished(res)
+    }
+
+    /// Wait at most `timeout` for the response, giving the handle back if
+    /// it has not arrived.
+    pub fn wait_timeout(self, timeout: Duration) -> std::result::Result<Result<Response>, Self> {
+        let res = match self.rx.recv_timeout(timeout) {
+            Ok(result) => result.and_then(decode_response),
+            Err(mpsc::RecvTimeoutError::Timeout) => return Err(self),
+            Err(e) => Err(Error::Others(format!(
+                "Recive packet from recver error {}",
+                e
+            ))),
+        };
+        Ok(self.finished(res))
+    }
+
+    fn finished(&self, res: Result<Response>) -> Result<Response> {
+        if let Some(log) = self.log.as_ref() {
+            log.finished(&res);
+        }
+        res
+    }
+}
+
+/// Cancels a [`Client::call_cancellable`] call when triggered or dropped.
+pub struct Canceller {
+    tx: mpsc::SyncSender<Result<Vec<u8>>>,
+    call: Arc<Call>,
+    client: Option<Client>,
+}
+
+impl Canceller {
+    /// Make the waiting side return [`Error::Cancelled`] and the server
+    /// drop the request, unless the call already completed.
+    pub fn cancel(&self) {
+        if self.call.cancelled.load(Ordering::SeqCst) {
+            return;
+        }
+        self.tx.try_send(Err(Error::Cancelled)).unwrap_or(());
+        if let Some(client) = self.client.as_ref() {
+            client.cancel(&self.call);
+        }
+    }
+}
+
+impl Drop for Canceller {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// The client end of a streaming call, wrapped by the types of
+/// [`stream`](crate::stream). Dropping it before the response arrived
+/// cancels the call.
+pub(crate) struct ClientStream {
+    // the client the call went through
+    client: Client,
+    call: Arc<Call>,
+    // disconnected once the response arrived
+    data: mpsc::Receiver<Vec<u8>>,
+    res: mpsc::Receiver<Result<Vec<u8>>>,
+    ended: Option<Result<Response>>,
+    send_closed: bool,
+    log: Option<CallLog>,
+    // held until the call ends
+    _slot: Option<StreamSlot>,
+}
+
+impl ClientStream {
+    /// Queue `buf` as the next message of the client.
+    pub(crate) fn send(&self, buf: Vec<u8>) -> Result<()> {
+        if self.send_closed {
+            return Err(Error::Others(
+                "the stream was closed for sending".to_string(),
+            ));
+        }
+        self.client.send_data(&self.call, buf, 0)
+    }
+
+    /// Number of frames queued on the connection and not written yet, the
+    /// messages of other calls included.
+    pub(crate) fn backlog(&self) -> usize {
+        let id = self.call.stream_id.load(Ordering::SeqCst);
+        // nothing more is written for a call which ended
+        if self.call.cancelled.load(Ordering::SeqCst)
+            || (id != 0 && !self.client.recver_map.lock().unwrap().contains_key(&id))
+        {
+            return 0;
+        }
+        self.client.stats.queued_writes.load(Ordering::SeqCst)
+    }
+
+    /// Tell the server the client sends no more messages.
+    pub(crate) fn close_send(&mut self) -> Result<()> {
+        if self.send_closed {
+            return Ok(());
+        }
+        self.send_closed = true;
+        let flags = FLAG_REMOTE_CLOSED | FLAG_NO_DATA;
+        self.client.send_data(&self.call, Vec::new(), flags)
+    }
+
+    /// Wait for the next message of the server, `None` once it answered.
+    /// An answer other than `OK` is returned as an error, once.
+    pub(crate) fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.ended.is_some() {
+            return Ok(None);
+        }
+        // the messages all come before the response
+        if let Ok(buf) = self.data.recv() {
+            return Ok(Some(buf));
+        }
+        self.response().map(|_| None)
+    }
+
+    /// Wait for the response ending the call.
+    pub(crate) fn response(&mut self) -> Result<Response> {
+        if let Some(res) = self.ended.as_ref() {
+            return res.clone();
+        }
+        let res = self
+            .res
+            .recv()
+            .map_err(err_to_Others!(e, "Recive packet from recver error "))
+            .and_then(|result| result.and_then(decode_response));
+        if let Some(log) = self.log.as_ref() {
+            log.finished(&res);
+        }
+        self.ended = Some(res.clone());
+        res
+    }
+}
+
+impl Drop for ClientStream {
+    fn drop(&mut self) {
+        if self.ended.is_none() {
+            self.client.cancel(&self.call);
+        }
+    }
+}
+
+/// Make each of `calls` on its client at once, and wait at most `timeout`
+/// in all for their responses, which are returned in the order of
+/// `calls`. Those not answered in time are cancelled and fail with
+/// `DEADLINE_EXCEEDED`.
+///
+/// The calls share the connections of their clients, so fanning out
+/// takes no thread per call.
+pub fn join_all<'a, I>(calls: I, timeout: Duration) -> Vec<Result<Response>>
+where
+    I: IntoIterator<Item = (&'a Client, Request)>,
+{
+    let deadline = Instant::now() + timeout;
+    let calls: Vec<(ResultHandle, Canceller)> = calls
+        .into_iter()
+        .map(|(client, req)| client.call_cancellable(req))
+        .collect();
+    calls
+        .into_iter()
+        .map(|(handle, canceller)| {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match handle.wait_timeout(left) {
+                Ok(res) => res,
+                Err(_) => {
+                    canceller.cancel();
+                    Err(get_rpc_status(
+                        Code::DEADLINE_EXCEEDED,
+                        "no response before the deadline of the calls".to_string(),
+                    ))
+                }
+            }
+        })
+        .collect()
+}
+
+fn deadline_exceeded() -> Error {
+    get_rpc_status(
+        Code::DEADLINE_EXCEEDED,
+        "no response before the request deadline".to_string(),
+    )
+}
+
+fn write_closed_error() -> Error {
+    Error::Others("the connection was shut down for writing".to_string())
+}
+
+fn decode_response(buf: Vec<u8>) -> Result<Response> {
+    let res = proto::decode_response(&buf)?;
+
+    let status = res.get_status();
+    // a code unknown to us is not success
+    if status.code() != Code::OK {
+        return Err(Error::RpcStatus((*status).clone()));
+    }
+
+    Ok(res)
 }
 
 struct ClientClose {
     fd: RawFd,
     close_fd: RawFd,
+    recver_fd: RawFd,
+    pid: u32,
 }
 
 impl Drop for ClientClose {
     fn drop(&mut self) {
-        close(self.close_fd).unwrap();
-        close(self.fd).unwrap();
+        if self.pid != process::id() {
+            // Inherited across fork: the threads are gone, and shutting
+            // the socket down would cut the parent's connection.
+            close(self.fd).unwrap_or(());
+            close(self.recver_fd).unwrap_or(());
+            close(self.close_fd).unwrap_or(());
+            return;
+        }
+        // Wake up the client threads. The socket itself is closed by the
+        // last of them to quit, so neither works on a reused fd.
+        shutdown(self.fd, Shutdown::Both).unwrap_or(());
+        close(self.close_fd).unwrap_or(());
         trace!("All client is droped");
     }
 }
 
+struct OwnedSocket(RawFd);
+
+impl Drop for OwnedSocket {
+    fn drop(&mut self) {
+        close(self.0).unwrap_or(());
+    }
+}
+
 #[macro_export]
 macro_rules! client_request {
     ($self: ident, $req: ident, $timeout_nano: ident, $server: expr, $method: expr, $cres: ident) => {
@@ -217,3 +2266,220 @@ macro_rules! client_request {
             .map_err(::ttrpc::Err_to_Others!(e, "Unpack get error "))?;
     };
 }
+
+/// Start a streaming call for a generated client, through one of
+/// [`Client::client_streaming`], [`Client::server_streaming`] and
+/// [`Client::duplex_streaming`] as `$open` says.
+#[macro_export]
+macro_rules! client_stream_request {
+    ($self: ident, $req: ident, $timeout_nano: ident, $server: expr, $method: expr, $open: ident) => {{
+        let mut creq = ::ttrpc::Request::new();
+        creq.set_service($server.to_string());
+        creq.set_method($method.to_string());
+        creq.set_timeout_nano($timeout_nano);
+        creq.payload.reserve($req.compute_size() as usize);
+        let mut s = CodedOutputStream::vec(&mut creq.payload);
+        $req.write_to(&mut s)
+            .map_err(::ttrpc::Err_to_Others!(e, ""))?;
+        s.flush().map_err(::ttrpc::Err_to_Others!(e, ""))?;
+
+        $self.client.$open(creq)
+    }};
+    ($self: ident, $timeout_nano: ident, $server: expr, $method: expr, $open: ident) => {{
+        let mut creq = ::ttrpc::Request::new();
+        creq.set_service($server.to_string());
+        creq.set_method($method.to_string());
+        creq.set_timeout_nano($timeout_nano);
+
+        $self.client.$open(creq)
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::{read_message, write_message};
+    use crate::pair::pair;
+    use crate::server::{MethodHandler, Server, ServerHandle, TtrpcContext};
+    use std::os::unix::io::IntoRawFd;
+
+    /// Answers once told to, or once the sender is dropped.
+    struct Held(Arc<Mutex<mpsc::Receiver<()>>>);
+
+    impl MethodHandler for Held {
+        fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<()> {
+            self.0.lock().unwrap().recv().unwrap_or(());
+            ctx.sink().send(Response::new())
+        }
+    }
+
+    /// A server over a socketpair calling `handler` for `/test.Test/Call`,
+    /// and a client of it.
+    fn serve<H>(handler: H) -> (Server, Client)
+    where
+        H: MethodHandler + Send + Sync + 'static,
+    {
+        let (fd, client) = pair().unwrap();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Test/Call".to_string(), Box::new(handler));
+        let mut server = Server::builder()
+            .register_service(methods)
+            .add_connection(fd.into_raw_fd())
+            .build()
+            .unwrap();
+        server.start().unwrap();
+        (server, client)
+    }
+
+    fn request(timeout: Duration) -> Request {
+        let mut req = Request::new();
+        req.set_service("test.Test".to_string());
+        req.set_method("Call".to_string());
+        req.set_timeout_nano(timeout.as_nanos() as i64);
+        req
+    }
+
+    fn code(res: Result<Response>) -> Code {
+        match res {
+            Ok(res) => res.get_status().get_code(),
+            Err(Error::RpcStatus(s)) => s.get_code(),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_hedged_deadline() {
+        let (release, hold) = mpsc::channel::<()>();
+        let hold = Arc::new(Mutex::new(hold));
+        let (primary_server, primary) = serve(Held(hold.clone()));
+        let (secondary_server, secondary) = serve(Held(hold));
+        let policy = HedgePolicy::new(Duration::from_millis(20)).idempotent("/test.Test/Call");
+        let client = primary.with_hedging(secondary, policy);
+
+        // both servers hang, after the hedge and before it
+        for timeout in [100, 10] {
+            let start = Instant::now();
+            let res = client.request(request(Duration::from_millis(timeout)));
+            assert_eq!(code(res), Code::DEADLINE_EXCEEDED);
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+
+        drop(release);
+        primary_server.shutdown().unwrap();
+        secondary_server.shutdown().unwrap();
+    }
+
+    /// A server on a unix socket of its own, and its address.
+    fn listen(name: &str) -> (ServerHandle, String) {
+        let addr = format!("unix://@ttrpc-test-{}-{}", name, std::process::id());
+        let handle = Server::builder()
+            .bind(&addr)
+            .register_diagnostics()
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        (handle, addr)
+    }
+
+    #[test]
+    fn test_stats_reconnects() {
+        let (server, addr) = listen("reconnects");
+        let client = Client::connect(&addr).unwrap().with_stream_id_limit(1);
+        assert!(client.stats().rtt.is_none());
+
+        client.ready(Duration::from_secs(5)).unwrap();
+        assert!(client.stats().rtt.is_some());
+        assert_eq!(client.stats().reconnects, 0);
+        // out of stream ids, so on a new connection
+        client.ready(Duration::from_secs(5)).unwrap();
+        assert_eq!(client.stats().reconnects, 1);
+
+        drop(client);
+        server.shutdown().unwrap();
+    }
+
+    /// The next frame of one of `types` the client sent on `fd`.
+    fn next_frame(fd: RawFd, types: &[u8]) -> MessageHeader {
+        loop {
+            let (mh, _) = read_message(fd).unwrap();
+            if types.contains(&mh.type_) {
+                return mh;
+            }
+        }
+    }
+
+    #[test]
+    fn test_late_responses() {
+        let (fd, client) = pair().unwrap();
+        let fd = fd.into_raw_fd();
+        let call = || {
+            let (handle, canceller) = client.call_cancellable(request(Duration::from_secs(5)));
+            let mh = next_frame(fd, &[MESSAGE_TYPE_REQUEST]);
+            canceller.cancel();
+            assert!(matches!(handle.wait(), Err(Error::Cancelled)));
+            assert_eq!(
+                next_frame(fd, &[MESSAGE_TYPE_CANCEL]).stream_id,
+                mh.stream_id
+            );
+            mh.stream_id
+        };
+
+        // answered after all, and dropped
+        let stream_id = call();
+        assert_eq!(client.stats().abandoned, 1);
+        let buf = Response::new().write_to_bytes().unwrap();
+        let mh = MessageHeader {
+            length: buf.len() as u32,
+            stream_id,
+            type_: MESSAGE_TYPE_RESPONSE,
+            flags: 0,
+        };
+        write_message(fd, mh, buf).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client.stats().late_responses == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = client.stats();
+        assert_eq!((stats.late_responses, stats.abandoned), (1, 0));
+
+        // never answered, and forgotten
+        call();
+        assert_eq!(client.stats().abandoned, 1);
+        let mut abandoned = client.stats.abandoned.lock().unwrap();
+        client
+            .stats
+            .expire(&mut abandoned, Instant::now() + ABANDONED_TTL);
+        drop(abandoned);
+        let stats = client.stats();
+        assert_eq!((stats.abandoned, stats.abandoned_expired), (0, 1));
+        assert_eq!(stats.late_responses, 1);
+
+        drop(client);
+        close(fd).unwrap();
+    }
+
+    #[test]
+    fn test_stream_id_cycling() {
+        let (server, addr) = listen("cycling");
+        let client = Client::connect(&addr).unwrap().with_stream_id_limit(2);
+        let mut ping = Request::new();
+        ping.set_service(DIAGNOSTICS_SERVICE.to_string());
+        ping.set_method("Ping".to_string());
+
+        for used in [1, 2].iter() {
+            client.request(ping.clone()).unwrap();
+            assert_eq!(client.stats().stream_ids_used, *used);
+        }
+        assert_eq!(client.stats().reconnects, 0);
+        // the connection ran out, so the third call goes to a new one
+        client.request(ping).unwrap();
+        let stats = client.stats();
+        assert_eq!((stats.stream_ids_used, stats.reconnects), (1, 1));
+        let debug = server.debug_handle().unwrap();
+        assert_eq!(debug.connections().len(), 2);
+
+        drop(client);
+        server.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Address and method path handling shared by the server and the client.
+
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::socket::*;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::error::{Error, Result};
+use crate::seccomp;
+
+/// Split `host` into its lower-cased scheme and the scheme specific rest.
+pub(crate) fn parse_host(host: &str) -> Result<(String, String)> {
+    let hostv: Vec<&str> = host.trim().split("://").collect();
+    if hostv.len() != 2 {
+        return Err(Error::Others(format!("Host {} is not right", host)));
+    }
+
+    Ok((hostv[0].to_lowercase(), hostv[1].to_string()))
+}
+
+/// The address of the unix socket named `name`, in the abstract namespace.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn unix_addr(name: String, _server: bool) -> Result<UnixAddr> {
+    let sockaddr_h = name + "\x00";
+    UnixAddr::new_abstract(sockaddr_h.as_bytes()).map_err(err_to_Others!(e, ""))
+}
+
+/// The address of the unix socket at path `name`, as there is no abstract
+/// namespace outside Linux. Servers replace a socket left there by a
+/// server gone before them.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn unix_addr(name: String, server: bool) -> Result<UnixAddr> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let stale = std::fs::symlink_metadata(&name)
+        .map(|m| m.file_type().is_socket())
+        .unwrap_or(false);
+    if server && stale {
+        // a server still accepting on it is no stale one
+        if std::os::unix::net::UnixStream::connect(&name).is_ok() {
+            return Err(Error::AddressInUse(format!("unix://{}", name), None));
+        }
+        std::fs::remove_file(&name)
+            .map_err(err_to_Others!(e, "failed to remove the stale socket: "))?;
+    }
+    UnixAddr::new(name.as_str()).map_err(err_to_Others!(e, ""))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn abstract_name(addr: &UnixAddr) -> Option<&[u8]> {
+    addr.as_abstract()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn abstract_name(_addr: &UnixAddr) -> Option<&[u8]> {
+    None
+}
+
+/// Lock `<path>.lock` for the server binding the unix socket at `path`,
+/// and write the pid of this process in it. The lock lasts as long as the
+/// returned file is open.
+fn lock_path(host: &str, path: &str) -> Result<File> {
+    let name = format!("{}.lock", path);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        // the pid of the owner is read before the lock is taken
+        .truncate(false)
+        .open(&name)
+        .map_err(|e| Error::Socket(format!("open {}: {}", name, e)))?;
+    if let Err(e) = flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        if e.as_errno() == Some(nix::errno::Errno::EWOULDBLOCK) {
+            let mut owner = String::new();
+            file.read_to_string(&mut owner).unwrap_or(0);
+            return Err(Error::AddressInUse(
+                host.to_string(),
+                owner.trim().parse().ok(),
+            ));
+        }
+        return Err(Error::Socket(format!("flock {}: {}", name, e)));
+    }
+
+    file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| writeln!(file, "{}", std::process::id()))
+        .map_err(|e| Error::Socket(format!("write {}: {}", name, e)))?;
+    Ok(file)
+}
+
+/// The lock of the unix socket file `host` names, see [`lock_path`].
+/// Abstract sockets need none, as the kernel refuses to bind one twice.
+pub(crate) fn lock_socket(host: &str) -> Result<Option<File>> {
+    let (scheme, addr) = parse_host(host)?;
+    if scheme != "unix" || cfg!(any(target_os = "linux", target_os = "android")) {
+        return Ok(None);
+    }
+    lock_path(host, &addr).map(Some)
+}
+
+/// Create a socket suitable for `host` and the address to bind or connect
+/// it to. Servers always bind vsock sockets to `VMADDR_CID_ANY`.
+pub(crate) fn make_socket(host: &str, server: bool) -> Result<(RawFd, SockAddr)> {
+    let (scheme, addr) = parse_host(host)?;
+
+    let sockaddr: SockAddr;
+    let fd: RawFd;
+
+    match scheme.as_str() {
+        "unix" => {
+            let sockaddr_u = unix_addr(addr, server)?;
+            fd = seccomp::socket_cloexec(AddressFamily::Unix)
+                .map_err(|e| Error::Socket(e.to_string()))?;
+            sockaddr = SockAddr::Unix(sockaddr_u);
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        "vsock" => {
+            let host_port_v: Vec<&str> = addr.split(':').collect();
+            if host_port_v.len() != 2 {
+                return Err(Error::Others(format!(
+                    "Host {} is not right for vsock",
+                    host
+                )));
+            }
+            let cid = if server {
+                libc::VMADDR_CID_ANY
+            } else {
+                u32::from_str(host_port_v[0])
+                    .map_err(err_to_Others!(e, "the vsock cid is not a number: "))?
+            };
+            let port = match host_port_v[1] {
+                // let the kernel pick a free port
+                "-1" | "0" if server => libc::VMADDR_PORT_ANY,
+                p => u32::from_str(p)
+                    .map_err(err_to_Others!(e, "the vsock port is not a number: "))?,
+            };
+            fd = seccomp::socket_cloexec(AddressFamily::Vsock)
+                .map_err(|e| Error::Socket(e.to_string()))?;
+            sockaddr = SockAddr::new_vsock(cid, port);
+        }
+        _ => return Err(Error::Others(format!("Scheme {} is not supported", scheme))),
+    };
+
+    Ok((fd, sockaddr))
+}
+
+/// Format a socket address the way hosts are given to
+/// [`ServerBuilder::bind`](crate::ServerBuilder::bind) and
+/// [`Client::connect`](crate::Client::connect).
+pub(crate) fn format_addr(addr: &SockAddr) -> String {
+    match addr {
+        SockAddr::Unix(u) => match (abstract_name(u), u.path()) {
+            (Some(name), _) => format!(
+                "unix://{}",
+                String::from_utf8_lossy(name).trim_end_matches('\0')
+            ),
+            (None, Some(path)) => format!("unix://{}", path.display()),
+            (None, None) => "unix://".to_string(),
+        },
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        SockAddr::Vsock(v) if v.cid() == libc::VMADDR_CID_ANY => {
+            format!("vsock://-1:{}", v.port())
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        SockAddr::Vsock(v) => format!("vsock://{}:{}", v.cid(), v.port()),
+        a => a.to_str(),
+    }
+}
+
+// beyond this, paths are built for each call, so unknown methods a peer
+// makes up cannot grow the table without bound
+const MAX_METHOD_PATHS: usize = 4096;
+
+/// Method paths, `/service/method`, built once for each method rather than
+/// for each call.
+#[derive(Default)]
+pub(crate) struct MethodPaths {
+    // by service, then method
+    paths: RwLock<HashMap<String, HashMap<String, Arc<str>>>>,
+}
+
+impl MethodPaths {
+    /// The path of `method` of `service`.
+    pub(crate) fn get(&self, service: &str, method: &str) -> Arc<str> {
+        if let Some(path) = self
+            .paths
+            .read()
+            .unwrap()
+            .get(service)
+            .and_then(|methods| methods.get(method))
+        {
+            return path.clone();
+        }
+
+        let path: Arc<str> = Arc::from(format!("/{}/{}", service, method));
+        let mut paths = self.paths.write().unwrap();
+        if paths.values().map(|methods| methods.len()).sum::<usize>() < MAX_METHOD_PATHS {
+            paths
+                .entry(service.to_string())
+                .or_default()
+                .insert(method.to_string(), path.clone());
+        }
+        path
+    }
+}
@@ -0,0 +1,523 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Servers and clients set up from one string or set of environment
+//! variables, so a daemon can take its ttrpc settings from its own
+//! configuration. See [`ServerConfig`] and [`ClientConfig`].
+
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::client::{Client, SocketOptions, StreamLimit};
+use crate::error::{Error, Result};
+use crate::server::{ListenerConfig, Phase, Server, ServerBuilder};
+
+const SERVER_KEYS: &[&str] = &[
+    "addr",
+    "threads_default",
+    "threads_min",
+    "threads_max",
+    "max_concurrent_requests",
+    "max_message_size",
+    "reply_grace",
+    "flush_timeout",
+    "shutdown_timeout",
+    "read_timeout",
+    "queue_timeout",
+    "handler_timeout",
+    "write_timeout",
+];
+
+const CLIENT_KEYS: &[&str] = &[
+    "addr",
+    "failover",
+    "stream_id_limit",
+    "stream_limit",
+    "batching",
+    "default_timeout",
+    "keepalive",
+    "send_timeout",
+    "recv_timeout",
+    "send_buffer_size",
+    "recv_buffer_size",
+];
+
+/// The settings of a [`Server`], parsed from comma separated `key=value`
+/// pairs, e.g.
+/// `addr=unix:///run/agent.sock,threads_max=16,handler_timeout=30s`, or
+/// read from environment variables with [`ServerConfig::from_env`].
+///
+/// The keys are `addr`, which may be given more than once,
+/// `threads_default`, `threads_min`, `threads_max`,
+/// `max_concurrent_requests`, `max_message_size`, `reply_grace`,
+/// `flush_timeout`, `shutdown_timeout` and the phase timeouts
+/// `read_timeout`, `queue_timeout`, `handler_timeout` and `write_timeout`.
+/// Durations take a unit: `ms`, `s`, `m` or `h`. Settings left out keep
+/// the defaults of [`Server`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub addrs: Vec<String>,
+    pub thread_count_default: Option<usize>,
+    pub thread_count_min: Option<usize>,
+    pub thread_count_max: Option<usize>,
+    pub max_concurrent_requests: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub reply_grace: Option<Duration>,
+    pub flush_timeout: Option<Duration>,
+    pub shutdown_timeout: Option<Duration>,
+    /// By [`Phase`], in the order of its variants.
+    pub phase_timeouts: [Option<Duration>; 4],
+}
+
+impl ServerConfig {
+    /// Read the settings from the variables named after their keys, upper
+    /// cased and prefixed with `prefix` and an underscore, e.g.
+    /// `AGENT_TTRPC_HANDLER_TIMEOUT` for the prefix `AGENT_TTRPC`. `_ADDR`
+    /// may hold several comma separated addresses.
+    pub fn from_env(prefix: &str) -> Result<ServerConfig> {
+        let mut config = ServerConfig::default();
+        for (key, value) in env_pairs(prefix, SERVER_KEYS) {
+            config.set(key, &value)?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "addr" => self.addrs.extend(value.split(',').map(|a| a.to_string())),
+            "threads_default" => self.thread_count_default = Some(parse(key, value)?),
+            "threads_min" => self.thread_count_min = Some(parse(key, value)?),
+            "threads_max" => self.thread_count_max = Some(parse(key, value)?),
+            "max_concurrent_requests" => self.max_concurrent_requests = Some(parse(key, value)?),
+            "max_message_size" => self.max_message_size = Some(parse(key, value)?),
+            "reply_grace" => self.reply_grace = Some(parse_duration(key, value)?),
+            "flush_timeout" => self.flush_timeout = Some(parse_duration(key, value)?),
+            "shutdown_timeout" => self.shutdown_timeout = Some(parse_duration(key, value)?),
+            "read_timeout" => {
+                self.phase_timeouts[Phase::Read as usize] = Some(parse_duration(key, value)?)
+            }
+            "queue_timeout" => {
+                self.phase_timeouts[Phase::Queue as usize] = Some(parse_duration(key, value)?)
+            }
+            "handler_timeout" => {
+                self.phase_timeouts[Phase::Handler as usize] = Some(parse_duration(key, value)?)
+            }
+            "write_timeout" => {
+                self.phase_timeouts[Phase::Write as usize] = Some(parse_duration(key, value)?)
+            }
+            _ => {
+                return Err(Error::Others(format!(
+                    "unknown ttrpc server setting {}",
+                    key
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// A builder bound to the addresses, with the settings applied, to
+    /// register services on.
+    pub fn builder(&self) -> ServerBuilder {
+        let mut builder = Server::builder();
+        for addr in self.addrs.iter() {
+            builder = match self.max_message_size {
+                Some(size) => builder.bind_with(addr, ListenerConfig::new().max_message_size(size)),
+                None => builder.bind(addr),
+            };
+        }
+        if let Some(count) = self.thread_count_default {
+            builder = builder.set_thread_count_default(count);
+        }
+        if let Some(count) = self.thread_count_min {
+            builder = builder.set_thread_count_min(count);
+        }
+        if let Some(count) = self.thread_count_max {
+            builder = builder.set_thread_count_max(count);
+        }
+        if let Some(max) = self.max_concurrent_requests {
+            builder = builder.set_max_concurrent_requests(max);
+        }
+        if let Some(grace) = self.reply_grace {
+            builder = builder.set_reply_grace(grace);
+        }
+        if let Some(timeout) = self.flush_timeout {
+            builder = builder.set_flush_timeout(timeout);
+        }
+        if let Some(timeout) = self.shutdown_timeout {
+            builder = builder.set_shutdown_timeout(timeout);
+        }
+        let phases = [Phase::Read, Phase::Queue, Phase::Handler, Phase::Write];
+        for phase in phases.iter() {
+            if let Some(timeout) = self.phase_timeouts[*phase as usize] {
+                builder = builder.set_phase_timeout(*phase, timeout);
+            }
+        }
+        builder
+    }
+}
+
+impl FromStr for ServerConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ServerConfig> {
+        let mut config = ServerConfig::default();
+        for (key, value) in pairs(s)? {
+            config.set(key, value)?;
+        }
+        Ok(config)
+    }
+}
+
+/// The settings of a [`Client`], parsed from comma separated `key=value`
+/// pairs, e.g. `addr=unix:///run/agent.sock,stream_limit=queue`, or read
+/// from environment variables with [`ClientConfig::from_env`].
+///
+/// The keys are `addr`, `failover`, which may be given more than once,
+/// `stream_id_limit`, `stream_limit` (`ignore`, `queue` or `fail_fast`),
+/// `batching` (`true` or `false`), `default_timeout`, see
+/// [`Client::with_default_timeout`], and the socket options `keepalive`,
+/// `send_timeout`, `recv_timeout`, `send_buffer_size` and
+/// `recv_buffer_size`. Durations take a unit: `ms`, `s`, `m` or `h`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientConfig {
+    pub addr: String,
+    pub failover: Vec<String>,
+    pub stream_id_limit: Option<usize>,
+    pub stream_limit: Option<StreamLimit>,
+    pub batching: bool,
+    pub default_timeout: Option<Duration>,
+    pub keepalive: Option<bool>,
+    pub send_timeout: Option<Duration>,
+    pub recv_timeout: Option<Duration>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl ClientConfig {
+    /// Read the settings from the variables named after their keys, like
+    /// [`ServerConfig::from_env`]. `_FAILOVER` may hold several comma
+    /// separated addresses.
+    pub fn from_env(prefix: &str) -> Result<ClientConfig> {
+        let mut config = ClientConfig::default();
+        for (key, value) in env_pairs(prefix, CLIENT_KEYS) {
+            config.set(key, &value)?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "addr" => self.addr = value.to_string(),
+            "failover" => self
+                .failover
+                .extend(value.split(',').map(|a| a.to_string())),
+            "stream_id_limit" => self.stream_id_limit = Some(parse(key, value)?),
+            "stream_limit" => {
+                self.stream_limit = Some(match value {
+                    "ignore" => StreamLimit::Ignore,
+                    "queue" => StreamLimit::Queue,
+                    "fail_fast" => StreamLimit::FailFast,
+                    _ => return Err(invalid(key, value)),
+                })
+            }
+            "batching" => self.batching = parse(key, value)?,
+            "default_timeout" => self.default_timeout = Some(parse_duration(key, value)?),
+            "keepalive" => self.keepalive = Some(parse(key, value)?),
+            "send_timeout" => self.send_timeout = Some(parse_duration(key, value)?),
+            "recv_timeout" => self.recv_timeout = Some(parse_duration(key, value)?),
+            "send_buffer_size" => self.send_buffer_size = Some(parse(key, value)?),
+            "recv_buffer_size" => self.recv_buffer_size = Some(parse(key, value)?),
+            _ => {
+                return Err(Error::Others(format!(
+                    "unknown ttrpc client setting {}",
+                    key
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Connect to `addr` with the settings applied.
+    pub fn connect(&self) -> Result<Client> {
+        if self.addr.is_empty() {
+            return Err(Error::Others("no ttrpc address to connect to".to_string()));
+        }
+        let mut client = match self.socket_options() {
+            Some(options) => Client::connect_with_options(&self.addr, options)?,
+            None => Client::connect(&self.addr)?,
+        };
+        if !self.failover.is_empty() {
+            let addrs: Vec<&str> = self.failover.iter().map(|a| a.as_str()).collect();
+            client = client.with_failover(&addrs);
+        }
+        if let Some(max) = self.stream_id_limit {
+            client = client.with_stream_id_limit(max);
+        }
+        if let Some(limit) = self.stream_limit {
+            client = client.with_stream_limit(limit);
+        }
+        if self.batching {
+            client = client.with_batching();
+        }
+        if let Some(timeout) = self.default_timeout {
+            client = client.with_default_timeout(timeout);
+        }
+        Ok(client)
+    }
+
+    fn socket_options(&self) -> Option<SocketOptions> {
+        if self.keepalive.is_none()
+            && self.send_timeout.is_none()
+            && self.recv_timeout.is_none()
+            && self.send_buffer_size.is_none()
+            && self.recv_buffer_size.is_none()
+        {
+            return None;
+        }
+        let mut options = SocketOptions::new();
+        if let Some(keepalive) = self.keepalive {
+            options = options.keepalive(keepalive);
+        }
+        if let Some(timeout) = self.send_timeout {
+            options = options.send_timeout(timeout);
+        }
+        if let Some(timeout) = self.recv_timeout {
+            options = options.recv_timeout(timeout);
+        }
+        if let Some(size) = self.send_buffer_size {
+            options = options.send_buffer_size(size);
+        }
+        if let Some(size) = self.recv_buffer_size {
+            options = options.recv_buffer_size(size);
+        }
+        Some(options)
+    }
+}
+
+impl FromStr for ClientConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ClientConfig> {
+        let mut config = ClientConfig::default();
+        for (key, value) in pairs(s)? {
+            config.set(key, value)?;
+        }
+        Ok(config)
+    }
+}
+
+/// The `key=value` pairs of `s`, separated by commas.
+fn pairs(s: &str) -> Result<Vec<(&str, &str)>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| Error::Others(format!("ttrpc setting {} has no value", pair)))
+        })
+        .collect()
+}
+
+/// The settings among `keys` set in the environment with `prefix`.
+fn env_pairs(prefix: &str, keys: &[&'static str]) -> Vec<(&'static str, String)> {
+    keys.iter()
+        .filter_map(|key| {
+            let name = format!("{}_{}", prefix, key.to_uppercase());
+            env::var(name).ok().map(|value| (*key, value))
+        })
+        .collect()
+}
+
+fn invalid(key: &str, value: &str) -> Error {
+    Error::Others(format!("invalid ttrpc setting {}={}", key, value))
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| invalid(key, value))
+}
+
+/// A duration such as `250ms`, `30s`, `5m` or `1h`.
+fn parse_duration(key: &str, value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (n, unit) = value.split_at(split);
+    let n: u64 = parse(key, n)?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(invalid(key, value)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn secs(n: u64) -> Option<Duration> {
+        Some(Duration::from_secs(n))
+    }
+
+    #[test]
+    fn test_pairs() {
+        assert_eq!(
+            pairs(" a = 1 ,, b=2=3, ").unwrap(),
+            vec![("a", "1"), ("b", "2=3")]
+        );
+        assert!(pairs("").unwrap().is_empty());
+        assert!(pairs("a=1,b").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        let cases = [
+            ("250ms", Duration::from_millis(250)),
+            ("30s", Duration::from_secs(30)),
+            ("5m", Duration::from_secs(300)),
+            ("2h", Duration::from_secs(7200)),
+            ("0s", Duration::from_secs(0)),
+        ];
+        for (value, duration) in cases.iter() {
+            assert_eq!(parse_duration("t", value).unwrap(), *duration, "{}", value);
+        }
+        for value in ["", "30", "s", "1d", "-1s", "1.5s", "1 s"].iter() {
+            assert!(parse_duration("t", value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_server_config() {
+        let config: ServerConfig = "addr=unix://@a,threads_default=2,threads_min=1,\
+            threads_max=16,max_concurrent_requests=64,max_message_size=1024,\
+            reply_grace=1s,flush_timeout=2s,shutdown_timeout=3s,read_timeout=4s,\
+            queue_timeout=5s,handler_timeout=6s,write_timeout=7s,addr=vsock://-1:1024"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config,
+            ServerConfig {
+                addrs: vec!["unix://@a".to_string(), "vsock://-1:1024".to_string()],
+                thread_count_default: Some(2),
+                thread_count_min: Some(1),
+                thread_count_max: Some(16),
+                max_concurrent_requests: Some(64),
+                max_message_size: Some(1024),
+                reply_grace: secs(1),
+                flush_timeout: secs(2),
+                shutdown_timeout: secs(3),
+                phase_timeouts: [secs(4), secs(5), secs(6), secs(7)],
+            }
+        );
+        assert_eq!("".parse::<ServerConfig>().unwrap(), ServerConfig::default());
+
+        for bad in [
+            "threads=2",
+            "addr",
+            "threads_max=-1",
+            "threads_max=many",
+            "reply_grace=1",
+            "handler_timeout=1d",
+        ]
+        .iter()
+        {
+            assert!(bad.parse::<ServerConfig>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_client_config() {
+        let config: ClientConfig = "addr=unix://@a,failover=unix://@b,stream_id_limit=100,\
+            stream_limit=queue,batching=true,default_timeout=10s,keepalive=false,\
+            send_timeout=1s,recv_timeout=2s,send_buffer_size=4096,\
+            recv_buffer_size=8192,failover=unix://@c"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config,
+            ClientConfig {
+                addr: "unix://@a".to_string(),
+                failover: vec!["unix://@b".to_string(), "unix://@c".to_string()],
+                stream_id_limit: Some(100),
+                stream_limit: Some(StreamLimit::Queue),
+                batching: true,
+                default_timeout: secs(10),
+                keepalive: Some(false),
+                send_timeout: secs(1),
+                recv_timeout: secs(2),
+                send_buffer_size: Some(4096),
+                recv_buffer_size: Some(8192),
+            }
+        );
+
+        let limits = [
+            ("ignore", StreamLimit::Ignore),
+            ("queue", StreamLimit::Queue),
+            ("fail_fast", StreamLimit::FailFast),
+        ];
+        for (value, limit) in limits.iter() {
+            let config: ClientConfig = format!("stream_limit={}", value).parse().unwrap();
+            assert_eq!(config.stream_limit, Some(*limit));
+        }
+        // the last address given wins
+        let config: ClientConfig = "addr=unix://@a,addr=unix://@b".parse().unwrap();
+        assert_eq!(config.addr, "unix://@b");
+
+        for bad in [
+            "threads_max=2",
+            "batching",
+            "batching=yes",
+            "stream_limit=drop",
+            "stream_id_limit=-1",
+            "default_timeout=10",
+            "keepalive=1",
+        ]
+        .iter()
+        {
+            assert!(bad.parse::<ClientConfig>().is_err(), "{}", bad);
+        }
+        assert!(ClientConfig::default().connect().is_err());
+    }
+
+    #[test]
+    fn test_from_env() {
+        // a prefix of its own, as tests share the environment
+        env::set_var("TTRPC_CONFIG_TEST_ADDR", "unix://@a,unix://@b");
+        env::set_var("TTRPC_CONFIG_TEST_HANDLER_TIMEOUT", "30s");
+        env::set_var("TTRPC_CONFIG_TEST_FAILOVER", "unix://@c,unix://@d");
+        env::set_var("TTRPC_CONFIG_TEST_BATCHING", "true");
+        env::set_var("TTRPC_CONFIG_TEST_threads_max", "8");
+
+        let server = ServerConfig::from_env("TTRPC_CONFIG_TEST").unwrap();
+        assert_eq!(server.addrs, vec!["unix://@a", "unix://@b"]);
+        assert_eq!(server.phase_timeouts[Phase::Handler as usize], secs(30));
+        // only upper cased names are read
+        assert_eq!(server.thread_count_max, None);
+
+        let client = ClientConfig::from_env("TTRPC_CONFIG_TEST").unwrap();
+        assert_eq!(client.addr, "unix://@a,unix://@b");
+        assert_eq!(client.failover, vec!["unix://@c", "unix://@d"]);
+        assert!(client.batching);
+
+        env::set_var("TTRPC_CONFIG_TEST_THREADS_MAX", "eight");
+        assert!(ServerConfig::from_env("TTRPC_CONFIG_TEST").is_err());
+        assert_eq!(
+            ServerConfig::from_env("TTRPC_CONFIG_UNSET").unwrap(),
+            ServerConfig::default()
+        );
+    }
+}
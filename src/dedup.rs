@@ -0,0 +1,372 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deduplication of retried requests, so a client retrying a `Create` or
+//! `Exec` after a timeout does not run it twice. See [`Dedup`].
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{get_status, Result};
+use crate::metadata::{self, IDEMPOTENCY_KEY, REQUEST_ID_KEY};
+use crate::server::{MethodHandler, Middleware, ResponseSink, TtrpcContext};
+use crate::ttrpc::{Code, Request, Response};
+
+// the method path and the idempotency key
+type Key = (String, String);
+
+enum State {
+    /// The first request is running; the repeated ones wait for its reply.
+    Running(Vec<ResponseSink>),
+    /// Answered at the given time.
+    Done(Response, Instant),
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<Key, State>,
+    // the answered entries, in the order they were answered
+    order: VecDeque<(Key, Instant)>,
+}
+
+impl Cache {
+    /// Forget the answers older than `ttl`, and the oldest ones beyond
+    /// `capacity`.
+    fn expire(&mut self, ttl: Duration, capacity: usize) {
+        let now = Instant::now();
+        while let Some((key, at)) = self.order.front() {
+            if now.duration_since(*at) < ttl && self.order.len() <= capacity {
+                break;
+            }
+            if let Some(State::Done(_, done)) = self.entries.get(key) {
+                if done == at {
+                    self.entries.remove(key);
+                }
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+/// A middleware answering a request which carries the
+/// [`IDEMPOTENCY_KEY`] metadata of an earlier request to the same method
+/// with the response to the earlier one, instead of running the handler
+/// again. A repeated request arriving while the first one still runs is
+/// answered once it completes.
+///
+/// Responses are kept for the given time to live, for at most
+/// [`Dedup::with_capacity`] requests. Only successes and client errors,
+/// see [`Code::is_client_error`], are kept: a request failing otherwise,
+/// e.g. with `UNAVAILABLE`, runs again when retried. Keys must be unique
+/// across clients,
+/// e.g. a UUID per operation. Requests without a key, and notifications,
+/// are passed through.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # fn f(service: Arc<dyn ttrpc::Service + Send + Sync>) {
+/// let dedup = Arc::new(ttrpc::dedup::Dedup::new(Duration::from_secs(300)));
+/// let builder = ttrpc::Server::builder()
+///     .register(ttrpc::with_middleware(service, dedup.middleware()));
+/// # }
+/// ```
+pub struct Dedup {
+    ttl: Duration,
+    capacity: usize,
+    cache: Mutex<Cache>,
+    hits: AtomicUsize,
+}
+
+impl Dedup {
+    /// Keep responses for `ttl`, for at most 1024 requests.
+    pub fn new(ttl: Duration) -> Dedup {
+        Dedup {
+            ttl,
+            capacity: 1024,
+            cache: Mutex::new(Cache::default()),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Keep the responses of at most `capacity` requests.
+    pub fn with_capacity(mut self, capacity: usize) -> Dedup {
+        self.capacity = capacity;
+        self
+    }
+
+    /// How many repeated requests were answered without running their
+    /// handler.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// The middleware to wrap the deduplicated methods with, see
+    /// [`with_middleware`](crate::with_middleware).
+    pub fn middleware(self: &Arc<Self>) -> Middleware {
+        let dedup = self.clone();
+        Arc::new(move |path, ctx, req, inner| dedup.handle(path, ctx, req, inner))
+    }
+
+    fn handle(
+        self: &Arc<Self>,
+        path: &str,
+        ctx: TtrpcContext,
+        req: Request,
+        inner: &dyn MethodHandler,
+    ) -> Result<()> {
+        let sink = ctx.sink();
+        let key = match metadata::get(ctx.metadata(), IDEMPOTENCY_KEY) {
+            Some(k) if sink.wants_reply() => (path.to_string(), k.to_string()),
+            _ => return inner.handler(ctx, req),
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.expire(self.ttl, self.capacity);
+        match cache.entries.get_mut(&key) {
+            Some(State::Done(res, _)) => {
+                let res = res.clone();
+                drop(cache);
+                self.hits.fetch_add(1, Ordering::SeqCst);
+                debug!("answering repeated {} {} from cache", key.0, key.1);
+                return sink.send(res);
+            }
+            Some(State::Running(waiting)) => {
+                waiting.push(sink);
+                self.hits.fetch_add(1, Ordering::SeqCst);
+                debug!("{} {} repeated while running", key.0, key.1);
+                return Ok(());
+            }
+            None => {
+                cache
+                    .entries
+                    .insert(key.clone(), State::Running(Vec::new()));
+            }
+        }
+        drop(cache);
+
+        let dedup = self.clone();
+        sink.on_complete(move |res| dedup.complete(key, res));
+        inner.handler(ctx, req)
+    }
+
+    /// Record the response to the first request of `key`, and pass it on
+    /// to the repeated ones.
+    fn complete(&self, key: Key, res: Option<&Response>) {
+        let mut cache = self.cache.lock().unwrap();
+        let (res, state) = match res {
+            Some(res) => {
+                let mut res = res.clone();
+                // each repeated request gets its own
+                res.mut_metadata().retain(|kv| kv.key != REQUEST_ID_KEY);
+                let code = res.get_status().get_code();
+                let state = if code == Code::OK || code.is_client_error() {
+                    let now = Instant::now();
+                    cache.order.push_back((key.clone(), now));
+                    cache.entries.insert(key, State::Done(res.clone(), now))
+                } else {
+                    // may succeed when retried, so run it again then
+                    cache.entries.remove(&key)
+                };
+                (res, state)
+            }
+            None => {
+                let mut res = Response::new();
+                res.set_status(get_status(
+                    Code::ABORTED,
+                    "the original request ended without a response".to_string(),
+                ));
+                // run it again on the next retry
+                (res, cache.entries.remove(&key))
+            }
+        };
+        drop(cache);
+
+        if let Some(State::Running(waiting)) = state {
+            for sink in waiting {
+                sink.send(res.clone()).unwrap_or(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::Client;
+    use crate::error::Error;
+    use crate::pair::pair;
+    use crate::server::Server;
+    use crate::ttrpc::KeyValue;
+    use std::os::unix::io::IntoRawFd;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+
+    /// Answers with `code`, once released if `hold` is set, counting its
+    /// runs.
+    #[derive(Default)]
+    struct Job {
+        runs: AtomicUsize,
+        code: Mutex<Code>,
+        hold: Mutex<Option<Receiver<()>>>,
+    }
+
+    impl MethodHandler for Job {
+        fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<()> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            if let Some(hold) = self.hold.lock().unwrap().as_ref() {
+                hold.recv().unwrap_or(());
+            }
+            let mut res = Response::new();
+            res.set_status(get_status(*self.code.lock().unwrap(), String::new()));
+            ctx.sink().send(res)
+        }
+    }
+
+    struct Deduped(Arc<Dedup>, Arc<Job>);
+
+    impl MethodHandler for Deduped {
+        fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+            self.0
+                .handle("/test.Jobs/Create", ctx, req, self.1.as_ref())
+        }
+    }
+
+    /// A server running `job` behind `dedup`, and a client of it.
+    fn serve(dedup: &Arc<Dedup>, job: &Arc<Job>) -> (Server, Client) {
+        let (fd, client) = pair().unwrap();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        let handler = Deduped(dedup.clone(), job.clone());
+        methods.insert("/test.Jobs/Create".to_string(), Box::new(handler));
+        let mut server = Server::builder()
+            .register_service(methods)
+            .add_connection(fd.into_raw_fd())
+            .build()
+            .unwrap();
+        server.start().unwrap();
+        (server, client)
+    }
+
+    fn call(client: &Client, key: &str) -> Code {
+        let mut req = Request::new();
+        req.set_service("test.Jobs".to_string());
+        req.set_method("Create".to_string());
+        let mut kv = KeyValue::new();
+        kv.set_key(IDEMPOTENCY_KEY.to_string());
+        kv.set_value(key.to_string());
+        req.mut_metadata().push(kv);
+        match client.request(req) {
+            Ok(res) => res.get_status().get_code(),
+            Err(Error::RpcStatus(s)) => s.get_code(),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    fn held(job: &Job) -> Sender<()> {
+        let (release, hold) = channel();
+        *job.hold.lock().unwrap() = Some(hold);
+        release
+    }
+
+    #[test]
+    fn test_dedup_hit() {
+        let dedup = Arc::new(Dedup::new(Duration::from_secs(60)));
+        let job = Arc::new(Job::default());
+        let (server, client) = serve(&dedup, &job);
+
+        assert_eq!(call(&client, "a"), Code::OK);
+        assert_eq!(call(&client, "a"), Code::OK);
+        assert_eq!(job.runs.load(Ordering::SeqCst), 1);
+        assert_eq!(dedup.hits(), 1);
+        // another key runs again
+        assert_eq!(call(&client, "b"), Code::OK);
+        assert_eq!(job.runs.load(Ordering::SeqCst), 2);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_dedup_waiter() {
+        let dedup = Arc::new(Dedup::new(Duration::from_secs(60)));
+        let job = Arc::new(Job::default());
+        let release = held(&job);
+        let (server, client) = serve(&dedup, &job);
+
+        let first = {
+            let client = client.clone();
+            thread::spawn(move || call(&client, "a"))
+        };
+        while job.runs.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let repeated = {
+            let client = client.clone();
+            thread::spawn(move || call(&client, "a"))
+        };
+        while dedup.hits() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        release.send(()).unwrap();
+        assert_eq!(first.join().unwrap(), Code::OK);
+        assert_eq!(repeated.join().unwrap(), Code::OK);
+        assert_eq!(job.runs.load(Ordering::SeqCst), 1);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_dedup_expiry() {
+        let dedup = Arc::new(Dedup::new(Duration::from_millis(50)));
+        let job = Arc::new(Job::default());
+        let (server, client) = serve(&dedup, &job);
+        call(&client, "a");
+        thread::sleep(Duration::from_millis(100));
+        call(&client, "a");
+        assert_eq!(job.runs.load(Ordering::SeqCst), 2);
+        assert_eq!(dedup.hits(), 0);
+        server.shutdown().unwrap();
+
+        let dedup = Arc::new(Dedup::new(Duration::from_secs(60)).with_capacity(1));
+        let job = Arc::new(Job::default());
+        let (server, client) = serve(&dedup, &job);
+        call(&client, "a");
+        call(&client, "b");
+        // pushed out by b
+        call(&client, "a");
+        assert_eq!(job.runs.load(Ordering::SeqCst), 3);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_dedup_failures() {
+        let dedup = Arc::new(Dedup::new(Duration::from_secs(60)));
+        let job = Arc::new(Job::default());
+        let (server, client) = serve(&dedup, &job);
+
+        *job.code.lock().unwrap() = Code::UNAVAILABLE;
+        assert_eq!(call(&client, "a"), Code::UNAVAILABLE);
+        *job.code.lock().unwrap() = Code::OK;
+        assert_eq!(call(&client, "a"), Code::OK);
+        assert_eq!(job.runs.load(Ordering::SeqCst), 2);
+
+        // failing the same way when retried
+        *job.code.lock().unwrap() = Code::INVALID_ARGUMENT;
+        assert_eq!(call(&client, "b"), Code::INVALID_ARGUMENT);
+        *job.code.lock().unwrap() = Code::OK;
+        assert_eq!(call(&client, "b"), Code::INVALID_ARGUMENT);
+        assert_eq!(job.runs.load(Ordering::SeqCst), 3);
+        server.shutdown().unwrap();
+    }
+}
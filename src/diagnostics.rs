@@ -0,0 +1,2400 @@
+// This file is generated by rust-protobuf 2.28.0. Do not edit
+// @generated
+
+// https://github.com/rust-lang/rust-clippy/issues/702
+#![allow(unknown_lints)]
+#![allow(clippy::all)]
+
+#![allow(unused_attributes)]
+#![cfg_attr(rustfmt, rustfmt::skip)]
+
+#![allow(box_pointers)]
+#![allow(dead_code)]
+#![allow(missing_docs)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+#![allow(trivial_casts)]
+#![allow(unused_imports)]
+#![allow(unused_results)]
+//! Generated file from `diagnostics.proto`
+
+/// Generated files are compatible only with the same version
+/// of protobuf runtime.
+// const _PROTOBUF_VERSION_CHECK: () = ::protobuf::VERSION_2_28_0;
+
+#[derive(PartialEq,Clone,Default)]
+pub struct EchoRequest {
+    // message fields
+    pub payload: ::std::vec::Vec<u8>,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a EchoRequest {
+    fn default() -> &'a EchoRequest {
+        <EchoRequest as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl EchoRequest {
+    pub fn new() -> EchoRequest {
+        ::std::default::Default::default()
+    }
+
+    // bytes payload = 1;
+
+
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn clear_payload(&mut self) {
+        self.payload.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_payload(&mut self, v: ::std::vec::Vec<u8>) {
+        self.payload = v;
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_payload(&mut self) -> &mut ::std::vec::Vec<u8> {
+        &mut self.payload
+    }
+
+    // Take field
+    pub fn take_payload(&mut self) -> ::std::vec::Vec<u8> {
+        ::std::mem::replace(&mut self.payload, ::std::vec::Vec::new())
+    }
+}
+
+impl ::protobuf::Message for EchoRequest {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    ::protobuf::rt::read_singular_proto3_bytes_into(wire_type, is, &mut self.payload)?;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if !self.payload.is_empty() {
+            my_size += ::protobuf::rt::bytes_size(1, &self.payload);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if !self.payload.is_empty() {
+            os.write_bytes(1, &self.payload)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> EchoRequest {
+        EchoRequest::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
+                "payload",
+                |m: &EchoRequest| { &m.payload },
+                |m: &mut EchoRequest| { &mut m.payload },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<EchoRequest>(
+                "EchoRequest",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static EchoRequest {
+        static instance: ::protobuf::rt::LazyV2<EchoRequest> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(EchoRequest::new)
+    }
+}
+
+impl ::protobuf::Clear for EchoRequest {
+    fn clear(&mut self) {
+        self.payload.clear();
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for EchoRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for EchoRequest {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct EchoResponse {
+    // message fields
+    pub payload: ::std::vec::Vec<u8>,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a EchoResponse {
+    fn default() -> &'a EchoResponse {
+        <EchoResponse as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl EchoResponse {
+    pub fn new() -> EchoResponse {
+        ::std::default::Default::default()
+    }
+
+    // bytes payload = 1;
+
+
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn clear_payload(&mut self) {
+        self.payload.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_payload(&mut self, v: ::std::vec::Vec<u8>) {
+        self.payload = v;
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_payload(&mut self) -> &mut ::std::vec::Vec<u8> {
+        &mut self.payload
+    }
+
+    // Take field
+    pub fn take_payload(&mut self) -> ::std::vec::Vec<u8> {
+        ::std::mem::replace(&mut self.payload, ::std::vec::Vec::new())
+    }
+}
+
+impl ::protobuf::Message for EchoResponse {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    ::protobuf::rt::read_singular_proto3_bytes_into(wire_type, is, &mut self.payload)?;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if !self.payload.is_empty() {
+            my_size += ::protobuf::rt::bytes_size(1, &self.payload);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if !self.payload.is_empty() {
+            os.write_bytes(1, &self.payload)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> EchoResponse {
+        EchoResponse::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
+                "payload",
+                |m: &EchoResponse| { &m.payload },
+                |m: &mut EchoResponse| { &mut m.payload },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<EchoResponse>(
+                "EchoResponse",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static EchoResponse {
+        static instance: ::protobuf::rt::LazyV2<EchoResponse> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(EchoResponse::new)
+    }
+}
+
+impl ::protobuf::Clear for EchoResponse {
+    fn clear(&mut self) {
+        self.payload.clear();
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for EchoResponse {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for EchoResponse {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct PingRequest {
+    // message fields
+    pub timestamp_nano: i64,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a PingRequest {
+    fn default() -> &'a PingRequest {
+        <PingRequest as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl PingRequest {
+    pub fn new() -> PingRequest {
+        ::std::default::Default::default()
+    }
+
+    // int64 timestamp_nano = 1;
+
+
+    pub fn get_timestamp_nano(&self) -> i64 {
+        self.timestamp_nano
+    }
+    pub fn clear_timestamp_nano(&mut self) {
+        self.timestamp_nano = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_timestamp_nano(&mut self, v: i64) {
+        self.timestamp_nano = v;
+    }
+}
+
+impl ::protobuf::Message for PingRequest {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_int64()?;
+                    self.timestamp_nano = tmp;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if self.timestamp_nano != 0 {
+            my_size += ::protobuf::rt::value_size(1, self.timestamp_nano, ::protobuf::wire_format::WireTypeVarint);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if self.timestamp_nano != 0 {
+            os.write_int64(1, self.timestamp_nano)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> PingRequest {
+        PingRequest::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
+                "timestamp_nano",
+                |m: &PingRequest| { &m.timestamp_nano },
+                |m: &mut PingRequest| { &mut m.timestamp_nano },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<PingRequest>(
+                "PingRequest",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static PingRequest {
+        static instance: ::protobuf::rt::LazyV2<PingRequest> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(PingRequest::new)
+    }
+}
+
+impl ::protobuf::Clear for PingRequest {
+    fn clear(&mut self) {
+        self.timestamp_nano = 0;
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for PingRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for PingRequest {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct PingResponse {
+    // message fields
+    pub client_timestamp_nano: i64,
+    pub server_timestamp_nano: i64,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a PingResponse {
+    fn default() -> &'a PingResponse {
+        <PingResponse as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl PingResponse {
+    pub fn new() -> PingResponse {
+        ::std::default::Default::default()
+    }
+
+    // int64 client_timestamp_nano = 1;
+
+
+    pub fn get_client_timestamp_nano(&self) -> i64 {
+        self.client_timestamp_nano
+    }
+    pub fn clear_client_timestamp_nano(&mut self) {
+        self.client_timestamp_nano = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_client_timestamp_nano(&mut self, v: i64) {
+        self.client_timestamp_nano = v;
+    }
+
+    // int64 server_timestamp_nano = 2;
+
+
+    pub fn get_server_timestamp_nano(&self) -> i64 {
+        self.server_timestamp_nano
+    }
+    pub fn clear_server_timestamp_nano(&mut self) {
+        self.server_timestamp_nano = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_server_timestamp_nano(&mut self, v: i64) {
+        self.server_timestamp_nano = v;
+    }
+}
+
+impl ::protobuf::Message for PingResponse {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_int64()?;
+                    self.client_timestamp_nano = tmp;
+                },
+                2 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_int64()?;
+                    self.server_timestamp_nano = tmp;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if self.client_timestamp_nano != 0 {
+            my_size += ::protobuf::rt::value_size(1, self.client_timestamp_nano, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.server_timestamp_nano != 0 {
+            my_size += ::protobuf::rt::value_size(2, self.server_timestamp_nano, ::protobuf::wire_format::WireTypeVarint);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if self.client_timestamp_nano != 0 {
+            os.write_int64(1, self.client_timestamp_nano)?;
+        }
+        if self.server_timestamp_nano != 0 {
+            os.write_int64(2, self.server_timestamp_nano)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> PingResponse {
+        PingResponse::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
+                "client_timestamp_nano",
+                |m: &PingResponse| { &m.client_timestamp_nano },
+                |m: &mut PingResponse| { &mut m.client_timestamp_nano },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
+                "server_timestamp_nano",
+                |m: &PingResponse| { &m.server_timestamp_nano },
+                |m: &mut PingResponse| { &mut m.server_timestamp_nano },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<PingResponse>(
+                "PingResponse",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static PingResponse {
+        static instance: ::protobuf::rt::LazyV2<PingResponse> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(PingResponse::new)
+    }
+}
+
+impl ::protobuf::Clear for PingResponse {
+    fn clear(&mut self) {
+        self.client_timestamp_nano = 0;
+        self.server_timestamp_nano = 0;
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for PingResponse {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for PingResponse {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct InfoRequest {
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a InfoRequest {
+    fn default() -> &'a InfoRequest {
+        <InfoRequest as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl InfoRequest {
+    pub fn new() -> InfoRequest {
+        ::std::default::Default::default()
+    }
+}
+
+impl ::protobuf::Message for InfoRequest {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> InfoRequest {
+        InfoRequest::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let fields = ::std::vec::Vec::new();
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<InfoRequest>(
+                "InfoRequest",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static InfoRequest {
+        static instance: ::protobuf::rt::LazyV2<InfoRequest> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(InfoRequest::new)
+    }
+}
+
+impl ::protobuf::Clear for InfoRequest {
+    fn clear(&mut self) {
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for InfoRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for InfoRequest {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct InfoResponse {
+    // message fields
+    pub version: ::std::string::String,
+    pub uptime_nano: i64,
+    pub connections: u64,
+    pub in_flight: u64,
+    pub served: u64,
+    pub abandoned: u64,
+    pub torn_writes: u64,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a InfoResponse {
+    fn default() -> &'a InfoResponse {
+        <InfoResponse as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl InfoResponse {
+    pub fn new() -> InfoResponse {
+        ::std::default::Default::default()
+    }
+
+    // string version = 1;
+
+
+    pub fn get_version(&self) -> &str {
+        &self.version
+    }
+    pub fn clear_version(&mut self) {
+        self.version.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_version(&mut self, v: ::std::string::String) {
+        self.version = v;
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_version(&mut self) -> &mut ::std::string::String {
+        &mut self.version
+    }
+
+    // Take field
+    pub fn take_version(&mut self) -> ::std::string::String {
+        ::std::mem::replace(&mut self.version, ::std::string::String::new())
+    }
+
+    // int64 uptime_nano = 2;
+
+
+    pub fn get_uptime_nano(&self) -> i64 {
+        self.uptime_nano
+    }
+    pub fn clear_uptime_nano(&mut self) {
+        self.uptime_nano = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_uptime_nano(&mut self, v: i64) {
+        self.uptime_nano = v;
+    }
+
+    // uint64 connections = 3;
+
+
+    pub fn get_connections(&self) -> u64 {
+        self.connections
+    }
+    pub fn clear_connections(&mut self) {
+        self.connections = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_connections(&mut self, v: u64) {
+        self.connections = v;
+    }
+
+    // uint64 in_flight = 4;
+
+
+    pub fn get_in_flight(&self) -> u64 {
+        self.in_flight
+    }
+    pub fn clear_in_flight(&mut self) {
+        self.in_flight = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_in_flight(&mut self, v: u64) {
+        self.in_flight = v;
+    }
+
+    // uint64 served = 5;
+
+
+    pub fn get_served(&self) -> u64 {
+        self.served
+    }
+    pub fn clear_served(&mut self) {
+        self.served = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_served(&mut self, v: u64) {
+        self.served = v;
+    }
+
+    // uint64 abandoned = 6;
+
+
+    pub fn get_abandoned(&self) -> u64 {
+        self.abandoned
+    }
+    pub fn clear_abandoned(&mut self) {
+        self.abandoned = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_abandoned(&mut self, v: u64) {
+        self.abandoned = v;
+    }
+
+    // uint64 torn_writes = 7;
+
+
+    pub fn get_torn_writes(&self) -> u64 {
+        self.torn_writes
+    }
+    pub fn clear_torn_writes(&mut self) {
+        self.torn_writes = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_torn_writes(&mut self, v: u64) {
+        self.torn_writes = v;
+    }
+}
+
+impl ::protobuf::Message for InfoResponse {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    ::protobuf::rt::read_singular_proto3_string_into(wire_type, is, &mut self.version)?;
+                },
+                2 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_int64()?;
+                    self.uptime_nano = tmp;
+                },
+                3 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.connections = tmp;
+                },
+                4 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.in_flight = tmp;
+                },
+                5 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.served = tmp;
+                },
+                6 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.abandoned = tmp;
+                },
+                7 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.torn_writes = tmp;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if !self.version.is_empty() {
+            my_size += ::protobuf::rt::string_size(1, &self.version);
+        }
+        if self.uptime_nano != 0 {
+            my_size += ::protobuf::rt::value_size(2, self.uptime_nano, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.connections != 0 {
+            my_size += ::protobuf::rt::value_size(3, self.connections, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.in_flight != 0 {
+            my_size += ::protobuf::rt::value_size(4, self.in_flight, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.served != 0 {
+            my_size += ::protobuf::rt::value_size(5, self.served, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.abandoned != 0 {
+            my_size += ::protobuf::rt::value_size(6, self.abandoned, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.torn_writes != 0 {
+            my_size += ::protobuf::rt::value_size(7, self.torn_writes, ::protobuf::wire_format::WireTypeVarint);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if !self.version.is_empty() {
+            os.write_string(1, &self.version)?;
+        }
+        if self.uptime_nano != 0 {
+            os.write_int64(2, self.uptime_nano)?;
+        }
+        if self.connections != 0 {
+            os.write_uint64(3, self.connections)?;
+        }
+        if self.in_flight != 0 {
+            os.write_uint64(4, self.in_flight)?;
+        }
+        if self.served != 0 {
+            os.write_uint64(5, self.served)?;
+        }
+        if self.abandoned != 0 {
+            os.write_uint64(6, self.abandoned)?;
+        }
+        if self.torn_writes != 0 {
+            os.write_uint64(7, self.torn_writes)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> InfoResponse {
+        InfoResponse::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "version",
+                |m: &InfoResponse| { &m.version },
+                |m: &mut InfoResponse| { &mut m.version },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
+                "uptime_nano",
+                |m: &InfoResponse| { &m.uptime_nano },
+                |m: &mut InfoResponse| { &mut m.uptime_nano },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "connections",
+                |m: &InfoResponse| { &m.connections },
+                |m: &mut InfoResponse| { &mut m.connections },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "in_flight",
+                |m: &InfoResponse| { &m.in_flight },
+                |m: &mut InfoResponse| { &mut m.in_flight },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "served",
+                |m: &InfoResponse| { &m.served },
+                |m: &mut InfoResponse| { &mut m.served },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "abandoned",
+                |m: &InfoResponse| { &m.abandoned },
+                |m: &mut InfoResponse| { &mut m.abandoned },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "torn_writes",
+                |m: &InfoResponse| { &m.torn_writes },
+                |m: &mut InfoResponse| { &mut m.torn_writes },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<InfoResponse>(
+                "InfoResponse",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static InfoResponse {
+        static instance: ::protobuf::rt::LazyV2<InfoResponse> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(InfoResponse::new)
+    }
+}
+
+impl ::protobuf::Clear for InfoResponse {
+    fn clear(&mut self) {
+        self.version.clear();
+        self.uptime_nano = 0;
+        self.connections = 0;
+        self.in_flight = 0;
+        self.served = 0;
+        self.abandoned = 0;
+        self.torn_writes = 0;
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for InfoResponse {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for InfoResponse {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct ListRequestsRequest {
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a ListRequestsRequest {
+    fn default() -> &'a ListRequestsRequest {
+        <ListRequestsRequest as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl ListRequestsRequest {
+    pub fn new() -> ListRequestsRequest {
+        ::std::default::Default::default()
+    }
+}
+
+impl ::protobuf::Message for ListRequestsRequest {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> ListRequestsRequest {
+        ListRequestsRequest::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let fields = ::std::vec::Vec::new();
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<ListRequestsRequest>(
+                "ListRequestsRequest",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static ListRequestsRequest {
+        static instance: ::protobuf::rt::LazyV2<ListRequestsRequest> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(ListRequestsRequest::new)
+    }
+}
+
+impl ::protobuf::Clear for ListRequestsRequest {
+    fn clear(&mut self) {
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for ListRequestsRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for ListRequestsRequest {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct RequestInfo {
+    // message fields
+    pub connection: i64,
+    pub stream_id: u32,
+    pub method: ::std::string::String,
+    pub age_nano: i64,
+    pub bytes: u64,
+    pub deferred: bool,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a RequestInfo {
+    fn default() -> &'a RequestInfo {
+        <RequestInfo as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl RequestInfo {
+    pub fn new() -> RequestInfo {
+        ::std::default::Default::default()
+    }
+
+    // int64 connection = 1;
+
+
+    pub fn get_connection(&self) -> i64 {
+        self.connection
+    }
+    pub fn clear_connection(&mut self) {
+        self.connection = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_connection(&mut self, v: i64) {
+        self.connection = v;
+    }
+
+    // uint32 stream_id = 2;
+
+
+    pub fn get_stream_id(&self) -> u32 {
+        self.stream_id
+    }
+    pub fn clear_stream_id(&mut self) {
+        self.stream_id = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_stream_id(&mut self, v: u32) {
+        self.stream_id = v;
+    }
+
+    // string method = 3;
+
+
+    pub fn get_method(&self) -> &str {
+        &self.method
+    }
+    pub fn clear_method(&mut self) {
+        self.method.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_method(&mut self, v: ::std::string::String) {
+        self.method = v;
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_method(&mut self) -> &mut ::std::string::String {
+        &mut self.method
+    }
+
+    // Take field
+    pub fn take_method(&mut self) -> ::std::string::String {
+        ::std::mem::replace(&mut self.method, ::std::string::String::new())
+    }
+
+    // int64 age_nano = 4;
+
+
+    pub fn get_age_nano(&self) -> i64 {
+        self.age_nano
+    }
+    pub fn clear_age_nano(&mut self) {
+        self.age_nano = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_age_nano(&mut self, v: i64) {
+        self.age_nano = v;
+    }
+
+    // uint64 bytes = 5;
+
+
+    pub fn get_bytes(&self) -> u64 {
+        self.bytes
+    }
+    pub fn clear_bytes(&mut self) {
+        self.bytes = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_bytes(&mut self, v: u64) {
+        self.bytes = v;
+    }
+
+    // bool deferred = 6;
+
+
+    pub fn get_deferred(&self) -> bool {
+        self.deferred
+    }
+    pub fn clear_deferred(&mut self) {
+        self.deferred = false;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_deferred(&mut self, v: bool) {
+        self.deferred = v;
+    }
+}
+
+impl ::protobuf::Message for RequestInfo {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_int64()?;
+                    self.connection = tmp;
+                },
+                2 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint32()?;
+                    self.stream_id = tmp;
+                },
+                3 => {
+                    ::protobuf::rt::read_singular_proto3_string_into(wire_type, is, &mut self.method)?;
+                },
+                4 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_int64()?;
+                    self.age_nano = tmp;
+                },
+                5 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.bytes = tmp;
+                },
+                6 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_bool()?;
+                    self.deferred = tmp;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if self.connection != 0 {
+            my_size += ::protobuf::rt::value_size(1, self.connection, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.stream_id != 0 {
+            my_size += ::protobuf::rt::value_size(2, self.stream_id, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if !self.method.is_empty() {
+            my_size += ::protobuf::rt::string_size(3, &self.method);
+        }
+        if self.age_nano != 0 {
+            my_size += ::protobuf::rt::value_size(4, self.age_nano, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.bytes != 0 {
+            my_size += ::protobuf::rt::value_size(5, self.bytes, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.deferred != false {
+            my_size += 2;
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if self.connection != 0 {
+            os.write_int64(1, self.connection)?;
+        }
+        if self.stream_id != 0 {
+            os.write_uint32(2, self.stream_id)?;
+        }
+        if !self.method.is_empty() {
+            os.write_string(3, &self.method)?;
+        }
+        if self.age_nano != 0 {
+            os.write_int64(4, self.age_nano)?;
+        }
+        if self.bytes != 0 {
+            os.write_uint64(5, self.bytes)?;
+        }
+        if self.deferred != false {
+            os.write_bool(6, self.deferred)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> RequestInfo {
+        RequestInfo::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
+                "connection",
+                |m: &RequestInfo| { &m.connection },
+                |m: &mut RequestInfo| { &mut m.connection },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint32>(
+                "stream_id",
+                |m: &RequestInfo| { &m.stream_id },
+                |m: &mut RequestInfo| { &mut m.stream_id },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "method",
+                |m: &RequestInfo| { &m.method },
+                |m: &mut RequestInfo| { &mut m.method },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
+                "age_nano",
+                |m: &RequestInfo| { &m.age_nano },
+                |m: &mut RequestInfo| { &mut m.age_nano },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "bytes",
+                |m: &RequestInfo| { &m.bytes },
+                |m: &mut RequestInfo| { &mut m.bytes },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBool>(
+                "deferred",
+                |m: &RequestInfo| { &m.deferred },
+                |m: &mut RequestInfo| { &mut m.deferred },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<RequestInfo>(
+                "RequestInfo",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static RequestInfo {
+        static instance: ::protobuf::rt::LazyV2<RequestInfo> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(RequestInfo::new)
+    }
+}
+
+impl ::protobuf::Clear for RequestInfo {
+    fn clear(&mut self) {
+        self.connection = 0;
+        self.stream_id = 0;
+        self.method.clear();
+        self.age_nano = 0;
+        self.bytes = 0;
+        self.deferred = false;
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for RequestInfo {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for RequestInfo {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct ListRequestsResponse {
+    // message fields
+    pub requests: ::protobuf::RepeatedField<RequestInfo>,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a ListRequestsResponse {
+    fn default() -> &'a ListRequestsResponse {
+        <ListRequestsResponse as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl ListRequestsResponse {
+    pub fn new() -> ListRequestsResponse {
+        ::std::default::Default::default()
+    }
+
+    // repeated .ttrpc.diagnostics.RequestInfo requests = 1;
+
+
+    pub fn get_requests(&self) -> &[RequestInfo] {
+        &self.requests
+    }
+    pub fn clear_requests(&mut self) {
+        self.requests.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_requests(&mut self, v: ::protobuf::RepeatedField<RequestInfo>) {
+        self.requests = v;
+    }
+
+    // Mutable pointer to the field.
+    pub fn mut_requests(&mut self) -> &mut ::protobuf::RepeatedField<RequestInfo> {
+        &mut self.requests
+    }
+
+    // Take field
+    pub fn take_requests(&mut self) -> ::protobuf::RepeatedField<RequestInfo> {
+        ::std::mem::replace(&mut self.requests, ::protobuf::RepeatedField::new())
+    }
+}
+
+impl ::protobuf::Message for ListRequestsResponse {
+    fn is_initialized(&self) -> bool {
+        for v in &self.requests {
+            if !v.is_initialized() {
+                return false;
+            }
+        };
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    ::protobuf::rt::read_repeated_message_into(wire_type, is, &mut self.requests)?;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        for value in &self.requests {
+            let len = value.compute_size();
+            my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
+        };
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        for v in &self.requests {
+            os.write_tag(1, ::protobuf::wire_format::WireTypeLengthDelimited)?;
+            os.write_raw_varint32(v.get_cached_size())?;
+            v.write_to_with_cached_sizes(os)?;
+        };
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> ListRequestsResponse {
+        ListRequestsResponse::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_repeated_field_accessor::<_, ::protobuf::types::ProtobufTypeMessage<RequestInfo>>(
+                "requests",
+                |m: &ListRequestsResponse| { &m.requests },
+                |m: &mut ListRequestsResponse| { &mut m.requests },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<ListRequestsResponse>(
+                "ListRequestsResponse",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static ListRequestsResponse {
+        static instance: ::protobuf::rt::LazyV2<ListRequestsResponse> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(ListRequestsResponse::new)
+    }
+}
+
+impl ::protobuf::Clear for ListRequestsResponse {
+    fn clear(&mut self) {
+        self.requests.clear();
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for ListRequestsResponse {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for ListRequestsResponse {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct CancelRequestRequest {
+    // message fields
+    pub connection: i64,
+    pub stream_id: u32,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a CancelRequestRequest {
+    fn default() -> &'a CancelRequestRequest {
+        <CancelRequestRequest as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl CancelRequestRequest {
+    pub fn new() -> CancelRequestRequest {
+        ::std::default::Default::default()
+    }
+
+    // int64 connection = 1;
+
+
+    pub fn get_connection(&self) -> i64 {
+        self.connection
+    }
+    pub fn clear_connection(&mut self) {
+        self.connection = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_connection(&mut self, v: i64) {
+        self.connection = v;
+    }
+
+    // uint32 stream_id = 2;
+
+
+    pub fn get_stream_id(&self) -> u32 {
+        self.stream_id
+    }
+    pub fn clear_stream_id(&mut self) {
+        self.stream_id = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_stream_id(&mut self, v: u32) {
+        self.stream_id = v;
+    }
+}
+
+impl ::protobuf::Message for CancelRequestRequest {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_int64()?;
+                    self.connection = tmp;
+                },
+                2 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint32()?;
+                    self.stream_id = tmp;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if self.connection != 0 {
+            my_size += ::protobuf::rt::value_size(1, self.connection, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.stream_id != 0 {
+            my_size += ::protobuf::rt::value_size(2, self.stream_id, ::protobuf::wire_format::WireTypeVarint);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if self.connection != 0 {
+            os.write_int64(1, self.connection)?;
+        }
+        if self.stream_id != 0 {
+            os.write_uint32(2, self.stream_id)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> CancelRequestRequest {
+        CancelRequestRequest::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
+                "connection",
+                |m: &CancelRequestRequest| { &m.connection },
+                |m: &mut CancelRequestRequest| { &mut m.connection },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint32>(
+                "stream_id",
+                |m: &CancelRequestRequest| { &m.stream_id },
+                |m: &mut CancelRequestRequest| { &mut m.stream_id },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<CancelRequestRequest>(
+                "CancelRequestRequest",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static CancelRequestRequest {
+        static instance: ::protobuf::rt::LazyV2<CancelRequestRequest> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(CancelRequestRequest::new)
+    }
+}
+
+impl ::protobuf::Clear for CancelRequestRequest {
+    fn clear(&mut self) {
+        self.connection = 0;
+        self.stream_id = 0;
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for CancelRequestRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for CancelRequestRequest {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct CancelRequestResponse {
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a CancelRequestResponse {
+    fn default() -> &'a CancelRequestResponse {
+        <CancelRequestResponse as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl CancelRequestResponse {
+    pub fn new() -> CancelRequestResponse {
+        ::std::default::Default::default()
+    }
+}
+
+impl ::protobuf::Message for CancelRequestResponse {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> CancelRequestResponse {
+        CancelRequestResponse::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let fields = ::std::vec::Vec::new();
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<CancelRequestResponse>(
+                "CancelRequestResponse",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static CancelRequestResponse {
+        static instance: ::protobuf::rt::LazyV2<CancelRequestResponse> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(CancelRequestResponse::new)
+    }
+}
+
+impl ::protobuf::Clear for CancelRequestResponse {
+    fn clear(&mut self) {
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for CancelRequestResponse {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for CancelRequestResponse {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct MetricsRequest {
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a MetricsRequest {
+    fn default() -> &'a MetricsRequest {
+        <MetricsRequest as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl MetricsRequest {
+    pub fn new() -> MetricsRequest {
+        ::std::default::Default::default()
+    }
+}
+
+impl ::protobuf::Message for MetricsRequest {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> MetricsRequest {
+        MetricsRequest::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let fields = ::std::vec::Vec::new();
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<MetricsRequest>(
+                "MetricsRequest",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static MetricsRequest {
+        static instance: ::protobuf::rt::LazyV2<MetricsRequest> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(MetricsRequest::new)
+    }
+}
+
+impl ::protobuf::Clear for MetricsRequest {
+    fn clear(&mut self) {
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for MetricsRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for MetricsRequest {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct MetricsResponse {
+    // message fields
+    pub text: ::std::string::String,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a MetricsResponse {
+    fn default() -> &'a MetricsResponse {
+        <MetricsResponse as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl MetricsResponse {
+    pub fn new() -> MetricsResponse {
+        ::std::default::Default::default()
+    }
+
+    // string text = 1;
+
+
+    pub fn get_text(&self) -> &str {
+        &self.text
+    }
+    pub fn clear_text(&mut self) {
+        self.text.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_text(&mut self, v: ::std::string::String) {
+        self.text = v;
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_text(&mut self) -> &mut ::std::string::String {
+        &mut self.text
+    }
+
+    // Take field
+    pub fn take_text(&mut self) -> ::std::string::String {
+        ::std::mem::replace(&mut self.text, ::std::string::String::new())
+    }
+}
+
+impl ::protobuf::Message for MetricsResponse {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    ::protobuf::rt::read_singular_proto3_string_into(wire_type, is, &mut self.text)?;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if !self.text.is_empty() {
+            my_size += ::protobuf::rt::string_size(1, &self.text);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if !self.text.is_empty() {
+            os.write_string(1, &self.text)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> MetricsResponse {
+        MetricsResponse::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "text",
+                |m: &MetricsResponse| { &m.text },
+                |m: &mut MetricsResponse| { &mut m.text },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<MetricsResponse>(
+                "MetricsResponse",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static MetricsResponse {
+        static instance: ::protobuf::rt::LazyV2<MetricsResponse> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(MetricsResponse::new)
+    }
+}
+
+impl ::protobuf::Clear for MetricsResponse {
+    fn clear(&mut self) {
+        self.text.clear();
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for MetricsResponse {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for MetricsResponse {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+static file_descriptor_proto_data: &'static [u8] = b"\
+    \n\x11diagnostics.proto\x12\x11ttrpc.diagnostics\"+\n\x0bEchoRequest\x12\
+    \x1a\n\x07payload\x18\x01\x20\x01(\x0cR\x07payloadB\0:\0\",\n\x0cEchoRes\
+    ponse\x12\x1a\n\x07payload\x18\x01\x20\x01(\x0cR\x07payloadB\0:\0\"8\n\
+    \x0bPingRequest\x12'\n\x0etimestamp_nano\x18\x01\x20\x01(\x03R\rtimestam\
+    pNanoB\0:\0\"|\n\x0cPingResponse\x124\n\x15client_timestamp_nano\x18\x01\
+    \x20\x01(\x03R\x13clientTimestampNanoB\0\x124\n\x15server_timestamp_nano\
+    \x18\x02\x20\x01(\x03R\x13serverTimestampNanoB\0:\0\"\x0f\n\x0bInfoReque\
+    st:\0\"\xef\x01\n\x0cInfoResponse\x12\x1a\n\x07version\x18\x01\x20\x01(\
+    \tR\x07versionB\0\x12!\n\x0buptime_nano\x18\x02\x20\x01(\x03R\nuptimeNan\
+    oB\0\x12\"\n\x0bconnections\x18\x03\x20\x01(\x04R\x0bconnectionsB\0\x12\
+    \x1d\n\tin_flight\x18\x04\x20\x01(\x04R\x08inFlightB\0\x12\x18\n\x06serv\
+    ed\x18\x05\x20\x01(\x04R\x06servedB\0\x12\x1e\n\tabandoned\x18\x06\x20\
+    \x01(\x04R\tabandonedB\0\x12!\n\x0btorn_writes\x18\x07\x20\x01(\x04R\nto\
+    rnWritesB\0:\0\"\x17\n\x13ListRequestsRequest:\0\"\xbd\x01\n\x0bRequestI\
+    nfo\x12\x20\n\nconnection\x18\x01\x20\x01(\x03R\nconnectionB\0\x12\x1d\n\
+    \tstream_id\x18\x02\x20\x01(\rR\x08streamIdB\0\x12\x18\n\x06method\x18\
+    \x03\x20\x01(\tR\x06methodB\0\x12\x1b\n\x08age_nano\x18\x04\x20\x01(\x03\
+    R\x07ageNanoB\0\x12\x16\n\x05bytes\x18\x05\x20\x01(\x04R\x05bytesB\0\x12\
+    \x1c\n\x08deferred\x18\x06\x20\x01(\x08R\x08deferredB\0:\0\"V\n\x14ListR\
+    equestsResponse\x12<\n\x08requests\x18\x01\x20\x03(\x0b2\x1e.ttrpc.diagn\
+    ostics.RequestInfoR\x08requestsB\0:\0\"Y\n\x14CancelRequestRequest\x12\
+    \x20\n\nconnection\x18\x01\x20\x01(\x03R\nconnectionB\0\x12\x1d\n\tstrea\
+    m_id\x18\x02\x20\x01(\rR\x08streamIdB\0:\0\"\x19\n\x15CancelRequestRespo\
+    nse:\0\"\x12\n\x0eMetricsRequest:\0\")\n\x0fMetricsResponse\x12\x14\n\
+    \x04text\x18\x01\x20\x01(\tR\x04textB\0:\0B\0b\x06proto3\
+";
+
+static file_descriptor_proto_lazy: ::protobuf::rt::LazyV2<::protobuf::descriptor::FileDescriptorProto> = ::protobuf::rt::LazyV2::INIT;
+
+fn parse_descriptor_proto() -> ::protobuf::descriptor::FileDescriptorProto {
+    ::protobuf::Message::parse_from_bytes(file_descriptor_proto_data).unwrap()
+}
+
+pub fn file_descriptor_proto() -> &'static ::protobuf::descriptor::FileDescriptorProto {
+    file_descriptor_proto_lazy.get(|| {
+        parse_descriptor_proto()
+    })
+}
@@ -13,12 +13,34 @@
 // limitations under the License.
 
 use crate::ttrpc::{Code, Status};
+use protobuf::ProtobufEnum;
+use std::fmt;
+use std::io;
 use std::result;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Error {
     Socket(String),
     RpcStatus(Status),
+    /// The peer closed the connection while we were writing to it.
+    ConnectionClosed,
+    /// The server announced it is shutting down, for the given reason.
+    ServerShutdown(String),
+    /// The caller gave up on the call, see [`Client::call_cancellable`].
+    ///
+    /// [`Client::call_cancellable`]: crate::Client::call_cancellable
+    Cancelled,
+    /// The response to a call failed the checks of its method, see
+    /// [`Client::with_response_validator`], for the given reason.
+    ///
+    /// [`Client::with_response_validator`]: crate::Client::with_response_validator
+    InvalidResponse(String),
+    /// Another server listens on the given address already; the pid of
+    /// the process holding its lock, if known, see
+    /// [`ListenerConfig::lock`].
+    ///
+    /// [`ListenerConfig::lock`]: crate::ListenerConfig::lock
+    AddressInUse(String, Option<i32>),
     Others(String),
 }
 
@@ -36,6 +58,219 @@ pub fn get_rpc_status(c: Code, msg: String) -> Error {
     Error::RpcStatus(get_status(c, msg))
 }
 
+/// The status code matching an errno value.
+pub fn errno_to_code(errno: i32) -> Code {
+    match errno {
+        libc::ENOENT | libc::ESRCH | libc::ENXIO | libc::ENODEV => Code::NOT_FOUND,
+        libc::EEXIST => Code::ALREADY_EXISTS,
+        libc::EACCES | libc::EPERM | libc::EROFS => Code::PERMISSION_DENIED,
+        libc::EINVAL | libc::EBADF | libc::ENAMETOOLONG => Code::INVALID_ARGUMENT,
+        libc::ETIMEDOUT => Code::DEADLINE_EXCEEDED,
+        libc::ENOSPC | libc::ENOMEM | libc::EMFILE | libc::ENFILE | libc::EDQUOT => {
+            Code::RESOURCE_EXHAUSTED
+        }
+        libc::EBUSY | libc::ENOTEMPTY | libc::ENOTDIR | libc::EISDIR | libc::EXDEV => {
+            Code::FAILED_PRECONDITION
+        }
+        libc::ERANGE | libc::EOVERFLOW | libc::EFBIG => Code::OUT_OF_RANGE,
+        libc::ENOSYS | libc::EOPNOTSUPP | libc::EAFNOSUPPORT => Code::UNIMPLEMENTED,
+        libc::EAGAIN
+        | libc::ECONNREFUSED
+        | libc::ECONNRESET
+        | libc::ECONNABORTED
+        | libc::EPIPE
+        | libc::ENOTCONN
+        | libc::EHOSTUNREACH
+        | libc::ENETUNREACH => Code::UNAVAILABLE,
+        libc::EINTR | libc::ECANCELED => Code::CANCELLED,
+        libc::EIO => Code::DATA_LOSS,
+        _ => Code::UNKNOWN,
+    }
+}
+
+fn io_kind_to_code(kind: io::ErrorKind) -> Code {
+    match kind {
+        io::ErrorKind::NotFound => Code::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => Code::PERMISSION_DENIED,
+        io::ErrorKind::AlreadyExists => Code::ALREADY_EXISTS,
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Code::INVALID_ARGUMENT,
+        io::ErrorKind::TimedOut => Code::DEADLINE_EXCEEDED,
+        io::ErrorKind::Interrupted => Code::CANCELLED,
+        io::ErrorKind::Unsupported => Code::UNIMPLEMENTED,
+        io::ErrorKind::UnexpectedEof => Code::OUT_OF_RANGE,
+        io::ErrorKind::WouldBlock
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe => Code::UNAVAILABLE,
+        _ => Code::UNKNOWN,
+    }
+}
+
+impl Error {
+    /// The status a handler failing with this error should answer with.
+    pub fn to_status(&self) -> Status {
+        match self {
+            Error::RpcStatus(s) => s.clone(),
+            Error::Socket(m) => get_status(Code::UNAVAILABLE, m.clone()),
+            Error::ConnectionClosed => {
+                get_status(Code::UNAVAILABLE, "connection closed".to_string())
+            }
+            Error::ServerShutdown(m) => {
+                get_status(Code::UNAVAILABLE, format!("server shutting down: {}", m))
+            }
+            Error::Cancelled => get_status(Code::CANCELLED, "call cancelled".to_string()),
+            Error::InvalidResponse(m) => {
+                get_status(Code::INTERNAL, format!("invalid response: {}", m))
+            }
+            Error::AddressInUse(addr, Some(pid)) => get_status(
+                Code::FAILED_PRECONDITION,
+                format!("{} is in use by process {}", addr, pid),
+            ),
+            Error::AddressInUse(addr, None) => {
+                get_status(Code::FAILED_PRECONDITION, format!("{} is in use", addr))
+            }
+            Error::Others(m) => get_status(Code::UNKNOWN, m.clone()),
+        }
+    }
+}
+
+/// The code and message of the status the error answers with, see
+/// [`Error::to_status`].
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = self.to_status();
+        write!(f, "{}: {}", status.get_code(), status.get_message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Code {
+    /// Whether a call failing with this code may succeed when made again
+    /// later, without the caller changing anything.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            Code::UNAVAILABLE | Code::RESOURCE_EXHAUSTED | Code::ABORTED
+        )
+    }
+
+    /// Whether this code blames the call itself, so making it again as it
+    /// is fails the same way.
+    pub fn is_client_error(self) -> bool {
+        matches!(
+            self,
+            Code::INVALID_ARGUMENT
+                | Code::NOT_FOUND
+                | Code::ALREADY_EXISTS
+                | Code::PERMISSION_DENIED
+                | Code::UNAUTHENTICATED
+                | Code::FAILED_PRECONDITION
+                | Code::OUT_OF_RANGE
+        )
+    }
+
+    /// Whether this code blames the server.
+    pub fn is_server_error(self) -> bool {
+        matches!(
+            self,
+            Code::UNKNOWN
+                | Code::UNIMPLEMENTED
+                | Code::INTERNAL
+                | Code::UNAVAILABLE
+                | Code::DATA_LOSS
+        )
+    }
+}
+
+/// Codes this crate does not know become `UNKNOWN`.
+impl From<i32> for Code {
+    fn from(value: i32) -> Code {
+        Code::from_i32(value).unwrap_or(Code::UNKNOWN)
+    }
+}
+
+impl From<Code> for i32 {
+    fn from(code: Code) -> i32 {
+        code.value()
+    }
+}
+
+/// The name of the code in the protocol, e.g. `NOT_FOUND`.
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl Status {
+    /// The code as it was sent. Codes this crate does not know decode as
+    /// `OK`, and are kept aside so they are encoded again as they were;
+    /// this finds them.
+    pub fn raw_code(&self) -> i32 {
+        if self.code != Code::OK {
+            return self.code.value();
+        }
+        self.unknown_fields
+            .get(1)
+            .and_then(|v| v.varint.last())
+            .map_or(0, |&v| v as i32)
+    }
+
+    /// The code, with codes this crate does not know as `UNKNOWN`. Use
+    /// this rather than `get_code`, which sees them as `OK`.
+    pub fn code(&self) -> Code {
+        Code::from(self.raw_code())
+    }
+
+    /// Convert into an `io::Error` of the closest kind, keeping the message.
+    pub fn to_io_error(&self) -> io::Error {
+        let kind = match self.code() {
+            Code::OK | Code::UNKNOWN | Code::INTERNAL => io::ErrorKind::Other,
+            Code::NOT_FOUND => io::ErrorKind::NotFound,
+            Code::PERMISSION_DENIED | Code::UNAUTHENTICATED => io::ErrorKind::PermissionDenied,
+            Code::ALREADY_EXISTS => io::ErrorKind::AlreadyExists,
+            Code::INVALID_ARGUMENT => io::ErrorKind::InvalidInput,
+            Code::DEADLINE_EXCEEDED => io::ErrorKind::TimedOut,
+            Code::CANCELLED | Code::ABORTED => io::ErrorKind::Interrupted,
+            Code::UNIMPLEMENTED => io::ErrorKind::Unsupported,
+            Code::UNAVAILABLE => io::ErrorKind::NotConnected,
+            Code::OUT_OF_RANGE => io::ErrorKind::UnexpectedEof,
+            Code::DATA_LOSS => io::ErrorKind::InvalidData,
+            Code::RESOURCE_EXHAUSTED | Code::FAILED_PRECONDITION => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, self.get_message().to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        let code = match e.raw_os_error() {
+            Some(errno) => errno_to_code(errno),
+            None => io_kind_to_code(e.kind()),
+        };
+        get_rpc_status(code, e.to_string())
+    }
+}
+
+impl From<nix::Error> for Error {
+    fn from(e: nix::Error) -> Error {
+        let code = match e.as_errno() {
+            Some(errno) => errno_to_code(errno as i32),
+            None => Code::INVALID_ARGUMENT,
+        };
+        get_rpc_status(code, e.to_string())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        e.to_status().to_io_error()
+    }
+}
+
 macro_rules! err_to_RpcStatus {
     ($c: expr, $e: ident, $s: expr) => {
         |$e| get_rpc_status($c, $s.to_string() + &$e.to_string())
@@ -54,3 +289,177 @@ macro_rules! Err_to_Others {
         |$e| ::ttrpc::Error::Others($s.to_string() + &$e.to_string())
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nix::errno::Errno;
+    use protobuf::Message;
+
+    fn code(e: Error) -> Code {
+        match e {
+            Error::RpcStatus(s) => s.code(),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_errno_to_code() {
+        let cases = [
+            (libc::ENOENT, Code::NOT_FOUND),
+            (libc::ESRCH, Code::NOT_FOUND),
+            (libc::EEXIST, Code::ALREADY_EXISTS),
+            (libc::EACCES, Code::PERMISSION_DENIED),
+            (libc::EPERM, Code::PERMISSION_DENIED),
+            (libc::EINVAL, Code::INVALID_ARGUMENT),
+            (libc::EBADF, Code::INVALID_ARGUMENT),
+            (libc::ETIMEDOUT, Code::DEADLINE_EXCEEDED),
+            (libc::ENOSPC, Code::RESOURCE_EXHAUSTED),
+            (libc::EMFILE, Code::RESOURCE_EXHAUSTED),
+            (libc::EBUSY, Code::FAILED_PRECONDITION),
+            (libc::ENOTEMPTY, Code::FAILED_PRECONDITION),
+            (libc::ERANGE, Code::OUT_OF_RANGE),
+            (libc::ENOSYS, Code::UNIMPLEMENTED),
+            (libc::EOPNOTSUPP, Code::UNIMPLEMENTED),
+            (libc::EAGAIN, Code::UNAVAILABLE),
+            (libc::ECONNREFUSED, Code::UNAVAILABLE),
+            (libc::EPIPE, Code::UNAVAILABLE),
+            (libc::EINTR, Code::CANCELLED),
+            (libc::ECANCELED, Code::CANCELLED),
+            (libc::EIO, Code::DATA_LOSS),
+            (libc::ELOOP, Code::UNKNOWN),
+            (0, Code::UNKNOWN),
+        ];
+        for (errno, c) in cases.iter() {
+            assert_eq!(errno_to_code(*errno), *c, "errno {}", errno);
+            let e = io::Error::from_raw_os_error(*errno);
+            assert_eq!(code(Error::from(e)), *c, "errno {}", errno);
+            if *errno != 0 {
+                let e = nix::Error::from_errno(Errno::from_i32(*errno));
+                assert_eq!(code(Error::from(e)), *c, "errno {}", errno);
+            }
+        }
+        assert_eq!(
+            code(Error::from(nix::Error::InvalidUtf8)),
+            Code::INVALID_ARGUMENT
+        );
+    }
+
+    #[test]
+    fn test_io_kind_to_code() {
+        let cases = [
+            (io::ErrorKind::NotFound, Code::NOT_FOUND),
+            (io::ErrorKind::PermissionDenied, Code::PERMISSION_DENIED),
+            (io::ErrorKind::AlreadyExists, Code::ALREADY_EXISTS),
+            (io::ErrorKind::InvalidInput, Code::INVALID_ARGUMENT),
+            (io::ErrorKind::InvalidData, Code::INVALID_ARGUMENT),
+            (io::ErrorKind::TimedOut, Code::DEADLINE_EXCEEDED),
+            (io::ErrorKind::Interrupted, Code::CANCELLED),
+            (io::ErrorKind::Unsupported, Code::UNIMPLEMENTED),
+            (io::ErrorKind::UnexpectedEof, Code::OUT_OF_RANGE),
+            (io::ErrorKind::WouldBlock, Code::UNAVAILABLE),
+            (io::ErrorKind::ConnectionReset, Code::UNAVAILABLE),
+            (io::ErrorKind::BrokenPipe, Code::UNAVAILABLE),
+            (io::ErrorKind::Other, Code::UNKNOWN),
+        ];
+        for (kind, c) in cases.iter() {
+            assert_eq!(io_kind_to_code(*kind), *c, "{:?}", kind);
+            // errors without an errno go by their kind
+            let e = io::Error::new(*kind, "oops");
+            assert_eq!(code(Error::from(e)), *c, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_to_io_error() {
+        let cases = [
+            (Code::OK, io::ErrorKind::Other),
+            (Code::UNKNOWN, io::ErrorKind::Other),
+            (Code::NOT_FOUND, io::ErrorKind::NotFound),
+            (Code::PERMISSION_DENIED, io::ErrorKind::PermissionDenied),
+            (Code::UNAUTHENTICATED, io::ErrorKind::PermissionDenied),
+            (Code::ALREADY_EXISTS, io::ErrorKind::AlreadyExists),
+            (Code::INVALID_ARGUMENT, io::ErrorKind::InvalidInput),
+            (Code::DEADLINE_EXCEEDED, io::ErrorKind::TimedOut),
+            (Code::CANCELLED, io::ErrorKind::Interrupted),
+            (Code::ABORTED, io::ErrorKind::Interrupted),
+            (Code::UNIMPLEMENTED, io::ErrorKind::Unsupported),
+            (Code::UNAVAILABLE, io::ErrorKind::NotConnected),
+            (Code::OUT_OF_RANGE, io::ErrorKind::UnexpectedEof),
+            (Code::DATA_LOSS, io::ErrorKind::InvalidData),
+            (Code::RESOURCE_EXHAUSTED, io::ErrorKind::Other),
+        ];
+        for (c, kind) in cases.iter() {
+            let e = get_status(*c, "oops".to_string()).to_io_error();
+            assert_eq!(e.kind(), *kind, "{}", c);
+            assert_eq!(e.to_string(), "oops");
+            let e = io::Error::from(get_rpc_status(*c, "oops".to_string()));
+            assert_eq!(e.kind(), *kind, "{}", c);
+        }
+        // the kinds going both ways keep the code
+        for c in [
+            Code::NOT_FOUND,
+            Code::ALREADY_EXISTS,
+            Code::DEADLINE_EXCEEDED,
+        ]
+        .iter()
+        {
+            let e = get_status(*c, "oops".to_string()).to_io_error();
+            assert_eq!(code(Error::from(e)), *c);
+        }
+        let e = io::Error::from(Error::ConnectionClosed);
+        assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn test_code_classes() {
+        // retryable, client error, server error
+        let cases = [
+            (Code::OK, false, false, false),
+            (Code::CANCELLED, false, false, false),
+            (Code::UNKNOWN, false, false, true),
+            (Code::INVALID_ARGUMENT, false, true, false),
+            (Code::DEADLINE_EXCEEDED, false, false, false),
+            (Code::NOT_FOUND, false, true, false),
+            (Code::ALREADY_EXISTS, false, true, false),
+            (Code::PERMISSION_DENIED, false, true, false),
+            (Code::RESOURCE_EXHAUSTED, true, false, false),
+            (Code::FAILED_PRECONDITION, false, true, false),
+            (Code::ABORTED, true, false, false),
+            (Code::OUT_OF_RANGE, false, true, false),
+            (Code::UNIMPLEMENTED, false, false, true),
+            (Code::INTERNAL, false, false, true),
+            (Code::UNAVAILABLE, true, false, true),
+            (Code::DATA_LOSS, false, false, true),
+            (Code::UNAUTHENTICATED, false, true, false),
+        ];
+        for (c, retryable, client, server) in cases.iter() {
+            assert_eq!(c.is_retryable(), *retryable, "{}", c);
+            assert_eq!(c.is_client_error(), *client, "{}", c);
+            assert_eq!(c.is_server_error(), *server, "{}", c);
+            assert_eq!(Code::from(i32::from(*c)), *c);
+        }
+        assert_eq!(Code::from(-1), Code::UNKNOWN);
+        assert_eq!(Code::from(42), Code::UNKNOWN);
+    }
+
+    #[test]
+    fn test_raw_code() {
+        assert_eq!(get_status(Code::NOT_FOUND, String::new()).raw_code(), 5);
+        assert_eq!(Status::new().raw_code(), 0);
+
+        // a code from a newer peer, 42
+        let buf = [0x08, 42, 0x12, 2, b'h', b'i'];
+        let status = Status::parse_from_bytes(&buf).unwrap();
+        assert_eq!(status.get_code(), Code::OK);
+        assert_eq!(status.raw_code(), 42);
+        assert_eq!(status.code(), Code::UNKNOWN);
+        assert_eq!(status.get_message(), "hi");
+
+        // passed on as it came
+        let again = Status::parse_from_bytes(&status.write_to_bytes().unwrap()).unwrap();
+        assert_eq!(again.raw_code(), 42);
+        let e = Error::RpcStatus(again);
+        assert_eq!(e.to_status().raw_code(), 42);
+    }
+}
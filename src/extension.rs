@@ -0,0 +1,314 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-frame extensions, carried ahead of the payload of frames flagged
+//! with [`FLAG_EXTENSIONS`], so new per-message data like priority hints
+//! or tenant tags does not need another change to the framing.
+//!
+//! The extension block is a big endian u16 of its length, followed by
+//! entries of a big endian u16 id, a big endian u16 length and the value.
+//! Peers only send it to peers with the `extensions` capability, see
+//! [`PeerInfo`](crate::PeerInfo).
+//!
+//! Ids are registered with [`register`] before use: values with ids
+//! nobody registered are refused when set and dropped when received.
+//! Ids below [`RESERVED`] are kept for this library.
+//!
+//! ```
+//! use ttrpc::extension::{self, Extensions};
+//!
+//! const TENANT: u16 = 0x1000;
+//! extension::register(TENANT, "tenant").unwrap();
+//!
+//! let mut ext = Extensions::new();
+//! ext.set(TENANT, b"team-a".to_vec()).unwrap();
+//! assert_eq!(ext.get(TENANT), Some(&b"team-a"[..]));
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use crate::error::{get_rpc_status, Result};
+use crate::proto::{MessageHeader, FLAG_EXTENSIONS};
+use crate::ttrpc::Code;
+
+/// Ids below this one are kept for this library.
+pub const RESERVED: u16 = 0x100;
+/// The longest extension block, length prefix included.
+pub const EXTENSIONS_LENGTH_MAX: usize = 2 + u16::MAX as usize;
+
+static REGISTRY: RwLock<BTreeMap<u16, &'static str>> = RwLock::new(BTreeMap::new());
+
+/// Register extension `id` under `name`. Registering an id again under the
+/// same name does nothing; under another name it fails, as does
+/// registering a reserved id.
+pub fn register(id: u16, name: &'static str) -> Result<()> {
+    if id < RESERVED {
+        return Err(get_rpc_status(
+            Code::INVALID_ARGUMENT,
+            format!("extension id {:#x} is reserved", id),
+        ));
+    }
+    let mut registry = REGISTRY.write().unwrap();
+    match registry.get(&id) {
+        Some(n) if *n != name => Err(get_rpc_status(
+            Code::ALREADY_EXISTS,
+            format!("extension id {:#x} is registered as {}", id, n),
+        )),
+        _ => {
+            registry.insert(id, name);
+            Ok(())
+        }
+    }
+}
+
+/// The name extension `id` was registered under.
+pub fn name(id: u16) -> Option<&'static str> {
+    REGISTRY.read().unwrap().get(&id).copied()
+}
+
+fn is_registered(id: u16) -> bool {
+    REGISTRY.read().unwrap().contains_key(&id)
+}
+
+/// The extensions of a frame, by id.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extensions {
+    values: BTreeMap<u16, Vec<u8>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// The value of extension `id`.
+    pub fn get(&self, id: u16) -> Option<&[u8]> {
+        self.values.get(&id).map(|v| v.as_slice())
+    }
+
+    /// Set extension `id` to `value`, which must fit in the block.
+    pub fn set(&mut self, id: u16, value: Vec<u8>) -> Result<()> {
+        if !is_registered(id) {
+            return Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("extension id {:#x} is not registered", id),
+            ));
+        }
+        if value.len() > u16::MAX as usize {
+            return Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("extension {:#x} is {} bytes long", id, value.len()),
+            ));
+        }
+        self.values.insert(id, value);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: u16) -> Option<Vec<u8>> {
+        self.values.remove(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The extensions by increasing id.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.values.iter().map(|(id, v)| (*id, v.as_slice()))
+    }
+
+    /// The extension block, length prefix included.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let len: usize = self.values.values().map(|v| 4 + v.len()).sum();
+        if 2 + len > EXTENSIONS_LENGTH_MAX {
+            return Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("extension block of {} bytes is too long", len),
+            ));
+        }
+        let mut buf = vec![0u8; 2 + len];
+        BigEndian::write_u16(&mut buf[..2], len as u16);
+        let mut at = 2;
+        for (id, v) in self.values.iter() {
+            BigEndian::write_u16(&mut buf[at..at + 2], *id);
+            BigEndian::write_u16(&mut buf[at + 2..at + 4], v.len() as u16);
+            buf[at + 4..at + 4 + v.len()].copy_from_slice(v);
+            at += 4 + v.len();
+        }
+        Ok(buf)
+    }
+
+    /// Read the extension block at the start of `buf`, returning the
+    /// extensions and the length of the block.
+    pub fn decode(buf: &[u8]) -> Result<(Extensions, usize)> {
+        let malformed = |what: &str| {
+            get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("malformed extension block: {}", what),
+            )
+        };
+        if buf.len() < 2 {
+            return Err(malformed("no length"));
+        }
+        let len = 2 + BigEndian::read_u16(&buf[..2]) as usize;
+        if buf.len() < len {
+            return Err(malformed("longer than the frame"));
+        }
+        let mut ext = Extensions::new();
+        let mut rest = &buf[2..len];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(malformed("truncated entry"));
+            }
+            let id = BigEndian::read_u16(&rest[..2]);
+            let n = BigEndian::read_u16(&rest[2..4]) as usize;
+            if rest.len() < 4 + n {
+                return Err(malformed("truncated value"));
+            }
+            if is_registered(id) {
+                ext.values.insert(id, rest[4..4 + n].to_vec());
+            } else {
+                trace!("dropping unregistered extension {:#x}", id);
+            }
+            rest = &rest[4 + n..];
+        }
+        Ok((ext, len))
+    }
+}
+
+/// Put `extensions` ahead of `payload`, flagging `mh` and adjusting its
+/// length. Nothing changes if there are none.
+pub fn prepend(
+    mh: &mut MessageHeader,
+    payload: Vec<u8>,
+    extensions: &Extensions,
+) -> Result<Vec<u8>> {
+    if extensions.is_empty() {
+        return Ok(payload);
+    }
+    let mut buf = extensions.encode()?;
+    buf.extend_from_slice(&payload);
+    mh.flags |= FLAG_EXTENSIONS;
+    mh.length = buf.len() as u32;
+    Ok(buf)
+}
+
+/// Take the extension block off the payload of a frame flagged with
+/// [`FLAG_EXTENSIONS`], clearing the flag. Frames without one have none.
+pub fn split(mh: &mut MessageHeader, mut payload: Vec<u8>) -> Result<(Extensions, Vec<u8>)> {
+    if mh.flags & FLAG_EXTENSIONS == 0 {
+        return Ok((Extensions::new(), payload));
+    }
+    let (ext, len) = Extensions::decode(&payload)?;
+    mh.flags &= !FLAG_EXTENSIONS;
+    mh.length -= len as u32;
+    payload.drain(..len);
+    Ok((ext, payload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+    use crate::proto::MESSAGE_TYPE_REQUEST;
+
+    const TENANT: u16 = 0x7e00;
+    const TRACE: u16 = 0x7e01;
+    // never registered
+    const UNKNOWN: u16 = 0x7eff;
+
+    fn registered() -> Extensions {
+        register(TENANT, "test-tenant").unwrap();
+        register(TRACE, "test-trace").unwrap();
+        let mut ext = Extensions::new();
+        ext.set(TENANT, b"team-a".to_vec()).unwrap();
+        ext.set(TRACE, vec![]).unwrap();
+        ext
+    }
+
+    fn malformed(buf: &[u8]) -> String {
+        match Extensions::decode(buf) {
+            Err(Error::RpcStatus(s)) => {
+                assert_eq!(s.get_code(), Code::INVALID_ARGUMENT);
+                s.get_message().to_string()
+            }
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let ext = registered();
+        let mut buf = ext.encode().unwrap();
+        let len = buf.len();
+        buf.extend_from_slice(b"payload");
+        assert_eq!(Extensions::decode(&buf).unwrap(), (ext, len));
+        assert_eq!(Extensions::decode(&[0, 0]).unwrap(), (Extensions::new(), 2));
+    }
+
+    #[test]
+    fn test_truncated() {
+        registered();
+        assert!(malformed(&[]).ends_with("no length"));
+        assert!(malformed(&[0]).ends_with("no length"));
+        assert!(malformed(&[0, 5, 0x7e, 0]).ends_with("longer than the frame"));
+        // an id and no length
+        assert!(malformed(&[0, 2, 0x7e, 0]).ends_with("truncated entry"));
+        // a value of 4 bytes, with 2 in the block
+        let buf = [0, 6, 0x7e, 0, 0, 4, b'a', b'b', b'c', b'd'];
+        assert!(malformed(&buf).ends_with("truncated value"));
+    }
+
+    #[test]
+    fn test_unregistered_dropped() {
+        let mut buf = registered().encode().unwrap();
+        // another entry, of an id nobody registered
+        buf.extend_from_slice(&[0x7e, 0xff, 0, 1, b'x']);
+        let len = BigEndian::read_u16(&buf[..2]) + 5;
+        BigEndian::write_u16(&mut buf[..2], len);
+
+        let (ext, n) = Extensions::decode(&buf).unwrap();
+        assert_eq!(n, buf.len());
+        assert_eq!(ext, registered());
+        assert_eq!(ext.get(UNKNOWN), None);
+        assert!(Extensions::new().set(UNKNOWN, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_split() {
+        let ext = registered();
+        let mut mh = MessageHeader {
+            length: 7,
+            stream_id: 1,
+            type_: MESSAGE_TYPE_REQUEST,
+            flags: 0,
+        };
+        let buf = prepend(&mut mh, b"payload".to_vec(), &ext).unwrap();
+        assert_ne!(mh.flags & FLAG_EXTENSIONS, 0);
+        assert_eq!(mh.length as usize, buf.len());
+
+        let (split_ext, payload) = split(&mut mh, buf).unwrap();
+        assert_eq!(split_ext, ext);
+        assert_eq!(payload, b"payload");
+        assert_eq!(mh.flags & FLAG_EXTENSIONS, 0);
+        assert_eq!(mh.length, 7);
+
+        // frames not flagged are left alone
+        let (none, payload) = split(&mut mh, payload).unwrap();
+        assert!(none.is_empty());
+        assert_eq!((payload.len(), mh.length), (7, 7));
+    }
+}
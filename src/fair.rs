@@ -0,0 +1,243 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The queue of a pool of threads shared by many connections, independent
+//! of the sockets they read from.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::sync::{Condvar, Mutex};
+
+/// The jobs of many connections, for the threads of a pool they share.
+/// Each connection has a queue of its own, and the queues are served in
+/// deficit round-robin order: on its turn a connection gets `quantum` more
+/// to spend, and has its jobs run while their cost, e.g. the size of
+/// their request, fits in what it has. So a connection sending many
+/// requests gets no more of the pool than one sending few, once both wait
+/// for it.
+pub(crate) struct FairQueue<T> {
+    state: Mutex<Flows<T>>,
+    ready: Condvar,
+}
+
+struct Flows<T> {
+    flows: HashMap<u64, Flow<T>>,
+    // the connections with jobs queued, the one whose turn it is first
+    active: VecDeque<u64>,
+    // whether the first of `active` got its quantum for this turn
+    turn: bool,
+    quantum: usize,
+    closed: bool,
+}
+
+struct Flow<T> {
+    jobs: VecDeque<(usize, T)>,
+    deficit: usize,
+    served: u64,
+    // forgotten: dropped once its jobs ran
+    gone: bool,
+}
+
+impl<T> FairQueue<T> {
+    pub(crate) fn new(quantum: usize) -> FairQueue<T> {
+        FairQueue {
+            state: Mutex::new(Flows {
+                flows: HashMap::new(),
+                active: VecDeque::new(),
+                turn: false,
+                quantum: quantum.max(1),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Queue `job` of connection `key`, costing `cost`. Returns false,
+    /// dropping the job, once the queue is closed.
+    pub(crate) fn push(&self, key: u64, cost: usize, job: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        let flow = state.flows.entry(key).or_insert_with(|| Flow {
+            jobs: VecDeque::new(),
+            deficit: 0,
+            served: 0,
+            gone: false,
+        });
+        flow.jobs.push_back((cost.max(1), job));
+        if flow.jobs.len() == 1 {
+            state.active.push_back(key);
+        }
+        drop(state);
+        self.ready.notify_one();
+        true
+    }
+
+    /// Wait for the next job due. Returns `None` once the queue is closed
+    /// and the jobs queued before ran.
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.next() {
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Take no more jobs, and wake up the threads waiting for one once
+    /// the jobs queued ran.
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    /// Drop the counters of connection `key`, once its jobs queued ran.
+    pub(crate) fn forget(&self, key: u64) {
+        let mut state = self.state.lock().unwrap();
+        match state.flows.get_mut(&key) {
+            Some(flow) if flow.jobs.is_empty() => {
+                state.flows.remove(&key);
+            }
+            Some(flow) => flow.gone = true,
+            None => (),
+        }
+    }
+
+    /// How many jobs of connection `key` were handed out.
+    #[cfg(test)]
+    pub(crate) fn served(&self, key: u64) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.flows.get(&key).map(|f| f.served).unwrap_or(0)
+    }
+}
+
+impl<T> Flows<T> {
+    fn next(&mut self) -> Option<T> {
+        loop {
+            let key = *self.active.front()?;
+            let flow = self.flows.get_mut(&key).expect("active flow");
+            if !self.turn {
+                flow.deficit += self.quantum;
+                self.turn = true;
+            }
+            let cost = flow.jobs.front().expect("queued job").0;
+            if cost > flow.deficit {
+                // saved for its next turn
+                self.active.rotate_left(1);
+                self.turn = false;
+                continue;
+            }
+            flow.deficit -= cost;
+            flow.served += 1;
+            let job = flow.jobs.pop_front().expect("queued job").1;
+            if flow.jobs.is_empty() {
+                flow.deficit = 0;
+                self.active.pop_front();
+                self.turn = false;
+                if flow.gone {
+                    self.flows.remove(&key);
+                }
+            }
+            return Some(job);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sync::Arc;
+
+    #[test]
+    fn test_fair_queue_round_robin() {
+        let queue = FairQueue::new(10);
+        // a chatty connection queues 100 jobs before a quiet one queues 5
+        for i in 0..100 {
+            assert!(queue.push(1, 10, (1, i)));
+        }
+        for i in 0..5 {
+            assert!(queue.push(2, 10, (2, i)));
+        }
+
+        // one job each per turn, so the quiet one is done within 10
+        let first: Vec<(u64, i32)> = (0..10).map(|_| queue.pop().unwrap()).collect();
+        assert_eq!(first.iter().filter(|j| j.0 == 2).count(), 5);
+        assert_eq!(queue.served(1), 5);
+        assert_eq!(queue.served(2), 5);
+        // in the order they came within a connection
+        let quiet: Vec<i32> = first.iter().filter(|j| j.0 == 2).map(|j| j.1).collect();
+        assert_eq!(quiet, vec![0, 1, 2, 3, 4]);
+
+        queue.close();
+        assert!(!queue.push(2, 10, (2, 5)));
+        assert_eq!((0..95).filter_map(|_| queue.pop()).count(), 95);
+        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.served(1), 100);
+    }
+
+    #[test]
+    fn test_fair_queue_deficit() {
+        let queue = FairQueue::new(10);
+        // connection 1 sends jobs three times as costly as connection 2's
+        for i in 0..30 {
+            queue.push(1, 30, i);
+            queue.push(2, 10, i);
+        }
+        for _ in 0..40 {
+            queue.pop().unwrap();
+        }
+        // the same cost served to each, not the same count
+        assert_eq!(queue.served(1), 10);
+        assert_eq!(queue.served(2), 30);
+
+        queue.forget(1);
+        assert_eq!(queue.served(1), 10);
+        for _ in 0..20 {
+            queue.pop().unwrap();
+        }
+        // dropped once its last job was handed out
+        assert_eq!(queue.served(1), 0);
+    }
+
+    #[test]
+    fn test_fair_queue_threads() {
+        let queue = Arc::new(FairQueue::new(1));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = queue.clone();
+                std::thread::spawn(move || {
+                    let mut n = 0;
+                    while queue.pop().is_some() {
+                        n += 1;
+                    }
+                    n
+                })
+            })
+            .collect();
+        for key in 0..8 {
+            for _ in 0..100 {
+                queue.push(key, 1, ());
+            }
+        }
+        queue.close();
+        let n: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+        assert_eq!(n, 800);
+        assert!((0..8).all(|key| queue.served(key) == 100));
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversions between the ttrpc and tonic representations of statuses
+//! and metadata, for gateways serving gRPC and calling ttrpc, or the
+//! other way around. Needs the `tonic` feature.
+//!
+//! The status codes of both are gRPC's. A ttrpc [`Status`] has the shape
+//! of `google.rpc.Status`, which is what tonic carries encoded in its
+//! details, so the details of a status survive the round trip.
+//!
+//! With the `gateway` feature, [`gateway::Gateway`] serves gRPC calls by
+//! forwarding them to a ttrpc server.
+
+use protobuf::{Message, ProtobufEnum, RepeatedField};
+use tonic::metadata::{
+    AsciiMetadataKey, AsciiMetadataValue, BinaryMetadataKey, BinaryMetadataValue, KeyAndValueRef,
+    MetadataMap,
+};
+
+use crate::error::{get_status, Error};
+use crate::metadata::Metadata;
+use crate::ttrpc::{Code, Status};
+
+#[cfg(feature = "gateway")]
+pub mod gateway;
+
+/// The tonic code of `code`.
+pub fn to_tonic_code(code: Code) -> tonic::Code {
+    tonic::Code::from_i32(code as i32)
+}
+
+/// The ttrpc code of `code`.
+pub fn from_tonic_code(code: tonic::Code) -> Code {
+    Code::from_i32(code as i32).unwrap_or(Code::UNKNOWN)
+}
+
+/// The tonic status of `status`, with `status` itself encoded as its
+/// details if it has any.
+pub fn to_tonic_status(status: &Status) -> tonic::Status {
+    let code = to_tonic_code(status.get_code());
+    if status.get_details().is_empty() {
+        return tonic::Status::new(code, status.get_message());
+    }
+    match status.write_to_bytes() {
+        Ok(buf) => tonic::Status::with_details(code, status.get_message(), buf.into()),
+        Err(e) => {
+            debug!("dropping the details of status {:?}: {}", code, e);
+            tonic::Status::new(code, status.get_message())
+        }
+    }
+}
+
+/// The ttrpc status of `status`, with the details of the
+/// `google.rpc.Status` it carries, if any.
+pub fn from_tonic_status(status: &tonic::Status) -> Status {
+    let mut s = get_status(from_tonic_code(status.code()), status.message().to_string());
+    if status.details().is_empty() {
+        return s;
+    }
+    match Status::parse_from_bytes(status.details()) {
+        Ok(carried) => s.set_details(RepeatedField::from_vec(carried.details.into_vec())),
+        Err(e) => debug!("dropping undecodable status details: {}", e),
+    }
+    s
+}
+
+/// The tonic status a gRPC caller should see for `e`, as a ttrpc method
+/// failing with it would answer.
+pub fn error_to_tonic_status(e: &Error) -> tonic::Status {
+    to_tonic_status(&e.to_status())
+}
+
+/// The ttrpc error matching `status`, which fails a call the same way.
+pub fn error_from_tonic_status(status: &tonic::Status) -> Error {
+    Error::RpcStatus(from_tonic_status(status))
+}
+
+// Headers gRPC and HTTP/2 use for themselves, rather than metadata of
+// the call.
+fn is_reserved(key: &str) -> bool {
+    key.starts_with("grpc-") || key == "te" || key == "content-type"
+}
+
+/// The tonic metadata of `md`. Keys ending in `-bin` carry binary values,
+/// which are the bytes of their ttrpc values. Keys and values tonic does
+/// not take, and gRPC's reserved headers, are left out.
+pub fn to_metadata_map(md: &Metadata) -> MetadataMap {
+    let mut map = MetadataMap::new();
+    for (k, vs) in md.iter() {
+        let k = k.to_lowercase();
+        if is_reserved(&k) {
+            continue;
+        }
+        for v in vs {
+            let added = if k.ends_with("-bin") {
+                BinaryMetadataKey::from_bytes(k.as_bytes())
+                    .map(|key| map.append_bin(key, BinaryMetadataValue::from_bytes(v.as_bytes())))
+                    .is_ok()
+            } else {
+                match (
+                    AsciiMetadataKey::from_bytes(k.as_bytes()),
+                    v.parse::<AsciiMetadataValue>(),
+                ) {
+                    (Ok(key), Ok(value)) => {
+                        map.append(key, value);
+                        true
+                    }
+                    _ => false,
+                }
+            };
+            if !added {
+                debug!("metadata {} does not fit gRPC, left out", k);
+            }
+        }
+    }
+    map
+}
+
+/// The ttrpc metadata of `map`. Binary values which are not UTF-8, and
+/// gRPC's reserved headers, are left out.
+pub fn from_metadata_map(map: &MetadataMap) -> Metadata {
+    let mut md = Metadata::new();
+    for kv in map.iter() {
+        let (k, v) = match kv {
+            KeyAndValueRef::Ascii(k, v) => (k.as_str(), v.to_str().map(str::to_string).ok()),
+            KeyAndValueRef::Binary(k, v) => (
+                k.as_str(),
+                v.to_bytes()
+                    .ok()
+                    .and_then(|b| String::from_utf8(b.to_vec()).ok()),
+            ),
+        };
+        if is_reserved(k) {
+            continue;
+        }
+        match v {
+            Some(v) => md.entry(k.to_string()).or_default().push(v),
+            None => debug!("metadata {} is not UTF-8, left out", k),
+        }
+    }
+    md
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ttrpc::Any;
+
+    #[test]
+    fn test_status_round_trip() {
+        let mut status = get_status(Code::NOT_FOUND, "no such container".to_string());
+        let mut detail = Any::new();
+        detail.set_type_url("type.googleapis.com/test.Detail".to_string());
+        detail.set_value(vec![1, 2, 3]);
+        status.mut_details().push(detail);
+
+        let t = to_tonic_status(&status);
+        assert_eq!(t.code(), tonic::Code::NotFound);
+        assert_eq!(t.message(), "no such container");
+        assert_eq!(from_tonic_status(&t), status);
+
+        let t = tonic::Status::unavailable("going away");
+        let s = from_tonic_status(&t);
+        assert_eq!(s.get_code(), Code::UNAVAILABLE);
+        assert!(s.get_details().is_empty());
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let mut md = Metadata::new();
+        md.insert(
+            "containerd-namespace-ttrpc".to_string(),
+            vec!["k8s.io".to_string()],
+        );
+        md.insert("trace-bin".to_string(), vec!["abc".to_string()]);
+        md.insert("x".to_string(), vec!["1".to_string(), "2".to_string()]);
+        md.insert("grpc-timeout".to_string(), vec!["1S".to_string()]);
+        md.insert("bad key".to_string(), vec!["1".to_string()]);
+
+        let map = to_metadata_map(&md);
+        assert_eq!(map.len(), 4);
+        md.remove("grpc-timeout");
+        md.remove("bad key");
+        assert_eq!(from_metadata_map(&map), md);
+    }
+}
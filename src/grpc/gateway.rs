@@ -0,0 +1,266 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serving gRPC calls with ttrpc methods, see [`Gateway`]. Needs the
+//! `gateway` feature.
+
+use bytes1::{Buf, BufMut};
+use http_body::Body;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::metadata::MetadataMap;
+use tonic::server::{Grpc, UnaryService};
+
+use super::{error_to_tonic_status, from_metadata_map, to_metadata_map};
+use crate::asynchronous::Client;
+use crate::metadata;
+use crate::ttrpc::Request;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Passes the messages through as they are encoded: the payload of a
+/// ttrpc call is the same protobuf message as that of the gRPC call.
+#[derive(Clone, Copy, Default)]
+struct RawCodec;
+
+impl Codec for RawCodec {
+    type Encode = Vec<u8>;
+    type Decode = Vec<u8>;
+    type Encoder = RawCodec;
+    type Decoder = RawCodec;
+
+    fn encoder(&mut self) -> RawCodec {
+        RawCodec
+    }
+
+    fn decoder(&mut self) -> RawCodec {
+        RawCodec
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = tonic::Status;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut EncodeBuf<'_>) -> Result<(), tonic::Status> {
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = tonic::Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Vec<u8>>, tonic::Status> {
+        let mut buf = vec![0; src.remaining()];
+        src.copy_to_slice(&mut buf);
+        Ok(Some(buf))
+    }
+}
+
+/// The deadline a gRPC client set in its `grpc-timeout` header: up to 8
+/// digits followed by their unit.
+fn grpc_timeout(md: &MetadataMap) -> Option<Duration> {
+    let value = md.get("grpc-timeout")?.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    let n: u64 = digits.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(n * 3600),
+        "M" => Duration::from_secs(n * 60),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+/// A gRPC service forwarding each unary call it gets, whatever its
+/// method, to the ttrpc method of the same path on a backend: the proxy
+/// plugin pattern of containerd.
+///
+/// The deadline of the gRPC call becomes the `timeout_nano` of the ttrpc
+/// call, and metadata is translated both ways, as in [`super`]. An
+/// answer other than `OK`, or a failure to reach the backend, is
+/// answered with the matching gRPC status.
+///
+/// It is a `tower::Service` of HTTP requests, which a hyper server, or
+/// a router as its fallback, serves. Call it from within a tokio runtime.
+#[derive(Clone)]
+pub struct Gateway {
+    client: Client,
+    timeout: Option<Duration>,
+}
+
+impl Gateway {
+    /// Forward the calls through `client`, connected to the backend.
+    pub fn new(client: Client) -> Gateway {
+        Gateway {
+            client,
+            timeout: None,
+        }
+    }
+
+    /// Give the calls which arrive without a deadline `timeout` on the
+    /// backend, rather than none.
+    pub fn default_timeout(mut self, timeout: Duration) -> Gateway {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<B> tower::Service<http::Request<B>> for Gateway
+where
+    B: Body + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Result<http::Response<BoxBody>, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let forward = Forward {
+            gateway: self.clone(),
+            path: req.uri().path().to_string(),
+        };
+        Box::pin(async move { Ok(Grpc::new(RawCodec).unary(forward, req).await) })
+    }
+}
+
+/// One call, forwarded to the method at `path`.
+struct Forward {
+    gateway: Gateway,
+    path: String,
+}
+
+impl UnaryService<Vec<u8>> for Forward {
+    type Response = Vec<u8>;
+    type Future = BoxFuture<Result<tonic::Response<Vec<u8>>, tonic::Status>>;
+
+    fn call(&mut self, req: tonic::Request<Vec<u8>>) -> Self::Future {
+        let (service, method) = match self.path.trim_start_matches('/').split_once('/') {
+            Some(names) => names,
+            None => {
+                let status = tonic::Status::unimplemented(format!("no method at {}", self.path));
+                return Box::pin(async move { Err(status) });
+            }
+        };
+        let mut creq = Request::new();
+        creq.set_service(service.to_string());
+        creq.set_method(method.to_string());
+        if let Some(timeout) = grpc_timeout(req.metadata()).or(self.gateway.timeout) {
+            creq.set_timeout_nano(timeout.as_nanos() as i64);
+        }
+        creq.set_metadata(metadata::to_pairs(&from_metadata_map(req.metadata())));
+        creq.payload = req.into_inner();
+
+        let client = self.gateway.client.clone();
+        Box::pin(async move {
+            let res = client
+                .request(creq)
+                .await
+                .map_err(|e| error_to_tonic_status(&e))?;
+            let md = to_metadata_map(&metadata::from_pairs(res.get_metadata()));
+            let mut answer = tonic::Response::new(res.payload);
+            *answer.metadata_mut() = md;
+            Ok(answer)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asynchronous::fixture::{echo, serve};
+    use crate::asynchronous::rt;
+    use bytes1::Bytes;
+    use http_body_util::{BodyExt, Full};
+
+    fn grpc_request(path: &str, timeout: &str, payload: &[u8]) -> http::Request<Full<Bytes>> {
+        let mut frame = vec![0];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        http::Request::builder()
+            .method("POST")
+            .uri(path)
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .header("grpc-timeout", timeout)
+            .header("sandbox-id", "s1")
+            .body(Full::new(Bytes::from(frame)))
+            .unwrap()
+    }
+
+    /// The headers, payload and gRPC status of the answer to `req`. A
+    /// failure is answered with headers only, which carry the status.
+    async fn answer(
+        gateway: &mut Gateway,
+        req: http::Request<Full<Bytes>>,
+    ) -> (http::HeaderMap, Vec<u8>, String) {
+        let res = tower::Service::call(gateway, req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let collected = body.collect().await.unwrap();
+        let trailers = collected.trailers().cloned().unwrap_or_default();
+        let data = collected.to_bytes();
+        let payload = if data.len() > 5 {
+            data[5..].to_vec()
+        } else {
+            Vec::new()
+        };
+        let status = trailers
+            .get("grpc-status")
+            .or_else(|| parts.headers.get("grpc-status"))
+            .map(|v| v.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        (parts.headers, payload, status)
+    }
+
+    #[test]
+    fn test_gateway() {
+        rt::block_on(async {
+            let (mut server, client) = serve(echo()).await;
+            let mut gateway = Gateway::new(client);
+
+            let req = grpc_request("/test.Echo/Echo", "5S", b"\0hello");
+            let (headers, payload, status) = answer(&mut gateway, req).await;
+            assert_eq!(payload, b"\0hello");
+            assert_eq!(headers["sandbox-id"], "s1");
+            assert_eq!(status, "0");
+
+            // answered after 200ms
+            let req = grpc_request("/test.Echo/Echo", "20m", b"\xc8hello");
+            let (_, _, status) = answer(&mut gateway, req).await;
+            assert_eq!(status, "4");
+
+            let req = grpc_request("/test.Echo/Missing", "5S", b"");
+            let (_, _, status) = answer(&mut gateway, req).await;
+            assert_eq!(status, "3");
+            server.shutdown().await.unwrap();
+        });
+    }
+}
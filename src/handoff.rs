@@ -0,0 +1,104 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handing listening sockets over to another process, for upgrading a
+//! server in place without refusing connections.
+//!
+//! The old and the new process share a connected unix stream socket, the
+//! control socket, e.g. one inherited through [`pair`](crate::pair) or
+//! accepted on a well-known path. The old process calls
+//! [`Server::hand_off`], which sends its listeners with `SCM_RIGHTS`. The
+//! new process takes them with [`receive_listeners`], passes them to
+//! [`ServerBuilder::add_listener`] and starts serving. Once the new process
+//! confirmed, the old one stops accepting and keeps serving the
+//! connections it already has until it is shut down.
+//!
+//! [`Server::hand_off`]: crate::Server::hand_off
+//! [`ServerBuilder::add_listener`]: crate::ServerBuilder::add_listener
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::*;
+use nix::sys::uio::IoVec;
+use nix::unistd::{read, write};
+use std::os::unix::io::RawFd;
+
+use crate::error::{Error, Result};
+
+/// The most listeners handed over at once.
+pub const MAX_LISTENERS: usize = 8;
+
+const HANDOFF: u8 = b'L';
+const CONFIRM: u8 = b'K';
+
+/// Send `listeners` over `control` and wait for the receiver to confirm it
+/// took them. The fds stay open in this process.
+pub fn send_listeners(control: RawFd, listeners: &[RawFd]) -> Result<()> {
+    if listeners.is_empty() || listeners.len() > MAX_LISTENERS {
+        return Err(Error::Others(format!(
+            "can hand over 1 to {} listeners, not {}",
+            MAX_LISTENERS,
+            listeners.len()
+        )));
+    }
+
+    let buf = [HANDOFF];
+    let iov = [IoVec::from_slice(&buf)];
+    let cmsg = [ControlMessage::ScmRights(listeners)];
+    sendmsg(control, &iov, &cmsg, MsgFlags::empty(), None)
+        .map_err(|e| Error::Socket(e.to_string()))?;
+
+    let mut ack = [0u8];
+    match read(control, &mut ack) {
+        Ok(1) if ack[0] == CONFIRM => Ok(()),
+        Ok(_) => Err(Error::Others(
+            "listener handoff was not confirmed".to_string(),
+        )),
+        Err(e) => Err(Error::Socket(e.to_string())),
+    }
+}
+
+/// Receive the listeners sent by [`send_listeners`] over `control` and
+/// confirm their receipt. The returned fds are close-on-exec.
+pub fn receive_listeners(control: RawFd) -> Result<Vec<RawFd>> {
+    let mut buf = [0u8];
+    let iov = [IoVec::from_mut_slice(&mut buf)];
+    let mut space = nix::cmsg_space!([RawFd; MAX_LISTENERS]);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = MsgFlags::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = MsgFlags::empty();
+    let msg = recvmsg(control, &iov, Some(&mut space), flags)
+        .map_err(|e| Error::Socket(e.to_string()))?;
+
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            fds.extend(received);
+        }
+    }
+    if msg.bytes != 1 || buf[0] != HANDOFF || fds.is_empty() {
+        for fd in fds {
+            nix::unistd::close(fd).unwrap_or(());
+        }
+        return Err(Error::Others("malformed listener handoff".to_string()));
+    }
+    for fd in fds.iter() {
+        // MSG_CMSG_CLOEXEC is ignored by some kernels for unix sockets, and
+        // missing on others
+        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).unwrap_or(0);
+    }
+
+    write(control, &[CONFIRM]).map_err(|e| Error::Socket(e.to_string()))?;
+    Ok(fds)
+}
@@ -0,0 +1,111 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A record of the last calls a server handled, for finding out what it
+//! was doing when it crashed. See [`ServerBuilder::set_journal`].
+//!
+//! [`ServerBuilder::set_journal`]: crate::ServerBuilder::set_journal
+
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::ttrpc::Code;
+
+/// One call recorded in the journal.
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    /// The connection the call arrived on.
+    pub fd: RawFd,
+    pub stream_id: u32,
+    /// The method path, e.g. `/grpc.Health/Check`.
+    pub method: String,
+    pub request_size: usize,
+    /// FNV-1a hash of the request payload truncated to 32 bits, to tell
+    /// apart calls with different arguments without keeping them.
+    pub payload_hash: u32,
+    pub received: SystemTime,
+    /// When the response was sent, if it was.
+    pub replied: Option<SystemTime>,
+    pub response_size: usize,
+    pub status: Option<Code>,
+}
+
+/// Ring buffer of the last calls handled.
+pub(crate) struct Journal {
+    capacity: usize,
+    entries: Mutex<VecDeque<JournalEntry>>,
+}
+
+fn payload_hash(payload: &[u8]) -> u32 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in payload {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h as u32
+}
+
+impl Journal {
+    pub(crate) fn new(capacity: usize) -> Journal {
+        Journal {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(crate) fn request(&self, fd: RawFd, stream_id: u32, method: &str, payload: &[u8]) {
+        let entry = JournalEntry {
+            fd,
+            stream_id,
+            method: method.to_string(),
+            request_size: payload.len(),
+            payload_hash: payload_hash(payload),
+            received: SystemTime::now(),
+            replied: None,
+            response_size: 0,
+            status: None,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    pub(crate) fn response(&self, fd: RawFd, stream_id: u32, status: Code, size: usize) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .iter_mut()
+            .rev()
+            .find(|e| e.fd == fd && e.stream_id == stream_id && e.replied.is_none());
+        if let Some(e) = entry {
+            e.replied = Some(SystemTime::now());
+            e.response_size = size;
+            e.status = Some(status);
+        }
+    }
+
+    pub(crate) fn entries(&self) -> Vec<JournalEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Log every entry, oldest first.
+    pub(crate) fn log(&self) {
+        for e in self.entries.lock().unwrap().iter() {
+            error!("journal: {:?}", e);
+        }
+    }
+}
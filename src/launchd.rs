@@ -0,0 +1,61 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Socket activation by launchd, the macOS counterpart of systemd's.
+//!
+//! launchd creates the sockets listed under `Sockets` in the plist of a
+//! job and starts the job on the first connection. The job takes them
+//! with [`activate_socket`] and passes them to [`ServerBuilder::add_listener`].
+//!
+//! [`ServerBuilder::add_listener`]: crate::ServerBuilder::add_listener
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+
+use crate::error::{Error, Result};
+
+extern "C" {
+    fn launch_activate_socket(
+        name: *const libc::c_char,
+        fds: *mut *mut libc::c_int,
+        cnt: *mut libc::size_t,
+    ) -> libc::c_int;
+}
+
+/// The listening sockets launchd created for the `name` entry of the
+/// `Sockets` dictionary of the job. The returned fds are close-on-exec.
+pub fn activate_socket(name: &str) -> Result<Vec<RawFd>> {
+    let cname = CString::new(name).map_err(err_to_Others!(e, "bad launchd socket name: "))?;
+    let mut fds: *mut libc::c_int = std::ptr::null_mut();
+    let mut cnt: libc::size_t = 0;
+    let ret = unsafe { launch_activate_socket(cname.as_ptr(), &mut fds, &mut cnt) };
+    if ret != 0 {
+        return Err(Error::Socket(format!(
+            "launch_activate_socket {}: {}",
+            name,
+            std::io::Error::from_raw_os_error(ret)
+        )));
+    }
+    if fds.is_null() {
+        return Ok(Vec::new());
+    }
+
+    let listeners = unsafe { std::slice::from_raw_parts(fds, cnt) }.to_vec();
+    unsafe { libc::free(fds as *mut libc::c_void) };
+    for fd in listeners.iter() {
+        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).unwrap_or(0);
+    }
+    Ok(listeners)
+}
@@ -19,18 +19,75 @@ extern crate log;
 pub mod error;
 #[macro_use]
 mod channel;
+pub mod acl;
+pub mod address;
+#[cfg(any(feature = "async", feature = "smol"))]
+pub mod asynchronous;
+pub mod builtin;
+mod common;
+pub mod config;
+pub mod dedup;
+pub mod extension;
+#[cfg(any(test, all(feature = "uring", target_os = "linux")))]
+mod fair;
+#[cfg(feature = "tonic")]
+pub mod grpc;
+pub mod handoff;
+pub mod journal;
+#[cfg(target_os = "macos")]
+pub mod launchd;
+pub mod message_pool;
+pub mod metadata;
+pub mod mirror;
+mod pair;
+#[allow(clippy::type_complexity)]
+mod pending;
+mod pool;
+pub mod proto;
+pub mod relay;
+pub mod resume;
+pub mod sched;
+pub mod seccomp;
+pub mod stream;
+mod sync;
 // TODO: address this after merging linters
 #[allow(clippy::type_complexity, clippy::redundant_clone)]
 pub mod client;
 // TODO: address this after merging linters
 #[allow(clippy::type_complexity, clippy::too_many_arguments)]
 pub mod server;
+// generated by protobuf-codegen, which lags behind newer compiler lints
+#[allow(renamed_and_removed_lints, unused_parens, mismatched_lifetime_syntaxes)]
+pub mod diagnostics;
+#[allow(renamed_and_removed_lints, unused_parens, mismatched_lifetime_syntaxes)]
+pub mod reflection;
+#[allow(renamed_and_removed_lints, unused_parens, mismatched_lifetime_syntaxes)]
 pub mod ttrpc;
 
-pub use crate::channel::{
-    write_message, MessageHeader, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE,
+pub use crate::address::{Address, VsockCid};
+pub use crate::channel::write_message;
+pub use crate::client::{
+    Canceller, Client, ClientStats, DebugLevel, DebugPayload, Dialer, HedgePolicy, Interceptor,
+    Redactor, ResultHandle, SocketOptions, StreamLimit, MAX_STREAM_IDS,
 };
-pub use crate::client::Client;
-pub use crate::error::{get_status, Error, Result};
-pub use crate::server::{response_to_channel, MethodHandler, Server, TtrpcContext};
-pub use crate::ttrpc::{Code, Request, Response, Status};
+pub use crate::error::{errno_to_code, get_status, Error, Result};
+pub use crate::pair::{pair, PairedFd};
+pub use crate::proto::{
+    MessageHeader, MethodHash, PeerInfo, FLAG_BATCH_OK, FLAG_EXTENSIONS, FLAG_NO_DATA,
+    FLAG_NO_REPLY, FLAG_PROGRESS_OK, FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_TYPE_BATCH,
+    MESSAGE_TYPE_CANCEL, MESSAGE_TYPE_DATA, MESSAGE_TYPE_GOAWAY, MESSAGE_TYPE_HELLO,
+    MESSAGE_TYPE_IDENTITY, MESSAGE_TYPE_PROGRESS, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE,
+};
+pub use crate::sched::{SchedPolicy, WorkerScheduling};
+pub use crate::server::{
+    response_to_channel, with_middleware, wrap_service, Authorizer, CloseReason, ConnectionData,
+    ConnectionRef, ConnectionStats, DebugHandle, DecodeError, DecodeErrorKind, DecodeErrorPolicy,
+    Disconnect, ErrorDetail, InFlightRequest, ListenerConfig, MethodHandler, Middleware, Phase,
+    QuiesceStatus, ResponseSink, Server, ServerBuilder, ServerHandle, Service, ShutdownReport,
+    ThreadPanic, TraceTarget, TtrpcContext, EVENT_TARGET,
+};
+pub use crate::stream::{
+    copy_to_stream, ClientStreamSender, CopyOptions, DuplexStream, RequestStream,
+    ServerStreamReceiver, StreamSender, StreamSink,
+};
+pub use crate::ttrpc::{Code, KeyValue, Request, Response, Status};
@@ -0,0 +1,104 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reusing decoded messages, so serving a request does not allocate its
+//! message anew. See [`MessagePool`].
+//!
+//! rust-protobuf has no arena to parse into, but clearing a message keeps
+//! the memory of its strings, repeated fields and nested messages, which
+//! decoding the next request into it then reuses. Services generated with
+//! the `message_pool` option of the compiler decode their requests this
+//! way, and get them by reference.
+
+use protobuf::{CodedInputStream, Message, ProtobufResult};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Messages of type `M` kept for decoding the next ones into.
+///
+/// Each kept message holds on to the memory of the biggest message decoded
+/// into it, so `max` bounds what a pool keeps, not what it uses while
+/// requests are handled.
+pub struct MessagePool<M> {
+    free: Mutex<Vec<M>>,
+    max: usize,
+}
+
+impl<M: Message> MessagePool<M> {
+    /// A pool keeping at most `max` messages, e.g. the number of handler
+    /// threads.
+    pub fn new(max: usize) -> MessagePool<M> {
+        MessagePool {
+            free: Mutex::new(Vec::new()),
+            max,
+        }
+    }
+
+    /// Decode `buf` into a message of the pool, or a new one if none is
+    /// left. The message goes back to the pool once dropped.
+    pub fn decode(&self, buf: &[u8]) -> ProtobufResult<Pooled<'_, M>> {
+        let message = self.free.lock().unwrap().pop().unwrap_or_else(M::new);
+        let mut pooled = Pooled {
+            pool: self,
+            message: Some(message),
+        };
+        let mut s = CodedInputStream::from_bytes(buf);
+        pooled.merge_from(&mut s)?;
+        Ok(pooled)
+    }
+
+    /// How many messages the pool keeps right now.
+    pub fn len(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A message of a [`MessagePool`], cleared and given back to it once
+/// dropped.
+pub struct Pooled<'a, M: Message> {
+    pool: &'a MessagePool<M>,
+    message: Option<M>,
+}
+
+impl<M: Message> Deref for Pooled<'_, M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        self.message.as_ref().unwrap()
+    }
+}
+
+impl<M: Message> DerefMut for Pooled<'_, M> {
+    fn deref_mut(&mut self) -> &mut M {
+        self.message.as_mut().unwrap()
+    }
+}
+
+impl<M: Message> Drop for Pooled<'_, M> {
+    fn drop(&mut self) {
+        let mut message = match self.message.take() {
+            Some(m) => m,
+            None => return,
+        };
+        message.clear();
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < self.pool.max {
+            free.push(message);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Request/response metadata carried in the ttrpc envelope.
+
+use protobuf::RepeatedField;
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::client::Interceptor;
+use crate::ttrpc::{KeyValue, Request};
+
+/// Metadata key used to correlate a request with its response and logs.
+pub const REQUEST_ID_KEY: &str = "request-id";
+
+/// Metadata key marking retries of the same operation, see
+/// [`Dedup`](crate::dedup::Dedup).
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Metadata key carrying the caller's token, see
+/// [`Principal::Token`](crate::acl::Principal::Token).
+pub const AUTHORIZATION_KEY: &str = "authorization";
+
+/// Metadata key carrying the containerd namespace of a call, as the Go
+/// containerd client sends it over ttrpc.
+pub const NAMESPACE_KEY: &str = "containerd-namespace-ttrpc";
+
+/// Metadata key carrying the id of the sandbox a call is about.
+pub const SANDBOX_ID_KEY: &str = "sandbox-id";
+
+/// Metadata key carrying the W3C trace context of a call.
+pub const TRACEPARENT_KEY: &str = "traceparent";
+
+/// Metadata key set on the copies of requests sent by a
+/// [`Mirror`](crate::mirror::Mirror).
+pub const MIRRORED_KEY: &str = "ttrpc-mirrored";
+
+/// Metadata key carrying the checkpoint a call resumes from, see
+/// [`Resumable`](crate::resume::Resumable).
+pub const RESUME_TOKEN_KEY: &str = "ttrpc-resume-token";
+
+/// Metadata in the same shape as the Go ttrpc `MD` type.
+pub type Metadata = HashMap<String, Vec<String>>;
+
+/// Convert the wire representation into a [`Metadata`] map.
+pub fn from_pairs(kvs: &[KeyValue]) -> Metadata {
+    let mut md = Metadata::new();
+    for kv in kvs {
+        md.entry(kv.key.to_lowercase())
+            .or_default()
+            .push(kv.value.clone());
+    }
+    md
+}
+
+/// Convert a [`Metadata`] map into its wire representation.
+pub fn to_pairs(md: &Metadata) -> RepeatedField<KeyValue> {
+    let mut kvs = RepeatedField::new();
+    for (k, vs) in md.iter() {
+        for v in vs {
+            let mut kv = KeyValue::new();
+            kv.set_key(k.clone());
+            kv.set_value(v.clone());
+            kvs.push(kv);
+        }
+    }
+    kvs
+}
+
+/// Get the first value of `key`.
+pub fn get<'a>(md: &'a Metadata, key: &str) -> Option<&'a str> {
+    md.get(key).and_then(|v| v.first()).map(|v| v.as_str())
+}
+
+/// The containerd namespace of a call, see [`NAMESPACE_KEY`].
+pub fn namespace(md: &Metadata) -> Option<&str> {
+    get(md, NAMESPACE_KEY).filter(|ns| !ns.is_empty())
+}
+
+/// Set the containerd namespace of `req` to `namespace`, replacing any it
+/// carried, as `namespaces.WithNamespace` does for Go clients.
+///
+/// ```
+/// use ttrpc::metadata::{self, with_namespace};
+///
+/// let req = with_namespace(ttrpc::Request::new(), "k8s.io");
+/// let md = metadata::from_pairs(req.get_metadata());
+/// assert_eq!(metadata::namespace(&md), Some("k8s.io"));
+/// ```
+pub fn with_namespace(mut req: Request, namespace: &str) -> Request {
+    req.mut_metadata()
+        .retain(|kv| !kv.key.eq_ignore_ascii_case(NAMESPACE_KEY));
+    let mut kv = KeyValue::new();
+    kv.set_key(NAMESPACE_KEY.to_string());
+    kv.set_value(namespace.to_string());
+    req.mut_metadata().push(kv);
+    req
+}
+
+static REQUEST_ID_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Generate a new request id in UUIDv7 layout: 48 bits of unix milliseconds
+/// followed by per-process sequence and entropy bits.
+pub fn new_request_id() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let ms = now.as_millis() as u64 & 0xffff_ffff_ffff;
+    let seq = REQUEST_ID_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    // splitmix64 over pid, sub-millisecond time and sequence.
+    let mut x = (u64::from(std::process::id()) << 32)
+        ^ u64::from(now.subsec_nanos())
+        ^ seq.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+
+    let rand_a = seq & 0x0fff;
+    let rand_b = x & 0x3fff_ffff_ffff_ffff;
+
+    format!(
+        "{:08x}-{:04x}-7{:03x}-{:04x}-{:012x}",
+        ms >> 16,
+        ms & 0xffff,
+        rand_a,
+        0x8000 | (rand_b >> 48),
+        rand_b & 0xffff_ffff_ffff
+    )
+}
+
+type TraceFn = Arc<dyn Fn() -> Option<String> + Send + Sync>;
+
+/// Builds an [`Interceptor`] adding the same metadata to every call, e.g.
+/// the containerd namespace, much like the Go containerd client does.
+///
+/// Keys the request already carries are left alone, so a single call can
+/// still override them.
+///
+/// ```no_run
+/// use ttrpc::metadata::MetadataInjector;
+/// use ttrpc::Client;
+///
+/// let client = Client::connect("unix:///run/containerd/containerd.sock.ttrpc")
+///     .unwrap()
+///     .with_interceptor(MetadataInjector::new().read_env().build());
+/// ```
+#[derive(Clone, Default)]
+pub struct MetadataInjector {
+    pairs: Vec<(String, String)>,
+    trace: Option<TraceFn>,
+}
+
+impl MetadataInjector {
+    pub fn new() -> MetadataInjector {
+        MetadataInjector::default()
+    }
+
+    /// Send `value` as `key`.
+    pub fn set(mut self, key: &str, value: &str) -> MetadataInjector {
+        let key = key.to_lowercase();
+        self.pairs.retain(|(k, _)| *k != key);
+        self.pairs.push((key, value.to_string()));
+        self
+    }
+
+    /// Send `namespace` as [`NAMESPACE_KEY`].
+    pub fn namespace(self, namespace: &str) -> MetadataInjector {
+        self.set(NAMESPACE_KEY, namespace)
+    }
+
+    /// Send `id` as [`SANDBOX_ID_KEY`].
+    pub fn sandbox_id(self, id: &str) -> MetadataInjector {
+        self.set(SANDBOX_ID_KEY, id)
+    }
+
+    /// Take the namespace from `CONTAINERD_NAMESPACE`, the sandbox id from
+    /// `SANDBOX_ID` and the trace context from `TRACEPARENT`, as far as
+    /// they are set.
+    pub fn read_env(mut self) -> MetadataInjector {
+        for (var, key) in [
+            ("CONTAINERD_NAMESPACE", NAMESPACE_KEY),
+            ("SANDBOX_ID", SANDBOX_ID_KEY),
+            ("TRACEPARENT", TRACEPARENT_KEY),
+        ]
+        .iter()
+        {
+            if let Ok(value) = env::var(var) {
+                if !value.is_empty() {
+                    self = self.set(key, &value);
+                }
+            }
+        }
+        self
+    }
+
+    /// Send the trace context returned by `f` as [`TRACEPARENT_KEY`], in
+    /// place of a fixed one. `f` runs for every call, so it can render the
+    /// current span of the calling thread, and returns `None` outside of
+    /// one.
+    pub fn trace_context<F>(mut self, f: F) -> MetadataInjector
+    where
+        F: Fn() -> Option<String> + Send + Sync + 'static,
+    {
+        self.trace = Some(Arc::new(f));
+        self
+    }
+
+    pub fn build(self) -> Interceptor {
+        Arc::new(move |req: &mut Request| {
+            let traceparent = self.trace.as_ref().and_then(|f| f());
+            let dynamic = traceparent.as_ref().map(|v| (TRACEPARENT_KEY, v.as_str()));
+            let fixed = self
+                .pairs
+                .iter()
+                .filter(|(k, _)| dynamic.is_none() || k != TRACEPARENT_KEY)
+                .map(|(k, v)| (k.as_str(), v.as_str()));
+            for (key, value) in dynamic.into_iter().chain(fixed) {
+                if req
+                    .get_metadata()
+                    .iter()
+                    .any(|kv| kv.key.eq_ignore_ascii_case(key))
+                {
+                    continue;
+                }
+                let mut kv = KeyValue::new();
+                kv.set_key(key.to_string());
+                kv.set_value(value.to_string());
+                req.mut_metadata().push(kv);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(id: &str) -> u128 {
+        u128::from_str_radix(&id.replace('-', ""), 16).unwrap()
+    }
+
+    #[test]
+    fn test_request_id_layout() {
+        let id = new_request_id();
+        let groups: Vec<usize> = id.split('-').map(|g| g.len()).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+
+        let bits = hex(&id);
+        // version 7, variant 0b10
+        assert_eq!((bits >> 76) & 0xf, 7);
+        assert_eq!((bits >> 62) & 0x3, 0b10);
+    }
+
+    #[test]
+    fn test_request_id_timestamp() {
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let ids: Vec<String> = (0..1000).map(|_| new_request_id()).collect();
+        let after = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        let ms: Vec<u128> = ids.iter().map(|id| hex(id) >> 80).collect();
+        assert!(ms.windows(2).all(|w| w[0] <= w[1]));
+        assert!(ms[0] >= before && ms[ms.len() - 1] <= after);
+
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+    }
+
+    #[test]
+    fn test_pairs_round_trip() {
+        let mut md = Metadata::new();
+        md.insert("key".to_string(), vec!["a".to_string(), "b".to_string()]);
+        md.insert(REQUEST_ID_KEY.to_string(), vec![new_request_id()]);
+
+        let kvs = to_pairs(&md);
+        assert_eq!(kvs.len(), 3);
+        assert_eq!(from_pairs(&kvs), md);
+    }
+
+    #[test]
+    fn test_from_pairs_lowercases_keys() {
+        let mut kv = KeyValue::new();
+        kv.set_key("Request-ID".to_string());
+        kv.set_value("abc".to_string());
+        let md = from_pairs(&[kv]);
+        assert_eq!(get(&md, REQUEST_ID_KEY), Some("abc"));
+    }
+}
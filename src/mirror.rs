@@ -0,0 +1,215 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mirroring requests to a second server, so a new implementation can be
+//! tried on production traffic before it takes over. See [`Mirror`].
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::client::Client;
+use crate::error::Result;
+use crate::metadata::MIRRORED_KEY;
+use crate::server::{MethodHandler, Middleware, TtrpcContext};
+use crate::ttrpc::{KeyValue, Request};
+
+#[derive(Default)]
+struct Counters {
+    mirrored: AtomicUsize,
+    dropped: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+/// A middleware sending a copy of a share of the requests to a second
+/// server, on a thread of its own, and ignoring its responses. The
+/// original requests are handled as usual whatever happens to the copies.
+///
+/// Copies carry the [`MIRRORED_KEY`] metadata. They are dropped rather
+/// than queued once [`Mirror::queue_size`] of them wait to be sent, so a
+/// slow second server never holds up the first one.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # fn f(service: Arc<dyn ttrpc::Service + Send + Sync>) -> ttrpc::Result<()> {
+/// let shadow = ttrpc::Client::connect("unix:///run/agent-next.sock")?;
+/// let mirror = Arc::new(ttrpc::mirror::Mirror::new(shadow).percent(10));
+/// let builder = ttrpc::Server::builder()
+///     .register(ttrpc::with_middleware(service, mirror.middleware()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Mirror {
+    client: Client,
+    percent: u64,
+    methods: Option<HashSet<String>>,
+    queue_size: usize,
+    timeout: Duration,
+    seen: AtomicU64,
+    // the copies, and whether they want a reply
+    queue: Mutex<Option<SyncSender<(Request, bool)>>>,
+    counters: Arc<Counters>,
+}
+
+impl Mirror {
+    /// Mirror every request to the server `client` is connected to,
+    /// waiting at most 5 seconds for each response.
+    pub fn new(client: Client) -> Mirror {
+        Mirror {
+            client,
+            percent: 100,
+            methods: None,
+            queue_size: 64,
+            timeout: Duration::from_secs(5),
+            seen: AtomicU64::new(0),
+            queue: Mutex::new(None),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Mirror `percent` of the requests, spread evenly over them.
+    pub fn percent(mut self, percent: u32) -> Mirror {
+        self.percent = u64::from(percent.min(100));
+        self
+    }
+
+    /// Only mirror requests to the method paths `methods`, e.g.
+    /// `/grpc.Health/Check`.
+    pub fn methods(mut self, methods: &[&str]) -> Mirror {
+        self.methods = Some(methods.iter().map(|m| m.to_string()).collect());
+        self
+    }
+
+    /// Drop copies once `size` of them wait to be sent, 64 by default.
+    pub fn queue_size(mut self, size: usize) -> Mirror {
+        self.queue_size = size;
+        self
+    }
+
+    /// Wait at most `timeout` for the response to a copy, or the deadline
+    /// of the original request if it is shorter.
+    pub fn timeout(mut self, timeout: Duration) -> Mirror {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many copies were sent.
+    pub fn mirrored(&self) -> usize {
+        self.counters.mirrored.load(Ordering::SeqCst)
+    }
+
+    /// How many copies were dropped because too many waited to be sent.
+    pub fn dropped(&self) -> usize {
+        self.counters.dropped.load(Ordering::SeqCst)
+    }
+
+    /// How many copies failed, including those the second server answered
+    /// with an error.
+    pub fn failed(&self) -> usize {
+        self.counters.failed.load(Ordering::SeqCst)
+    }
+
+    /// The middleware to wrap the mirrored methods with, see
+    /// [`with_middleware`](crate::with_middleware).
+    pub fn middleware(self: &Arc<Self>) -> Middleware {
+        let mirror = self.clone();
+        Arc::new(move |path, ctx, req, inner| mirror.handle(path, ctx, req, inner))
+    }
+
+    fn handle(
+        &self,
+        path: &str,
+        ctx: TtrpcContext,
+        req: Request,
+        inner: &dyn MethodHandler,
+    ) -> Result<()> {
+        if self.sampled(path) {
+            let mut copy = req.clone();
+            let mut kv = KeyValue::new();
+            kv.set_key(MIRRORED_KEY.to_string());
+            kv.set_value("true".to_string());
+            copy.mut_metadata().push(kv);
+            self.send(copy, ctx.sink().wants_reply());
+        }
+        inner.handler(ctx, req)
+    }
+
+    /// Whether to mirror the request to `path`: `percent` out of every
+    /// hundred requests to the mirrored methods are.
+    fn sampled(&self, path: &str) -> bool {
+        if self.methods.as_ref().is_some_and(|m| !m.contains(path)) {
+            return false;
+        }
+        let n = self.seen.fetch_add(1, Ordering::Relaxed);
+        (n + 1) * self.percent / 100 != n * self.percent / 100
+    }
+
+    fn send(&self, req: Request, wants_reply: bool) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_none() {
+            let (tx, rx) = sync_channel(self.queue_size);
+            let (client, counters, timeout) =
+                (self.client.clone(), self.counters.clone(), self.timeout);
+            let started = thread::Builder::new()
+                .name("mirror".to_string())
+                .spawn(move || forward(client, rx, counters, timeout));
+            if let Err(e) = started {
+                warn!("failed to start mirror thread: {}", e);
+                self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+                return;
+            }
+            *queue = Some(tx);
+        }
+        let tx = queue.as_ref().unwrap();
+        match tx.try_send((req, wants_reply)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+                *queue = None;
+            }
+        }
+    }
+}
+
+/// Send the copies received on `rx` until the [`Mirror`] is dropped.
+fn forward(
+    client: Client,
+    rx: Receiver<(Request, bool)>,
+    counters: Arc<Counters>,
+    timeout: Duration,
+) {
+    let timeout_nano = timeout.as_nanos().min(i64::MAX as u128) as i64;
+    for (mut req, wants_reply) in rx.iter() {
+        counters.mirrored.fetch_add(1, Ordering::SeqCst);
+        // a notification is mirrored as one
+        let result = if !wants_reply {
+            client.notify(req)
+        } else {
+            if req.timeout_nano == 0 || req.timeout_nano > timeout_nano {
+                req.timeout_nano = timeout_nano;
+            }
+            client.request(req).map(|_| ())
+        };
+        if let Err(e) = result {
+            debug!("mirrored request failed: {:?}", e);
+            counters.failed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parent/child RPC over a socketpair.
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::{close, dup2};
+use std::io;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+
+use crate::client::Client;
+use crate::error::{Error, Result};
+use crate::seccomp;
+
+/// The end of a [`pair`] meant to be served by a child process.
+///
+/// The fd is close-on-exec, so it is not leaked into unrelated children.
+/// Use [`inherit_as`](PairedFd::inherit_as) from a `pre_exec` hook to pass
+/// it through `exec`, or hand it to [`ServerBuilder::add_connection`] after a plain
+/// `fork`. It is closed on drop, which is what the parent wants once the
+/// child has been spawned.
+///
+/// [`ServerBuilder::add_connection`]: crate::ServerBuilder::add_connection
+#[derive(Debug)]
+pub struct PairedFd {
+    fd: RawFd,
+}
+
+impl PairedFd {
+    /// Make the fd available as `target` in a process about to `exec`.
+    ///
+    /// Only calls async-signal-safe functions, so it may be used from
+    /// `std::os::unix::process::CommandExt::pre_exec`.
+    pub fn inherit_as(&self, target: RawFd) -> io::Result<()> {
+        if self.fd == target {
+            fcntl(self.fd, FcntlArg::F_SETFD(FdFlag::empty()))
+        } else {
+            // the duplicate does not inherit FD_CLOEXEC
+            dup2(self.fd, target)
+        }
+        .map(|_| ())
+        .map_err(|e| {
+            e.as_errno()
+                .map(|errno| io::Error::from_raw_os_error(errno as i32))
+                .unwrap_or_else(|| io::Error::other("invalid fd"))
+        })
+    }
+}
+
+impl AsRawFd for PairedFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl IntoRawFd for PairedFd {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for PairedFd {
+    fn drop(&mut self) {
+        close(self.fd).unwrap_or(());
+    }
+}
+
+/// Create a connected socketpair and a [`Client`] talking over one end.
+///
+/// The other end is returned for a child process to serve, the usual
+/// setup for a supervisor talking to a forked or exec'd helper.
+pub fn pair() -> Result<(PairedFd, Client)> {
+    let (client_fd, server_fd) =
+        seccomp::socketpair_cloexec().map_err(|e| Error::Socket(e.to_string()))?;
+
+    Ok((PairedFd { fd: server_fd }, Client::new(client_fd)))
+}
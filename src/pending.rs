@@ -0,0 +1,324 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The replies a connection owes, independent of the socket they are
+//! written to.
+
+use std::collections::{HashMap, VecDeque};
+// the flags handed to handlers are std's, even when checked with loom
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::channel::MessageHeader;
+use crate::error::{get_status, Error, Result};
+use crate::server::response_to_channel;
+use crate::sync::{Condvar, Mutex, Ordering};
+use crate::ttrpc::{Code, Response, Status};
+
+struct PendingReply {
+    deadline: Option<Instant>,
+    // when the handler runs out of time, deferred or not
+    limit: Option<Instant>,
+    deferred: bool,
+    cancelled: Arc<AtomicBool>,
+    path: String,
+    started: Instant,
+    bytes: usize,
+}
+
+/// A reply still owed, as listed by [`PendingReplies::snapshot`].
+pub(crate) struct Owed {
+    pub(crate) stream_id: u32,
+    pub(crate) path: String,
+    pub(crate) age: Duration,
+    pub(crate) bytes: usize,
+    pub(crate) deferred: bool,
+}
+
+// Cancellations of streams not begun yet, e.g. still being read by
+// another thread. Late cancellations of answered streams end up here
+// too, so only the most recent are kept.
+const EARLY_CANCELS_MAX: usize = 64;
+
+/// The replies a connection still owes, keyed by stream id.
+///
+/// A request is pending from the moment it is dispatched until its
+/// `ResponseSink` replies or the last clone of the sink is dropped. Once the
+/// handler returned the reply is deferred: its deadline, taken from the
+/// request's `timeout_nano`, is enforced by the connection, and teardown
+/// waits up to the server's reply grace period for it. The time limit of
+/// its handler, if any, is enforced whether the handler returned or not.
+pub(crate) struct PendingReplies {
+    tx: Mutex<Option<Sender<(MessageHeader, Vec<u8>)>>>,
+    streams: Mutex<HashMap<u32, PendingReply>>,
+    early_cancels: Mutex<VecDeque<u32>>,
+    drained: Condvar,
+    // streamed messages queued through `tx` and not written yet
+    data_backlog: Arc<AtomicUsize>,
+}
+
+impl PendingReplies {
+    pub(crate) fn new(tx: Sender<(MessageHeader, Vec<u8>)>) -> PendingReplies {
+        PendingReplies {
+            tx: Mutex::new(Some(tx)),
+            streams: Mutex::new(HashMap::new()),
+            early_cancels: Mutex::new(VecDeque::new()),
+            drained: Condvar::new(),
+            data_backlog: Arc::default(),
+        }
+    }
+
+    /// The count of streamed messages queued and not written yet, which
+    /// whoever writes them decrements.
+    pub(crate) fn data_backlog(&self) -> Arc<AtomicUsize> {
+        self.data_backlog.clone()
+    }
+
+    pub(crate) fn sender(&self) -> Result<Sender<(MessageHeader, Vec<u8>)>> {
+        self.tx
+            .lock()
+            .unwrap()
+            .as_ref()
+            .cloned()
+            .ok_or(Error::ConnectionClosed)
+    }
+
+    /// Start owing a reply to `stream_id`, a request for `path` with a
+    /// payload of `bytes`. Returns the flag telling whether the client
+    /// cancelled the request, which is already set if the cancellation
+    /// came first; nothing is owed then.
+    pub(crate) fn begin(
+        &self,
+        stream_id: u32,
+        timeout_nano: i64,
+        path: &str,
+        bytes: usize,
+    ) -> Arc<AtomicBool> {
+        let mut early = self.early_cancels.lock().unwrap();
+        if let Some(i) = early.iter().position(|id| *id == stream_id) {
+            early.remove(i);
+            return Arc::new(AtomicBool::new(true));
+        }
+
+        let deadline = if timeout_nano > 0 {
+            Some(Instant::now() + Duration::from_nanos(timeout_nano as u64))
+        } else {
+            None
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let reply = PendingReply {
+            deadline,
+            limit: None,
+            deferred: false,
+            cancelled: cancelled.clone(),
+            path: path.to_string(),
+            started: Instant::now(),
+            bytes,
+        };
+        self.streams.lock().unwrap().insert(stream_id, reply);
+        cancelled
+    }
+
+    /// The client cancelled `stream_id`: stop owing it a reply.
+    pub(crate) fn cancel(&self, stream_id: u32) {
+        let mut early = self.early_cancels.lock().unwrap();
+        let mut streams = self.streams.lock().unwrap();
+        match streams.remove(&stream_id) {
+            Some(reply) => {
+                reply.cancelled.store(true, Ordering::SeqCst);
+                if streams.is_empty() {
+                    self.drained.notify_all();
+                }
+            }
+            None => {
+                if early.len() == EARLY_CANCELS_MAX {
+                    early.pop_front();
+                }
+                early.push_back(stream_id);
+            }
+        }
+    }
+
+    /// Stop owing a reply to `stream_id` and answer it with `status`
+    /// instead, flagging the request as cancelled so its handler can stop.
+    /// Returns false if no reply was owed.
+    pub(crate) fn abort(&self, stream_id: u32, status: Status) -> bool {
+        let reply = {
+            let mut streams = self.streams.lock().unwrap();
+            let reply = streams.remove(&stream_id);
+            if streams.is_empty() {
+                self.drained.notify_all();
+            }
+            reply
+        };
+        let reply = match reply {
+            Some(reply) => reply,
+            None => return false,
+        };
+        reply.cancelled.store(true, Ordering::SeqCst);
+
+        let mut res = Response::new();
+        res.set_status(status);
+        if let Ok(tx) = self.sender() {
+            response_to_channel(stream_id, res, tx).unwrap_or(());
+        }
+        true
+    }
+
+    /// The replies still owed.
+    pub(crate) fn snapshot(&self) -> Vec<Owed> {
+        let now = Instant::now();
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, reply)| Owed {
+                stream_id: *id,
+                path: reply.path.clone(),
+                age: now.saturating_duration_since(reply.started),
+                bytes: reply.bytes,
+                deferred: reply.deferred,
+            })
+            .collect()
+    }
+
+    /// Give the handler of `stream_id`, starting now, `timeout` to reply.
+    pub(crate) fn limit(&self, stream_id: u32, timeout: Duration) {
+        if let Some(reply) = self.streams.lock().unwrap().get_mut(&stream_id) {
+            reply.limit = Some(Instant::now() + timeout);
+        }
+    }
+
+    /// Mark the reply to `stream_id` as deferred once its handler returned.
+    /// Returns true if the reply is still owed and has a deadline to watch.
+    pub(crate) fn defer(&self, stream_id: u32) -> bool {
+        match self.streams.lock().unwrap().get_mut(&stream_id) {
+            Some(reply) => {
+                reply.deferred = true;
+                reply.deadline.is_some()
+            }
+            None => false,
+        }
+    }
+
+    /// Whether no reply is owed.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.streams.lock().unwrap().is_empty()
+    }
+
+    /// How many replies are owed.
+    pub(crate) fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub(crate) fn is_pending(&self, stream_id: u32) -> bool {
+        self.streams.lock().unwrap().contains_key(&stream_id)
+    }
+
+    pub(crate) fn finish(&self, stream_id: u32) -> bool {
+        let mut streams = self.streams.lock().unwrap();
+        let found = streams.remove(&stream_id).is_some();
+        if streams.is_empty() {
+            self.drained.notify_all();
+        }
+        found
+    }
+
+    /// Answer the deferred replies whose deadline passed, and those whose
+    /// handler ran out of time, with `DEADLINE_EXCEEDED`, and return the
+    /// time left until the next one. `overran` is called with the method
+    /// of each handler which ran out of time.
+    pub(crate) fn expire(&self, mut overran: impl FnMut(&str)) -> Option<Duration> {
+        let now = Instant::now();
+        // the streams, with the method of those whose handler overran
+        let mut expired = Vec::new();
+        let mut next: Option<Instant> = None;
+        {
+            let mut streams = self.streams.lock().unwrap();
+            streams.retain(|id, reply| {
+                let deadline = reply.deadline.filter(|_| reply.deferred);
+                if deadline.is_some_and(|d| d <= now) {
+                    expired.push((*id, None));
+                    return false;
+                }
+                if reply.limit.is_some_and(|l| l <= now) {
+                    reply.cancelled.store(true, Ordering::SeqCst);
+                    expired.push((*id, Some(std::mem::take(&mut reply.path))));
+                    return false;
+                }
+                for d in deadline.into_iter().chain(reply.limit) {
+                    next = Some(next.map_or(d, |n| n.min(d)));
+                }
+                true
+            });
+            if streams.is_empty() {
+                self.drained.notify_all();
+            }
+        }
+
+        for (stream_id, path) in expired {
+            let status = match path {
+                Some(path) => {
+                    overran(&path);
+                    get_status(
+                        Code::DEADLINE_EXCEEDED,
+                        format!("{} handler ran out of time", path),
+                    )
+                }
+                None => {
+                    debug!(
+                        "deferred reply to stream {} exceeded its deadline",
+                        stream_id
+                    );
+                    get_status(
+                        Code::DEADLINE_EXCEEDED,
+                        "deferred reply exceeded the request deadline".to_string(),
+                    )
+                }
+            };
+            let mut res = Response::new();
+            res.set_status(status);
+            if let Ok(tx) = self.sender() {
+                response_to_channel(stream_id, res, tx).unwrap_or(());
+            }
+        }
+
+        next.map(|n| n.saturating_duration_since(now))
+    }
+
+    /// Wait up to `grace` for the pending replies to be sent, then stop
+    /// accepting replies. Returns how many were abandoned.
+    pub(crate) fn drain(&self, grace: Duration) -> usize {
+        let deadline = Instant::now() + grace;
+        let mut streams = self.streams.lock().unwrap();
+        while !streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            streams = self
+                .drained
+                .wait_timeout(streams, deadline - now)
+                .unwrap()
+                .0;
+        }
+        let abandoned = streams.len();
+        drop(streams);
+
+        self.tx.lock().unwrap().take();
+        abandoned
+    }
+}
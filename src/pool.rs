@@ -0,0 +1,122 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accounting of the method handler threads of a connection, independent
+//! of the socket they read from.
+
+use std::sync::mpsc::SyncSender;
+
+use crate::sync::{Arc, AtomicUsize, Ordering};
+
+/// The method handler threads of a connection. Threads count as waiting
+/// while they read the next request; the connection keeps between `min`
+/// and `max` of them waiting by starting more when woken up.
+pub(crate) struct WorkerPool {
+    waiting: AtomicUsize,
+    threads: AtomicUsize,
+    pub(crate) default: usize,
+    pub(crate) min: usize,
+    pub(crate) max: usize,
+    // wakes up the connection, e.g. to grow the pool or to quit
+    control_tx: SyncSender<()>,
+}
+
+impl WorkerPool {
+    pub(crate) fn new(
+        default: usize,
+        min: usize,
+        max: usize,
+        control_tx: SyncSender<()>,
+    ) -> WorkerPool {
+        WorkerPool {
+            waiting: AtomicUsize::new(0),
+            threads: AtomicUsize::new(0),
+            default,
+            min,
+            max,
+            control_tx,
+        }
+    }
+
+    /// Count the current thread as waiting for a request. Returns false,
+    /// without counting it, if enough threads are waiting already and the
+    /// current one should exit.
+    pub(crate) fn enter(&self) -> bool {
+        let c = self.waiting.fetch_add(1, Ordering::SeqCst) + 1;
+        if c > self.max {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    /// Stop counting the current thread as waiting, asking for more
+    /// threads if too few are left.
+    pub(crate) fn leave(&self) {
+        if self.leave_quietly() < self.min {
+            self.wake();
+        }
+    }
+
+    /// Stop counting the current thread as waiting, as it is about to wait
+    /// again. Returns how many threads are left waiting.
+    pub(crate) fn leave_quietly(&self) -> usize {
+        self.waiting.fetch_sub(1, Ordering::SeqCst) - 1
+    }
+
+    /// How many threads are waiting.
+    pub(crate) fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// How many threads to start to get back to the default count, if too
+    /// few are waiting.
+    pub(crate) fn wanted(&self) -> usize {
+        let c = self.waiting();
+        if c < self.min {
+            self.default - c
+        } else {
+            0
+        }
+    }
+
+    /// Count a thread about to be started until the returned guard, moved
+    /// into the thread, is dropped.
+    pub(crate) fn spawned(self: &Arc<Self>) -> Worker {
+        self.threads.fetch_add(1, Ordering::SeqCst);
+        Worker(self.clone())
+    }
+
+    /// How many threads are running, waiting or not.
+    pub(crate) fn threads(&self) -> usize {
+        self.threads.load(Ordering::SeqCst)
+    }
+
+    /// Wake up the connection. A wake-up already queued will do, so a full
+    /// channel is fine.
+    pub(crate) fn wake(&self) {
+        self.control_tx.try_send(()).unwrap_or(());
+    }
+}
+
+/// A running method handler thread, see [`WorkerPool::spawned`]. The
+/// connection is woken up when it exits.
+pub(crate) struct Worker(Arc<WorkerPool>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.0.threads.fetch_sub(1, Ordering::SeqCst);
+        self.0.wake();
+    }
+}
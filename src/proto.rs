@@ -0,0 +1,1081 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The ttrpc protocol without any IO: frame and envelope encoding over
+//! byte slices, and connection state machines which are fed the bytes
+//! read from a connection and hand back the bytes to write. This lets
+//! runtimes with their own IO, such as io_uring or embedded executors,
+//! speak ttrpc. [`Client`](crate::Client) and [`Server`](crate::Server)
+//! use the same encoding over blocking sockets.
+
+use byteorder::{BigEndian, ByteOrder};
+use protobuf::{CodedInputStream, CodedOutputStream, Message};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+use crate::error::{get_rpc_status, Error, Result};
+use crate::extension::{self, Extensions};
+use crate::ttrpc::{Code, Request, Response};
+
+/// The length of a frame header.
+pub const MESSAGE_HEADER_LENGTH: usize = 10;
+/// The longest frame payload accepted.
+pub const MESSAGE_LENGTH_MAX: usize = 4 << 20;
+
+pub const MESSAGE_TYPE_REQUEST: u8 = 0x1;
+pub const MESSAGE_TYPE_RESPONSE: u8 = 0x2;
+
+/// Set on a request the client does not want a response to.
+pub const FLAG_NO_REPLY: u8 = 0x8;
+
+/// A frame carrying several small frames, each with its own header.
+pub const MESSAGE_TYPE_BATCH: u8 = 0x10;
+/// Set on responses by a server able to unpack batch frames. Clients only
+/// send batch frames after seeing it.
+pub const FLAG_BATCH_OK: u8 = 0x10;
+
+/// Sent by a server on stream 0 before closing a connection it is shutting
+/// down. The payload is the grace period in milliseconds as a big endian
+/// u32, followed by the reason in UTF-8.
+pub const MESSAGE_TYPE_GOAWAY: u8 = 0x20;
+
+/// Sent by a client on stream 0 as the first frame of a connection. The
+/// payload is an application-defined identity, e.g. a sandbox id or token.
+pub const MESSAGE_TYPE_IDENTITY: u8 = 0x40;
+
+/// Sent by a client on the stream of a request it gave up on. The server
+/// flags the request as cancelled and sends no response to it. There is
+/// no payload.
+pub const MESSAGE_TYPE_CANCEL: u8 = 0x80;
+
+/// Sent by a server on the stream of a request still being handled, any
+/// number of times before its response, e.g. to report how far a long
+/// operation got. The payload is defined by the method.
+pub const MESSAGE_TYPE_PROGRESS: u8 = 0x4;
+/// Set on a request by a client handling progress frames. Servers send
+/// none otherwise.
+pub const FLAG_PROGRESS_OK: u8 = 0x20;
+
+/// Set on a frame whose payload starts with an extension block, see
+/// [`extension`](crate::extension). Only sent to peers with the
+/// `extensions` capability.
+pub const FLAG_EXTENSIONS: u8 = 0x40;
+
+/// Sent by a client on stream 0 once connected, after its identity frame
+/// if any, and by the server in answer. The payload is UTF-8 `key=value`
+/// lines: `version`, `capabilities` as a comma separated list, and
+/// optionally `methods`, see [`PeerInfo`]. Unknown keys are ignored.
+pub const MESSAGE_TYPE_HELLO: u8 = 0x8;
+
+/// Carries one message of a streaming call: from the client after a
+/// request flagged with [`FLAG_REMOTE_OPEN`], or from the server before
+/// the response ending the call. The payload is the message, as defined
+/// by the method.
+pub const MESSAGE_TYPE_DATA: u8 = 0x3;
+/// Set on the data frame ending the messages of its sender, and on the
+/// request of a streaming call the client streams nothing to.
+pub const FLAG_REMOTE_CLOSED: u8 = 0x1;
+/// Set on the request of a call the client streams messages to, in data
+/// frames on the same stream.
+pub const FLAG_REMOTE_OPEN: u8 = 0x2;
+/// Set on a data frame carrying no message, such as one only closing
+/// the stream.
+pub const FLAG_NO_DATA: u8 = 0x4;
+
+/// The protocol extensions this library supports.
+const CAPABILITIES: &[&str] = &[
+    "batch",
+    "cancel",
+    "extensions",
+    "goaway",
+    "identity",
+    "method_hash",
+    "progress",
+];
+
+/// The definition of a method as a peer knows it, see [`PeerInfo::methods`].
+///
+/// On the wire, `methods` is a comma separated list of `path:hash` with the
+/// hash in hex, `?` for [`MethodHash::Unknown`] and `-` for
+/// [`MethodHash::Missing`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MethodHash {
+    /// The hash ttrpc-compiler generates for the method, which changes
+    /// with its name, streaming and the fields of its messages.
+    Hash(u64),
+    /// Served, but registered without a hash.
+    Unknown,
+    /// Not served.
+    Missing,
+}
+
+impl MethodHash {
+    fn parse(s: &str) -> Option<MethodHash> {
+        match s {
+            "?" => Some(MethodHash::Unknown),
+            "-" => Some(MethodHash::Missing),
+            _ => u64::from_str_radix(s, 16).ok().map(MethodHash::Hash),
+        }
+    }
+}
+
+impl fmt::Display for MethodHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MethodHash::Hash(h) => write!(f, "{:016x}", h),
+            MethodHash::Unknown => f.write_str("?"),
+            MethodHash::Missing => f.write_str("-"),
+        }
+    }
+}
+
+/// What the peer of a connection told about itself in its HELLO frame,
+/// see [`MESSAGE_TYPE_HELLO`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The library and its version, e.g. `ttrpc-rust/0.3.0`.
+    pub version: String,
+    /// The protocol extensions the peer supports, e.g. `batch`.
+    pub capabilities: Vec<String>,
+    /// From a client, the methods it is going to call with their hashes.
+    /// From a server, its own hashes of the methods the client named.
+    pub methods: Vec<(String, MethodHash)>,
+    /// How many requests the peer handles at once on the connection, if
+    /// it limits them.
+    pub max_streams: Option<usize>,
+}
+
+impl PeerInfo {
+    /// This library.
+    pub fn local() -> PeerInfo {
+        PeerInfo {
+            version: format!("ttrpc-rust/{}", env!("CARGO_PKG_VERSION")),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            methods: Vec::new(),
+            max_streams: None,
+        }
+    }
+
+    /// Whether the peer supports `capability`.
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct MessageHeader {
+    pub length: u32,
+    pub stream_id: u32,
+    pub type_: u8,
+    pub flags: u8,
+}
+
+/// Write `mh` into the first [`MESSAGE_HEADER_LENGTH`] bytes of `buf`.
+pub fn encode_message_header(mh: &MessageHeader, buf: &mut [u8]) {
+    BigEndian::write_u32(&mut buf[..4], mh.length);
+    BigEndian::write_u32(&mut buf[4..8], mh.stream_id);
+    buf[8] = mh.type_;
+    buf[9] = mh.flags;
+}
+
+/// Read a header out of the first [`MESSAGE_HEADER_LENGTH`] bytes of `buf`.
+pub fn decode_message_header(buf: &[u8]) -> Result<MessageHeader> {
+    if buf.len() < MESSAGE_HEADER_LENGTH {
+        return Err(get_rpc_status(
+            Code::INVALID_ARGUMENT,
+            format!("Message header length {} is too small", buf.len()),
+        ));
+    }
+    Ok(MessageHeader {
+        length: BigEndian::read_u32(&buf[..4]),
+        stream_id: BigEndian::read_u32(&buf[4..8]),
+        type_: buf[8],
+        flags: buf[9],
+    })
+}
+
+/// A frame as it goes on the wire: `mh`, with its length set to that of
+/// `payload`, followed by `payload`.
+pub fn encode_frame(mut mh: MessageHeader, payload: &[u8]) -> Vec<u8> {
+    mh.length = payload.len() as u32;
+    let mut buf = vec![0u8; MESSAGE_HEADER_LENGTH + payload.len()];
+    encode_message_header(&mh, &mut buf);
+    buf[MESSAGE_HEADER_LENGTH..].copy_from_slice(payload);
+    buf
+}
+
+pub(crate) fn encode_message<M: Message>(m: &M) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(m.compute_size() as usize);
+    let mut s = CodedOutputStream::vec(&mut buf);
+    m.write_to(&mut s).map_err(err_to_Others!(e, ""))?;
+    s.flush().map_err(err_to_Others!(e, ""))?;
+    drop(s);
+    Ok(buf)
+}
+
+/// The payload of a request frame carrying `req`.
+pub fn encode_request(req: &Request) -> Result<Vec<u8>> {
+    encode_message(req)
+}
+
+/// The request carried by the payload of a request frame.
+pub fn decode_request(buf: &[u8]) -> Result<Request> {
+    let mut s = CodedInputStream::from_bytes(buf);
+    let mut req = Request::new();
+    req.merge_from(&mut s)
+        .map_err(err_to_RpcStatus!(Code::INVALID_ARGUMENT, e, ""))?;
+    Ok(req)
+}
+
+/// The payload of a response frame carrying `res`.
+pub fn encode_response(res: &Response) -> Result<Vec<u8>> {
+    encode_message(res)
+}
+
+/// The response carried by the payload of a response frame, whatever its
+/// status.
+pub fn decode_response(buf: &[u8]) -> Result<Response> {
+    let mut s = CodedInputStream::from_bytes(buf);
+    let mut res = Response::new();
+    res.merge_from(&mut s)
+        .map_err(err_to_Others!(e, "Unpack response error "))?;
+    Ok(res)
+}
+
+/// Pack `frames` into the payload of one batch frame, see
+/// [`MESSAGE_TYPE_BATCH`].
+pub fn pack_batch(frames: Vec<(MessageHeader, Vec<u8>)>) -> Vec<u8> {
+    let len = frames
+        .iter()
+        .map(|f| MESSAGE_HEADER_LENGTH + f.1.len())
+        .sum();
+    let mut batch = vec![0u8; len];
+    let mut off = 0;
+    for (mh, buf) in frames {
+        encode_message_header(&mh, &mut batch[off..]);
+        off += MESSAGE_HEADER_LENGTH;
+        batch[off..off + buf.len()].copy_from_slice(&buf);
+        off += buf.len();
+    }
+    batch
+}
+
+/// Split the payload of a batch frame into the frames it carries.
+pub fn unpack_batch(buf: &[u8]) -> Result<Vec<(MessageHeader, Vec<u8>)>> {
+    let mut frames = Vec::new();
+    let mut rest = buf;
+    while !rest.is_empty() {
+        if rest.len() < MESSAGE_HEADER_LENGTH {
+            return Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                "truncated frame header in batch".to_string(),
+            ));
+        }
+        let mh = decode_message_header(&rest[..MESSAGE_HEADER_LENGTH])?;
+        rest = &rest[MESSAGE_HEADER_LENGTH..];
+        let len = mh.length as usize;
+        if rest.len() < len || mh.type_ == MESSAGE_TYPE_BATCH {
+            return Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("malformed frame {:?} in batch", mh),
+            ));
+        }
+        frames.push((mh, rest[..len].to_vec()));
+        rest = &rest[len..];
+    }
+    Ok(frames)
+}
+
+/// Build a HELLO frame describing this library.
+pub fn hello_frame() -> (MessageHeader, Vec<u8>) {
+    hello_frame_with(&[])
+}
+
+/// Build a HELLO frame describing this library and naming `methods`.
+pub fn hello_frame_with(methods: &[(String, MethodHash)]) -> (MessageHeader, Vec<u8>) {
+    hello_frame_limited(methods, None)
+}
+
+/// Like [`hello_frame_with`], also advertising that at most `max_streams`
+/// requests are handled at once, if given.
+pub fn hello_frame_limited(
+    methods: &[(String, MethodHash)],
+    max_streams: Option<usize>,
+) -> (MessageHeader, Vec<u8>) {
+    let info = PeerInfo::local();
+    let mut buf = format!(
+        "version={}\ncapabilities={}\n",
+        info.version,
+        info.capabilities.join(",")
+    );
+    if !methods.is_empty() {
+        let methods: Vec<String> = methods
+            .iter()
+            .map(|(path, hash)| format!("{}:{}", path, hash))
+            .collect();
+        buf.push_str(&format!("methods={}\n", methods.join(",")));
+    }
+    if let Some(max) = max_streams {
+        buf.push_str(&format!("max_streams={}\n", max));
+    }
+    let buf = buf.into_bytes();
+    let mh = MessageHeader {
+        length: buf.len() as u32,
+        stream_id: 0,
+        type_: MESSAGE_TYPE_HELLO,
+        flags: 0,
+    };
+    (mh, buf)
+}
+
+/// Get the peer's description out of the payload of a HELLO frame.
+pub fn parse_hello(buf: &[u8]) -> PeerInfo {
+    let mut info = PeerInfo::default();
+    for line in String::from_utf8_lossy(buf).lines() {
+        match line.split_once('=') {
+            Some(("version", v)) => info.version = v.to_string(),
+            Some(("capabilities", v)) => {
+                info.capabilities = v
+                    .split(',')
+                    .filter(|c| !c.is_empty())
+                    .map(|c| c.to_string())
+                    .collect()
+            }
+            Some(("methods", v)) => {
+                info.methods = v
+                    .split(',')
+                    .filter_map(|m| m.rsplit_once(':'))
+                    .filter_map(|(path, hash)| Some((path.to_string(), MethodHash::parse(hash)?)))
+                    .collect()
+            }
+            Some(("max_streams", v)) => info.max_streams = v.parse().ok(),
+            _ => {}
+        }
+    }
+    info
+}
+
+/// Build a GOAWAY frame telling the peer the connection closes after
+/// `grace` because of `reason`.
+pub fn goaway_frame(grace: Duration, reason: &str) -> (MessageHeader, Vec<u8>) {
+    let mut buf = vec![0u8; 4];
+    BigEndian::write_u32(&mut buf, grace.as_millis().min(u32::MAX as u128) as u32);
+    buf.extend_from_slice(reason.as_bytes());
+    let mh = MessageHeader {
+        length: buf.len() as u32,
+        stream_id: 0,
+        type_: MESSAGE_TYPE_GOAWAY,
+        flags: 0,
+    };
+    (mh, buf)
+}
+
+/// Get the grace period and reason out of the payload of a GOAWAY frame.
+pub fn parse_goaway(buf: &[u8]) -> Result<(Duration, String)> {
+    if buf.len() < 4 {
+        return Err(get_rpc_status(
+            Code::INVALID_ARGUMENT,
+            "truncated goaway frame".to_string(),
+        ));
+    }
+    let grace = Duration::from_millis(u64::from(BigEndian::read_u32(&buf[..4])));
+    Ok((grace, String::from_utf8_lossy(&buf[4..]).into_owned()))
+}
+
+/// Splits the bytes read from a connection into frames, whatever chunks
+/// they were read in. Batch frames are unpacked.
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    max: usize,
+    frames: VecDeque<(MessageHeader, Vec<u8>)>,
+    // what is left of the payload of a frame which was too long
+    skip: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new(MESSAGE_LENGTH_MAX)
+    }
+}
+
+impl FrameDecoder {
+    /// Decode frames with payloads of at most `max` bytes.
+    pub fn new(max: usize) -> FrameDecoder {
+        FrameDecoder {
+            buf: Vec::new(),
+            max,
+            frames: VecDeque::new(),
+            skip: 0,
+        }
+    }
+
+    /// Add the bytes read from the connection.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// How many bytes were fed but not decoded into frames yet: once the
+    /// complete frames are taken, those of a frame received in part.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Whether the payload of a frame which was too long is still being
+    /// skipped.
+    pub fn skipping(&self) -> bool {
+        self.skip > 0
+    }
+
+    /// The next complete frame. Fails on a frame which is too long, whose
+    /// payload is then skipped as it is fed, and on a malformed batch,
+    /// which is dropped. Either way the frames after it decode as usual.
+    pub fn next_frame(&mut self) -> Result<Option<(MessageHeader, Vec<u8>)>> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(Some(frame));
+            }
+            if self.skip > 0 {
+                let n = self.skip.min(self.buf.len());
+                self.buf.drain(..n);
+                self.skip -= n;
+                if self.skip > 0 {
+                    return Ok(None);
+                }
+            }
+            if self.buf.len() < MESSAGE_HEADER_LENGTH {
+                return Ok(None);
+            }
+            let mh = decode_message_header(&self.buf)?;
+            let len = mh.length as usize;
+            if len > self.max {
+                self.buf.drain(..MESSAGE_HEADER_LENGTH);
+                self.skip = len;
+                return Err(get_rpc_status(
+                    Code::INVALID_ARGUMENT,
+                    format!(
+                        "message length {} exceed maximum message size of {}",
+                        len, self.max
+                    ),
+                ));
+            }
+            if self.buf.len() < MESSAGE_HEADER_LENGTH + len {
+                return Ok(None);
+            }
+            let payload = self.buf[MESSAGE_HEADER_LENGTH..MESSAGE_HEADER_LENGTH + len].to_vec();
+            self.buf.drain(..MESSAGE_HEADER_LENGTH + len);
+            if mh.type_ == MESSAGE_TYPE_BATCH {
+                self.frames.extend(unpack_batch(&payload)?);
+            } else {
+                self.frames.push_back((mh, payload));
+            }
+        }
+    }
+}
+
+/// What a [`ClientConnection`] got from the server.
+#[derive(Debug)]
+pub enum ClientEvent {
+    /// The response to the request sent on a stream. Responses with a
+    /// status other than OK are errors.
+    Response(u32, Result<Response>),
+    /// A progress update for the request sent on a stream.
+    Progress(u32, Vec<u8>),
+    /// The server described itself.
+    Hello(PeerInfo),
+    /// The server closes the connection after a grace period, for a
+    /// reason.
+    GoAway(Duration, String),
+    /// A message the server streamed ahead of its response to the
+    /// request sent on a stream, see [`stream`](crate::stream).
+    Data(u32, Vec<u8>),
+}
+
+/// The client side of a connection: assigns stream ids, encodes requests
+/// into the bytes to write, and turns the bytes read into
+/// [`ClientEvent`]s.
+pub struct ClientConnection {
+    next_stream_id: u32,
+    waiting: HashSet<u32>,
+    decoder: FrameDecoder,
+}
+
+impl Default for ClientConnection {
+    fn default() -> Self {
+        ClientConnection::new()
+    }
+}
+
+impl ClientConnection {
+    pub fn new() -> ClientConnection {
+        ClientConnection {
+            next_stream_id: 1,
+            waiting: HashSet::new(),
+            decoder: FrameDecoder::default(),
+        }
+    }
+
+    /// The bytes introducing this client, to write before any request.
+    /// Optional; the server answers with its own HELLO frame.
+    pub fn hello(&self) -> Vec<u8> {
+        self.hello_with(&[])
+    }
+
+    /// Like [`hello`](Self::hello), naming the methods this client is
+    /// going to call, so the server answers with its hashes of them.
+    pub fn hello_with(&self, methods: &[(String, MethodHash)]) -> Vec<u8> {
+        let (mh, buf) = hello_frame_with(methods);
+        encode_frame(mh, &buf)
+    }
+
+    /// The bytes carrying the identity of this client, to write first
+    /// thing, see [`MESSAGE_TYPE_IDENTITY`].
+    pub fn identity(&self, identity: &[u8]) -> Vec<u8> {
+        let mh = MessageHeader {
+            type_: MESSAGE_TYPE_IDENTITY,
+            ..Default::default()
+        };
+        encode_frame(mh, identity)
+    }
+
+    fn frame(&mut self, req: &Request, flags: u8, ext: &Extensions) -> Result<(u32, Vec<u8>)> {
+        if self.next_stream_id > u32::MAX - 2 {
+            return Err(Error::Others("stream ids exhausted".to_string()));
+        }
+        let mut mh = MessageHeader {
+            stream_id: self.next_stream_id,
+            type_: MESSAGE_TYPE_REQUEST,
+            flags,
+            ..Default::default()
+        };
+        let buf = extension::prepend(&mut mh, encode_request(req)?, ext)?;
+        self.next_stream_id += 2;
+        Ok((mh.stream_id, encode_frame(mh, &buf)))
+    }
+
+    /// The stream `req` goes on, and the bytes to write to send it.
+    pub fn request(&mut self, req: &Request) -> Result<(u32, Vec<u8>)> {
+        self.request_with_extensions(req, &Extensions::new())
+    }
+
+    /// Like [`request`](Self::request), with extensions. Only for servers
+    /// with the `extensions` capability.
+    pub fn request_with_extensions(
+        &mut self,
+        req: &Request,
+        ext: &Extensions,
+    ) -> Result<(u32, Vec<u8>)> {
+        let (stream_id, buf) = self.frame(req, 0, ext)?;
+        self.waiting.insert(stream_id);
+        Ok((stream_id, buf))
+    }
+
+    /// The bytes to write to send `req` without wanting a response.
+    pub fn notify(&mut self, req: &Request) -> Result<Vec<u8>> {
+        Ok(self.frame(req, FLAG_NO_REPLY, &Extensions::new())?.1)
+    }
+
+    /// The bytes to write to cancel the request on `stream_id`, unless it
+    /// was answered already. Its response is dropped should it come.
+    pub fn cancel(&mut self, stream_id: u32) -> Option<Vec<u8>> {
+        if !self.waiting.remove(&stream_id) {
+            return None;
+        }
+        let mh = MessageHeader {
+            stream_id,
+            type_: MESSAGE_TYPE_CANCEL,
+            ..Default::default()
+        };
+        Some(encode_frame(mh, &[]))
+    }
+
+    /// How many requests wait for their response.
+    pub fn in_flight(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Add the bytes read from the connection.
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.decoder.feed(bytes);
+    }
+
+    /// The next event out of the bytes received so far. Frames which do
+    /// not belong to a request waiting for them are skipped. Fails on a
+    /// frame which does not decode, see [`FrameDecoder::next_frame`].
+    pub fn poll_event(&mut self) -> Result<Option<ClientEvent>> {
+        while let Some((mut mh, buf)) = self.decoder.next_frame()? {
+            match mh.type_ {
+                MESSAGE_TYPE_RESPONSE if self.waiting.remove(&mh.stream_id) => {
+                    let res =
+                        extension::split(&mut mh, buf).and_then(|(_, buf)| decode_response(&buf));
+                    let res = res.and_then(|res| {
+                        if res.get_status().code() != Code::OK {
+                            return Err(Error::RpcStatus(res.get_status().clone()));
+                        }
+                        Ok(res)
+                    });
+                    return Ok(Some(ClientEvent::Response(mh.stream_id, res)));
+                }
+                MESSAGE_TYPE_PROGRESS if self.waiting.contains(&mh.stream_id) => {
+                    return Ok(Some(ClientEvent::Progress(mh.stream_id, buf)));
+                }
+                MESSAGE_TYPE_DATA
+                    if mh.flags & FLAG_NO_DATA == 0 && self.waiting.contains(&mh.stream_id) =>
+                {
+                    return Ok(Some(ClientEvent::Data(mh.stream_id, buf)));
+                }
+                MESSAGE_TYPE_HELLO if mh.stream_id == 0 => {
+                    return Ok(Some(ClientEvent::Hello(parse_hello(&buf))));
+                }
+                MESSAGE_TYPE_GOAWAY if mh.stream_id == 0 => {
+                    let (grace, reason) = parse_goaway(&buf)?;
+                    return Ok(Some(ClientEvent::GoAway(grace, reason)));
+                }
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// What a [`ServerConnection`] got from the client.
+#[derive(Debug)]
+pub enum ServerEvent {
+    /// A request on a stream. `wants_reply` is false for notifications,
+    /// `wants_progress` tells whether the client handles progress updates.
+    /// `streaming` tells whether the request starts a streaming call, see
+    /// [`stream`](crate::stream), whose messages from the client, if any,
+    /// follow as [`ServerEvent::Data`].
+    Request {
+        stream_id: u32,
+        request: Request,
+        wants_reply: bool,
+        wants_progress: bool,
+        streaming: bool,
+        extensions: Extensions,
+    },
+    /// A request on a stream whose envelope did not decode, to answer
+    /// with `INVALID_ARGUMENT` if `wants_reply`.
+    Undecodable {
+        stream_id: u32,
+        wants_reply: bool,
+        message: String,
+    },
+    /// A message the client streamed on a stream, unless the frame only
+    /// closed it. `closed` is set on the last frame of the client.
+    Data {
+        stream_id: u32,
+        message: Option<Vec<u8>>,
+        closed: bool,
+    },
+    /// The client gave up on the request on a stream.
+    Cancel(u32),
+    /// The client described itself; answer with
+    /// [`ServerConnection::hello`].
+    Hello(PeerInfo),
+    /// The identity the client connected with.
+    Identity(Vec<u8>),
+}
+
+/// The server side of a connection: turns the bytes read into
+/// [`ServerEvent`]s, and encodes responses into the bytes to write.
+#[derive(Default)]
+pub struct ServerConnection {
+    decoder: FrameDecoder,
+}
+
+impl ServerConnection {
+    /// Accept requests of at most `max` bytes.
+    pub fn new(max: usize) -> ServerConnection {
+        ServerConnection {
+            decoder: FrameDecoder::new(max),
+        }
+    }
+
+    /// Add the bytes read from the connection.
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.decoder.feed(bytes);
+    }
+
+    /// Whether a frame was received in part, once the events out of the
+    /// bytes received so far are polled.
+    pub fn mid_frame(&self) -> bool {
+        self.decoder.buffered() > 0 || self.decoder.skipping()
+    }
+
+    /// The next event out of the bytes received so far. Fails on a frame
+    /// which does not decode, see [`FrameDecoder::next_frame`].
+    pub fn poll_event(&mut self) -> Result<Option<ServerEvent>> {
+        while let Some((mut mh, buf)) = self.decoder.next_frame()? {
+            match mh.type_ {
+                MESSAGE_TYPE_REQUEST => {
+                    let wants_reply = mh.flags & FLAG_NO_REPLY == 0;
+                    let wants_progress = mh.flags & FLAG_PROGRESS_OK != 0;
+                    let streaming = mh.flags & (FLAG_REMOTE_OPEN | FLAG_REMOTE_CLOSED) != 0;
+                    let decoded = extension::split(&mut mh, buf)
+                        .and_then(|(extensions, buf)| Ok((extensions, decode_request(&buf)?)));
+                    return Ok(Some(match decoded {
+                        Ok((extensions, request)) => ServerEvent::Request {
+                            stream_id: mh.stream_id,
+                            request,
+                            wants_reply,
+                            wants_progress,
+                            streaming,
+                            extensions,
+                        },
+                        Err(e) => ServerEvent::Undecodable {
+                            stream_id: mh.stream_id,
+                            wants_reply,
+                            message: e.to_status().message,
+                        },
+                    }));
+                }
+                MESSAGE_TYPE_DATA if mh.stream_id != 0 => {
+                    return Ok(Some(ServerEvent::Data {
+                        stream_id: mh.stream_id,
+                        message: Some(buf).filter(|_| mh.flags & FLAG_NO_DATA == 0),
+                        closed: mh.flags & FLAG_REMOTE_CLOSED != 0,
+                    }));
+                }
+                MESSAGE_TYPE_CANCEL => return Ok(Some(ServerEvent::Cancel(mh.stream_id))),
+                MESSAGE_TYPE_HELLO if mh.stream_id == 0 => {
+                    return Ok(Some(ServerEvent::Hello(parse_hello(&buf))));
+                }
+                MESSAGE_TYPE_IDENTITY if mh.stream_id == 0 => {
+                    return Ok(Some(ServerEvent::Identity(buf)));
+                }
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    /// The bytes answering a client's HELLO frame.
+    pub fn hello(&self) -> Vec<u8> {
+        self.hello_with(&[])
+    }
+
+    /// The bytes answering a client's HELLO frame which named methods,
+    /// with the server's hashes of them.
+    pub fn hello_with(&self, methods: &[(String, MethodHash)]) -> Vec<u8> {
+        self.hello_limited(methods, None)
+    }
+
+    /// Like [`hello_with`](Self::hello_with), also advertising that the
+    /// server handles at most `max_streams` requests at once, if given.
+    pub fn hello_limited(
+        &self,
+        methods: &[(String, MethodHash)],
+        max_streams: Option<usize>,
+    ) -> Vec<u8> {
+        let (mh, buf) = hello_frame_limited(methods, max_streams);
+        encode_frame(mh, &buf)
+    }
+
+    /// The bytes to write to answer the request on `stream_id` with `res`.
+    pub fn respond(&self, stream_id: u32, res: &Response) -> Result<Vec<u8>> {
+        let mh = MessageHeader {
+            stream_id,
+            type_: MESSAGE_TYPE_RESPONSE,
+            ..Default::default()
+        };
+        Ok(encode_frame(mh, &encode_response(res)?))
+    }
+
+    /// The bytes to write to report progress on the request on
+    /// `stream_id`, if the client wants progress updates.
+    pub fn progress(&self, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mh = MessageHeader {
+            stream_id,
+            type_: MESSAGE_TYPE_PROGRESS,
+            ..Default::default()
+        };
+        encode_frame(mh, payload)
+    }
+
+    /// The bytes telling the client the connection closes after `grace`.
+    pub fn goaway(&self, grace: Duration, reason: &str) -> Vec<u8> {
+        let (mh, buf) = goaway_frame(grace, reason);
+        encode_frame(mh, &buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::get_status;
+
+    fn frame(type_: u8, stream_id: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mh = MessageHeader {
+            stream_id,
+            type_,
+            flags,
+            ..Default::default()
+        };
+        encode_frame(mh, payload)
+    }
+
+    fn request(service: &str, method: &str) -> Request {
+        let mut req = Request::new();
+        req.set_service(service.to_string());
+        req.set_method(method.to_string());
+        req
+    }
+
+    fn response(code: Code) -> Response {
+        let mut res = Response::new();
+        res.set_status(get_status(code, String::new()));
+        res
+    }
+
+    #[test]
+    fn test_decoder_partial_header() {
+        let bytes = frame(MESSAGE_TYPE_DATA, 1, 0, b"abc");
+        let mut decoder = FrameDecoder::default();
+
+        decoder.feed(&bytes[..MESSAGE_HEADER_LENGTH - 1]);
+        assert!(decoder.next_frame().unwrap().is_none());
+        assert_eq!(decoder.buffered(), MESSAGE_HEADER_LENGTH - 1);
+
+        decoder.feed(&bytes[MESSAGE_HEADER_LENGTH - 1..]);
+        let (mh, payload) = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(
+            (mh.type_, mh.stream_id, mh.length),
+            (MESSAGE_TYPE_DATA, 1, 3)
+        );
+        assert_eq!(payload, b"abc");
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_decoder_split_body() {
+        let mut bytes = frame(MESSAGE_TYPE_DATA, 1, 0, b"first");
+        bytes.extend(frame(MESSAGE_TYPE_DATA, 3, 0, b"second"));
+        let mut decoder = FrameDecoder::default();
+
+        // byte by byte, each frame comes out once its last byte is in
+        let mut frames = Vec::new();
+        for b in bytes.iter() {
+            decoder.feed(&[*b]);
+            while let Some((mh, payload)) = decoder.next_frame().unwrap() {
+                frames.push((mh.stream_id, payload));
+            }
+        }
+        assert_eq!(
+            frames,
+            vec![(1, b"first".to_vec()), (3, b"second".to_vec())]
+        );
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_decoder_oversize() {
+        let mut decoder = FrameDecoder::new(4);
+        let long = frame(MESSAGE_TYPE_DATA, 1, 0, b"too long");
+        let next = frame(MESSAGE_TYPE_DATA, 3, 0, b"ok");
+
+        // the payload is skipped as it comes, reported once
+        decoder.feed(&long[..MESSAGE_HEADER_LENGTH + 2]);
+        assert!(decoder.next_frame().is_err());
+        assert!(decoder.skipping());
+        assert!(decoder.next_frame().unwrap().is_none());
+
+        decoder.feed(&long[MESSAGE_HEADER_LENGTH + 2..]);
+        decoder.feed(&next);
+        let (mh, payload) = decoder.next_frame().unwrap().unwrap();
+        assert_eq!((mh.stream_id, payload), (3, b"ok".to_vec()));
+        assert!(!decoder.skipping());
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decoder_batch() {
+        let frames = vec![
+            (
+                MessageHeader {
+                    length: 1,
+                    stream_id: 1,
+                    type_: MESSAGE_TYPE_DATA,
+                    flags: 0,
+                },
+                b"a".to_vec(),
+            ),
+            (
+                MessageHeader {
+                    length: 2,
+                    stream_id: 3,
+                    type_: MESSAGE_TYPE_DATA,
+                    flags: 0,
+                },
+                b"bc".to_vec(),
+            ),
+        ];
+        let mut decoder = FrameDecoder::default();
+        decoder.feed(&frame(MESSAGE_TYPE_BATCH, 0, 0, &pack_batch(frames)));
+        // a batch which does not unpack is dropped whole
+        decoder.feed(&frame(MESSAGE_TYPE_BATCH, 0, 0, &[0; 4]));
+        decoder.feed(&frame(MESSAGE_TYPE_DATA, 5, 0, b"d"));
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap().1, b"a");
+        assert_eq!(decoder.next_frame().unwrap().unwrap().1, b"bc");
+        assert!(decoder.next_frame().is_err());
+        assert_eq!(decoder.next_frame().unwrap().unwrap().1, b"d");
+    }
+
+    #[test]
+    fn test_server_connection_events() {
+        let mut conn = ServerConnection::new(MESSAGE_LENGTH_MAX);
+        let mut client = ClientConnection::new();
+        conn.receive(&client.identity(b"me"));
+        conn.receive(&client.hello());
+        let (stream_id, bytes) = client.request(&request("svc", "call")).unwrap();
+        conn.receive(&bytes);
+        conn.receive(&client.notify(&request("svc", "notify")).unwrap());
+        conn.receive(&frame(MESSAGE_TYPE_REQUEST, 7, 0, &[0xff; 4]));
+        conn.receive(&client.cancel(stream_id).unwrap());
+
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Identity(id)) => assert_eq!(id, b"me"),
+            e => panic!("expected the identity, got {:?}", e),
+        }
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Hello(info)) => assert!(info.version.starts_with("ttrpc-rust/")),
+            e => panic!("expected a hello, got {:?}", e),
+        }
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Request {
+                stream_id: 1,
+                request,
+                wants_reply: true,
+                ..
+            }) => assert_eq!(request.get_method(), "call"),
+            e => panic!("expected the call, got {:?}", e),
+        }
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Request {
+                stream_id: 3,
+                request,
+                wants_reply: false,
+                ..
+            }) => assert_eq!(request.get_method(), "notify"),
+            e => panic!("expected the notification, got {:?}", e),
+        }
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Undecodable {
+                stream_id: 7,
+                wants_reply: true,
+                ..
+            }) => (),
+            e => panic!("expected an undecodable request, got {:?}", e),
+        }
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Cancel(1)) => (),
+            e => panic!("expected the cancellation, got {:?}", e),
+        }
+        assert!(conn.poll_event().unwrap().is_none());
+        assert!(!conn.mid_frame());
+    }
+
+    #[test]
+    fn test_server_connection_data() {
+        let mut conn = ServerConnection::new(MESSAGE_LENGTH_MAX);
+        let req = encode_request(&request("svc", "upload")).unwrap();
+        conn.receive(&frame(MESSAGE_TYPE_REQUEST, 1, FLAG_REMOTE_OPEN, &req));
+        conn.receive(&frame(MESSAGE_TYPE_DATA, 1, 0, b"chunk"));
+        conn.receive(&frame(
+            MESSAGE_TYPE_DATA,
+            1,
+            FLAG_REMOTE_CLOSED | FLAG_NO_DATA,
+            &[],
+        ));
+
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Request {
+                stream_id: 1,
+                streaming: true,
+                ..
+            }) => (),
+            e => panic!("expected a streaming call, got {:?}", e),
+        }
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Data {
+                stream_id: 1,
+                message: Some(message),
+                closed: false,
+            }) => assert_eq!(message, b"chunk"),
+            e => panic!("expected a message, got {:?}", e),
+        }
+        match conn.poll_event().unwrap() {
+            Some(ServerEvent::Data {
+                stream_id: 1,
+                message: None,
+                closed: true,
+            }) => (),
+            e => panic!("expected the stream to close, got {:?}", e),
+        }
+        assert!(conn.poll_event().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_client_connection_events() {
+        let mut client = ClientConnection::new();
+        let server = ServerConnection::new(MESSAGE_LENGTH_MAX);
+        let (first, _) = client.request(&request("svc", "a")).unwrap();
+        let (second, _) = client.request(&request("svc", "b")).unwrap();
+        assert_eq!((first, second), (1, 3));
+        assert_eq!(client.in_flight(), 2);
+
+        client.receive(&server.hello());
+        client.receive(&server.progress(first, b"half"));
+        client.receive(&frame(MESSAGE_TYPE_DATA, first, 0, b"item"));
+        client.receive(&server.respond(first, &response(Code::OK)).unwrap());
+        // answered already, and never asked for
+        client.receive(&server.respond(first, &response(Code::OK)).unwrap());
+        client.receive(&server.respond(9, &response(Code::OK)).unwrap());
+        client.receive(&server.respond(second, &response(Code::NOT_FOUND)).unwrap());
+        client.receive(&server.goaway(Duration::from_secs(1), "bye"));
+
+        match client.poll_event().unwrap() {
+            Some(ClientEvent::Hello(_)) => (),
+            e => panic!("expected a hello, got {:?}", e),
+        }
+        match client.poll_event().unwrap() {
+            Some(ClientEvent::Progress(1, payload)) => assert_eq!(payload, b"half"),
+            e => panic!("expected progress, got {:?}", e),
+        }
+        match client.poll_event().unwrap() {
+            Some(ClientEvent::Data(1, message)) => assert_eq!(message, b"item"),
+            e => panic!("expected a message, got {:?}", e),
+        }
+        match client.poll_event().unwrap() {
+            Some(ClientEvent::Response(1, Ok(_))) => (),
+            e => panic!("expected the first response, got {:?}", e),
+        }
+        match client.poll_event().unwrap() {
+            Some(ClientEvent::Response(3, Err(Error::RpcStatus(s)))) => {
+                assert_eq!(s.get_code(), Code::NOT_FOUND)
+            }
+            e => panic!("expected the second response, got {:?}", e),
+        }
+        match client.poll_event().unwrap() {
+            Some(ClientEvent::GoAway(grace, reason)) => {
+                assert_eq!((grace, reason.as_str()), (Duration::from_secs(1), "bye"))
+            }
+            e => panic!("expected a goaway, got {:?}", e),
+        }
+        assert!(client.poll_event().unwrap().is_none());
+        assert_eq!(client.in_flight(), 0);
+        assert!(client.cancel(first).is_none());
+    }
+}
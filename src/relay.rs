@@ -0,0 +1,419 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relaying ttrpc connections frame by frame, e.g. in a gateway between
+//! a client in a guest and a server on the host. See [`Relay`].
+
+use nix::sys::socket::{shutdown, Shutdown};
+use nix::unistd::close;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::thread;
+
+use crate::channel::{
+    read_count, read_message_header, write_count, write_message_header, SOCK_DICONNECTED,
+};
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::{MessageHeader, MESSAGE_LENGTH_MAX};
+use crate::ttrpc::Code;
+
+/// The most payload bytes copied through the relay's memory at a time.
+const COPY_CHUNK: usize = 64 << 10;
+
+/// Which way a frame goes through a [`Relay`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client to the server: requests.
+    ToServer,
+    /// From the server to the client: responses.
+    ToClient,
+}
+
+/// Rewrites the header of each frame before it is passed on. The length
+/// must be left alone, the payload is passed on as it is.
+pub type HeaderRewrite = Arc<dyn Fn(Direction, &mut MessageHeader) + Send + Sync>;
+
+/// What a [`Relay`] passed on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RelayStats {
+    pub frames: u64,
+    /// Payload bytes moved with `splice(2)`, without being copied through
+    /// the relay's memory.
+    pub spliced: u64,
+    /// Payload bytes read and written.
+    pub copied: u64,
+}
+
+impl RelayStats {
+    fn add(&mut self, other: RelayStats) {
+        self.frames += other.frames;
+        self.spliced += other.spliced;
+        self.copied += other.copied;
+    }
+}
+
+/// Passes the frames of a client connection on to a server connection and
+/// back, rewriting their headers on the way if need be. Payloads of at
+/// least [`Relay::splice_min`] bytes are moved between the sockets with
+/// `splice(2)` through a pipe on Linux, so large payloads are not copied
+/// through userspace.
+///
+/// ```no_run
+/// # fn f(client: std::os::unix::io::RawFd, server: std::os::unix::io::RawFd) {
+/// use ttrpc::relay::{Direction, Relay};
+///
+/// // keep the stream ids of two clients sharing a server apart
+/// let stats = Relay::new(client, server)
+///     .rewrite(|dir, mh| match dir {
+///         Direction::ToServer => mh.stream_id += 1 << 30,
+///         Direction::ToClient => mh.stream_id -= 1 << 30,
+///     })
+///     .run();
+/// # }
+/// ```
+pub struct Relay {
+    client: RawFd,
+    server: RawFd,
+    rewrite: Option<HeaderRewrite>,
+    splice_min: usize,
+    max_frame: usize,
+}
+
+impl Relay {
+    /// Relay between the connected sockets `client` and `server`, which
+    /// the relay closes once done.
+    pub fn new(client: RawFd, server: RawFd) -> Relay {
+        Relay {
+            client,
+            server,
+            rewrite: None,
+            splice_min: 16 << 10,
+            max_frame: MESSAGE_LENGTH_MAX,
+        }
+    }
+
+    /// Rewrite the header of each frame before passing it on.
+    pub fn rewrite<F>(mut self, f: F) -> Relay
+    where
+        F: Fn(Direction, &mut MessageHeader) + Send + Sync + 'static,
+    {
+        self.rewrite = Some(Arc::new(f));
+        self
+    }
+
+    /// Splice payloads of at least `bytes` bytes, 16KiB by default. Below
+    /// that, copying them is cheaper than the extra system calls.
+    pub fn splice_min(mut self, bytes: usize) -> Relay {
+        self.splice_min = bytes;
+        self
+    }
+
+    /// Refuse frames with payloads over `bytes` bytes, [`MESSAGE_LENGTH_MAX`]
+    /// by default. Such a frame is not passed on: the relay ends both
+    /// connections instead.
+    pub fn max_frame(mut self, bytes: usize) -> Relay {
+        self.max_frame = bytes;
+        self
+    }
+
+    /// Relay until both peers closed their side, or either connection
+    /// failed. The client's requests are relayed on the calling thread,
+    /// the server's responses on a thread of its own.
+    ///
+    /// A peer shutting down its write side is passed on to the other one.
+    pub fn run(self) -> Result<RelayStats> {
+        let (client, server) = (self.client, self.server);
+        let limits = (self.splice_min, self.max_frame);
+        let rewrite = self.rewrite.clone();
+        let responses = thread::Builder::new()
+            .name(format!("relay-{}", server))
+            .spawn(move || pass_on(server, client, Direction::ToClient, &rewrite, limits))
+            .map_err(|e| Error::Others(format!("failed to start relay thread: {}", e)))?;
+        let requests = pass_on(client, server, Direction::ToServer, &self.rewrite, limits);
+        let responses = responses
+            .join()
+            .map_err(|_| Error::Others("relay thread panicked".to_string()))?;
+
+        close(client).unwrap_or(());
+        close(server).unwrap_or(());
+        let mut stats = requests?;
+        stats.add(responses?);
+        Ok(stats)
+    }
+}
+
+/// Pass frames from `from` on to `to` until `from` is shut down.
+fn pass_on(
+    from: RawFd,
+    to: RawFd,
+    dir: Direction,
+    rewrite: &Option<HeaderRewrite>,
+    (splice_min, max_frame): (usize, usize),
+) -> Result<RelayStats> {
+    let mut stats = RelayStats::default();
+    let mut splicer = None;
+    let result = loop {
+        let mut mh = match read_message_header(from) {
+            Ok(mh) => mh,
+            // closed between frames
+            Err(Error::Socket(e)) if e == SOCK_DICONNECTED => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let len = mh.length;
+        if len as usize > max_frame {
+            break Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!(
+                    "message length {} exceed maximum message size of {}",
+                    len, max_frame
+                ),
+            ));
+        }
+        if let Some(f) = rewrite.as_ref() {
+            f(dir, &mut mh);
+            mh.length = len;
+        }
+        if let Err(e) = write_message_header(to, mh) {
+            break Err(e);
+        }
+        let len = len as usize;
+        let moved = if len >= splice_min && cfg!(any(target_os = "linux", target_os = "android")) {
+            let splicer = match splicer.as_ref() {
+                Some(s) => Ok(s),
+                None => Splicer::new().map(|s| &*splicer.insert(s)),
+            };
+            splicer.and_then(|s| s.splice(from, to, len)).map(|()| {
+                stats.spliced += len as u64;
+            })
+        } else {
+            copy(from, to, len).map(|()| {
+                stats.copied += len as u64;
+            })
+        };
+        if let Err(e) = moved {
+            break Err(e);
+        }
+        stats.frames += 1;
+    };
+    if let Some(s) = splicer {
+        s.close();
+    }
+    trace!("relay {:?} ended with {:?}: {:?}", dir, result, stats);
+    if result.is_ok() {
+        // the other peer gets no more from this one
+        shutdown(to, Shutdown::Write).unwrap_or(());
+    } else {
+        // a frame got cut: end the other direction too
+        shutdown(from, Shutdown::Both).unwrap_or(());
+        shutdown(to, Shutdown::Both).unwrap_or(());
+    }
+    result.map(|()| stats)
+}
+
+/// Move `len` bytes from `from` to `to`, [`COPY_CHUNK`] bytes at a time.
+fn copy(from: RawFd, to: RawFd, len: usize) -> Result<()> {
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(COPY_CHUNK);
+        let buf = read_count(from, n)?;
+        if buf.len() != n {
+            return Err(Error::Socket(
+                "connection closed inside a frame".to_string(),
+            ));
+        }
+        write_count(to, &buf, n)?;
+        left -= n;
+    }
+    Ok(())
+}
+
+/// The pipe payloads are spliced through.
+struct Splicer {
+    r: RawFd,
+    w: RawFd,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Splicer {
+    fn new() -> Result<Splicer> {
+        use nix::fcntl::OFlag;
+        let (r, w) = nix::unistd::pipe2(OFlag::O_CLOEXEC)
+            .map_err(|e| Error::Others(format!("failed to create relay pipe: {}", e)))?;
+        Ok(Splicer { r, w })
+    }
+
+    /// Move `len` bytes from `from` to `to`.
+    fn splice(&self, from: RawFd, to: RawFd, len: usize) -> Result<()> {
+        use nix::fcntl::{splice, SpliceFFlags};
+        let _sigpipe = SigpipeBlock::new();
+        let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_MORE;
+        let mut left = len;
+        while left > 0 {
+            let n = retry(|| splice(from, None, self.w, None, left, flags))?;
+            if n == 0 {
+                return Err(Error::Socket(
+                    "connection closed inside a frame".to_string(),
+                ));
+            }
+            let mut piped = n;
+            while piped > 0 {
+                piped -= retry(|| splice(self.r, None, to, None, piped, flags))?;
+            }
+            left -= n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+impl Splicer {
+    fn new() -> Result<Splicer> {
+        Err(Error::Others("splice is not supported".to_string()))
+    }
+
+    fn splice(&self, _from: RawFd, _to: RawFd, _len: usize) -> Result<()> {
+        Err(Error::Others("splice is not supported".to_string()))
+    }
+}
+
+/// Unlike `send(2)`, `splice(2)` has no `MSG_NOSIGNAL`: keeps the
+/// `SIGPIPE` of a peer gone away from killing the process, like the rest
+/// of the crate does.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+struct SigpipeBlock {
+    old: libc::sigset_t,
+    set: libc::sigset_t,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl SigpipeBlock {
+    fn new() -> SigpipeBlock {
+        unsafe {
+            let mut set = std::mem::zeroed();
+            let mut old = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGPIPE);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old);
+            SigpipeBlock { old, set }
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for SigpipeBlock {
+    fn drop(&mut self) {
+        unsafe {
+            // take the SIGPIPE raised meanwhile, unless it was blocked and
+            // pending before
+            if libc::sigismember(&self.old, libc::SIGPIPE) == 0 {
+                let timeout = libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: 0,
+                };
+                while libc::sigtimedwait(&self.set, std::ptr::null_mut(), &timeout) == libc::SIGPIPE
+                {
+                }
+            }
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.old, std::ptr::null_mut());
+        }
+    }
+}
+
+impl Splicer {
+    fn close(&self) {
+        close(self.r).unwrap_or(());
+        close(self.w).unwrap_or(());
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn retry<F: FnMut() -> nix::Result<usize>>(mut f: F) -> Result<usize> {
+    loop {
+        match f() {
+            Err(e) if e == nix::Error::from_errno(nix::errno::Errno::EINTR) => continue,
+            Err(e) if e == nix::Error::from_errno(nix::errno::Errno::EPIPE) => {
+                return Err(Error::ConnectionClosed)
+            }
+            Err(e) => return Err(Error::Socket(e.to_string())),
+            Ok(n) => return Ok(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proto::MESSAGE_TYPE_REQUEST;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    /// Relay between two socket pairs, returning the client's and the
+    /// server's ends.
+    fn relay(
+        relay: fn(RawFd, RawFd) -> Relay,
+    ) -> (RawFd, RawFd, thread::JoinHandle<Result<RelayStats>>) {
+        let (client, ours) = UnixStream::pair().unwrap();
+        let (theirs, server) = UnixStream::pair().unwrap();
+        let (ours, theirs) = (ours.into_raw_fd(), theirs.into_raw_fd());
+        let relayed = thread::spawn(move || relay(ours, theirs).run());
+        (client.into_raw_fd(), server.into_raw_fd(), relayed)
+    }
+
+    fn header(length: u32) -> MessageHeader {
+        MessageHeader {
+            length,
+            stream_id: 1,
+            type_: MESSAGE_TYPE_REQUEST,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn test_relay_copy() {
+        let len = 3 * COPY_CHUNK + 1;
+        let (client, server, relayed) = relay(|c, s| Relay::new(c, s).splice_min(usize::MAX));
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let sent = payload.clone();
+        let writer = thread::spawn(move || {
+            write_message_header(client, header(len as u32)).unwrap();
+            write_count(client, &sent, len).unwrap();
+            shutdown(client, Shutdown::Write).unwrap();
+            client
+        });
+
+        assert_eq!(read_message_header(server).unwrap().length, len as u32);
+        assert_eq!(read_count(server, len).unwrap(), payload);
+        let client = writer.join().unwrap();
+        shutdown(server, Shutdown::Write).unwrap();
+        let stats = relayed.join().unwrap().unwrap();
+        assert_eq!((stats.frames, stats.copied), (1, len as u64));
+        close(client).unwrap();
+        close(server).unwrap();
+    }
+
+    #[test]
+    fn test_relay_max_frame() {
+        let (client, server, relayed) = relay(|c, s| Relay::new(c, s).max_frame(1024));
+        write_message_header(client, header(u32::MAX)).unwrap();
+
+        assert!(relayed.join().unwrap().is_err());
+        // neither the header nor anything after it was passed on
+        assert!(matches!(
+            read_message_header(server),
+            Err(Error::Socket(ref e)) if e == SOCK_DICONNECTED
+        ));
+        close(client).unwrap();
+        close(server).unwrap();
+    }
+}
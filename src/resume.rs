@@ -0,0 +1,190 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resuming long calls from a checkpoint once their connection dropped,
+//! e.g. because the agent restarted, rather than starting them over. See
+//! [`Resumable`].
+//!
+//! A call with progress updates stands in for a stream: the server puts
+//! checkpoints of its own making in its updates, and the client keeps the
+//! last one. When the connection drops, the call is made again on a new
+//! connection, carrying that checkpoint in the [`RESUME_TOKEN_KEY`]
+//! metadata, and the handler picks up from it, see
+//! [`TtrpcContext::resume_token`](crate::TtrpcContext::resume_token).
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::client::Client;
+use crate::error::{Error, Result};
+use crate::metadata::RESUME_TOKEN_KEY;
+use crate::ttrpc::{Code, KeyValue, Request, Response};
+
+/// The longest wait between two attempts of a [`Resumable`] call.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Where a [`Resumable`] call is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResumeState {
+    /// Not made yet.
+    Idle,
+    /// Waiting for the answer to the given attempt, counting from 1.
+    Running(usize),
+    /// The given attempt lost its connection; waiting to make the next one.
+    Reconnecting(usize),
+    /// Answered.
+    Done,
+    /// Failed for good.
+    Failed,
+}
+
+/// A call made again from its last checkpoint each time its connection
+/// drops, up to a number of attempts.
+///
+/// `connect` gives the connection of each attempt but the first one made
+/// on [`Resumable::with_client`], e.g. `|| Client::connect(addr)`.
+pub struct Resumable<C> {
+    req: Request,
+    connect: C,
+    client: Option<Client>,
+    token: Arc<Mutex<Option<String>>>,
+    max_attempts: usize,
+    backoff: Duration,
+    state: ResumeState,
+}
+
+impl<C: FnMut() -> Result<Client>> Resumable<C> {
+    pub fn new(req: Request, connect: C) -> Resumable<C> {
+        Resumable {
+            req,
+            connect,
+            client: None,
+            token: Arc::default(),
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            state: ResumeState::Idle,
+        }
+    }
+
+    /// Make the first attempt on `client` rather than a new connection.
+    pub fn with_client(mut self, client: Client) -> Resumable<C> {
+        self.client = Some(client);
+        self
+    }
+
+    /// Resume from `token` already, e.g. a checkpoint saved before the
+    /// caller itself restarted.
+    pub fn resume_from(self, token: &str) -> Resumable<C> {
+        *self.token.lock().unwrap() = Some(token.to_string());
+        self
+    }
+
+    /// Give up after `attempts` attempts, 3 by default.
+    pub fn max_attempts(mut self, attempts: usize) -> Resumable<C> {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait `backoff` before the second attempt, twice as long before each
+    /// next one, up to 5s. 100ms by default.
+    pub fn backoff(mut self, backoff: Duration) -> Resumable<C> {
+        self.backoff = backoff;
+        self
+    }
+
+    /// The last checkpoint the server sent, which the next attempt resumes
+    /// from.
+    pub fn token(&self) -> Option<String> {
+        self.token.lock().unwrap().clone()
+    }
+
+    pub fn state(&self) -> ResumeState {
+        self.state
+    }
+
+    /// Make the call, calling `on_progress` with the payload of each
+    /// progress update, see [`Client::request_with_progress`].
+    /// `on_progress` returns the checkpoint the update carries, if any.
+    ///
+    /// Attempts failing with [`Code::UNAVAILABLE`], as those losing their
+    /// connection do, are made again; other errors are returned as they
+    /// are.
+    pub fn run<F>(&mut self, on_progress: F) -> Result<Response>
+    where
+        F: Fn(&[u8]) -> Option<String> + Send + Sync + 'static,
+    {
+        let on_progress = Arc::new(on_progress);
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.state = ResumeState::Running(attempt);
+            let err = match self.attempt(on_progress.clone()) {
+                Ok(res) => {
+                    self.state = ResumeState::Done;
+                    return Ok(res);
+                }
+                Err(e) => e,
+            };
+            if attempt >= self.max_attempts || !is_unavailable(&err) {
+                self.state = ResumeState::Failed;
+                return Err(err);
+            }
+
+            self.state = ResumeState::Reconnecting(attempt);
+            debug!(
+                "{}/{} attempt {} failed, resuming from {:?}: {:?}",
+                self.req.service,
+                self.req.method,
+                attempt,
+                self.token(),
+                err
+            );
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    fn attempt<F>(&mut self, on_progress: Arc<F>) -> Result<Response>
+    where
+        F: Fn(&[u8]) -> Option<String> + Send + Sync + 'static,
+    {
+        let client = match self.client.take() {
+            Some(c) => c,
+            None => (self.connect)()?,
+        };
+
+        let mut req = self.req.clone();
+        if let Some(token) = self.token() {
+            req.mut_metadata()
+                .retain(|kv| !kv.key.eq_ignore_ascii_case(RESUME_TOKEN_KEY));
+            let mut kv = KeyValue::new();
+            kv.set_key(RESUME_TOKEN_KEY.to_string());
+            kv.set_value(token);
+            req.mut_metadata().push(kv);
+        }
+
+        let token = self.token.clone();
+        client.request_with_progress(req, move |buf| {
+            if let Some(t) = on_progress(buf) {
+                *token.lock().unwrap() = Some(t);
+            }
+        })
+    }
+}
+
+fn is_unavailable(e: &Error) -> bool {
+    e.to_status().get_code() == Code::UNAVAILABLE
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scheduling of the server's method handler threads.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::sched::{sched_setaffinity, CpuSet};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::unistd::Pid;
+
+use crate::error::{Error, Result};
+
+/// Linux scheduling policy of a thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// `SCHED_OTHER`, the default time-sharing policy.
+    Other,
+    /// `SCHED_BATCH`, for CPU-bound threads that should not preempt others.
+    Batch,
+    /// `SCHED_IDLE`, only run when nothing else wants the CPU.
+    Idle,
+    /// `SCHED_FIFO` with the given real-time priority.
+    Fifo(i32),
+    /// `SCHED_RR` with the given real-time priority.
+    RoundRobin(i32),
+}
+
+/// Scheduling applied to each method handler thread as it starts, see
+/// [`ServerBuilder::set_worker_scheduling`].
+///
+/// [`ServerBuilder::set_worker_scheduling`]: crate::ServerBuilder::set_worker_scheduling
+#[derive(Clone, Debug, Default)]
+pub struct WorkerScheduling {
+    /// Nice value, from -20 (highest priority) to 19.
+    pub nice: Option<i32>,
+    pub policy: Option<SchedPolicy>,
+    /// CPUs the threads may run on. Empty for no restriction.
+    pub cpus: Vec<usize>,
+}
+
+impl WorkerScheduling {
+    /// Apply the settings to the calling thread.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn apply(&self) -> Result<()> {
+        if self.nice.is_none() && self.policy.is_none() && self.cpus.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Others(
+            "worker scheduling is only supported on Linux".to_string(),
+        ))
+    }
+
+    /// Apply the settings to the calling thread.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn apply(&self) -> Result<()> {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+
+        if let Some(policy) = self.policy {
+            let (policy, priority) = match policy {
+                SchedPolicy::Other => (libc::SCHED_OTHER, 0),
+                SchedPolicy::Batch => (libc::SCHED_BATCH, 0),
+                SchedPolicy::Idle => (libc::SCHED_IDLE, 0),
+                SchedPolicy::Fifo(p) => (libc::SCHED_FIFO, p),
+                SchedPolicy::RoundRobin(p) => (libc::SCHED_RR, p),
+            };
+            let param = libc::sched_param {
+                sched_priority: priority,
+            };
+            if unsafe { libc::sched_setscheduler(tid, policy, &param) } < 0 {
+                return Err(Error::Others(format!(
+                    "sched_setscheduler: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } < 0 {
+                return Err(Error::Others(format!(
+                    "setpriority: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+
+        if !self.cpus.is_empty() {
+            let mut set = CpuSet::new();
+            for cpu in &self.cpus {
+                set.set(*cpu).map_err(err_to_Others!(e, "bad cpu: "))?;
+            }
+            sched_setaffinity(Pid::from_raw(tid), &set)
+                .map_err(err_to_Others!(e, "sched_setaffinity: "))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running under tight seccomp profiles, as shims often do.
+//!
+//! Once [`set_minimal`] is called, the [`Server`](crate::Server) and the
+//! [`Client`](crate::Client) stick to the system calls of [`SYSCALLS`],
+//! avoiding newer ones like `pipe2` and `accept4`, which older profiles
+//! do not allow. Call it before creating either. The same fallbacks are
+//! used where those calls do not exist, as on macOS.
+//!
+//! Not covered are the io_uring backend, the splice path of
+//! [`Relay`](crate::relay::Relay), worker scheduling, which needs
+//! `sched_setscheduler`, `setpriority` and `sched_setaffinity`, and
+//! whatever handlers call themselves.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::fcntl::OFlag;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::sys::socket::accept4;
+use nix::sys::socket::{accept, socket, socketpair, AddressFamily, SockFlag, SockType};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::unistd::pipe2;
+use nix::unistd::{close, pipe};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The system calls made by the server and the client in minimal mode, by
+/// their x86_64 names, including those the standard library makes to
+/// allocate memory and run threads. Profiles for other architectures use
+/// their equivalents, e.g. `pselect6` and `ppoll` on aarch64.
+pub const SYSCALLS: &[&str] = &[
+    "accept",
+    "bind",
+    "brk",
+    "clock_nanosleep",
+    "clone",
+    "clone3",
+    "close",
+    "connect",
+    "exit",
+    "exit_group",
+    "fcntl",
+    "futex",
+    "getpeername",
+    "getrandom",
+    "getsockname",
+    "getsockopt",
+    "listen",
+    "madvise",
+    "mmap",
+    "mprotect",
+    "munmap",
+    "nanosleep",
+    "pipe",
+    "poll",
+    "read",
+    "recvfrom",
+    "rseq",
+    "rt_sigprocmask",
+    "sched_getaffinity",
+    "sched_yield",
+    "select",
+    "sendto",
+    "set_robust_list",
+    "setsockopt",
+    "shutdown",
+    "sigaltstack",
+    "socket",
+    "socketpair",
+    "write",
+];
+
+static MINIMAL: AtomicBool = AtomicBool::new(false);
+
+/// Stick to the system calls of [`SYSCALLS`] from now on, or stop to.
+pub fn set_minimal(minimal: bool) {
+    MINIMAL.store(minimal, Ordering::SeqCst);
+}
+
+/// Whether only the system calls of [`SYSCALLS`] are made.
+pub fn is_minimal() -> bool {
+    MINIMAL.load(Ordering::SeqCst)
+}
+
+fn set_cloexec(fds: &[RawFd]) -> nix::Result<()> {
+    for fd in fds {
+        if let Err(e) = fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            for fd in fds {
+                close(*fd).unwrap_or(());
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Without `SOCK_CLOEXEC` there is no `MSG_NOSIGNAL` either, so sockets
+// are kept from raising SIGPIPE with `SO_NOSIGPIPE` instead.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_socket_flags(fds: &[RawFd]) -> nix::Result<()> {
+    set_cloexec(fds)?;
+    for fd in fds {
+        if let Err(e) = set_nosigpipe(*fd) {
+            for fd in fds {
+                close(*fd).unwrap_or(());
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Keep writing to the socket `fd` from raising SIGPIPE once its peer
+/// went away, where sends cannot ask for that with `MSG_NOSIGNAL`. For
+/// sockets the caller hands in; those created here have it set already.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd"
+))]
+pub(crate) fn set_nosigpipe(fd: RawFd) -> nix::Result<()> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_NOSIGPIPE,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(nix::Error::last());
+    }
+    Ok(())
+}
+
+/// Sends on Linux pass `MSG_NOSIGNAL`, and other targets have no
+/// `SO_NOSIGPIPE`.
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd"
+)))]
+pub(crate) fn set_nosigpipe(_fd: RawFd) -> nix::Result<()> {
+    Ok(())
+}
+
+/// `pipe2(O_CLOEXEC)`, or `pipe` and `fcntl` in minimal mode.
+pub(crate) fn pipe_cloexec() -> nix::Result<(RawFd, RawFd)> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if !is_minimal() {
+            return pipe2(OFlag::O_CLOEXEC);
+        }
+    }
+    let (r, w) = pipe()?;
+    set_cloexec(&[r, w])?;
+    Ok((r, w))
+}
+
+/// `accept4(SOCK_CLOEXEC)`, or `accept` and `fcntl` in minimal mode.
+pub(crate) fn accept_cloexec(listener: RawFd) -> nix::Result<RawFd> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if !is_minimal() {
+            return accept4(listener, SockFlag::SOCK_CLOEXEC);
+        }
+    }
+    let fd = accept(listener)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    set_cloexec(&[fd])?;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    set_socket_flags(&[fd])?;
+    Ok(fd)
+}
+
+/// A stream socket of `family` created with `SOCK_CLOEXEC`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn socket_cloexec(family: AddressFamily) -> nix::Result<RawFd> {
+    socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+}
+
+/// A stream socket of `family` made close-on-exec with `fcntl`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn socket_cloexec(family: AddressFamily) -> nix::Result<RawFd> {
+    let fd = socket(family, SockType::Stream, SockFlag::empty(), None)?;
+    set_socket_flags(&[fd])?;
+    Ok(fd)
+}
+
+/// A connected pair of unix stream sockets, like [`socket_cloexec`].
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn socketpair_cloexec() -> nix::Result<(RawFd, RawFd)> {
+    socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )
+}
+
+/// A connected pair of unix stream sockets, like [`socket_cloexec`].
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn socketpair_cloexec() -> nix::Result<(RawFd, RawFd)> {
+    let (a, b) = socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::empty(),
+    )?;
+    set_socket_flags(&[a, b])?;
+    Ok((a, b))
+}
@@ -13,25 +13,54 @@
 // limitations under the License.
 
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::select::{select, FdSet};
 use nix::sys::socket::{self, *};
+use nix::sys::time::{TimeVal, TimeValLike};
 use nix::unistd::close;
-use nix::unistd::pipe2;
-use protobuf::{CodedInputStream, CodedOutputStream, Message};
-use std::collections::HashMap;
-use std::os::unix::io::RawFd;
-use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
-use std::sync::{Arc, Mutex};
+use nix::unistd::{read, write};
+use protobuf::{CodedInputStream, Message};
+use std::any::Any;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use crate::acl::{Acl, Caller};
+use crate::builtin;
 use crate::channel::{
-    read_message, write_message, MessageHeader, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE,
+    discard, discard_within, goaway_frame, hello_frame_limited, parse_hello, read_body_decoding,
+    read_message_header_within, unpack_batch, write_batched, write_frame, Body, MessageHeader,
+    MethodHash, PeerInfo, BATCH_QUEUE_MAX, FLAG_BATCH_OK, FLAG_NO_REPLY, FLAG_PROGRESS_OK,
+    FLAG_REMOTE_OPEN, MESSAGE_LENGTH_MAX, MESSAGE_TYPE_BATCH, MESSAGE_TYPE_CANCEL,
+    MESSAGE_TYPE_DATA, MESSAGE_TYPE_GOAWAY, MESSAGE_TYPE_HELLO, MESSAGE_TYPE_IDENTITY,
+    MESSAGE_TYPE_PROGRESS, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE, SOCK_DICONNECTED,
+    SOCK_READ_TIMEOUT, SOCK_WRITE_TIMEOUT,
 };
-use crate::error::{get_status, Error, Result};
-use crate::ttrpc::{Code, Request, Response};
+use crate::client::escape_payload;
+use crate::common::{self, MethodPaths};
+use crate::error::{get_rpc_status, get_status, Error, Result};
+use crate::extension::{self, Extensions};
+use crate::handoff;
+use crate::journal::{Journal, JournalEntry};
+use crate::metadata::{self, Metadata, REQUEST_ID_KEY, RESUME_TOKEN_KEY};
+use crate::pending::PendingReplies;
+use crate::pool::WorkerPool;
+use crate::proto;
+use crate::sched::WorkerScheduling;
+use crate::seccomp::{self, accept_cloexec, pipe_cloexec};
+use crate::stream::{InboundStreams, RequestStream, StreamInbound, StreamSink};
+use crate::ttrpc::{Code, KeyValue, Request, Response, Status};
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
 
 // poll_queue will create WAIT_THREAD_COUNT_DEFAULT threads in begin.
 // If wait thread count < WAIT_THREAD_COUNT_MIN, create number to WAIT_THREAD_COUNT_DEFAULT.
@@ -40,547 +69,4043 @@ const DEFAULT_WAIT_THREAD_COUNT_DEFAULT: usize = 3;
 const DEFAULT_WAIT_THREAD_COUNT_MIN: usize = 1;
 const DEFAULT_WAIT_THREAD_COUNT_MAX: usize = 5;
 
+// How often a connection whose peer shut down its write side checks on
+// the replies it still owes.
+const HALF_CLOSE_POLL: Duration = Duration::from_millis(50);
+
+// How often a closing connection checks whether its queued responses are
+// written.
+const FLUSH_POLL: Duration = Duration::from_millis(10);
+
+/// `log` target of the events a server emits about connections being
+/// accepted and closed, handler pool scaling, requests in flight, slow
+/// handlers and frames written in part. Each message is the event name
+/// followed by `key=value` fields; `tracing` subscribers get them through
+/// `tracing-log`.
+pub const EVENT_TARGET: &str = "ttrpc::events";
+
+/// A ttrpc server, built by a [`ServerBuilder`]. Its configuration is
+/// fixed; what changes while it runs, such as its connections, is shared
+/// with the [`ServerHandle`]s controlling it.
 pub struct Server {
+    config: Config,
+    control: Arc<Control>,
+    handler: Option<JoinHandle<()>>,
+}
+
+// What a ServerBuilder sets up.
+struct Config {
     listeners: Vec<RawFd>,
-    monitor_fd: (RawFd, RawFd),
-    quit: Arc<AtomicBool>,
-    connections: Arc<Mutex<HashMap<RawFd, Connection>>>,
+    listener_configs: HashMap<RawFd, ListenerConfig>,
     methods: Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
-    handler: Option<JoinHandle<()>>,
     thread_count_default: usize,
     thread_count_min: usize,
     thread_count_max: usize,
+    panic_handler: Option<PanicHandler>,
+    attached: Vec<RawFd>,
+    // the locks of the socket files listened on, see ListenerConfig::lock
+    socket_locks: Vec<File>,
+    reply_grace: Duration,
+    flush_timeout: Duration,
+    shutdown_timeout: Option<Duration>,
+    policy: MethodPolicy,
+    journal: Option<Arc<Journal>>,
+    descriptors: builtin::Descriptors,
+    scheduling: Option<Arc<WorkerScheduling>>,
+    on_connect: Option<ConnectHook>,
+    on_disconnect: Option<DisconnectHook>,
+    idle: Option<(Duration, IdleHook)>,
 }
 
-struct Connection {
-    fd: RawFd,
+// The state of a server shared by its threads and handles, which never
+// take a lock on the server itself.
+struct Control {
+    // written to wake up the listener thread, closed once no thread or
+    // handle uses it
+    monitor_fd: (RawFd, RawFd),
     quit: Arc<AtomicBool>,
-    handler: Option<JoinHandle<()>>,
+    connections: Arc<Mutex<HashMap<RawFd, Connection>>>,
+    abandoned: Arc<AtomicUsize>,
+    torn_writes: Arc<AtomicUsize>,
+    served: Arc<AtomicU64>,
+    gate: Arc<Gate>,
+    accepting: Arc<AtomicBool>,
+    // the listeners of the started server still open, held while they are
+    // handed off or looked at from outside the listener thread
+    listeners: Mutex<Vec<RawFd>>,
 }
 
-impl Connection {
-    fn close(&self) {
-        self.quit.store(true, Ordering::SeqCst);
-        // in case the connection had closed
-        socket::shutdown(self.fd, Shutdown::Read).unwrap_or(());
-    }
+/// What happened while shutting a [`Server`] down, see [`Server::shutdown`].
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    /// Connections which were still open and got closed under their peer.
+    pub connections_closed: Vec<RawFd>,
+    /// Deferred replies still owed by handlers when their connection closed.
+    pub replies_abandoned: usize,
+    /// Threads still running when the shutdown timeout expired. They are
+    /// left behind.
+    pub join_timeouts: Vec<String>,
+    /// Errors releasing the server's resources.
+    pub errors: Vec<Error>,
 }
 
-struct ThreadS<'a> {
-    fd: RawFd,
-    fdlock: &'a Arc<Mutex<()>>,
-    wtc: &'a Arc<AtomicUsize>,
-    quit: &'a Arc<AtomicBool>,
-    methods: &'a Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
-    res_tx: &'a Sender<(MessageHeader, Vec<u8>)>,
-    control_tx: &'a SyncSender<()>,
-    default: usize,
-    min: usize,
-    max: usize,
+impl ShutdownReport {
+    /// Whether everything was torn down without losing replies.
+    pub fn is_clean(&self) -> bool {
+        self.replies_abandoned == 0 && self.join_timeouts.is_empty() && self.errors.is_empty()
+    }
 }
 
-fn start_method_handler_thread(
-    fd: RawFd,
-    fdlock: Arc<Mutex<()>>,
-    wtc: Arc<AtomicUsize>,
-    quit: Arc<AtomicBool>,
-    methods: Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
-    res_tx: Sender<(MessageHeader, Vec<u8>)>,
-    control_tx: SyncSender<()>,
-    min: usize,
-    max: usize,
-) {
-    thread::spawn(move || {
-        while !quit.load(Ordering::SeqCst) {
-            let c = wtc.fetch_add(1, Ordering::SeqCst) + 1;
-            if c > max {
-                wtc.fetch_sub(1, Ordering::SeqCst);
-                break;
-            }
+/// A panic caught in one of the server's internal threads.
+#[derive(Debug)]
+pub struct ThreadPanic {
+    /// Name of the thread, which includes the connection fd for
+    /// per-connection threads.
+    pub thread: String,
+    /// The connection fd, or the listener fd for server-wide threads.
+    pub fd: RawFd,
+    /// The method being served when the panic happened, if any.
+    pub method: Option<String>,
+    pub message: String,
+}
 
-            let result;
-            {
-                let _guard = fdlock.lock().unwrap();
-                if quit.load(Ordering::SeqCst) {
-                    // notify the connection dealing main thread to stop.
-                    control_tx
-                        .try_send(())
-                        .unwrap_or_else(|err| warn!("Failed to try send {:?}", err));
-                    break;
-                }
-                result = read_message(fd);
-            }
+pub type PanicHandler = Arc<dyn Fn(&ThreadPanic) + Send + Sync>;
 
-            if quit.load(Ordering::SeqCst) {
-                // notify the connection dealing main thread to stop.
-                control_tx
-                    .try_send(())
-                    .unwrap_or_else(|err| warn!("Failed to try send {:?}", err));
-                break;
-            }
+/// State attached to a connection by the [`ServerBuilder::set_on_connect`]
+/// callback.
+pub type ConnectionData = Arc<dyn Any + Send + Sync>;
 
-            let c = wtc.fetch_sub(1, Ordering::SeqCst) - 1;
-            if c < min {
-                control_tx
-                    .try_send(())
-                    .unwrap_or_else(|err| warn!("Failed to try send {:?}", err));
-            }
+type ConnectHook = Arc<dyn Fn(&ConnectionRef) -> Option<ConnectionData> + Send + Sync>;
+type DisconnectHook = Arc<dyn Fn(&ConnectionRef, &Disconnect) + Send + Sync>;
+type IdleHook = Arc<dyn Fn() + Send + Sync>;
 
-            let mh;
-            let buf;
-            match result {
-                Ok((x, y)) => {
-                    mh = x;
-                    buf = y;
-                }
-                Err(x) => match x {
-                    Error::Socket(y) => {
-                        trace!("Socket error {}", y);
-                        quit.store(true, Ordering::SeqCst);
-                        // the client connection would be closed and
-                        // the connection dealing main thread would
-                        // have exited.
-                        control_tx
-                            .try_send(())
-                            .unwrap_or_else(|err| warn!("Failed to try send {:?}", err));
-                        break;
-                    }
-                    _ => {
-                        trace!("Others error {:?}", x);
-                        continue;
-                    }
-                },
-            }
+// how often the listener thread checks whether the server went idle
+const IDLE_POLL: Duration = Duration::from_secs(1);
 
-            if mh.type_ != MESSAGE_TYPE_REQUEST {
-                continue;
-            }
-            let mut s = CodedInputStream::from_bytes(&buf);
-            let mut req = Request::new();
-            if let Err(x) = req.merge_from(&mut s) {
-                let status = get_status(Code::INVALID_ARGUMENT, x.to_string());
-                let mut res = Response::new();
-                res.set_status(status);
-                if let Err(x) = response_to_channel(mh.stream_id, res, res_tx.clone()) {
-                    debug!("response_to_channel get error {:?}", x);
-                    quit.store(true, Ordering::SeqCst);
-                    // the client connection would be closed and
-                    // the connection dealing main thread would have
-                    // exited.
-                    control_tx
-                        .try_send(())
-                        .unwrap_or_else(|err| warn!("Failed to try send {:?}", err));
-                    break;
-                }
-                continue;
-            }
-            trace!("Got Message request {:?}", req);
+/// Decides whether a connection just accepted may be served.
+pub type Authorizer = Arc<dyn Fn(&ConnectionRef) -> Result<()> + Send + Sync>;
 
-            let path = format!("/{}/{}", req.service, req.method);
-            let method;
-            if let Some(x) = methods.get(&path) {
-                method = x;
-            } else {
-                let status = get_status(Code::INVALID_ARGUMENT, format!("{} does not exist", path));
-                let mut res = Response::new();
-                res.set_status(status);
-                if let Err(x) = response_to_channel(mh.stream_id, res, res_tx.clone()) {
-                    info!("response_to_channel get error {:?}", x);
-                    quit.store(true, Ordering::SeqCst);
-                    // the client connection would be closed and
-                    // the connection dealing main thread would have
-                    // exited.
-                    control_tx
-                        .try_send(())
-                        .unwrap_or_else(|err| warn!("Failed to try send {:?}", err));
-                    break;
-                }
-                continue;
-            }
-            let ctx = TtrpcContext {
-                fd,
-                mh,
-                res_tx: res_tx.clone(),
-            };
-            if let Err(x) = method.handler(ctx, req) {
-                debug!("method handle {} get error {:?}", path, x);
-                quit.store(true, Ordering::SeqCst);
-                // the client connection would be closed and
-                // the connection dealing main thread would have
-                // exited.
-                control_tx
-                    .try_send(())
-                    .unwrap_or_else(|err| warn!("Failed to try send {:?}", err));
-                break;
-            }
-        }
-    });
+/// Settings of the connections accepted on one listener, so that e.g. a
+/// local unix socket can expose admin methods while a vsock listener
+/// only exposes the task API. See [`ServerBuilder::bind_with`].
+#[derive(Clone, Default)]
+pub struct ListenerConfig {
+    services: Option<HashSet<String>>,
+    max_message_size: Option<usize>,
+    authorize: Option<Authorizer>,
+    lock: bool,
 }
 
-fn start_method_handler_threads(num: usize, ts: &ThreadS) {
-    for _ in 0..num {
-        if ts.quit.load(Ordering::SeqCst) {
-            break;
-        }
-        start_method_handler_thread(
-            ts.fd,
-            ts.fdlock.clone(),
-            ts.wtc.clone(),
-            ts.quit.clone(),
-            ts.methods.clone(),
-            ts.res_tx.clone(),
-            ts.control_tx.clone(),
-            ts.min,
-            ts.max,
-        );
+impl ListenerConfig {
+    pub fn new() -> ListenerConfig {
+        ListenerConfig::default()
     }
-}
 
-fn check_method_handler_threads(ts: &ThreadS) {
-    let c = ts.wtc.load(Ordering::SeqCst);
-    if c < ts.min {
-        start_method_handler_threads(ts.default - c, &ts);
+    /// Serve the methods of `service`, e.g. `grpc.Health`. Once a service
+    /// is allowed, those not allowed look as if they did not exist.
+    pub fn allow_service(mut self, service: &str) -> ListenerConfig {
+        self.services
+            .get_or_insert_with(HashSet::new)
+            .insert(service.to_string());
+        self
     }
-}
 
-impl Default for Server {
-    fn default() -> Self {
-        let (rfd, wfd) = pipe2(OFlag::O_CLOEXEC).unwrap();
-        Server {
-            listeners: Vec::with_capacity(1),
-            monitor_fd: (rfd, wfd),
-            quit: Arc::new(AtomicBool::new(false)),
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            methods: Arc::new(HashMap::new()),
-            handler: None,
-            thread_count_default: DEFAULT_WAIT_THREAD_COUNT_DEFAULT,
-            thread_count_min: DEFAULT_WAIT_THREAD_COUNT_MIN,
-            thread_count_max: DEFAULT_WAIT_THREAD_COUNT_MAX,
-        }
+    /// Treat messages longer than `size` as undecodable, see
+    /// [`ServerBuilder::set_decode_error_policy`]. Limits above the protocol's
+    /// 4MiB have no effect.
+    pub fn max_message_size(mut self, size: usize) -> ListenerConfig {
+        self.max_message_size = Some(size);
+        self
     }
-}
 
-impl Server {
-    pub fn new() -> Server {
-        Server::default()
+    /// Check each connection with `f` once accepted, e.g. its peer
+    /// credentials, and close those it fails.
+    pub fn authorize<F>(mut self, f: F) -> ListenerConfig
+    where
+        F: Fn(&ConnectionRef) -> Result<()> + Send + Sync + 'static,
+    {
+        self.authorize = Some(Arc::new(f));
+        self
     }
 
-    pub fn bind(mut self, host: &str) -> Result<Server> {
-        if !self.listeners.is_empty() {
-            return Err(Error::Others(
-                "ttrpc-rust just support 1 host now".to_string(),
-            ));
-        }
+    /// Lock the socket file before binding it, so that a second server
+    /// fails with [`Error::AddressInUse`] naming the first one, rather than
+    /// replacing its socket. The lock is `<path>.lock`, holding the pid of
+    /// its owner, and is released once the server is gone.
+    ///
+    /// Only sockets in the filesystem need it: Linux puts `unix://`
+    /// sockets in the abstract namespace, where the kernel refuses a
+    /// second bind.
+    pub fn lock(mut self) -> ListenerConfig {
+        self.lock = true;
+        self
+    }
+}
+
+/// Why a connection was closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed its end.
+    PeerClosed,
+    /// The server was shut down.
+    ServerShutdown,
+    /// The client sent frames or requests which could not be decoded.
+    ProtocolError(String),
+    /// Reading a frame or writing replies ran out of time, see
+    /// [`ServerBuilder::set_phase_timeout`].
+    Timeout(Phase),
+    /// Writing to the socket failed.
+    WriteError(String),
+    /// Reading from the socket failed, or a handler did.
+    Error(String),
+}
 
-        let hostv: Vec<&str> = host.trim().split("://").collect();
-        if hostv.len() != 2 {
-            return Err(Error::Others(format!("Host {} is not right", host)));
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CloseReason::PeerClosed => write!(f, "closed by peer"),
+            CloseReason::ServerShutdown => write!(f, "server shutdown"),
+            CloseReason::ProtocolError(e) => write!(f, "protocol error: {}", e),
+            CloseReason::Timeout(phase) => write!(f, "{} timed out", phase),
+            CloseReason::WriteError(e) => write!(f, "write error: {}", e),
+            CloseReason::Error(e) => write!(f, "error: {}", e),
         }
-        let scheme = hostv[0].to_lowercase();
+    }
+}
 
-        let sockaddr: SockAddr;
-        let fd: RawFd;
+/// A closed connection, as passed to the [`ServerBuilder::set_on_disconnect`]
+/// callback.
+pub struct Disconnect {
+    pub reason: CloseReason,
+    /// How long the connection was served.
+    pub duration: Duration,
+    /// What the [`ServerBuilder::set_on_connect`] callback attached, if anything.
+    pub data: Option<ConnectionData>,
+}
 
-        match scheme.as_str() {
-            "unix" => {
-                fd = socket(
-                    AddressFamily::Unix,
-                    SockType::Stream,
-                    SockFlag::SOCK_CLOEXEC,
-                    None,
-                )
-                .map_err(|e| Error::Socket(e.to_string()))?;
-                let sockaddr_h = hostv[1].to_owned() + &"\x00".to_string();
-                let sockaddr_u =
-                    UnixAddr::new_abstract(sockaddr_h.as_bytes()).map_err(err_to_Others!(e, ""))?;
-                sockaddr = SockAddr::Unix(sockaddr_u);
-            }
-
-            "vsock" => {
-                let host_port_v: Vec<&str> = hostv[1].split(':').collect();
-                if host_port_v.len() != 2 {
-                    return Err(Error::Others(format!(
-                        "Host {} is not right for vsock",
-                        host
-                    )));
-                }
-                let cid = libc::VMADDR_CID_ANY;
-                let port: u32 =
-                    FromStr::from_str(host_port_v[1]).expect("the vsock port is not an number");
-                fd = socket(
-                    AddressFamily::Vsock,
-                    SockType::Stream,
-                    SockFlag::SOCK_CLOEXEC,
-                    None,
-                )
-                .map_err(|e| Error::Socket(e.to_string()))?;
-                sockaddr = SockAddr::new_vsock(cid, port);
-            }
-            _ => return Err(Error::Others(format!("Scheme {} is not supported", scheme))),
-        };
+/// A phase of serving a request, with a timeout of its own, see
+/// [`ServerBuilder::set_phase_timeout`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Reading a frame, from its first byte to its last. The connection
+    /// of a client stalling midway is closed.
+    Read,
+    /// From reading a request to its handler starting. Requests waiting
+    /// longer are answered with `UNAVAILABLE` without being handled, so
+    /// clients may retry them.
+    Queue,
+    /// From a handler starting to the reply. Requests taking longer are
+    /// answered with `DEADLINE_EXCEEDED` and flagged as cancelled.
+    Handler,
+    /// Writing replies, with the client not reading any of them. The
+    /// connection is closed.
+    Write,
+}
 
-        bind(fd, &sockaddr).map_err(err_to_Others!(e, ""))?;
-        self.listeners.push(fd);
+const PHASES: usize = 4;
 
-        Ok(self)
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Phase::Read => write!(f, "read"),
+            Phase::Queue => write!(f, "queue"),
+            Phase::Handler => write!(f, "handler"),
+            Phase::Write => write!(f, "write"),
+        }
     }
+}
 
-    pub fn add_listener(mut self, fd: RawFd) -> Result<Server> {
-        self.listeners.push(fd);
+/// The timeouts of the phases of serving a request, and how many times
+/// each of them ran out.
+#[derive(Clone, Default)]
+struct PhaseTimeouts {
+    limits: [Option<Duration>; PHASES],
+    counts: Arc<[AtomicUsize; PHASES]>,
+}
 
-        Ok(self)
+impl PhaseTimeouts {
+    fn get(&self, phase: Phase) -> Option<Duration> {
+        self.limits[phase as usize]
     }
 
-    pub fn register_service(
-        mut self,
-        methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
-    ) -> Server {
-        let mut_methods = Arc::get_mut(&mut self.methods).unwrap();
-        mut_methods.extend(methods);
-        self
+    /// Whether `since` is longer ago than the timeout of `phase`.
+    fn passed(&self, phase: Phase, since: Instant) -> bool {
+        self.get(phase).is_some_and(|t| since.elapsed() >= t)
     }
 
-    pub fn set_thread_count_default(mut self, count: usize) -> Server {
-        self.thread_count_default = count;
-        self
+    /// Count a request to `method`, or connection `fd` if none, running
+    /// out of time in `phase`.
+    fn ran_out(&self, fd: RawFd, phase: Phase, method: Option<&str>) {
+        warn!(
+            target: EVENT_TARGET,
+            "phase_timeout fd={} phase={} method={}",
+            fd,
+            phase,
+            method.unwrap_or("-")
+        );
+        self.counts[phase as usize].fetch_add(1, Ordering::SeqCst);
     }
+}
 
-    pub fn set_thread_count_min(mut self, count: usize) -> Server {
-        self.thread_count_min = count;
-        self
-    }
+/// What the server does with a request it cannot decode, see
+/// [`ServerBuilder::set_decode_error_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// Answer the request with `INVALID_ARGUMENT` and keep serving the
+    /// connection.
+    #[default]
+    Respond,
+    /// Close the connection.
+    Close,
+    /// Drop the request without answering it and keep serving the
+    /// connection, e.g. for peers on a noisy link which would only
+    /// retry.
+    Ignore,
+}
 
-    pub fn set_thread_count_max(mut self, count: usize) -> Server {
-        self.thread_count_max = count;
-        self
-    }
+/// How much of the message of an error status the server sends to
+/// clients, see [`ServerBuilder::set_error_detail`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorDetail {
+    /// Send messages as they are.
+    #[default]
+    Full,
+    /// Send at most this many bytes of a message.
+    Truncated(usize),
+    /// Send the name of the status code in place of the message, e.g.
+    /// `NOT_FOUND`.
+    Generic,
+}
 
-    pub fn start(&mut self) -> Result<()> {
-        if self.thread_count_default >= self.thread_count_max {
-            return Err(Error::Others(
-                "thread_count_default should smaller than thread_count_max".to_string(),
-            ));
-        }
-        if self.thread_count_default <= self.thread_count_min {
-            return Err(Error::Others(
-                "thread_count_default should biger than thread_count_min".to_string(),
-            ));
+impl ErrorDetail {
+    /// Cut down the message of `status` as far as this allows, returning
+    /// the message withheld from the client, if any.
+    fn apply(self, status: &mut Status) -> Option<String> {
+        if status.code == Code::OK {
+            return None;
         }
+        let message = match self {
+            ErrorDetail::Full => return None,
+            ErrorDetail::Truncated(max) if status.message.len() <= max => return None,
+            ErrorDetail::Truncated(max) => {
+                let mut end = max;
+                while !status.message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                status.message[..end].to_string()
+            }
+            ErrorDetail::Generic => format!("{:?}", status.code),
+        };
+        Some(std::mem::replace(&mut status.message, message))
+    }
+}
 
-        let connections = self.connections.clone();
-
-        if self.listeners.is_empty() {
-            return Err(Error::Others("ttrpc-rust not bind".to_string()));
-        }
+/// What did not decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    /// A frame longer than the maximum message size.
+    Oversized,
+    /// A batch frame which does not unpack into frames.
+    Batch,
+    /// The `Request` envelope of a request.
+    Envelope,
+    /// The payload of a request, as the message its method takes.
+    Payload,
+    /// A frame header with a length beyond the protocol's 4MiB, which is
+    /// more likely corrupt than true. Its body is only read and dropped
+    /// if the connection is served on.
+    Length,
+    /// A frame header with a type byte no message type has.
+    UnknownType,
+    /// A request on stream 0, which no client opens.
+    ZeroStream,
+}
 
-        let listener = self.listeners[0];
+const DECODE_ERROR_KINDS: usize = 7;
 
-        let methods = self.methods.clone();
-        let default = self.thread_count_default;
-        let min = self.thread_count_min;
-        let max = self.thread_count_max;
-        let service_quit = self.quit.clone();
-        let monitor_fd = self.monitor_fd.0;
+impl DecodeErrorKind {
+    const ALL: [DecodeErrorKind; DECODE_ERROR_KINDS] = [
+        DecodeErrorKind::Oversized,
+        DecodeErrorKind::Batch,
+        DecodeErrorKind::Envelope,
+        DecodeErrorKind::Payload,
+        DecodeErrorKind::Length,
+        DecodeErrorKind::UnknownType,
+        DecodeErrorKind::ZeroStream,
+    ];
+}
 
-        if let Err(e) = fcntl(listener, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) {
-            return Err(Error::Others(format!(
-                "failed to set listener fd: {} as non block: {}",
-                listener, e
-            )));
+impl std::fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeErrorKind::Oversized => write!(f, "oversized"),
+            DecodeErrorKind::Batch => write!(f, "batch"),
+            DecodeErrorKind::Envelope => write!(f, "envelope"),
+            DecodeErrorKind::Payload => write!(f, "payload"),
+            DecodeErrorKind::Length => write!(f, "length"),
+            DecodeErrorKind::UnknownType => write!(f, "unknown_type"),
+            DecodeErrorKind::ZeroStream => write!(f, "zero_stream"),
         }
+    }
+}
 
-        let handler = thread::Builder::new()
-            .name("listener_loop".into())
-            .spawn(move || {
-                listen(listener, 10)
-                    .map_err(|e| Error::Socket(e.to_string()))
-                    .unwrap();
-
-                let (reaper_tx, reaper_rx) = channel();
-                let reaper_connections = connections.clone();
-
-                let reaper = thread::Builder::new()
-                    .name("reaper".into())
-                    .spawn(move || {
-                        for fd in reaper_rx.iter() {
-                            reaper_connections
-                                .lock()
-                                .unwrap()
-                                .remove(&fd)
-                                .map(|mut cn| {
-                                    cn.handler.take().map(|handler| handler.join().unwrap())
-                                });
-                        }
-                    })
-                    .unwrap();
-
-                loop {
-                    if service_quit.load(Ordering::SeqCst) {
-                        break;
-                    }
-
-                    let mut fd_set = FdSet::new();
-                    fd_set.insert(listener);
-                    fd_set.insert(monitor_fd);
-
-                    match select(
-                        Some(fd_set.highest().unwrap() + 1),
-                        &mut fd_set,
-                        None,
-                        None,
-                        None,
-                    ) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            if e == nix::Error::from(nix::errno::Errno::EINTR) {
-                                continue;
-                            } else {
-                                break;
-                            }
-                        }
-                    }
-
-                    if fd_set.contains(monitor_fd) || !fd_set.contains(listener) {
-                        continue;
-                    }
+/// What is wrong with the header of a frame a server read, if anything.
+fn check_header(mh: &MessageHeader) -> Option<(DecodeErrorKind, String)> {
+    match mh.type_ {
+        MESSAGE_TYPE_REQUEST if mh.stream_id == 0 => Some((
+            DecodeErrorKind::ZeroStream,
+            "request on stream 0".to_string(),
+        )),
+        MESSAGE_TYPE_REQUEST
+        | MESSAGE_TYPE_RESPONSE
+        | MESSAGE_TYPE_PROGRESS
+        | MESSAGE_TYPE_HELLO
+        | MESSAGE_TYPE_BATCH
+        | MESSAGE_TYPE_GOAWAY
+        | MESSAGE_TYPE_IDENTITY
+        | MESSAGE_TYPE_CANCEL
+        | MESSAGE_TYPE_DATA => None,
+        t => Some((
+            DecodeErrorKind::UnknownType,
+            format!("unknown message type {:#x}", t),
+        )),
+    }
+}
 
-                    if service_quit.load(Ordering::SeqCst) {
-                        break;
-                    }
+/// Why a frame was refused once its header was read. Its body is read
+/// and dropped, unless its length is absurd, not held.
+enum Refusal {
+    /// Longer than the maximum message size, with the error saying so.
+    Oversized(Error),
+    /// The header itself is wrong.
+    Malformed(DecodeErrorKind, String),
+    /// The header filter failed the request with this error.
+    Filtered(Error),
+}
 
-                    let fd = match accept4(listener, SockFlag::SOCK_CLOEXEC) {
-                        Ok(fd) => fd,
-                        Err(_e) => break,
-                    };
+/// A request the server could not decode, as passed to the
+/// [`ServerBuilder::set_on_decode_error`] callback.
+#[derive(Clone, Debug)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    /// The connection the request arrived on.
+    pub connection: RawFd,
+    /// The stream of the request, or of the frame which carried it.
+    pub stream_id: u32,
+    /// The method called, once known.
+    pub method: Option<String>,
+    pub message: String,
+}
 
-                    let methods = methods.clone();
-                    let quit = Arc::new(AtomicBool::new(false));
-                    let child_quit = quit.clone();
-                    let reaper_tx_child = reaper_tx.clone();
-
-                    let handler = thread::Builder::new()
-                        .name("client_handler".into())
-                        .spawn(move || {
-                            debug!("Got new client");
-                            // Start response thread
-                            let quit_res = child_quit.clone();
-                            let (res_tx, res_rx): (
-                                Sender<(MessageHeader, Vec<u8>)>,
-                                Receiver<(MessageHeader, Vec<u8>)>,
-                            ) = channel();
-                            let handler = thread::spawn(move || {
-                                for r in res_rx.iter() {
-                                    info!("response thread get {:?}", r);
-                                    if let Err(e) = write_message(fd, r.0, r.1) {
-                                        info!("write_message got {:?}", e);
-                                        quit_res.store(true, Ordering::SeqCst);
-                                        break;
-                                    }
-                                }
-
-                                trace!("response thread quit");
-                            });
-
-                            let (control_tx, control_rx): (SyncSender<()>, Receiver<()>) =
-                                sync_channel(0);
-                            let ts = ThreadS {
-                                fd,
-                                fdlock: &Arc::new(Mutex::new(())),
-                                wtc: &Arc::new(AtomicUsize::new(0)),
-                                methods: &methods,
-                                res_tx: &res_tx,
-                                control_tx: &control_tx,
-                                quit: &child_quit,
-                                default,
-                                min,
-                                max,
-                            };
-                            start_method_handler_threads(ts.default, &ts);
+type DecodeErrorHook = Arc<dyn Fn(&DecodeError) -> DecodeErrorPolicy + Send + Sync>;
 
-                            while !child_quit.load(Ordering::SeqCst) {
-                                check_method_handler_threads(&ts);
-                                if control_rx.recv().is_err() {
-                                    break;
-                                }
-                            }
+/// Per-connection state shared by its threads.
+struct ConnectionState {
+    data: Option<ConnectionData>,
+    close_reason: Mutex<Option<CloseReason>>,
+    // no more requests will be read, but replies are still sent
+    read_closed: AtomicBool,
+    client_info: Mutex<Option<Arc<PeerInfo>>>,
+    // requests handed to their handler
+    served: Arc<AtomicU64>,
+    // the same, on any connection of the server
+    served_total: Arc<AtomicU64>,
+    listener: Option<String>,
+    // connections of the server closed over a frame written in part
+    torn_writes: Arc<AtomicUsize>,
+    gate: Arc<Gate>,
+    drain: Arc<Drain>,
+    timeouts: PhaseTimeouts,
+    // the streaming calls of the client still sending messages
+    streams: Arc<InboundStreams>,
+}
 
-                            // drop the res_tx, thus the res_rx would get terminated notification.
-                            drop(res_tx);
-                            handler.join().unwrap_or(());
-                            close(fd).unwrap_or(());
-                            reaper_tx_child.send(fd).unwrap();
+impl ConnectionState {
+    /// Record why the connection is closing, unless a reason is known.
+    fn closing(&self, reason: CloseReason) {
+        self.close_reason.lock().unwrap().get_or_insert(reason);
+    }
 
-                            info!("client thread quit");
-                        })
-                        .unwrap();
+    /// Count writing to connection `fd` failing with `e` if it timed out,
+    /// and record the timeout as the reason for closing.
+    fn write_failed(&self, fd: RawFd, e: &Error) {
+        if matches!(e, Error::Socket(m) if m == SOCK_WRITE_TIMEOUT) {
+            self.timeouts.ran_out(fd, Phase::Write, None);
+            self.closing(CloseReason::Timeout(Phase::Write));
+        }
+    }
 
-                    let mut cns = connections.lock().unwrap();
-                    cns.insert(
-                        fd,
-                        Connection {
-                            fd,
-                            handler: Some(handler),
-                            quit: quit.clone(),
-                        },
-                    );
-                } // end loop
+    /// Close connection `fd` once writing a frame to it failed midway. The
+    /// client would read the next frame from the middle of this one, so it
+    /// is better off seeing the connection close.
+    fn torn(&self, fd: RawFd, e: &Error) {
+        warn!(target: EVENT_TARGET, "torn_write fd={} error={:?}", fd, e);
+        self.torn_writes.fetch_add(1, Ordering::SeqCst);
+        self.closing(CloseReason::WriteError(format!(
+            "frame written in part: {:?}",
+            e
+        )));
+        self.read_closed.store(true, Ordering::SeqCst);
+        socket::shutdown(fd, Shutdown::Both).unwrap_or(());
+    }
+}
 
-                // notify reaper thread to exit.
-                drop(reaper_tx);
-                reaper.join().unwrap();
-                info!("ttrpc server stopped");
-            })
-            .unwrap();
+/// Holds back the requests read while the server is quiesced, see
+/// [`Server::quiesce`].
+#[derive(Default)]
+struct Gate {
+    closed: Mutex<bool>,
+    opened: Condvar,
+}
 
-        self.handler = Some(handler);
+impl Gate {
+    /// Wait for the gate to open, counting the request held meanwhile.
+    fn pass(&self, drain: &Drain) {
+        let closed = self.closed.lock().unwrap();
+        if !*closed {
+            return;
+        }
+        drain.held.fetch_add(1, Ordering::SeqCst);
+        drop(self.opened.wait_while(closed, |c| *c).unwrap());
+        drain.held.fetch_sub(1, Ordering::SeqCst);
+    }
 
-        Ok(())
+    fn set(&self, closed: bool) {
+        *self.closed.lock().unwrap() = closed;
+        if !closed {
+            self.opened.notify_all();
+        }
     }
+}
 
-    pub fn shutdown(mut self) {
-        let connections = self.connections.lock().unwrap();
+/// Where a connection stands while the server is quiesced.
+#[derive(Default)]
+struct Drain {
+    // requests read and held back at the gate
+    held: AtomicUsize,
+    // handlers running
+    running: AtomicUsize,
+    // flush markers the response thread went past
+    flushed: Mutex<u64>,
+    flush: Condvar,
+    // nothing left to write since the server was quiesced
+    drained: AtomicBool,
+}
 
-        self.quit.store(true, Ordering::SeqCst);
-        close(self.monitor_fd.1).unwrap_or_else(|e| {
-            warn!(
-                "failed to close notify fd: {} with error: {}",
-                self.monitor_fd.1, e
-            )
-        });
+/// Queued for the response thread to tell when it wrote everything queued
+/// before. It is not written itself.
+const FLUSH_MARKER: MessageHeader = MessageHeader {
+    length: 0,
+    stream_id: 0,
+    type_: 0,
+    flags: 0,
+};
 
-        for (_fd, c) in connections.iter() {
-            c.close();
+impl Drain {
+    /// Wait until `deadline` for the handlers of the connection to return,
+    /// its replies to be sent and written out through `pending`.
+    fn wait(&self, pending: &PendingReplies, deadline: Instant) -> bool {
+        while self.running.load(Ordering::SeqCst) > 0 || !pending.is_empty() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
         }
-
-        // release connections's lock, since the following handler.join()
-        // would wait on the other thread's exit in which would take the lock.
-        drop(connections);
-
-        if let Some(handler) = self.handler.take() {
-            handler.join().unwrap();
+        let tx = match pending.sender() {
+            Ok(tx) => tx,
+            // closing, so nothing more is written
+            Err(_) => return true,
+        };
+        let mut flushed = self.flushed.lock().unwrap();
+        let wanted = *flushed + 1;
+        if tx.send((FLUSH_MARKER, Vec::new())).is_err() {
+            return true;
         }
+        while *flushed < wanted {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return false;
+            }
+            flushed = self.flush.wait_timeout(flushed, left).unwrap().0;
+        }
+        true
     }
-}
 
-pub struct TtrpcContext {
-    pub fd: RawFd,
-    pub mh: MessageHeader,
-    pub res_tx: Sender<(MessageHeader, Vec<u8>)>,
+    fn flushed(&self, markers: usize) {
+        *self.flushed.lock().unwrap() += markers as u64;
+        self.flush.notify_all();
+    }
 }
 
-pub trait MethodHandler {
-    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()>;
+/// Where a connection stands while the server is quiesced, see
+/// [`Server::quiesce_status`].
+#[derive(Clone, Debug)]
+pub struct QuiesceStatus {
+    /// The fd of the connection.
+    pub connection: RawFd,
+    /// Requests read from the connection and held back until the server
+    /// resumes.
+    pub held: usize,
+    /// Requests whose handler is still running.
+    pub running: usize,
+    /// Requests still waiting for their reply.
+    pub pending: usize,
+    /// Whether every reply was written out, with no bytes left in flight
+    /// on the connection.
+    pub drained: bool,
 }
 
-pub fn response_to_channel(
-    stream_id: u32,
+pub(crate) fn report_panic(
+    fd: RawFd,
+    method: Option<&str>,
+    panic_handler: &Option<PanicHandler>,
+    payload: Box<dyn Any + Send>,
+) {
+    let message = if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    };
+    let p = ThreadPanic {
+        thread: thread::current().name().unwrap_or("<unnamed>").to_string(),
+        fd,
+        method: method.map(|m| m.to_string()),
+        message,
+    };
+    error!(
+        "thread {} (fd {}) panicked serving {:?}: {}",
+        p.thread, p.fd, p.method, p.message
+    );
+    if let Some(f) = panic_handler {
+        f(&p);
+    }
+}
+
+/// Spawn a named thread whose panics are reported instead of unwinding
+/// silently into a `JoinHandle` nobody joins.
+fn spawn_guarded<F>(
+    name: String,
+    fd: RawFd,
+    panic_handler: Option<PanicHandler>,
+    f: F,
+) -> JoinHandle<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                report_panic(fd, None, &panic_handler, e);
+            }
+        })
+        .unwrap()
+}
+
+/// The open connections with the number of requests each one served, and
+/// whether any request is still being handled or waiting for its reply, to
+/// tell whether a server is idle, see [`ServerBuilder::set_idle_timeout`].
+fn activity(connections: &Mutex<HashMap<RawFd, Connection>>) -> (Vec<(RawFd, u64)>, bool) {
+    let connections = connections.lock().unwrap();
+    let mut served: Vec<(RawFd, u64)> = connections
+        .values()
+        .map(|cn| (cn.fd, cn.served.load(Ordering::Relaxed)))
+        .collect();
+    served.sort_unstable();
+    let busy = connections
+        .values()
+        .any(|cn| cn.drain.running.load(Ordering::SeqCst) > 0 || !cn.pending.is_empty());
+    (served, busy)
+}
+
+struct Connection {
+    fd: RawFd,
+    quit: Arc<AtomicBool>,
+    going_away: Arc<AtomicBool>,
+    handler: Option<JoinHandle<()>>,
+    pending: Arc<PendingReplies>,
+    served: Arc<AtomicU64>,
+    drain: Arc<Drain>,
+}
+
+impl Connection {
+    fn close(&self) {
+        self.going_away.store(true, Ordering::SeqCst);
+        self.quit.store(true, Ordering::SeqCst);
+        // in case the connection had closed
+        socket::shutdown(self.fd, Shutdown::Read).unwrap_or(());
+    }
+}
+
+struct ThreadS<'a> {
+    fd: RawFd,
+    fdlock: &'a Arc<Mutex<()>>,
+    fd_open: &'a Arc<RwLock<bool>>,
+    pool: &'a Arc<WorkerPool>,
+    quit: &'a Arc<AtomicBool>,
+    methods: &'a Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
+    policy: &'a Arc<MethodPolicy>,
+    in_flight: &'a Arc<AtomicUsize>,
+    res_tx: &'a Sender<(MessageHeader, Vec<u8>)>,
+    wlock: &'a Arc<Mutex<()>>,
+    pending: &'a Arc<PendingReplies>,
+    peer_batches: &'a Arc<AtomicBool>,
+    identity: &'a Arc<Mutex<Option<Arc<Vec<u8>>>>>,
+    state: &'a Arc<ConnectionState>,
+    journal: &'a Option<Arc<Journal>>,
+    scheduling: &'a Option<Arc<WorkerScheduling>>,
+    panic_handler: &'a Option<PanicHandler>,
+}
+
+type RequestFilter = Arc<dyn Fn(&str, usize) -> Result<()> + Send + Sync>;
+type HeaderFilter = Arc<dyn Fn(&MessageHeader) -> Result<()> + Send + Sync>;
+// fails with the decode error if the payload does not decode
+type Validator = Arc<dyn Fn(&[u8]) -> std::result::Result<Result<()>, String> + Send + Sync>;
+
+/// How a server treats particular methods.
+#[derive(Clone)]
+struct MethodPolicy {
+    inline: HashSet<String>,
+    priority: HashSet<String>,
+    // 0 for no limit
+    max_in_flight: usize,
+    filter: Option<RequestFilter>,
+    header_filter: Option<HeaderFilter>,
+    validators: HashMap<String, Validator>,
+    slow_handler: Option<Duration>,
+    decode_errors: DecodeErrorPolicy,
+    on_decode_error: Option<DecodeErrorHook>,
+    // by kind
+    decode_error_counts: Arc<[AtomicUsize; DECODE_ERROR_KINDS]>,
+    // None for all of them
+    services: Option<HashSet<String>>,
+    max_message_size: usize,
+    // None to read every frame whole
+    decode_above: Option<usize>,
+    acl: Option<Arc<Acl>>,
+    error_detail: ErrorDetail,
+    method_hashes: HashMap<String, u64>,
+    tenants: Arc<Tenants>,
+    timeouts: PhaseTimeouts,
+    traces: Arc<Traces>,
+    paths: Arc<MethodPaths>,
+}
+
+impl Default for MethodPolicy {
+    fn default() -> Self {
+        MethodPolicy {
+            inline: HashSet::new(),
+            priority: HashSet::new(),
+            max_in_flight: 0,
+            filter: None,
+            header_filter: None,
+            validators: HashMap::new(),
+            slow_handler: None,
+            decode_errors: DecodeErrorPolicy::default(),
+            on_decode_error: None,
+            decode_error_counts: Arc::default(),
+            services: None,
+            max_message_size: MESSAGE_LENGTH_MAX,
+            decode_above: None,
+            acl: None,
+            error_detail: ErrorDetail::default(),
+            method_hashes: HashMap::new(),
+            tenants: Arc::default(),
+            timeouts: PhaseTimeouts::default(),
+            traces: Arc::default(),
+            paths: Arc::default(),
+        }
+    }
+}
+
+type TenantMethods = HashMap<String, Arc<dyn MethodHandler + Send + Sync>>;
+
+/// The methods of the tenants of a server, see [`Server::add_tenant`].
+#[derive(Default)]
+struct Tenants {
+    // by identity prefix, longest prefix first
+    tables: RwLock<Vec<(Vec<u8>, TenantMethods)>>,
+}
+
+impl Tenants {
+    fn add(&self, prefix: &[u8], methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>) {
+        debug!("adding tenant {}", String::from_utf8_lossy(prefix));
+        let methods: TenantMethods = methods
+            .into_iter()
+            .map(|(path, m)| (path, Arc::from(m)))
+            .collect();
+        let mut tables = self.tables.write().unwrap();
+        match tables.iter_mut().find(|(p, _)| p == prefix) {
+            Some((_, table)) => table.extend(methods),
+            None => {
+                tables.push((prefix.to_vec(), methods));
+                tables.sort_by_key(|(p, _)| Reverse(p.len()));
+            }
+        }
+    }
+
+    fn remove(&self, prefix: &[u8]) -> bool {
+        debug!("removing tenant {}", String::from_utf8_lossy(prefix));
+        let mut tables = self.tables.write().unwrap();
+        let before = tables.len();
+        tables.retain(|(p, _)| p != prefix);
+        tables.len() != before
+    }
+
+    /// The handler of `path` of the tenant a connection with `identity`
+    /// belongs to, if it has one.
+    fn route(
+        &self,
+        identity: Option<&[u8]>,
+        path: &str,
+    ) -> Option<Arc<dyn MethodHandler + Send + Sync>> {
+        let identity = identity?;
+        let tables = self.tables.read().unwrap();
+        let (_, table) = tables.iter().find(|(p, _)| identity.starts_with(p))?;
+        table.get(path).cloned()
+    }
+}
+
+/// Which calls [`Server::trace`] logs in detail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceTarget {
+    /// The calls to the method at this path, e.g. `/grpc.Health/Check`.
+    Method(String),
+    /// The calls on the connection with this fd.
+    Connection(RawFd),
+}
+
+struct Trace {
+    target: TraceTarget,
+    percent: u64,
+    until: Instant,
+    seen: AtomicU64,
+}
+
+/// The calls being traced, see [`Server::trace`].
+#[derive(Default)]
+struct Traces {
+    traces: RwLock<Vec<Trace>>,
+}
+
+impl Traces {
+    fn start(&self, target: TraceTarget, percent: u32, duration: Duration) {
+        debug!("tracing {:?} for {:?}", target, duration);
+        let mut traces = self.traces.write().unwrap();
+        let now = Instant::now();
+        traces.retain(|t| t.target != target && t.until > now);
+        traces.push(Trace {
+            target,
+            percent: u64::from(percent.min(100)),
+            until: now + duration,
+            seen: AtomicU64::new(0),
+        });
+    }
+
+    fn stop(&self, target: &TraceTarget) -> bool {
+        let mut traces = self.traces.write().unwrap();
+        let now = Instant::now();
+        let before = traces.iter().filter(|t| t.until > now).count();
+        traces.retain(|t| t.target != *target && t.until > now);
+        traces.len() != before
+    }
+
+    /// Whether to trace the call to `path` on connection `fd`: `percent`
+    /// out of every hundred calls a trace matches are, until it expires.
+    fn sampled(&self, fd: RawFd, path: &str) -> bool {
+        let traces = self.traces.read().unwrap();
+        let now = Instant::now();
+        traces.iter().any(|t| {
+            let matches = match &t.target {
+                TraceTarget::Method(m) => m == path,
+                TraceTarget::Connection(c) => *c == fd,
+            };
+            if !matches || t.until <= now {
+                return false;
+            }
+            let n = t.seen.fetch_add(1, Ordering::Relaxed);
+            (n + 1) * t.percent / 100 != n * t.percent / 100
+        })
+    }
+}
+
+/// A call picked by [`Server::trace`], logged as it goes.
+struct TracedCall {
+    fd: RawFd,
+    stream_id: u32,
+    path: String,
+    started: Instant,
+}
+
+impl TracedCall {
+    /// Log the call of `req` read at `arrived`, about to be handled, and
+    /// its reply once `sink` sends it.
+    fn start(
+        fd: RawFd,
+        path: &str,
+        req: &Request,
+        sink: &ResponseSink,
+        arrived: Instant,
+    ) -> TracedCall {
+        let call = TracedCall {
+            fd,
+            stream_id: sink.stream_id(),
+            path: path.to_string(),
+            started: Instant::now(),
+        };
+        info!(
+            target: EVENT_TARGET,
+            "trace_request fd={} stream={} method={} bytes={} queue_us={} payload=\"{}\"",
+            fd,
+            call.stream_id,
+            path,
+            req.payload.len(),
+            call.started.duration_since(arrived).as_micros(),
+            escape_payload(&req.payload)
+        );
+        let (stream_id, path) = (call.stream_id, call.path.clone());
+        sink.on_complete(move |res| match res {
+            Some(res) => info!(
+                target: EVENT_TARGET,
+                "trace_response fd={} stream={} method={} code={:?} bytes={} total_us={} payload=\"{}\"",
+                fd,
+                stream_id,
+                path,
+                res.get_status().code,
+                res.payload.len(),
+                arrived.elapsed().as_micros(),
+                escape_payload(&res.payload)
+            ),
+            None => info!(
+                target: EVENT_TARGET,
+                "trace_unanswered fd={} stream={} method={} total_us={}",
+                fd,
+                stream_id,
+                path,
+                arrived.elapsed().as_micros()
+            ),
+        });
+        call
+    }
+
+    /// Log the handler returning `result`.
+    fn returned(&self, result: &Result<()>) {
+        info!(
+            target: EVENT_TARGET,
+            "trace_handler fd={} stream={} method={} handler_us={} result={:?}",
+            self.fd,
+            self.stream_id,
+            self.path,
+            self.started.elapsed().as_micros(),
+            result
+        );
+    }
+}
+
+impl MethodPolicy {
+    /// The policy of the connections accepted on a listener with `conf`.
+    fn for_listener(&self, conf: &ListenerConfig) -> MethodPolicy {
+        MethodPolicy {
+            services: conf.services.clone(),
+            max_message_size: conf
+                .max_message_size
+                .map_or(self.max_message_size, |m| m.min(MESSAGE_LENGTH_MAX)),
+            ..self.clone()
+        }
+    }
+
+    fn serves(&self, service: &str) -> bool {
+        self.services.as_ref().is_none_or(|s| s.contains(service))
+    }
+
+    /// The limit on concurrent requests to advertise to clients.
+    fn max_streams(&self) -> Option<usize> {
+        Some(self.max_in_flight).filter(|&max| max > 0)
+    }
+
+    /// Answer the methods client `fd` named in its HELLO frame with their
+    /// hashes here, logging those which differ from the client's.
+    fn hello_methods(
+        &self,
+        fd: RawFd,
+        methods: &HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
+        identity: Option<&[u8]>,
+        named: &[(String, MethodHash)],
+    ) -> Vec<(String, MethodHash)> {
+        named
+            .iter()
+            .map(|(path, theirs)| {
+                let service = path
+                    .trim_start_matches('/')
+                    .rsplit_once('/')
+                    .map_or("", |(s, _)| s);
+                let served =
+                    methods.contains_key(path) || self.tenants.route(identity, path).is_some();
+                let ours = if !served || !self.serves(service) {
+                    MethodHash::Missing
+                } else {
+                    self.method_hashes
+                        .get(path)
+                        .map_or(MethodHash::Unknown, |h| MethodHash::Hash(*h))
+                };
+                if ours != *theirs && ours != MethodHash::Unknown {
+                    warn!(
+                        target: EVENT_TARGET,
+                        "method_mismatch fd={} method={} client={} server={}",
+                        fd,
+                        path,
+                        theirs,
+                        ours
+                    );
+                }
+                (path.clone(), ours)
+            })
+            .collect()
+    }
+
+    /// Read a frame from `fd`, refusing it on its header where it can, so
+    /// that no buffer is allocated for the body of a refused frame.
+    fn read_frame(&self, fd: RawFd) -> Result<(MessageHeader, std::result::Result<Body, Refusal>)> {
+        let timeout = self.timeouts.get(Phase::Read);
+        let mut deadline = None;
+        let mh = read_message_header_within(fd, timeout, &mut deadline)?;
+        trace!("Got Message header {:?}", mh);
+
+        let len = mh.length as usize;
+        let refusal = if len > self.max_message_size {
+            None
+        } else if let Some((kind, message)) = check_header(&mh) {
+            Some(Refusal::Malformed(kind, message))
+        } else if mh.type_ == MESSAGE_TYPE_REQUEST {
+            let filter = self.header_filter.as_ref();
+            filter.and_then(|f| f(&mh).err()).map(Refusal::Filtered)
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            discard_within(fd, len, timeout, &mut deadline)?;
+            return Ok((mh, Err(refusal)));
+        }
+
+        let decode_above = self.decode_above.unwrap_or(usize::MAX);
+        let body = read_body_decoding(
+            fd,
+            &mh,
+            self.max_message_size,
+            decode_above,
+            timeout,
+            &mut deadline,
+        )?;
+        Ok((mh, body.map_err(Refusal::Oversized)))
+    }
+
+    /// Run the request filter and the validator of `path` on `payload`,
+    /// failing with the decode error if the validator cannot decode it.
+    fn check(&self, path: &str, payload: &[u8]) -> std::result::Result<Result<()>, String> {
+        if let Some(filter) = self.filter.as_ref() {
+            if let Err(e) = filter(path, payload.len()) {
+                return Ok(Err(e));
+            }
+        }
+        match self.validators.get(path) {
+            Some(validate) => validate(payload),
+            None => Ok(Ok(())),
+        }
+    }
+
+    /// Report a request which did not decode, failing if the connection
+    /// is to be closed over it, else returning whether to answer it.
+    fn undecodable(&self, state: &ConnectionState, e: &DecodeError) -> Result<bool> {
+        warn!(
+            target: EVENT_TARGET,
+            "decode_error fd={} kind={:?} stream={} method={} error={}",
+            e.connection,
+            e.kind,
+            e.stream_id,
+            e.method.as_deref().unwrap_or("-"),
+            e.message
+        );
+        self.decode_error_counts[e.kind as usize].fetch_add(1, Ordering::SeqCst);
+        let policy = match self.on_decode_error.as_ref() {
+            Some(decide) => decide(e),
+            None => self.decode_errors,
+        };
+        if policy != DecodeErrorPolicy::Close {
+            return Ok(policy == DecodeErrorPolicy::Respond);
+        }
+        let reason = format!("undecodable request: {}", e.message);
+        state.closing(CloseReason::ProtocolError(reason.clone()));
+        Err(Error::Others(reason))
+    }
+}
+
+/// Counts a running handler against the connection's concurrency limit.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn enter(count: &'a AtomicUsize, max: usize) -> Option<InFlight<'a>> {
+        let c = count.fetch_add(1, Ordering::SeqCst) + 1;
+        let entered = InFlight(count);
+        if max > 0 && c > max {
+            return None;
+        }
+        Some(entered)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn start_method_handler_thread(
+    fd: RawFd,
+    fdlock: Arc<Mutex<()>>,
+    fd_open: Arc<RwLock<bool>>,
+    pool: Arc<WorkerPool>,
+    quit: Arc<AtomicBool>,
+    methods: Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
+    policy: Arc<MethodPolicy>,
+    in_flight: Arc<AtomicUsize>,
+    res_tx: Sender<(MessageHeader, Vec<u8>)>,
+    wlock: Arc<Mutex<()>>,
+    pending: Arc<PendingReplies>,
+    peer_batches: Arc<AtomicBool>,
+    identity: Arc<Mutex<Option<Arc<Vec<u8>>>>>,
+    state: Arc<ConnectionState>,
+    journal: Option<Arc<Journal>>,
+    scheduling: Option<Arc<WorkerScheduling>>,
+    panic_handler: Option<PanicHandler>,
+) {
+    let worker = pool.spawned();
+    let ph = panic_handler.clone();
+    spawn_guarded(format!("method_handler-{}", fd), fd, ph, move || {
+        let _worker = worker;
+        if let Some(Err(e)) = scheduling.as_ref().map(|s| s.apply()) {
+            warn!("failed to set scheduling of method handler: {:?}", e);
+        }
+
+        // answers a request which did not decode, unless the connection
+        // is to be closed over it
+        let reject = |mh: &MessageHeader, e: DecodeError| -> Result<()> {
+            let answer = policy.undecodable(&state, &e)?;
+            if !answer
+                || mh.type_ != MESSAGE_TYPE_REQUEST
+                || mh.stream_id == 0
+                || mh.flags & FLAG_NO_REPLY != 0
+            {
+                return Ok(());
+            }
+            let mut res = Response::new();
+            res.set_status(get_status(Code::INVALID_ARGUMENT, e.message));
+            response_to_channel(mh.stream_id, res, res_tx.clone())
+        };
+        let refuse = |mh: &MessageHeader, e: Error| -> Result<()> {
+            if mh.flags & FLAG_NO_REPLY != 0 {
+                debug!("dropping notification on stream {}: {:?}", mh.stream_id, e);
+                return Ok(());
+            }
+            let mut res = Response::new();
+            res.set_status(e.to_status());
+            response_to_channel(mh.stream_id, res, res_tx.clone())
+        };
+        let decode_error = |kind, mh: &MessageHeader, method: Option<&str>, message| DecodeError {
+            kind,
+            connection: fd,
+            stream_id: mh.stream_id,
+            method: method.map(|m| m.to_string()),
+            message,
+        };
+
+        let dispatch = |mut mh: MessageHeader,
+                        body: Body,
+                        read_at: Instant,
+                        waiting: &mut bool|
+         -> Result<()> {
+            // those of frames out of a batch; the others were checked
+            // as they were read
+            if let Some((kind, message)) = check_header(&mh) {
+                return reject(&mh, decode_error(kind, &mh, None, message));
+            }
+            if mh.type_ == MESSAGE_TYPE_CANCEL {
+                debug!("client cancelled stream {} on fd {}", mh.stream_id, fd);
+                pending.cancel(mh.stream_id);
+                state.streams.close(mh.stream_id);
+                return Ok(());
+            }
+            // data frames were routed to their stream as they were read
+            if mh.type_ != MESSAGE_TYPE_REQUEST {
+                return Ok(());
+            }
+            // dropped, and so forgotten, unless the handler gets to it
+            let inbound = if mh.flags & FLAG_REMOTE_OPEN != 0 {
+                state.streams.take(mh.stream_id)
+            } else {
+                None
+            };
+            let no_reply = mh.flags & FLAG_NO_REPLY != 0;
+            let (extensions, req) = match body {
+                Body::Frame(buf) => {
+                    let (extensions, buf) = match extension::split(&mut mh, buf) {
+                        Ok(split) => split,
+                        Err(x) => {
+                            let message = x.to_status().message;
+                            let e = decode_error(DecodeErrorKind::Envelope, &mh, None, message);
+                            return reject(&mh, e);
+                        }
+                    };
+                    let mut s = CodedInputStream::from_bytes(&buf);
+                    let mut req = Request::new();
+                    if let Err(x) = req.merge_from(&mut s) {
+                        let e = decode_error(DecodeErrorKind::Envelope, &mh, None, x.to_string());
+                        return reject(&mh, e);
+                    }
+                    (extensions, req)
+                }
+                Body::Request(req) => (Extensions::new(), req),
+                Body::Undecodable(message) => {
+                    let e = decode_error(DecodeErrorKind::Envelope, &mh, None, message);
+                    return reject(&mh, e);
+                }
+            };
+            trace!("Got Message request {:?}", req);
+
+            let mut metadata = metadata::from_pairs(req.get_metadata());
+            if metadata::get(&metadata, REQUEST_ID_KEY).is_none() {
+                metadata.insert(REQUEST_ID_KEY.to_string(), vec![metadata::new_request_id()]);
+            }
+
+            let path = policy.paths.get(&req.service, &req.method);
+            if let Some(j) = journal.as_ref() {
+                j.request(fd, mh.stream_id, &path, &req.payload);
+            }
+            let denied = policy.acl.as_ref().is_some_and(|acl| {
+                let identity = identity.lock().unwrap().clone();
+                let caller = Caller {
+                    fd,
+                    listener: state.listener.as_deref(),
+                    identity: identity.as_ref().map(|id| id.as_slice()),
+                    metadata: &metadata,
+                };
+                !acl.permits(&caller, &path)
+            });
+            if denied {
+                info!(
+                    target: EVENT_TARGET,
+                    "acl_denied fd={} method={}",
+                    fd,
+                    path
+                );
+            }
+            let reply = |res: Response| {
+                if let Some(j) = journal.as_ref() {
+                    j.response(
+                        fd,
+                        mh.stream_id,
+                        res.get_status().code,
+                        res.compute_size() as usize,
+                    );
+                }
+                response_to_channel(mh.stream_id, res, res_tx.clone())
+            };
+            if denied {
+                if no_reply {
+                    return Ok(());
+                }
+                let status =
+                    get_status(Code::PERMISSION_DENIED, format!("{} is not allowed", path));
+                let mut res = Response::new();
+                res.set_status(status);
+                echo_request_id(&metadata, &mut res);
+                return reply(res);
+            }
+            let id = identity.lock().unwrap().clone();
+            let routed = policy
+                .tenants
+                .route(id.as_ref().map(|id| id.as_slice()), &path);
+            let method: &(dyn MethodHandler + Send + Sync);
+            if let Some(x) = routed
+                .as_deref()
+                .or_else(|| methods.get(&*path).map(|m| &**m))
+                .filter(|_| policy.serves(&req.service))
+            {
+                method = x;
+            } else if no_reply {
+                debug!("dropping notification for {}, which does not exist", path);
+                return Ok(());
+            } else {
+                let status = get_status(Code::INVALID_ARGUMENT, format!("{} does not exist", path));
+                let mut res = Response::new();
+                res.set_status(status);
+                echo_request_id(&metadata, &mut res);
+                return reply(res);
+            }
+            let checked = match policy.check(&path, &req.payload) {
+                Ok(checked) => checked,
+                Err(x) => {
+                    let e = decode_error(DecodeErrorKind::Payload, &mh, Some(&path), x);
+                    return reject(&mh, e);
+                }
+            };
+            if let Err(e) = checked {
+                if no_reply {
+                    debug!("dropping notification for {}: {:?}", path, e);
+                    return Ok(());
+                }
+                let mut res = Response::new();
+                res.set_status(e.to_status());
+                echo_request_id(&metadata, &mut res);
+                return reply(res);
+            }
+            let _in_flight = if policy.priority.contains(&*path) {
+                None
+            } else {
+                match InFlight::enter(&in_flight, policy.max_in_flight) {
+                    Some(x) => Some(x),
+                    None if no_reply => {
+                        debug!("dropping notification for {}, too many requests", path);
+                        return Ok(());
+                    }
+                    None => {
+                        let status = get_status(
+                            Code::RESOURCE_EXHAUSTED,
+                            "too many concurrent requests".to_string(),
+                        );
+                        let mut res = Response::new();
+                        res.set_status(status);
+                        echo_request_id(&metadata, &mut res);
+                        return reply(res);
+                    }
+                }
+            };
+            trace!(
+                target: EVENT_TARGET,
+                "requests_in_flight fd={} count={}",
+                fd,
+                in_flight.load(Ordering::SeqCst)
+            );
+            if policy.timeouts.passed(Phase::Queue, read_at) {
+                policy.timeouts.ran_out(fd, Phase::Queue, Some(&path));
+                if no_reply {
+                    return Ok(());
+                }
+                let status = get_status(
+                    Code::UNAVAILABLE,
+                    format!("{} waited too long to be handled", path),
+                );
+                let mut res = Response::new();
+                res.set_status(status);
+                echo_request_id(&metadata, &mut res);
+                return reply(res);
+            }
+            let cancelled = if no_reply {
+                Arc::new(AtomicBool::new(false))
+            } else {
+                pending.begin(mh.stream_id, req.timeout_nano, &path, req.payload.len())
+            };
+            if cancelled.load(Ordering::SeqCst) {
+                debug!("skipping {} cancelled before it started", path);
+                return Ok(());
+            }
+            if let Some(t) = policy.timeouts.get(Phase::Handler).filter(|_| !no_reply) {
+                pending.limit(mh.stream_id, t);
+                // let the connection watch the time limit
+                pool.wake();
+            }
+            let direct = if policy.inline.contains(&*path) {
+                Some(DirectWrite {
+                    fd,
+                    fd_open: fd_open.clone(),
+                    wlock: wlock.clone(),
+                    state: state.clone(),
+                })
+            } else {
+                if *waiting {
+                    *waiting = false;
+                    pool.leave();
+                }
+                None
+            };
+            let sink = ResponseSink {
+                inner: Arc::new(SinkInner {
+                    stream_id: mh.stream_id,
+                    no_reply,
+                    progress_ok: mh.flags & FLAG_PROGRESS_OK != 0,
+                    request_id: metadata::get(&metadata, REQUEST_ID_KEY).map(|s| s.to_string()),
+                    pending: pending.clone(),
+                    journal: journal.clone().map(|j| (j, fd)),
+                    direct,
+                    wake: None,
+                    cancelled: cancelled.clone(),
+                    on_complete: Mutex::new(Vec::new()),
+                    error_detail: policy.error_detail,
+                }),
+            };
+            #[allow(deprecated)]
+            let ctx = TtrpcContext {
+                fd,
+                mh,
+                res_tx: res_tx.clone(),
+                metadata,
+                fd_open: fd_open.clone(),
+                sink: sink.clone(),
+                identity: identity.lock().unwrap().clone(),
+                client_info: state.client_info.lock().unwrap().clone(),
+                connection_data: state.data.clone(),
+                cancelled,
+                path: path.clone(),
+                policy: policy.clone(),
+                state: state.clone(),
+                extensions,
+                inbound: Mutex::new(inbound),
+            };
+            let traced = policy
+                .traces
+                .sampled(fd, &path)
+                .then(|| TracedCall::start(fd, &path, &req, &sink, read_at));
+            state.served.fetch_add(1, Ordering::Relaxed);
+            state.served_total.fetch_add(1, Ordering::Relaxed);
+            let started = Instant::now();
+            state.drain.running.fetch_add(1, Ordering::SeqCst);
+            let caught = panic::catch_unwind(AssertUnwindSafe(|| method.handler(ctx, req)));
+            state.drain.running.fetch_sub(1, Ordering::SeqCst);
+            let result = match caught {
+                Ok(result) => result,
+                Err(e) => {
+                    report_panic(fd, Some(&path), &panic_handler, e);
+                    if let Some(j) = journal.as_ref() {
+                        j.log();
+                    }
+                    let mut res = Response::new();
+                    res.set_status(get_status(
+                        Code::INTERNAL,
+                        format!("{} handler panicked", path),
+                    ));
+                    if sink.is_pending() {
+                        sink.send(res)
+                    } else {
+                        Ok(())
+                    }
+                }
+            };
+            if let Some(t) = traced.as_ref() {
+                t.returned(&result);
+            }
+            let elapsed = started.elapsed();
+            if policy.slow_handler.is_some_and(|t| elapsed >= t) {
+                warn!(
+                    target: EVENT_TARGET,
+                    "slow_handler fd={} method={} elapsed_us={}",
+                    fd,
+                    path,
+                    elapsed.as_micros()
+                );
+            }
+            if result.is_ok() && pending.defer(sink.stream_id()) {
+                // let the connection watch the deadline of the deferred reply
+                pool.wake();
+            }
+            result.map_err(|x| {
+                debug!("method handle {} get error {:?}", path, x);
+                x
+            })
+        };
+
+        while !quit.load(Ordering::SeqCst) {
+            if !pool.enter() {
+                debug!(
+                    target: EVENT_TARGET,
+                    "pool_shrink fd={} waiting={}",
+                    fd,
+                    pool.waiting()
+                );
+                break;
+            }
+
+            let mut result;
+            {
+                let _guard = fdlock.lock().unwrap();
+                if quit.load(Ordering::SeqCst) {
+                    // notify the connection dealing main thread to stop.
+                    pool.wake();
+                    break;
+                }
+                result = policy.read_frame(fd);
+                // record it before the requests following it are read
+                if let Ok((mh, Ok(Body::Frame(buf)))) = result.as_ref() {
+                    if mh.type_ == MESSAGE_TYPE_IDENTITY {
+                        let mut id = identity.lock().unwrap();
+                        if id.is_none() {
+                            *id = Some(Arc::new(buf.clone()));
+                        } else {
+                            debug!("ignoring identity sent again on fd {}", fd);
+                        }
+                    }
+                    // answered before any response, so a client knows
+                    // about the server once its first call returned
+                    if mh.type_ == MESSAGE_TYPE_HELLO && mh.stream_id == 0 {
+                        let info = parse_hello(buf);
+                        info!(
+                            target: EVENT_TARGET,
+                            "client_hello fd={} version={} capabilities={}",
+                            fd,
+                            info.version,
+                            info.capabilities.join(",")
+                        );
+                        let id = identity.lock().unwrap().clone();
+                        let id = id.as_ref().map(|id| id.as_slice());
+                        let answer = policy.hello_methods(fd, &methods, id, &info.methods);
+                        *state.client_info.lock().unwrap() = Some(Arc::new(info));
+                        let max_streams = policy.max_streams();
+                        res_tx
+                            .send(hello_frame_limited(&answer, max_streams))
+                            .unwrap_or(());
+                    }
+                }
+                // so the messages of a stream follow its request in order
+                match result.as_mut() {
+                    Ok((mh, Ok(Body::Frame(buf)))) if mh.type_ == MESSAGE_TYPE_DATA => {
+                        state.streams.deliver(mh, std::mem::take(buf));
+                    }
+                    Ok((mh, Ok(_)))
+                        if mh.type_ == MESSAGE_TYPE_REQUEST
+                            && mh.flags & FLAG_REMOTE_OPEN != 0
+                            && mh.stream_id != 0 =>
+                    {
+                        state.streams.open(mh.stream_id);
+                    }
+                    _ => (),
+                }
+            }
+
+            if let Ok((_, Ok(_))) = result.as_ref() {
+                state.gate.pass(&state.drain);
+            }
+            let read_at = Instant::now();
+
+            if quit.load(Ordering::SeqCst) {
+                // notify the connection dealing main thread to stop.
+                pool.wake();
+                break;
+            }
+
+            let (mh, body) = match result {
+                Ok((mh, Ok(body))) => (mh, body),
+                Ok((mh, Err(refusal))) => {
+                    pool.leave();
+                    let absurd = mh.length as usize > MESSAGE_LENGTH_MAX;
+                    let answered = match refusal {
+                        Refusal::Oversized(x) => {
+                            let kind = if absurd {
+                                DecodeErrorKind::Length
+                            } else {
+                                DecodeErrorKind::Oversized
+                            };
+                            let e = decode_error(kind, &mh, None, x.to_status().message);
+                            reject(&mh, e)
+                        }
+                        Refusal::Malformed(kind, message) => {
+                            reject(&mh, decode_error(kind, &mh, None, message))
+                        }
+                        Refusal::Filtered(e) => refuse(&mh, e),
+                    };
+                    // the body of an absurd length is left unread until
+                    // the connection is known to be served on
+                    let served_on = answered.is_ok() && {
+                        !absurd || {
+                            let _guard = fdlock.lock().unwrap();
+                            let t = policy.timeouts.get(Phase::Read);
+                            discard(fd, mh.length as usize, t).is_ok()
+                        }
+                    };
+                    if !served_on {
+                        quit.store(true, Ordering::SeqCst);
+                        pool.wake();
+                        break;
+                    }
+                    continue;
+                }
+                Err(Error::Socket(y)) => {
+                    pool.leave();
+                    debug!("reading from connection {} failed: {}", fd, y);
+                    if y == SOCK_DICONNECTED {
+                        // The peer may only have shut down its write side
+                        // and still wait for replies: stop reading, and
+                        // let the connection close once they are sent.
+                        state.closing(CloseReason::PeerClosed);
+                        state.read_closed.store(true, Ordering::SeqCst);
+                    } else {
+                        if y == SOCK_READ_TIMEOUT {
+                            policy.timeouts.ran_out(fd, Phase::Read, None);
+                            state.closing(CloseReason::Timeout(Phase::Read));
+                        } else {
+                            state.closing(CloseReason::Error(y));
+                        }
+                        quit.store(true, Ordering::SeqCst);
+                    }
+                    // the client connection would be closed and
+                    // the connection dealing main thread would
+                    // have exited.
+                    pool.wake();
+                    break;
+                }
+                Err(x) => {
+                    pool.leave();
+                    trace!("Others error {:?}", x);
+                    continue;
+                }
+            };
+
+            let frames: Vec<(MessageHeader, Body)> = match body {
+                Body::Frame(buf) if mh.type_ == MESSAGE_TYPE_BATCH => {
+                    peer_batches.store(true, Ordering::SeqCst);
+                    match unpack_batch(&buf) {
+                        Ok(frames) => frames
+                            .into_iter()
+                            .map(|(mh, buf)| (mh, Body::Frame(buf)))
+                            .collect(),
+                        Err(x) => {
+                            pool.leave();
+                            let e = decode_error(
+                                DecodeErrorKind::Batch,
+                                &mh,
+                                None,
+                                x.to_status().message,
+                            );
+                            if reject(&mh, e).is_err() {
+                                quit.store(true, Ordering::SeqCst);
+                                pool.wake();
+                                break;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                body => vec![(mh, body)],
+            };
+
+            // The thread keeps counting as waiting until it runs a pooled
+            // handler, so inline handlers never make the pool grow. The
+            // requests of a batch frame are served one after another.
+            let mut waiting = true;
+            let result = frames
+                .into_iter()
+                .try_for_each(|(mh, body)| dispatch(mh, body, read_at, &mut waiting));
+            if waiting {
+                pool.leave_quietly();
+            }
+            if let Err(x) = result {
+                debug!("serving request get error {:?}", x);
+                quit.store(true, Ordering::SeqCst);
+                // the client connection would be closed and
+                // the connection dealing main thread would have
+                // exited.
+                pool.wake();
+                break;
+            }
+        }
+    });
+}
+
+fn start_method_handler_threads(num: usize, ts: &ThreadS) {
+    for _ in 0..num {
+        if ts.quit.load(Ordering::SeqCst) {
+            break;
+        }
+        start_method_handler_thread(
+            ts.fd,
+            ts.fdlock.clone(),
+            ts.fd_open.clone(),
+            ts.pool.clone(),
+            ts.quit.clone(),
+            ts.methods.clone(),
+            ts.policy.clone(),
+            ts.in_flight.clone(),
+            ts.res_tx.clone(),
+            ts.wlock.clone(),
+            ts.pending.clone(),
+            ts.peer_batches.clone(),
+            ts.identity.clone(),
+            ts.state.clone(),
+            ts.journal.clone(),
+            ts.scheduling.clone(),
+            ts.panic_handler.clone(),
+        );
+    }
+}
+
+fn check_method_handler_threads(ts: &ThreadS) {
+    if ts.state.read_closed.load(Ordering::SeqCst) {
+        return;
+    }
+    let wanted = ts.pool.wanted();
+    if wanted > 0 {
+        debug!(
+            target: EVENT_TARGET,
+            "pool_grow fd={} waiting={} started={}",
+            ts.fd,
+            ts.pool.waiting(),
+            wanted
+        );
+        start_method_handler_threads(wanted, ts);
+    }
+}
+
+/// Settings every connection of a server, or of one of its listeners, is
+/// started with.
+#[derive(Clone)]
+struct ConnectionConfig {
+    methods: Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
+    default: usize,
+    min: usize,
+    max: usize,
+    panic_handler: Option<PanicHandler>,
+    reply_grace: Duration,
+    flush_timeout: Duration,
+    abandoned: Arc<AtomicUsize>,
+    torn_writes: Arc<AtomicUsize>,
+    served: Arc<AtomicU64>,
+    gate: Arc<Gate>,
+    policy: Arc<MethodPolicy>,
+    journal: Option<Arc<Journal>>,
+    scheduling: Option<Arc<WorkerScheduling>>,
+    on_connect: Option<ConnectHook>,
+    on_disconnect: Option<DisconnectHook>,
+    authorize: Option<Authorizer>,
+    // the address of the listener the connection was accepted on
+    listener: Option<String>,
+}
+
+/// Whether the peer closed both directions of `fd`, rather than only
+/// shutting down its write side.
+fn peer_hung_up(fd: RawFd) -> bool {
+    let mut fds = [PollFd::new(fd, PollFlags::empty())];
+    match poll(&mut fds, 0) {
+        Ok(_) => fds[0]
+            .revents()
+            .is_some_and(|r| r.intersects(PollFlags::POLLHUP | PollFlags::POLLERR)),
+        Err(_) => true,
+    }
+}
+
+/// Make the listeners non-blocking and start listening on them.
+fn listen_all(listeners: &[RawFd]) -> Result<()> {
+    for listener in listeners.iter() {
+        if let Err(e) = fcntl(*listener, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) {
+            return Err(Error::Others(format!(
+                "failed to set listener fd: {} as non block: {}",
+                listener, e
+            )));
+        }
+        listen(*listener, 10).map_err(|e| Error::Socket(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Start serving the connected socket `fd`. Its fd is sent to `reaper_tx`
+/// once the connection is done, so the caller must hold the lock on the
+/// connections while adding it to them.
+fn start_connection(fd: RawFd, conf: &ConnectionConfig, reaper_tx: Sender<RawFd>) -> Connection {
+    let quit = Arc::new(AtomicBool::new(false));
+    let child_quit = quit.clone();
+    let going_away = Arc::new(AtomicBool::new(false));
+    let child_going_away = going_away.clone();
+
+    let methods = conf.methods.clone();
+    let policy = conf.policy.clone();
+    let panic_handler = conf.panic_handler.clone();
+    let (default, min, max) = (conf.default, conf.min, conf.max);
+    let reply_grace = conf.reply_grace;
+    let flush_timeout = conf.flush_timeout;
+    let abandoned_total = conf.abandoned.clone();
+    let journal = conf.journal.clone();
+    let scheduling = conf.scheduling.clone();
+    let on_connect = conf.on_connect.clone();
+    let on_disconnect = conf.on_disconnect.clone();
+    let listener = conf.listener.clone();
+    let torn_writes = conf.torn_writes.clone();
+    let served_total = conf.served.clone();
+    let gate = conf.gate.clone();
+    let drain = Arc::new(Drain::default());
+    let conn_drain = drain.clone();
+    let (res_tx, res_rx): (
+        Sender<(MessageHeader, Vec<u8>)>,
+        Receiver<(MessageHeader, Vec<u8>)>,
+    ) = channel();
+    let pending = Arc::new(PendingReplies::new(res_tx.clone()));
+    let conn_pending = pending.clone();
+    let data_backlog = pending.data_backlog();
+    let served = Arc::new(AtomicU64::new(0));
+    let conn_served = served.clone();
+    let ph = panic_handler.clone();
+    let handler = spawn_guarded(format!("client_handler-{}", fd), fd, ph, move || {
+        debug!("Got new client");
+        info!(target: EVENT_TARGET, "connection_accepted fd={}", fd);
+        let connected = Instant::now();
+        if let Some(t) = policy.timeouts.get(Phase::Write) {
+            let tv = TimeVal::microseconds(t.as_micros() as i64);
+            if let Err(e) = setsockopt(fd, sockopt::SendTimeout, &tv) {
+                warn!("failed to set write timeout of fd {}: {}", fd, e);
+            }
+        }
+        let conn_ref = ConnectionRef {
+            fd: unsafe { BorrowedFd::borrow_raw(fd) },
+        };
+        let state = Arc::new(ConnectionState {
+            data: on_connect.and_then(|f| f(&conn_ref)),
+            close_reason: Mutex::new(None),
+            read_closed: AtomicBool::new(false),
+            client_info: Mutex::new(None),
+            served: conn_served,
+            served_total,
+            listener,
+            torn_writes,
+            gate,
+            drain: conn_drain,
+            timeouts: policy.timeouts.clone(),
+            streams: Arc::default(),
+        });
+        let res_state = state.clone();
+        // Start response thread
+        let quit_res = child_quit.clone();
+        let peer_batches = Arc::new(AtomicBool::new(false));
+        let res_batches = peer_batches.clone();
+        // serializes the response thread with inline handlers writing
+        let wlock = Arc::new(Mutex::new(()));
+        let res_wlock = wlock.clone();
+        // set once the connection gives up on the responses left, as the
+        // fd may then be closed and reused
+        let unflushed = Arc::new(AtomicBool::new(false));
+        let res_unflushed = unflushed.clone();
+        let ph = panic_handler.clone();
+        let handler = spawn_guarded(format!("response-{}", fd), fd, ph, move || {
+            let mut dropped = 0;
+            for r in res_rx.iter() {
+                info!("response thread get {:?}", r);
+                let mut frames = vec![r];
+                // a client sending batches unpacks them too, so pack what
+                // is already queued
+                if res_batches.load(Ordering::SeqCst) {
+                    frames.extend(res_rx.try_iter().take(BATCH_QUEUE_MAX));
+                }
+                for (mh, _) in frames.iter_mut() {
+                    if mh.type_ == MESSAGE_TYPE_RESPONSE {
+                        mh.flags |= FLAG_BATCH_OK;
+                    }
+                }
+                let markers = frames.len();
+                frames.retain(|(mh, _)| mh.type_ != FLUSH_MARKER.type_);
+                let markers = markers - frames.len();
+                let data = frames
+                    .iter()
+                    .filter(|(mh, _)| mh.type_ == MESSAGE_TYPE_DATA)
+                    .count();
+                let _guard = res_wlock.lock().unwrap();
+                let written = if res_unflushed.load(Ordering::SeqCst) {
+                    dropped += frames
+                        .iter()
+                        .map(|(_, buf)| proto::MESSAGE_HEADER_LENGTH + buf.len())
+                        .sum::<usize>();
+                    Ok(())
+                } else {
+                    write_batched(fd, frames)
+                };
+                if markers > 0 {
+                    res_state.drain.flushed(markers);
+                }
+                data_backlog.fetch_sub(data, Ordering::SeqCst);
+                if let Err(e) = written {
+                    info!("write_message got {:?}", e.error);
+                    res_state.write_failed(fd, &e.error);
+                    if e.torn {
+                        res_state.torn(fd, &e.error);
+                    } else {
+                        res_state.closing(CloseReason::WriteError(format!("{:?}", e.error)));
+                    }
+                    quit_res.store(true, Ordering::SeqCst);
+                    break;
+                }
+            }
+
+            if dropped > 0 {
+                warn!(
+                    target: EVENT_TARGET,
+                    "responses_dropped fd={} bytes={}",
+                    fd,
+                    dropped
+                );
+            }
+            trace!("response thread quit");
+        });
+
+        let (control_tx, control_rx): (SyncSender<()>, Receiver<()>) = sync_channel(1);
+        let fd_open = Arc::new(RwLock::new(true));
+        let ts = ThreadS {
+            fd,
+            fdlock: &Arc::new(Mutex::new(())),
+            fd_open: &fd_open,
+            pool: &Arc::new(WorkerPool::new(default, min, max, control_tx)),
+            methods: &methods,
+            policy: &policy,
+            in_flight: &Arc::new(AtomicUsize::new(0)),
+            res_tx: &res_tx,
+            wlock: &wlock,
+            pending: &pending,
+            peer_batches: &peer_batches,
+            identity: &Arc::new(Mutex::new(None)),
+            state: &state,
+            journal: &journal,
+            scheduling: &scheduling,
+            panic_handler: &panic_handler,
+            quit: &child_quit,
+        };
+        start_method_handler_threads(ts.pool.default, &ts);
+
+        let overran = |path: &str| policy.timeouts.ran_out(fd, Phase::Handler, Some(path));
+
+        while !child_quit.load(Ordering::SeqCst) && !state.read_closed.load(Ordering::SeqCst) {
+            check_method_handler_threads(&ts);
+            let disconnected = match pending.expire(overran) {
+                Some(t) => control_rx.recv_timeout(t) == Err(RecvTimeoutError::Disconnected),
+                None => control_rx.recv().is_err(),
+            };
+            if disconnected {
+                break;
+            }
+        }
+        // no more stream messages will arrive
+        state.streams.close_all();
+
+        // The peer shut down its write side: answer the requests it sent
+        // before, unless it turns out to be gone altogether.
+        while state.read_closed.load(Ordering::SeqCst)
+            && !child_quit.load(Ordering::SeqCst)
+            && (ts.pool.threads() > 0 || !pending.is_empty())
+            && !peer_hung_up(fd)
+        {
+            let wait = pending
+                .expire(overran)
+                .map_or(HALF_CLOSE_POLL, |t| t.min(HALF_CLOSE_POLL));
+            control_rx.recv_timeout(wait).unwrap_or(());
+        }
+
+        // another handler thread may be blocked reading the next request
+        if child_quit.load(Ordering::SeqCst) {
+            socket::shutdown(fd, Shutdown::Read).unwrap_or(());
+        }
+
+        // tell the client not to send more calls before waiting for
+        // the replies still pending, if it said hello and knows the frame
+        let goaway_ok = state
+            .client_info
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|info| info.has("goaway"));
+        if goaway_ok && child_going_away.load(Ordering::SeqCst) {
+            res_tx
+                .send(goaway_frame(reply_grace, "server shutdown"))
+                .unwrap_or(());
+        }
+
+        let abandoned = pending.drain(reply_grace);
+        if abandoned > 0 {
+            warn!("connection closed with {} replies still pending", abandoned);
+            abandoned_total.fetch_add(abandoned, Ordering::SeqCst);
+        }
+
+        // drop the res_tx, thus the res_rx would get terminated notification.
+        drop(res_tx);
+        // write the responses already queued, but not for ever: handlers
+        // still running keep the response thread waiting for more, and a
+        // peer not reading keeps it writing
+        let flush_by = Instant::now() + flush_timeout;
+        while !handler.is_finished() && Instant::now() < flush_by {
+            thread::sleep(FLUSH_POLL);
+        }
+        if handler.is_finished() {
+            handler.join().unwrap_or(());
+        } else {
+            unflushed.store(true, Ordering::SeqCst);
+            // ends a write the peer does not read, then waits for it
+            socket::shutdown(fd, Shutdown::Write).unwrap_or(());
+            drop(wlock.lock().unwrap());
+            info!(target: EVENT_TARGET, "flush_timeout fd={}", fd);
+        }
+        // wait for handlers inside with_connection() to finish with the fd
+        *fd_open.write().unwrap() = false;
+
+        let reason = if child_going_away.load(Ordering::SeqCst) {
+            CloseReason::ServerShutdown
+        } else {
+            let reason = state.close_reason.lock().unwrap().take();
+            reason.unwrap_or(CloseReason::PeerClosed)
+        };
+        if let Some(f) = on_disconnect {
+            let disconnect = Disconnect {
+                reason: reason.clone(),
+                duration: connected.elapsed(),
+                data: state.data.clone(),
+            };
+            f(&conn_ref, &disconnect);
+        }
+        // while the fd is ours, so the entry of a new connection reusing
+        // it is not reaped instead
+        reaper_tx.send(fd).unwrap_or(());
+        close(fd).unwrap_or(());
+        info!(
+            target: EVENT_TARGET,
+            "connection_closed fd={} abandoned={} reason={}",
+            fd,
+            abandoned,
+            reason
+        );
+
+        info!("client thread quit");
+    });
+
+    Connection {
+        fd,
+        handler: Some(handler),
+        quit,
+        going_away,
+        pending: conn_pending,
+        served,
+        drain,
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listeners: Vec::with_capacity(1),
+            listener_configs: HashMap::new(),
+            methods: Arc::new(HashMap::new()),
+            thread_count_default: DEFAULT_WAIT_THREAD_COUNT_DEFAULT,
+            thread_count_min: DEFAULT_WAIT_THREAD_COUNT_MIN,
+            thread_count_max: DEFAULT_WAIT_THREAD_COUNT_MAX,
+            panic_handler: None,
+            attached: Vec::new(),
+            socket_locks: Vec::new(),
+            reply_grace: Duration::from_secs(0),
+            flush_timeout: Duration::from_secs(5),
+            shutdown_timeout: None,
+            policy: MethodPolicy::default(),
+            journal: None,
+            descriptors: Default::default(),
+            scheduling: None,
+            on_connect: None,
+            on_disconnect: None,
+            idle: None,
+        }
+    }
+}
+
+impl Config {
+    fn bind_socket(&mut self, host: &str, lock: bool) -> Result<()> {
+        let lock = if lock {
+            common::lock_socket(host)?
+        } else {
+            None
+        };
+        let (fd, sockaddr) = common::make_socket(host, true)?;
+
+        if let Err(e) = bind(fd, &sockaddr) {
+            close(fd).unwrap_or(());
+            if e.as_errno() == Some(nix::errno::Errno::EADDRINUSE) {
+                return Err(Error::AddressInUse(host.to_string(), None));
+            }
+            return Err(Error::Others(format!("bind {}: {}", host, e)));
+        }
+        self.listeners.push(fd);
+        self.socket_locks.extend(lock);
+
+        Ok(())
+    }
+}
+
+impl Drop for Config {
+    /// Close the listeners and connections of a server which never
+    /// started serving them.
+    fn drop(&mut self) {
+        for fd in self.listeners.drain(..).chain(self.attached.drain(..)) {
+            close(fd).unwrap_or(());
+        }
+    }
+}
+
+impl Control {
+    fn new() -> Control {
+        let (rfd, wfd) = pipe_cloexec().unwrap();
+        Control {
+            monitor_fd: (rfd, wfd),
+            quit: Arc::new(AtomicBool::new(false)),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            abandoned: Arc::new(AtomicUsize::new(0)),
+            torn_writes: Arc::new(AtomicUsize::new(0)),
+            served: Arc::default(),
+            gate: Arc::default(),
+            accepting: Arc::new(AtomicBool::new(true)),
+            listeners: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn running(&self) -> Result<()> {
+        if self.quit.load(Ordering::SeqCst) {
+            return Err(Error::Others("server already shut down".to_string()));
+        }
+        Ok(())
+    }
+
+    fn wake(&self) -> Result<()> {
+        write(self.monitor_fd.1, &[0]).map_err(|e| Error::Socket(e.to_string()))?;
+        Ok(())
+    }
+
+    fn hand_off(&self, control: RawFd) -> Result<()> {
+        // the listener thread closes them once it sees they were handed off
+        let listeners = self.listeners.lock().unwrap();
+        if listeners.is_empty() && self.accepting.load(Ordering::SeqCst) {
+            return Err(Error::Others(
+                "only a started server with listeners can hand them off".to_string(),
+            ));
+        }
+        if self
+            .accepting
+            .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(Error::Others("listeners already handed off".to_string()));
+        }
+        if let Err(e) = handoff::send_listeners(control, &listeners) {
+            self.accepting.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+        drop(listeners);
+        self.wake()
+    }
+
+    /// The addresses of the listeners of the started server, none once
+    /// they were handed off.
+    fn listen_addresses(&self) -> Result<Vec<String>> {
+        let listeners = self.listeners.lock().unwrap();
+        if !self.accepting.load(Ordering::SeqCst) {
+            return Ok(Vec::new());
+        }
+        listen_addresses(&listeners)
+    }
+
+    /// Close the listener `fd` of the started server.
+    fn close_listener(&self, fd: RawFd) {
+        self.listeners.lock().unwrap().retain(|l| *l != fd);
+        close(fd).unwrap_or(());
+    }
+
+    fn quiesce(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        self.gate.set(true);
+        // collected first, so the connections are not locked while waiting
+        let connections: Vec<(RawFd, Arc<Drain>, Arc<PendingReplies>)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|cn| (cn.fd, cn.drain.clone(), cn.pending.clone()))
+            .collect();
+        let mut busy = Vec::new();
+        for (fd, drain, pending) in connections {
+            let drained = drain.wait(&pending, deadline);
+            drain.drained.store(drained, Ordering::SeqCst);
+            if !drained {
+                busy.push(fd);
+            }
+        }
+        if !busy.is_empty() {
+            return Err(get_rpc_status(
+                Code::DEADLINE_EXCEEDED,
+                format!("connections {:?} did not drain in time", busy),
+            ));
+        }
+        info!(target: EVENT_TARGET, "server_quiesced");
+        Ok(())
+    }
+
+    fn resume(&self) {
+        for cn in self.connections.lock().unwrap().values() {
+            cn.drain.drained.store(false, Ordering::SeqCst);
+        }
+        self.gate.set(false);
+    }
+
+    fn quiesce_status(&self) -> Vec<QuiesceStatus> {
+        let mut status: Vec<QuiesceStatus> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|cn| QuiesceStatus {
+                connection: cn.fd,
+                held: cn.drain.held.load(Ordering::SeqCst),
+                running: cn.drain.running.load(Ordering::SeqCst),
+                pending: cn.pending.len(),
+                drained: cn.drain.drained.load(Ordering::SeqCst),
+            })
+            .collect();
+        status.sort_by_key(|s| s.connection);
+        status
+    }
+
+    fn debug_handle(&self, policy: &MethodPolicy) -> DebugHandle {
+        DebugHandle {
+            connections: self.connections.clone(),
+            served: self.served.clone(),
+            abandoned: self.abandoned.clone(),
+            torn_writes: self.torn_writes.clone(),
+            timeouts: policy.timeouts.counts.clone(),
+            decode_errors: policy.decode_error_counts.clone(),
+        }
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        close(self.monitor_fd.0).unwrap_or(());
+        close(self.monitor_fd.1).unwrap_or(());
+    }
+}
+
+/// The addresses `listeners` are bound to.
+fn listen_addresses(listeners: &[RawFd]) -> Result<Vec<String>> {
+    listeners
+        .iter()
+        .map(|fd| {
+            getsockname(*fd)
+                .map(|a| common::format_addr(&a))
+                .map_err(|e| Error::Socket(e.to_string()))
+        })
+        .collect()
+}
+
+impl Server {
+    /// Start a builder, which reports configuration errors once at
+    /// [`ServerBuilder::build`] instead of at every step.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            config: Config::default(),
+            control: Arc::new(Control::new()),
+            error: None,
+        }
+    }
+
+    #[deprecated(note = "use Server::builder() instead")]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> ServerBuilder {
+        Server::builder()
+    }
+
+    /// Start a builder listening on `host`, reporting a bad address or a
+    /// failed bind right away as `Server::new().bind()` used to.
+    #[deprecated(note = "use Server::builder().bind() instead")]
+    pub fn bind(host: &str) -> Result<ServerBuilder> {
+        let mut builder = Server::builder().bind(host);
+        match builder.error.take() {
+            Some(e) => Err(e),
+            None => Ok(builder),
+        }
+    }
+
+    /// The addresses the server listens on, in the order they were bound
+    /// or added, with auto-allocated vsock ports resolved. Servers listen
+    /// on any vsock cid, reported as `-1`. Once the listeners were handed
+    /// off, see [`Server::hand_off`], there are none.
+    pub fn listen_addresses(&self) -> Result<Vec<String>> {
+        if self.handler.is_some() {
+            return self.control.listen_addresses();
+        }
+        listen_addresses(&self.config.listeners)
+    }
+
+    /// Serve `methods` to the connections whose identity starts with
+    /// `prefix`, e.g. a sandbox id, on top of the methods registered for
+    /// all of them. Each tenant can so have its own handler for the same
+    /// method path, without clients rewriting paths. A connection belongs
+    /// to the tenant with the longest prefix of its identity, and never
+    /// reaches the methods of other tenants.
+    ///
+    /// Tenants can be added to and removed while the server runs.
+    /// Connections without an identity belong to none.
+    pub fn add_tenant(
+        &self,
+        prefix: &[u8],
+        methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
+    ) {
+        self.config.policy.tenants.add(prefix, methods);
+    }
+
+    /// Stop serving the methods of the tenant with `prefix`. Returns
+    /// whether there was one. Calls already running finish.
+    pub fn remove_tenant(&self, prefix: &[u8]) -> bool {
+        self.config.policy.tenants.remove(prefix)
+    }
+
+    /// Log `percent` of the calls matching `target` in detail for
+    /// `duration`, at the `info` level with [`EVENT_TARGET`]: the request
+    /// and response payloads, as escaped bytes, and how long each call
+    /// waited, ran its handler, and took overall. Tracing `target` again
+    /// replaces its trace.
+    ///
+    /// Traces can be started and stopped while the server runs, so a
+    /// method misbehaving in production can be looked at without a
+    /// restart. Payloads may hold secrets: mind where the log goes.
+    pub fn trace(&self, target: TraceTarget, percent: u32, duration: Duration) {
+        self.config.policy.traces.start(target, percent, duration);
+    }
+
+    /// Stop tracing the calls matching `target`. Returns whether they were
+    /// traced.
+    pub fn stop_trace(&self, target: &TraceTarget) -> bool {
+        self.config.policy.traces.stop(target)
+    }
+
+    /// The identity prefixes of the tenants, longest first.
+    pub fn tenants(&self) -> Vec<Vec<u8>> {
+        let tables = self.config.policy.tenants.tables.read().unwrap();
+        tables.iter().map(|(p, _)| p.clone()).collect()
+    }
+
+    /// The names of the services with registered methods, sorted.
+    pub fn services(&self) -> Vec<String> {
+        let mut services: Vec<String> = self
+            .config
+            .methods
+            .keys()
+            .filter_map(|path| path.trim_start_matches('/').rsplit_once('/'))
+            .map(|(service, _)| service.to_string())
+            .collect();
+        services.sort();
+        services.dedup();
+        services
+    }
+
+    /// Hand the listeners of this running server over to another process
+    /// through the unix socket `control`, then stop accepting connections.
+    ///
+    /// Connections already accepted are served until the server is shut
+    /// down. See [`handoff`](crate::handoff) for the other side.
+    pub fn hand_off(&self, control: RawFd) -> Result<()> {
+        if self.handler.is_none() {
+            return Err(Error::Others(
+                "only a started server with listeners can hand them off".to_string(),
+            ));
+        }
+        self.control.hand_off(control)
+    }
+
+    /// The calls recorded in the journal, oldest first. Empty unless
+    /// enabled with [`ServerBuilder::set_journal`].
+    pub fn dump_journal(&self) -> Vec<JournalEntry> {
+        self.config
+            .journal
+            .as_ref()
+            .map(|j| j.entries())
+            .unwrap_or_default()
+    }
+
+    /// Stop handing requests to handlers and wait up to `timeout` for
+    /// every connection to drain: for the requests already handed over to
+    /// be answered and their replies written out. Requests arriving
+    /// meanwhile are read and held back until [`Server::resume`], so no
+    /// bytes are left in flight, e.g. to checkpoint the process with CRIU.
+    ///
+    /// Fails with `DEADLINE_EXCEEDED` if some connection did not drain in
+    /// time; the server stays quiesced, see [`Server::quiesce_status`].
+    /// Only the connections of the threaded backend are quiesced.
+    pub fn quiesce(&self, timeout: Duration) -> Result<()> {
+        self.control.quiesce(timeout)
+    }
+
+    /// Hand the requests held back since [`Server::quiesce`] to their
+    /// handlers, and serve on.
+    pub fn resume(&self) {
+        self.control.resume()
+    }
+
+    /// Where each connection stands while the server is quiesced, sorted
+    /// by fd.
+    pub fn quiesce_status(&self) -> Vec<QuiesceStatus> {
+        self.control.quiesce_status()
+    }
+
+    /// A handle listing the requests being served and cancelling them,
+    /// for debugging. It stays valid while the server runs.
+    pub fn debug_handle(&self) -> DebugHandle {
+        self.control.debug_handle(&self.config.policy)
+    }
+
+    fn check_config(&self) -> Result<()> {
+        if self.config.thread_count_default >= self.config.thread_count_max {
+            return Err(Error::Others(
+                "thread_count_default should smaller than thread_count_max".to_string(),
+            ));
+        }
+        if self.config.thread_count_default <= self.config.thread_count_min {
+            return Err(Error::Others(
+                "thread_count_default should biger than thread_count_min".to_string(),
+            ));
+        }
+        if self.config.listeners.is_empty() && self.config.attached.is_empty() {
+            return Err(Error::Others("ttrpc-rust not bind".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The configuration of the attached connections, and that of the
+    /// connections accepted on each listener.
+    fn connection_configs(&self) -> (ConnectionConfig, HashMap<RawFd, ConnectionConfig>) {
+        let conf = ConnectionConfig {
+            methods: self.config.methods.clone(),
+            default: self.config.thread_count_default,
+            min: self.config.thread_count_min,
+            max: self.config.thread_count_max,
+            panic_handler: self.config.panic_handler.clone(),
+            reply_grace: self.config.reply_grace,
+            flush_timeout: self.config.flush_timeout,
+            abandoned: self.control.abandoned.clone(),
+            torn_writes: self.control.torn_writes.clone(),
+            served: self.control.served.clone(),
+            gate: self.control.gate.clone(),
+            policy: Arc::new(self.config.policy.clone()),
+            journal: self.config.journal.clone(),
+            scheduling: self.config.scheduling.clone(),
+            on_connect: self.config.on_connect.clone(),
+            on_disconnect: self.config.on_disconnect.clone(),
+            authorize: None,
+            listener: None,
+        };
+        let listener_confs = self
+            .config
+            .listeners
+            .iter()
+            .map(|fd| {
+                let mut c = conf.clone();
+                c.listener = getsockname(*fd).ok().map(|a| common::format_addr(&a));
+                if let Some(lc) = self.config.listener_configs.get(fd) {
+                    c.policy = Arc::new(self.config.policy.for_listener(lc));
+                    c.authorize = lc.authorize.clone();
+                }
+                (*fd, c)
+            })
+            .collect();
+        (conf, listener_confs)
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        self.check_config()?;
+
+        let connections = self.control.connections.clone();
+
+        let listeners = self.config.listeners.clone();
+        let attached = std::mem::take(&mut self.config.attached);
+        let (conf, listener_confs) = self.connection_configs();
+        let service_quit = self.control.quit.clone();
+        let accepting = self.control.accepting.clone();
+        let control = self.control.clone();
+        let monitor_fd = control.monitor_fd.0;
+        let panic_handler = self.config.panic_handler.clone();
+        let idle = self.config.idle.clone();
+
+        listen_all(&listeners)?;
+        *self.control.listeners.lock().unwrap() = listeners.clone();
+
+        let loop_fd = listeners.first().copied().unwrap_or(monitor_fd);
+        let ph = panic_handler.clone();
+        let handler = spawn_guarded("listener_loop".into(), loop_fd, ph, move || {
+            // keeps monitor_fd open
+            let control = control;
+            let mut listeners = listeners;
+
+            let (reaper_tx, reaper_rx) = channel();
+            let reaper_connections = connections.clone();
+
+            let ph = panic_handler.clone();
+            let reaper = spawn_guarded("reaper".into(), loop_fd, ph, move || {
+                for fd in reaper_rx.iter() {
+                    // without holding the lock, the thread is about to exit
+                    let cn = reaper_connections.lock().unwrap().remove(&fd);
+                    if let Some(handler) = cn.and_then(|mut cn| cn.handler.take()) {
+                        handler.join().unwrap();
+                    }
+                }
+            });
+
+            for fd in attached {
+                let mut connections = connections.lock().unwrap();
+                connections.insert(fd, start_connection(fd, &conf, reaper_tx.clone()));
+            }
+
+            // since when nothing happened, what was seen then, and whether
+            // the idle hook was called already
+            let mut idle_since = (Instant::now(), Vec::new(), false);
+            loop {
+                if service_quit.load(Ordering::SeqCst) {
+                    break;
+                }
+                if !accepting.load(Ordering::SeqCst) {
+                    // handed over to another process
+                    for l in listeners.drain(..) {
+                        control.close_listener(l);
+                    }
+                }
+
+                let mut fd_set = FdSet::new();
+                for listener in listeners.iter() {
+                    fd_set.insert(*listener);
+                }
+                fd_set.insert(monitor_fd);
+
+                let mut tv = idle.as_ref().map(|(timeout, _)| {
+                    TimeVal::milliseconds((*timeout).min(IDLE_POLL).as_millis() as i64)
+                });
+                match select(
+                    Some(fd_set.highest().unwrap() + 1),
+                    &mut fd_set,
+                    None,
+                    None,
+                    tv.as_mut(),
+                ) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        if e == nix::Error::from(nix::errno::Errno::EINTR) {
+                            continue;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+
+                if let Some((timeout, f)) = idle.as_ref() {
+                    let (since, seen, fired) = &mut idle_since;
+                    let (now_seen, busy) = activity(&connections);
+                    if busy || now_seen != *seen {
+                        *since = Instant::now();
+                        *seen = now_seen;
+                        *fired = false;
+                    } else if !*fired && since.elapsed() >= *timeout {
+                        info!(target: EVENT_TARGET, "server_idle for={:?}", since.elapsed());
+                        *fired = true;
+                        f();
+                    }
+                }
+
+                if fd_set.contains(monitor_fd) {
+                    // woken up
+                    read(monitor_fd, &mut [0u8; 8]).unwrap_or(0);
+                    continue;
+                }
+                if service_quit.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let mut failed = false;
+                for listener in listeners.iter().filter(|l| fd_set.contains(**l)) {
+                    let fd = match accept_cloexec(*listener) {
+                        Ok(fd) => fd,
+                        // taken by another process sharing the listener
+                        Err(e) if e == nix::Error::from(nix::errno::Errno::EAGAIN) => continue,
+                        Err(_e) => {
+                            failed = true;
+                            break;
+                        }
+                    };
+
+                    let conf = listener_confs.get(listener).unwrap_or(&conf);
+                    if let Some(authorize) = conf.authorize.as_ref() {
+                        let conn_ref = ConnectionRef {
+                            fd: unsafe { BorrowedFd::borrow_raw(fd) },
+                        };
+                        if let Err(e) = authorize(&conn_ref) {
+                            info!(
+                                target: EVENT_TARGET,
+                                "connection_refused fd={} listener={} reason={}",
+                                fd,
+                                listener,
+                                e.to_status().message
+                            );
+                            close(fd).unwrap_or(());
+                            continue;
+                        }
+                    }
+
+                    let mut connections = connections.lock().unwrap();
+                    connections.insert(fd, start_connection(fd, conf, reaper_tx.clone()));
+                }
+                if failed {
+                    break;
+                }
+            } // end loop
+
+            // notify reaper thread to exit.
+            drop(reaper_tx);
+            reaper.join().unwrap();
+            for listener in listeners {
+                control.close_listener(listener);
+            }
+            info!("ttrpc server stopped");
+        });
+
+        self.handler = Some(handler);
+
+        Ok(())
+    }
+
+    /// Tell the server's threads to stop, without waiting for them.
+    fn signal_quit(&mut self, report: &mut ShutdownReport) {
+        let connections = self.control.connections.lock().unwrap();
+
+        if self.control.quit.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = self.control.wake() {
+            warn!(
+                "failed to notify fd: {} with error: {}",
+                self.control.monitor_fd.1, e
+            );
+            report.errors.push(Error::Others(format!(
+                "failed to notify fd {}: {}",
+                self.control.monitor_fd.1, e
+            )));
+        }
+
+        for (fd, c) in connections.iter() {
+            c.close();
+            report.connections_closed.push(*fd);
+        }
+
+        // release connections's lock, since joining the listener thread
+        // would wait on the other thread's exit in which would take the lock.
+        drop(connections);
+
+        // the listener thread closes what it uses when it quits
+        let used = if self.handler.is_some() {
+            self.config.listeners.len()
+        } else {
+            for fd in self.config.attached.drain(..) {
+                close(fd).unwrap_or(());
+            }
+            0
+        };
+        for fd in self.config.listeners.drain(..).skip(used) {
+            close(fd).unwrap_or(());
+        }
+    }
+
+    /// Stop the server, close its connections and wait for its threads.
+    ///
+    /// The report tells what could not be torn down cleanly, so callers
+    /// can decide whether to escalate, e.g. by aborting the process.
+    pub fn shutdown(mut self) -> Result<ShutdownReport> {
+        self.resume();
+        let mut report = ShutdownReport::default();
+        let abandoned = self.control.abandoned.load(Ordering::SeqCst);
+        self.signal_quit(&mut report);
+
+        if let Some(handler) = self.handler.take() {
+            if let Some(timeout) = self.config.shutdown_timeout {
+                let deadline = Instant::now() + timeout;
+                while !handler.is_finished() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+            if self.config.shutdown_timeout.is_some() && !handler.is_finished() {
+                report.join_timeouts.push("listener_loop".to_string());
+                for fd in self.control.connections.lock().unwrap().keys() {
+                    report.join_timeouts.push(format!("client_handler-{}", fd));
+                }
+                warn!("shutdown left threads behind: {:?}", report.join_timeouts);
+            } else {
+                handler
+                    .join()
+                    .map_err(|_| Error::Others("listener thread panicked".to_string()))?;
+            }
+        }
+
+        report.replies_abandoned = self.control.abandoned.load(Ordering::SeqCst) - abandoned;
+        Ok(report)
+    }
+}
+
+impl Drop for Server {
+    /// Stop a server which was not shut down, leaving its threads to quit
+    /// on their own.
+    fn drop(&mut self) {
+        self.signal_quit(&mut ShutdownReport::default());
+    }
+}
+
+/// Configures a [`Server`]. Errors, e.g. from binding, are kept until
+/// [`ServerBuilder::build`], which also checks the configuration as a
+/// whole.
+///
+/// ```no_run
+/// # use ttrpc::Server;
+/// let handle = Server::builder()
+///     .bind("unix:///run/agent.sock")
+///     .set_thread_count_max(20)
+///     .register_diagnostics()
+///     .build()?
+///     .spawn()?;
+/// # Ok::<(), ttrpc::Error>(())
+/// ```
+pub struct ServerBuilder {
+    config: Config,
+    // created up front, so that the handles of built-in services stay
+    // valid once the server is built
+    control: Arc<Control>,
+    // the first error met
+    error: Option<Error>,
+}
+
+impl ServerBuilder {
+    fn try_config<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: FnOnce(&mut Config) -> Result<()>,
+    {
+        if self.error.is_none() {
+            if let Err(e) = f(&mut self.config) {
+                self.error = Some(e);
+            }
+        }
+        self
+    }
+
+    /// Listen on `host`. May be called more than once to serve several
+    /// addresses, e.g. a control port and per-container vsock ports.
+    ///
+    /// A vsock port of `-1` or `0`, as in `vsock://-1:0`, lets the kernel
+    /// pick a free port; see [`Server::listen_addresses`] for which one.
+    pub fn bind(self, host: &str) -> ServerBuilder {
+        self.try_config(|c| c.bind_socket(host, false))
+    }
+
+    /// Listen on the listening socket `fd`. The server takes ownership
+    /// of it.
+    pub fn add_listener(mut self, fd: RawFd) -> ServerBuilder {
+        self.config.listeners.push(fd);
+        self
+    }
+
+    /// Listen on `host`, serving its connections as `config` says rather
+    /// than with the server-wide settings only.
+    pub fn bind_with(self, host: &str, config: ListenerConfig) -> ServerBuilder {
+        self.try_config(|c| {
+            c.bind_socket(host, config.lock)?;
+            let fd = *c.listeners.last().unwrap();
+            c.listener_configs.insert(fd, config);
+            Ok(())
+        })
+    }
+
+    /// Add the listener `fd`, serving its connections as `config` says.
+    pub fn add_listener_with(mut self, fd: RawFd, config: ListenerConfig) -> ServerBuilder {
+        self.config.listener_configs.insert(fd, config);
+        self.add_listener(fd)
+    }
+
+    /// Serve an already connected socket, such as one end of a socketpair
+    /// inherited from a parent process. See [`pair`](crate::pair).
+    ///
+    /// The server takes ownership of `fd`. A server may be started with
+    /// attached connections only and no listener.
+    pub fn add_connection(mut self, fd: RawFd) -> ServerBuilder {
+        self.config.attached.push(fd);
+        self.try_config(|_| seccomp::set_nosigpipe(fd).map_err(|e| Error::Socket(e.to_string())))
+    }
+
+    pub fn register_service(
+        mut self,
+        methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
+    ) -> ServerBuilder {
+        let mut_methods = Arc::get_mut(&mut self.config.methods).unwrap();
+        mut_methods.extend(methods);
+        self
+    }
+
+    /// Register every method of `service`, with their hashes if it has.
+    pub fn register(self, service: Arc<dyn Service + Send + Sync>) -> ServerBuilder {
+        debug!("registering service {}", service.name());
+        self.register_service(service.methods())
+            .register_method_hashes(service.method_hashes())
+    }
+
+    /// Register the hashes ttrpc-compiler generated for methods, by method
+    /// path, e.g. `GREETER_METHOD_HASHES`. Clients naming those methods in
+    /// their handshake are told these hashes, so they can tell whether
+    /// they were built from the same definitions, see
+    /// [`Client::check_methods`](crate::Client::check_methods).
+    pub fn register_method_hashes(mut self, hashes: &[(&str, u64)]) -> ServerBuilder {
+        for (path, hash) in hashes {
+            self.config
+                .policy
+                .method_hashes
+                .insert(path.to_string(), *hash);
+        }
+        self
+    }
+
+    /// Register the built-in `ttrpc.diagnostics.Diagnostics` service, which
+    /// answers echo, ping and server info calls, and lists and cancels the
+    /// requests being served, see [`Server::debug_handle`], and serves
+    /// the server metrics, see [`DebugHandle::openmetrics`]. Any client
+    /// can cancel any request through it, so only register it where
+    /// clients are trusted.
+    pub fn register_diagnostics(self) -> ServerBuilder {
+        let debug = self.control.debug_handle(&self.config.policy);
+        let mut methods = builtin::create_diagnostics(debug.clone());
+        methods.extend(builtin::create_metrics(debug.clone()));
+        methods.extend(builtin::create_debug(debug));
+        self.register_descriptor(&builtin::diagnostics_descriptor())
+            .register_service(methods)
+    }
+
+    /// Register the serialized `FileDescriptorProto` of a service, which
+    /// the reflection service serves. Code generated with descriptor
+    /// embedding enabled provides it as `FILE_DESCRIPTOR`.
+    pub fn register_descriptor(self, descriptor: &[u8]) -> ServerBuilder {
+        self.config
+            .descriptors
+            .write()
+            .unwrap()
+            .push(descriptor.to_vec());
+        self
+    }
+
+    /// Register the built-in `ttrpc.reflection.Reflection` service, which
+    /// lists the services with a registered descriptor and serves those
+    /// descriptors, so dynamic clients can call any of them.
+    pub fn register_reflection(self) -> ServerBuilder {
+        let methods = builtin::create_reflection(self.config.descriptors.clone());
+        self.register_descriptor(&builtin::reflection_descriptor())
+            .register_service(methods)
+    }
+
+    pub fn set_thread_count_default(mut self, count: usize) -> ServerBuilder {
+        self.config.thread_count_default = count;
+        self
+    }
+
+    pub fn set_thread_count_min(mut self, count: usize) -> ServerBuilder {
+        self.config.thread_count_min = count;
+        self
+    }
+
+    pub fn set_thread_count_max(mut self, count: usize) -> ServerBuilder {
+        self.config.thread_count_max = count;
+        self
+    }
+
+    /// Set a callback invoked when one of the server's threads panics,
+    /// e.g. to abort the process or record a metric. A method handler
+    /// panic is answered with `INTERNAL` and the connection keeps going.
+    pub fn set_panic_handler<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: Fn(&ThreadPanic) + Send + Sync + 'static,
+    {
+        self.config.panic_handler = Some(Arc::new(f));
+        self
+    }
+
+    /// Set how long a closing connection waits for deferred replies still
+    /// owed by handlers, see [`ResponseSink`]. Defaults to not waiting.
+    pub fn set_reply_grace(mut self, grace: Duration) -> ServerBuilder {
+        self.config.reply_grace = grace;
+        self
+    }
+
+    /// Set how long a closing connection keeps writing the responses
+    /// already queued, e.g. after failing to read the next request.
+    /// Those still unwritten then are dropped, and reported by a
+    /// `responses_dropped` event. Defaults to 5 seconds.
+    pub fn set_flush_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.flush_timeout = timeout;
+        self
+    }
+
+    /// Run the handler of method `path` (e.g. `/grpc.Health/Check`) inline.
+    ///
+    /// An inline handler runs on the thread which read the request without
+    /// leaving the waiting pool, and its reply is written to the socket
+    /// directly instead of through the connection's response thread. This
+    /// saves thread hops for handlers taking microseconds, but a slow one
+    /// holds up the requests queued behind it.
+    pub fn set_inline(mut self, path: &str) -> ServerBuilder {
+        self.config.policy.inline.insert(path.to_string());
+        self
+    }
+
+    /// Serve method `path` (e.g. `/containerd.task.v2.Task/Shutdown`) even
+    /// when the connection is at its concurrency limit, so an overloaded
+    /// server can still be told to stop.
+    pub fn set_priority(mut self, path: &str) -> ServerBuilder {
+        self.config.policy.priority.insert(path.to_string());
+        self
+    }
+
+    /// Limit how many handlers run at once for each connection. Requests
+    /// over the limit are answered with `RESOURCE_EXHAUSTED`, except for
+    /// priority methods. Unlimited by default.
+    ///
+    /// The limit is advertised in the answer to the handshake, so clients
+    /// may hold back calls rather than have them rejected, see
+    /// [`Client::with_stream_limit`](crate::Client::with_stream_limit).
+    pub fn set_max_concurrent_requests(mut self, max: usize) -> ServerBuilder {
+        self.config.policy.max_in_flight = max;
+        self
+    }
+
+    /// Check every request before its payload is decoded. `filter` gets
+    /// the method path and the payload length; requests it fails are
+    /// answered with its error, e.g. to refuse a 50MB `State` call.
+    ///
+    /// The method is named in the request envelope, so by then the frame
+    /// was read whole and the envelope decoded: the filter spares the
+    /// decoding of the payload into the method's message and running the
+    /// handler, not reading the frame. To refuse requests before their
+    /// body is read, see [`ServerBuilder::set_header_filter`].
+    pub fn set_request_filter<F>(mut self, filter: F) -> ServerBuilder
+    where
+        F: Fn(&str, usize) -> Result<()> + Send + Sync + 'static,
+    {
+        self.config.policy.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Check the header of every request as soon as it is read, before
+    /// any room is made for its body. Requests `filter` fails are answered
+    /// with its error, and their body is read and dropped as it arrives.
+    ///
+    /// Only the length, flags and stream of a request are known by then;
+    /// frames longer than the maximum message size, of an unknown type or
+    /// requests on stream 0 are refused before the filter is asked, see
+    /// [`ListenerConfig::max_message_size`].
+    pub fn set_header_filter<F>(mut self, filter: F) -> ServerBuilder
+    where
+        F: Fn(&MessageHeader) -> Result<()> + Send + Sync + 'static,
+    {
+        self.config.policy.header_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Check the requests of method `path` with `validate` before running
+    /// its handler. Requests which `validate` fails are answered with its
+    /// error, those which do not decode as `M` as the decode error policy
+    /// says, see [`ServerBuilder::set_decode_error_policy`].
+    ///
+    /// The payload is decoded once more by the handler itself.
+    pub fn add_validator<M, F>(mut self, path: &str, validate: F) -> ServerBuilder
+    where
+        M: Message,
+        F: Fn(&M) -> Result<()> + Send + Sync + 'static,
+    {
+        let validator: Validator = Arc::new(move |payload: &[u8]| {
+            let mut s = CodedInputStream::from_bytes(payload);
+            let mut m = M::new();
+            m.merge_from(&mut s).map_err(|e| e.to_string())?;
+            Ok(validate(&m))
+        });
+        self.config
+            .policy
+            .validators
+            .insert(path.to_string(), validator);
+        self
+    }
+
+    /// Set what happens to a request which does not decode: a frame longer
+    /// than the maximum message size, a batch frame which does not unpack,
+    /// or a request whose envelope or payload is not valid protobuf. By
+    /// default it is answered with `INVALID_ARGUMENT`, when it is a request
+    /// expecting a reply, and the connection served on. Each one also emits
+    /// a `decode_error` event, see [`EVENT_TARGET`], and is counted by kind
+    /// by [`DebugHandle::decode_errors`].
+    ///
+    /// Frame headers are checked too, against corrupt or fuzzed peers,
+    /// e.g. on serial or vsock links: lengths beyond the protocol's 4MiB,
+    /// type bytes of no message type and requests on stream 0 are
+    /// undecodable, see [`DecodeErrorKind`]. They are never answered, so
+    /// only [`DecodeErrorPolicy::Close`] tells them apart from the
+    /// default. The io_uring backend does not check them.
+    ///
+    /// Payloads are decoded by the handlers generated for a service; other
+    /// handlers report theirs with [`TtrpcContext::undecodable`].
+    pub fn set_decode_error_policy(mut self, policy: DecodeErrorPolicy) -> ServerBuilder {
+        self.config.policy.decode_errors = policy;
+        self
+    }
+
+    /// Set how much of the message of an error status sent by a handler
+    /// reaches the client, to keep internal paths and details from callers
+    /// which should not see them. Messages cut down are logged in full at
+    /// the `debug` level, with the request id. By default messages are
+    /// sent as they are.
+    pub fn set_error_detail(mut self, detail: ErrorDetail) -> ServerBuilder {
+        self.config.policy.error_detail = detail;
+        self
+    }
+
+    /// Decide per request what happens to one which does not decode,
+    /// instead of [`ServerBuilder::set_decode_error_policy`].
+    pub fn set_on_decode_error<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: Fn(&DecodeError) -> DecodeErrorPolicy + Send + Sync + 'static,
+    {
+        self.config.policy.on_decode_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Check every call against `acl`, denying those it does not allow
+    /// with `PERMISSION_DENIED` before their method is looked up. Each
+    /// denied call emits an `acl_denied` event, see [`EVENT_TARGET`].
+    ///
+    /// Keep a clone of `acl` to [`reload`](Acl::reload) its rules while the
+    /// server runs.
+    pub fn set_acl(mut self, acl: Arc<Acl>) -> ServerBuilder {
+        self.config.policy.acl = Some(acl);
+        self
+    }
+
+    /// Decode request frames longer than `len` bytes from the socket as
+    /// they arrive rather than reading them whole first, so that a large
+    /// request is not held in memory twice, as a frame and as a request,
+    /// while it decodes. The read timeout covers decoding them.
+    ///
+    /// Frames carrying extensions are still read whole, and so is every
+    /// frame served by the io_uring backend.
+    pub fn set_streaming_decode(mut self, len: usize) -> ServerBuilder {
+        self.config.policy.decode_above = Some(len);
+        self
+    }
+
+    /// Emit a `slow_handler` event (see [`EVENT_TARGET`]) for every
+    /// handler running for `threshold` or longer.
+    pub fn set_slow_handler_threshold(mut self, threshold: Duration) -> ServerBuilder {
+        self.config.policy.slow_handler = Some(threshold);
+        self
+    }
+
+    /// Give up on requests, or on their connection, spending longer than
+    /// `timeout` in `phase`, see [`Phase`] for what happens to them. Each
+    /// emits a `phase_timeout` event (see [`EVENT_TARGET`]), and is counted
+    /// by [`DebugHandle::timeouts`].
+    ///
+    /// The io_uring backend checks the read, write and handler timeouts
+    /// every 50 milliseconds.
+    pub fn set_phase_timeout(mut self, phase: Phase, timeout: Duration) -> ServerBuilder {
+        self.config.policy.timeouts.limits[phase as usize] = Some(timeout);
+        self
+    }
+
+    /// Keep a journal of the last `capacity` calls: their method, sizes,
+    /// status, timestamps and a hash of their payload. It is logged when
+    /// a handler panics and can be read with [`Server::dump_journal`].
+    pub fn set_journal(mut self, capacity: usize) -> ServerBuilder {
+        self.config.journal = Some(Arc::new(Journal::new(capacity.max(1))));
+        self
+    }
+
+    /// Set the nice value, scheduling policy and CPU affinity of the
+    /// method handler threads, to bound their impact on a workload they
+    /// share CPUs with. Settings which cannot be applied, e.g. real-time
+    /// policies without `CAP_SYS_NICE`, are logged and skipped.
+    pub fn set_worker_scheduling(mut self, scheduling: WorkerScheduling) -> ServerBuilder {
+        self.config.scheduling = Some(Arc::new(scheduling));
+        self
+    }
+
+    /// Call `f` for every new connection before serving it. What it
+    /// returns is attached to the connection, available to handlers
+    /// through [`TtrpcContext::connection_data`] and handed back to the
+    /// [`ServerBuilder::set_on_disconnect`] callback.
+    pub fn set_on_connect<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: Fn(&ConnectionRef) -> Option<ConnectionData> + Send + Sync + 'static,
+    {
+        self.config.on_connect = Some(Arc::new(f));
+        self
+    }
+
+    /// Call `f` when a connection is closed, with why, for how long it was
+    /// served and the data attached by [`ServerBuilder::set_on_connect`].
+    pub fn set_on_disconnect<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: Fn(&ConnectionRef, &Disconnect) + Send + Sync + 'static,
+    {
+        self.config.on_disconnect = Some(Arc::new(f));
+        self
+    }
+
+    /// Call `f` once the server has been idle for `timeout`, so a service
+    /// started by socket activation can exit and be started again on
+    /// demand. Idle means no request is being handled, and none
+    /// arrived and no connection was accepted or closed meanwhile;
+    /// connections left open do not keep the server busy by themselves.
+    ///
+    /// `f` runs on the listener thread, once per idle period, e.g. to
+    /// shut the server down through a [`ServerHandle`] and exit.
+    pub fn set_idle_timeout<F>(mut self, timeout: Duration, f: F) -> ServerBuilder
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.config.idle = Some((timeout, Arc::new(f)));
+        self
+    }
+
+    /// Bound how long [`Server::shutdown`] waits for the server's threads.
+    /// By default it waits for as long as they take.
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Return the first error met while configuring, or the server if its
+    /// configuration is consistent and it has something to serve.
+    pub fn build(self) -> Result<Server> {
+        let ServerBuilder {
+            config,
+            control,
+            error,
+        } = self;
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let server = Server {
+            config,
+            control,
+            handler: None,
+        };
+        server.check_config()?;
+        Ok(server)
+    }
+}
+
+impl Server {
+    /// Start the server and hand it over to a [`ServerHandle`], which can
+    /// control it from any number of threads.
+    pub fn spawn(mut self) -> Result<ServerHandle> {
+        self.start()?;
+        Ok(ServerHandle {
+            control: self.control.clone(),
+            tenants: self.config.policy.tenants.clone(),
+            traces: self.config.policy.traces.clone(),
+            journal: self.config.journal.clone(),
+            debug: self.debug_handle(),
+            server: Arc::new(Mutex::new(Some(self))),
+        })
+    }
+}
+
+/// Controls a running [`Server`], see [`Server::spawn`]. Clones control
+/// the same server. They take no lock on it, so that e.g. a long
+/// [`ServerHandle::quiesce`] does not hold up calls through the others.
+#[derive(Clone)]
+pub struct ServerHandle {
+    control: Arc<Control>,
+    tenants: Arc<Tenants>,
+    traces: Arc<Traces>,
+    journal: Option<Arc<Journal>>,
+    debug: DebugHandle,
+    // taken by the first shutdown
+    server: Arc<Mutex<Option<Server>>>,
+}
+
+impl ServerHandle {
+    /// Whether the server is still running.
+    pub fn is_running(&self) -> bool {
+        !self.control.quit.load(Ordering::SeqCst)
+    }
+
+    /// Number of connections being served.
+    pub fn connection_count(&self) -> usize {
+        self.control.connections.lock().unwrap().len()
+    }
+
+    /// See [`Server::hand_off`].
+    pub fn hand_off(&self, control: RawFd) -> Result<()> {
+        self.control.running()?;
+        self.control.hand_off(control)
+    }
+
+    /// See [`Server::listen_addresses`].
+    pub fn listen_addresses(&self) -> Result<Vec<String>> {
+        self.control.running()?;
+        self.control.listen_addresses()
+    }
+
+    /// See [`Server::quiesce`].
+    pub fn quiesce(&self, timeout: Duration) -> Result<()> {
+        self.control.running()?;
+        self.control.quiesce(timeout)
+    }
+
+    /// See [`Server::add_tenant`].
+    pub fn add_tenant(
+        &self,
+        prefix: &[u8],
+        methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
+    ) -> Result<()> {
+        self.control.running()?;
+        self.tenants.add(prefix, methods);
+        Ok(())
+    }
+
+    /// See [`Server::remove_tenant`].
+    pub fn remove_tenant(&self, prefix: &[u8]) -> Result<bool> {
+        self.control.running()?;
+        Ok(self.tenants.remove(prefix))
+    }
+
+    /// See [`Server::trace`].
+    pub fn trace(&self, target: TraceTarget, percent: u32, duration: Duration) -> Result<()> {
+        self.control.running()?;
+        self.traces.start(target, percent, duration);
+        Ok(())
+    }
+
+    /// See [`Server::stop_trace`].
+    pub fn stop_trace(&self, target: &TraceTarget) -> Result<bool> {
+        self.control.running()?;
+        Ok(self.traces.stop(target))
+    }
+
+    /// See [`Server::resume`].
+    pub fn resume(&self) -> Result<()> {
+        self.control.running()?;
+        self.control.resume();
+        Ok(())
+    }
+
+    /// See [`Server::quiesce_status`].
+    pub fn quiesce_status(&self) -> Result<Vec<QuiesceStatus>> {
+        self.control.running()?;
+        Ok(self.control.quiesce_status())
+    }
+
+    /// See [`Server::debug_handle`].
+    pub fn debug_handle(&self) -> Result<DebugHandle> {
+        self.control.running()?;
+        Ok(self.debug.clone())
+    }
+
+    /// See [`Server::dump_journal`].
+    pub fn dump_journal(&self) -> Result<Vec<JournalEntry>> {
+        self.control.running()?;
+        Ok(self
+            .journal
+            .as_ref()
+            .map(|j| j.entries())
+            .unwrap_or_default())
+    }
+
+    /// Shut the server down, see [`Server::shutdown`]. Only the first call
+    /// through any clone of the handle does so; later ones fail.
+    pub fn shutdown(&self) -> Result<ShutdownReport> {
+        // don't hold the lock while joining threads
+        let server = self.server.lock().unwrap().take();
+        match server {
+            Some(server) => server.shutdown(),
+            None => Err(Error::Others("server already shut down".to_string())),
+        }
+    }
+}
+
+/// A request being served, see [`DebugHandle::requests`].
+#[derive(Clone, Debug)]
+pub struct InFlightRequest {
+    /// The fd of the connection the request arrived on.
+    pub connection: RawFd,
+    pub stream_id: u32,
+    /// `/service/method`.
+    pub method: String,
+    /// Time since the request was dispatched to its handler.
+    pub age: Duration,
+    /// Size of the request payload.
+    pub bytes: usize,
+    /// Whether the handler returned and the reply is deferred.
+    pub deferred: bool,
+}
+
+/// A connection being served, see [`DebugHandle::connections`].
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    /// The fd of the connection.
+    pub connection: RawFd,
+    /// Requests, notifications included, handed to their handler so far.
+    pub served: u64,
+    /// Requests still waiting for their reply.
+    pub pending: usize,
+}
+
+/// Live view of the requests a server is serving, like Go's `/debug`
+/// endpoints, see [`Server::debug_handle`]. Notifications, which get no
+/// reply, are not listed.
+#[derive(Clone)]
+pub struct DebugHandle {
+    connections: Arc<Mutex<HashMap<RawFd, Connection>>>,
+    served: Arc<AtomicU64>,
+    abandoned: Arc<AtomicUsize>,
+    torn_writes: Arc<AtomicUsize>,
+    timeouts: Arc<[AtomicUsize; PHASES]>,
+    decode_errors: Arc<[AtomicUsize; DECODE_ERROR_KINDS]>,
+}
+
+impl DebugHandle {
+    /// Number of connections being served.
+    pub fn connection_count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    /// Number of requests, notifications included, handed to their
+    /// handler on any connection since the server started.
+    pub fn served(&self) -> u64 {
+        self.served.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests still waiting for their reply.
+    pub fn in_flight(&self) -> usize {
+        let connections = self.connections.lock().unwrap();
+        connections.values().map(|cn| cn.pending.len()).sum()
+    }
+
+    /// Number of deferred replies still owed by handlers when their
+    /// connection closed, see [`ServerBuilder::set_reply_grace`].
+    pub fn abandoned(&self) -> usize {
+        self.abandoned.load(Ordering::SeqCst)
+    }
+
+    /// Number of connections closed because writing a frame to them failed
+    /// midway, each also emitting a `torn_write` event, see
+    /// [`EVENT_TARGET`].
+    pub fn torn_writes(&self) -> usize {
+        self.torn_writes.load(Ordering::SeqCst)
+    }
+
+    /// Number of requests, or connections for reads and writes, which ran
+    /// out of time in `phase`, see [`ServerBuilder::set_phase_timeout`].
+    pub fn timeouts(&self, phase: Phase) -> usize {
+        self.timeouts[phase as usize].load(Ordering::SeqCst)
+    }
+
+    /// Number of frames or requests which did not decode as `kind`,
+    /// whatever was done with them, see [`ServerBuilder::set_decode_error_policy`].
+    pub fn decode_errors(&self, kind: DecodeErrorKind) -> usize {
+        self.decode_errors[kind as usize].load(Ordering::SeqCst)
+    }
+
+    /// The connections being served, by fd.
+    ///
+    /// Each connection has its own method handler threads, so a busy
+    /// client only competes with others for CPU; comparing `served`
+    /// across connections shows how evenly they are served.
+    pub fn connections(&self) -> Vec<ConnectionStats> {
+        let mut stats: Vec<ConnectionStats> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|cn| ConnectionStats {
+                connection: cn.fd,
+                served: cn.served.load(Ordering::Relaxed),
+                pending: cn.pending.len(),
+            })
+            .collect();
+        stats.sort_by_key(|s| s.connection);
+        stats
+    }
+
+    /// The counters of this handle in OpenMetrics text format, for
+    /// scrapers reaching the server through ttrpc only, see
+    /// [`builtin::create_metrics`].
+    pub fn openmetrics(&self) -> String {
+        let connections = self.connections();
+        let pending: usize = connections.iter().map(|c| c.pending).sum();
+        let mut text = String::new();
+        text.push_str("# TYPE ttrpc_connections gauge\n");
+        text.push_str("# HELP ttrpc_connections Connections being served.\n");
+        text.push_str(&format!("ttrpc_connections {}\n", connections.len()));
+        text.push_str("# TYPE ttrpc_requests_pending gauge\n");
+        text.push_str("# HELP ttrpc_requests_pending Requests waiting for their reply.\n");
+        text.push_str(&format!("ttrpc_requests_pending {}\n", pending));
+        text.push_str("# TYPE ttrpc_torn_writes counter\n");
+        text.push_str("# HELP ttrpc_torn_writes Connections closed by a frame written midway.\n");
+        text.push_str(&format!("ttrpc_torn_writes_total {}\n", self.torn_writes()));
+        text.push_str("# TYPE ttrpc_timeouts counter\n");
+        text.push_str("# HELP ttrpc_timeouts Requests or connections out of time, by phase.\n");
+        for phase in &[Phase::Read, Phase::Queue, Phase::Handler, Phase::Write] {
+            text.push_str(&format!(
+                "ttrpc_timeouts_total{{phase=\"{}\"}} {}\n",
+                phase,
+                self.timeouts(*phase)
+            ));
+        }
+        text.push_str("# TYPE ttrpc_decode_errors counter\n");
+        text.push_str(
+            "# HELP ttrpc_decode_errors Frames or requests which did not decode, by kind.\n",
+        );
+        for kind in DecodeErrorKind::ALL.iter() {
+            text.push_str(&format!(
+                "ttrpc_decode_errors_total{{kind=\"{}\"}} {}\n",
+                kind,
+                self.decode_errors(*kind)
+            ));
+        }
+        text.push_str("# EOF\n");
+        text
+    }
+
+    /// The requests still waiting for their reply, oldest first.
+    pub fn requests(&self) -> Vec<InFlightRequest> {
+        let mut requests: Vec<InFlightRequest> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|cn| {
+                cn.pending
+                    .snapshot()
+                    .into_iter()
+                    .map(move |o| InFlightRequest {
+                        connection: cn.fd,
+                        stream_id: o.stream_id,
+                        method: o.path,
+                        age: o.age,
+                        bytes: o.bytes,
+                        deferred: o.deferred,
+                    })
+            })
+            .collect();
+        requests.sort_by_key(|q| std::cmp::Reverse(q.age));
+        requests
+    }
+
+    /// Answer a request with `CANCELLED` right away and flag it as
+    /// cancelled, see [`TtrpcContext::is_cancelled`]. Its handler keeps
+    /// running until it notices or returns; its reply is dropped.
+    pub fn cancel(&self, connection: RawFd, stream_id: u32) -> Result<()> {
+        let pending = match self.connections.lock().unwrap().get(&connection) {
+            Some(cn) => cn.pending.clone(),
+            None => {
+                return Err(get_rpc_status(
+                    Code::NOT_FOUND,
+                    format!("no connection {}", connection),
+                ))
+            }
+        };
+        let status = get_status(Code::CANCELLED, "cancelled by the server".to_string());
+        if !pending.abort(stream_id, status) {
+            return Err(get_rpc_status(
+                Code::NOT_FOUND,
+                format!("no request {} on connection {}", stream_id, connection),
+            ));
+        }
+        warn!(
+            target: EVENT_TARGET,
+            "request_cancelled fd={} stream={}", connection, stream_id
+        );
+        Ok(())
+    }
+}
+
+pub struct TtrpcContext {
+    #[deprecated(note = "use TtrpcContext::with_connection() instead")]
+    pub fd: RawFd,
+    pub mh: MessageHeader,
+    #[deprecated(note = "use TtrpcContext::sink() instead")]
+    pub res_tx: Sender<(MessageHeader, Vec<u8>)>,
+    metadata: Metadata,
+    fd_open: Arc<RwLock<bool>>,
+    sink: ResponseSink,
+    identity: Option<Arc<Vec<u8>>>,
+    client_info: Option<Arc<PeerInfo>>,
+    connection_data: Option<ConnectionData>,
+    cancelled: Arc<AtomicBool>,
+    path: Arc<str>,
+    policy: Arc<MethodPolicy>,
+    state: Arc<ConnectionState>,
+    extensions: Extensions,
+    // the messages of a streaming call, until taken
+    inbound: Mutex<Option<StreamInbound>>,
+}
+
+/// Sends the response to a request back on the connection it came from.
+///
+/// A handler may keep a sink after returning and reply later, e.g. from
+/// another thread once an event completes. The connection counts such a
+/// reply as pending until it is sent or every clone of the sink is dropped:
+/// it is answered with `DEADLINE_EXCEEDED` once the request's timeout
+/// passes, and a closing connection waits for it up to
+/// [`ServerBuilder::set_reply_grace`].
+#[derive(Clone)]
+pub struct ResponseSink {
+    inner: Arc<SinkInner>,
+}
+
+struct SinkInner {
+    stream_id: u32,
+    no_reply: bool,
+    progress_ok: bool,
+    request_id: Option<String>,
+    pending: Arc<PendingReplies>,
+    journal: Option<(Arc<Journal>, RawFd)>,
+    direct: Option<DirectWrite>,
+    // tells the connection a frame was queued, unless it watches the queue
+    wake: Option<Waker>,
+    cancelled: Arc<AtomicBool>,
+    on_complete: Mutex<Vec<CompleteHook>>,
+    error_detail: ErrorDetail,
+}
+
+type CompleteHook = Box<dyn FnOnce(Option<&Response>) + Send>;
+type Waker = Arc<dyn Fn() + Send + Sync>;
+
+impl SinkInner {
+    fn complete(&self, res: Option<&Response>) {
+        let hooks = std::mem::take(&mut *self.on_complete.lock().unwrap());
+        for f in hooks {
+            f(res);
+        }
+    }
+}
+
+/// Writes the replies of inline methods straight to the socket.
+struct DirectWrite {
+    fd: RawFd,
+    fd_open: Arc<RwLock<bool>>,
+    wlock: Arc<Mutex<()>>,
+    state: Arc<ConnectionState>,
+}
+
+impl DirectWrite {
+    fn write(&self, mut mh: MessageHeader, buf: Vec<u8>) -> Result<()> {
+        let open = self.fd_open.read().unwrap();
+        if !*open {
+            return Err(Error::ConnectionClosed);
+        }
+        if mh.type_ == MESSAGE_TYPE_RESPONSE {
+            mh.flags |= FLAG_BATCH_OK;
+        }
+        let _guard = self.wlock.lock().unwrap();
+        write_frame(self.fd, mh, &buf).map_err(|e| {
+            self.state.write_failed(self.fd, &e.error);
+            if e.torn {
+                self.state.torn(self.fd, &e.error);
+            }
+            e.error
+        })
+    }
+}
+
+impl Drop for SinkInner {
+    fn drop(&mut self) {
+        if self.pending.finish(self.stream_id) {
+            debug!(
+                "reply to stream {} dropped without being sent",
+                self.stream_id
+            );
+        }
+        self.complete(None);
+    }
+}
+
+impl ResponseSink {
+    /// Send `res` as the response, echoing the request id in its metadata.
+    ///
+    /// Fails if the request was already answered, including by the
+    /// connection once its deadline passed, or if the connection is closed.
+    ///
+    /// Does nothing for a request sent with [`Client::notify`].
+    ///
+    /// [`Client::notify`]: crate::Client::notify
+    pub fn send(&self, mut res: Response) -> Result<()> {
+        if self.inner.no_reply || self.is_cancelled() {
+            return Ok(());
+        }
+        let tx = self.inner.pending.sender()?;
+        if !self.inner.pending.finish(self.inner.stream_id) {
+            return Err(Error::Others(format!(
+                "stream {} was already answered",
+                self.inner.stream_id
+            )));
+        }
+        if res.has_status() {
+            if let Some(message) = self.inner.error_detail.apply(res.mut_status()) {
+                debug!(
+                    "withheld error from stream {} request-id {:?}: {}",
+                    self.inner.stream_id, self.inner.request_id, message
+                );
+            }
+        }
+        if let Some(id) = self.inner.request_id.as_ref() {
+            if !res.get_metadata().iter().any(|kv| kv.key == REQUEST_ID_KEY) {
+                let mut kv = KeyValue::new();
+                kv.set_key(REQUEST_ID_KEY.to_string());
+                kv.set_value(id.clone());
+                res.mut_metadata().push(kv);
+            }
+        }
+        let (mh, buf) = encode_response(self.inner.stream_id, &res)?;
+        if let Some((j, fd)) = self.inner.journal.as_ref() {
+            j.response(*fd, mh.stream_id, res.get_status().code, buf.len());
+        }
+        self.inner.complete(Some(&res));
+        self.write(mh, buf, tx)
+    }
+
+    /// Run `f` with the response once it is sent, or with `None` once
+    /// the request ended without one: when it was cancelled, answered by
+    /// the connection once its deadline passed, or every clone of the sink
+    /// was dropped. Responses sent with [`ResponseSink::send_raw`] are not
+    /// seen.
+    pub fn on_complete<F>(&self, f: F)
+    where
+        F: FnOnce(Option<&Response>) + Send + 'static,
+    {
+        self.inner.on_complete.lock().unwrap().push(Box::new(f));
+    }
+
+    /// Queue an already encoded frame on the connection. A frame on the
+    /// sink's own stream counts as its reply.
+    pub fn send_raw(&self, mh: MessageHeader, buf: Vec<u8>) -> Result<()> {
+        if (self.inner.no_reply || self.is_cancelled()) && mh.stream_id == self.inner.stream_id {
+            return Ok(());
+        }
+        let tx = self.inner.pending.sender()?;
+        if mh.stream_id == self.inner.stream_id {
+            self.inner.pending.finish(self.inner.stream_id);
+        }
+        self.write(mh, buf, tx)
+    }
+
+    /// Send a progress update ahead of the response, see
+    /// [`MESSAGE_TYPE_PROGRESS`](crate::MESSAGE_TYPE_PROGRESS).
+    ///
+    /// Does nothing if the client does not handle progress updates, see
+    /// [`ResponseSink::wants_progress`]. Fails once the request was
+    /// answered.
+    pub fn progress(&self, payload: Vec<u8>) -> Result<()> {
+        if !self.wants_progress() || self.is_cancelled() {
+            return Ok(());
+        }
+        let tx = self.inner.pending.sender()?;
+        if !self.is_pending() {
+            return Err(Error::Others(format!(
+                "stream {} was already answered",
+                self.inner.stream_id
+            )));
+        }
+        let mh = MessageHeader {
+            length: payload.len() as u32,
+            stream_id: self.inner.stream_id,
+            type_: MESSAGE_TYPE_PROGRESS,
+            flags: 0,
+        };
+        self.write(mh, payload, tx)
+    }
+
+    /// Send a message of a streaming method ahead of the response, see
+    /// [`StreamSink`].
+    pub(crate) fn data(&self, payload: Vec<u8>) -> Result<()> {
+        if self.inner.no_reply {
+            return Ok(());
+        }
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let tx = self.inner.pending.sender()?;
+        if !self.is_pending() {
+            return Err(Error::Others(format!(
+                "stream {} was already answered",
+                self.inner.stream_id
+            )));
+        }
+        let mh = MessageHeader {
+            length: payload.len() as u32,
+            stream_id: self.inner.stream_id,
+            type_: MESSAGE_TYPE_DATA,
+            flags: 0,
+        };
+        if self.inner.direct.is_some() {
+            return self.write(mh, payload, tx);
+        }
+        let backlog = self.inner.pending.data_backlog();
+        backlog.fetch_add(1, Ordering::SeqCst);
+        let result = self.write(mh, payload, tx);
+        if result.is_err() {
+            backlog.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Number of streamed messages of the connection queued and not
+    /// written yet.
+    pub(crate) fn data_backlog(&self) -> usize {
+        // nothing more is written for a call which ended
+        if self.is_cancelled() || !self.is_pending() || self.inner.pending.sender().is_err() {
+            return 0;
+        }
+        self.inner.pending.data_backlog().load(Ordering::SeqCst)
+    }
+
+    fn write(
+        &self,
+        mh: MessageHeader,
+        buf: Vec<u8>,
+        tx: Sender<(MessageHeader, Vec<u8>)>,
+    ) -> Result<()> {
+        match self.inner.direct.as_ref() {
+            Some(direct) => direct.write(mh, buf),
+            None => {
+                tx.send((mh, buf)).map_err(err_to_Others!(e, ""))?;
+                if let Some(wake) = self.inner.wake.as_ref() {
+                    wake();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The stream id of the request this sink answers.
+    pub fn stream_id(&self) -> u32 {
+        self.inner.stream_id
+    }
+
+    /// Whether the client cancelled the request. Responses to a cancelled
+    /// request are dropped.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Whether the client asked for a reply at all, see [`Client::notify`].
+    ///
+    /// [`Client::notify`]: crate::Client::notify
+    pub fn wants_reply(&self) -> bool {
+        !self.inner.no_reply
+    }
+
+    /// Whether the client handles progress updates, see
+    /// [`Client::request_with_progress`].
+    ///
+    /// [`Client::request_with_progress`]: crate::Client::request_with_progress
+    pub fn wants_progress(&self) -> bool {
+        !self.inner.no_reply && self.inner.progress_ok
+    }
+
+    /// Whether the request still waits for its reply.
+    pub fn is_pending(&self) -> bool {
+        self.inner.pending.is_pending(self.inner.stream_id)
+    }
+}
+
+/// Temporary access to the connection a request arrived on, see
+/// [`TtrpcContext::with_connection`].
+pub struct ConnectionRef<'a> {
+    fd: BorrowedFd<'a>,
+}
+
+impl<'a> ConnectionRef<'a> {
+    /// The connected socket.
+    ///
+    /// The server reads and writes ttrpc frames on this fd concurrently, so
+    /// it must not be read from, written to, closed or switched to
+    /// non-blocking mode. Socket options, ioctls and the like are fine.
+    pub fn fd(&self) -> BorrowedFd<'a> {
+        self.fd
+    }
+
+    /// The address of the peer.
+    pub fn peer_addr(&self) -> Result<SockAddr> {
+        getpeername(self.fd.as_raw_fd()).map_err(|e| Error::Socket(e.to_string()))
+    }
+
+    /// The credentials of the peer process, for unix sockets. Linux and
+    /// Android only.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn peer_credentials(&self) -> Result<UnixCredentials> {
+        getsockopt(self.fd.as_raw_fd(), sockopt::PeerCredentials)
+            .map_err(|e| Error::Socket(e.to_string()))
+    }
+
+    /// Stop reading requests. The replies owed for requests already read
+    /// are still sent, then the connection is closed.
+    pub fn shutdown_read(&self) -> Result<()> {
+        socket::shutdown(self.fd.as_raw_fd(), Shutdown::Read)
+            .map_err(|e| Error::Socket(e.to_string()))
+    }
+
+    /// Tell the peer no more replies will be sent, as the peer sees end of
+    /// file. Replies sent afterwards fail and close the connection.
+    pub fn shutdown_write(&self) -> Result<()> {
+        socket::shutdown(self.fd.as_raw_fd(), Shutdown::Write)
+            .map_err(|e| Error::Socket(e.to_string()))
+    }
+}
+
+impl TtrpcContext {
+    /// A handle for sending the response, now or after the handler returned.
+    pub fn sink(&self) -> ResponseSink {
+        self.sink.clone()
+    }
+
+    /// The messages the client streams to this method, see
+    /// [`stream`](crate::stream). Fails with `INVALID_ARGUMENT` if the
+    /// client did not open a stream, or once they were taken.
+    pub fn request_stream<T: Message>(&self) -> Result<RequestStream<T>> {
+        let mut inbound = self.inbound.lock().unwrap();
+        if self.mh.flags & FLAG_REMOTE_OPEN == 0 {
+            return Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!(
+                    "{} takes a stream, which the client did not open",
+                    self.path
+                ),
+            ));
+        }
+        match inbound.take() {
+            Some(inbound) => Ok(RequestStream::new(inbound)),
+            None => Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("the stream of {} was already taken", self.path),
+            )),
+        }
+    }
+
+    /// Streams messages to the client ahead of the response, which the
+    /// handler still returns.
+    pub fn stream_sink<T: Message>(&self) -> StreamSink<T> {
+        StreamSink::new(self.sink.clone())
+    }
+
+    /// Report that the request payload does not decode. It is answered
+    /// with `INVALID_ARGUMENT`, or fails so the connection is closed, as
+    /// [`ServerBuilder::set_decode_error_policy`] says.
+    pub fn undecodable(&self, message: String) -> Result<()> {
+        #[allow(deprecated)]
+        let e = DecodeError {
+            kind: DecodeErrorKind::Payload,
+            connection: self.fd,
+            stream_id: self.mh.stream_id,
+            method: Some(self.path.to_string()),
+            message,
+        };
+        if !self.policy.undecodable(&self.state, &e)? {
+            return Ok(());
+        }
+        let mut res = Response::new();
+        res.set_status(get_status(Code::INVALID_ARGUMENT, e.message));
+        self.sink.send(res)
+    }
+
+    /// Run `f` with access to the connection this request arrived on.
+    ///
+    /// The connection is kept open until `f` returns, so `f` should be
+    /// short. Fails with [`Error::ConnectionClosed`] if the connection
+    /// was already torn down, which can happen for handlers replying late.
+    pub fn with_connection<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&ConnectionRef) -> T,
+    {
+        let open = self.fd_open.read().unwrap();
+        if !*open {
+            return Err(Error::ConnectionClosed);
+        }
+
+        #[allow(deprecated)]
+        let conn = ConnectionRef {
+            // safe: the fd is not closed while we hold the read lock
+            fd: unsafe { BorrowedFd::borrow_raw(self.fd) },
+        };
+        Ok(f(&conn))
+    }
+
+    /// The identity the client sent when it connected, see
+    /// [`Client::connect_with_identity`].
+    ///
+    /// [`Client::connect_with_identity`]: crate::Client::connect_with_identity
+    pub fn identity(&self) -> Option<&[u8]> {
+        self.identity.as_ref().map(|id| id.as_slice())
+    }
+
+    /// The library version and protocol capabilities the client sent
+    /// when it connected, see [`MESSAGE_TYPE_HELLO`]. `None` for clients
+    /// predating the handshake.
+    pub fn client_info(&self) -> Option<&PeerInfo> {
+        self.client_info.as_deref()
+    }
+
+    /// The registered extensions the client sent with the request, see
+    /// [`extension`](crate::extension).
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// The data the [`ServerBuilder::set_on_connect`] callback attached to the
+    /// connection, if it is a `T`.
+    pub fn connection_data<T: Any>(&self) -> Option<&T> {
+        self.connection_data.as_ref()?.downcast_ref()
+    }
+
+    /// Whether the client cancelled the request, see
+    /// [`MESSAGE_TYPE_CANCEL`](crate::MESSAGE_TYPE_CANCEL). Long handlers
+    /// can poll it to stop early; their response is dropped anyway.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// The metadata the client sent with the request, keys lower-cased.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The id correlating this request with its response and logs.
+    ///
+    /// Taken from the `request-id` metadata sent by the client, or generated
+    /// by the server when the client did not send one.
+    pub fn request_id(&self) -> &str {
+        metadata::get(&self.metadata, REQUEST_ID_KEY).unwrap_or_default()
+    }
+
+    /// The containerd namespace the client sent, see
+    /// [`metadata::NAMESPACE_KEY`].
+    pub fn namespace(&self) -> Option<&str> {
+        metadata::namespace(&self.metadata)
+    }
+
+    /// The checkpoint a call made again after losing its connection
+    /// resumes from, as the handler sent it in a progress update, see
+    /// [`Resumable`](crate::resume::Resumable).
+    pub fn resume_token(&self) -> Option<&str> {
+        metadata::get(&self.metadata, RESUME_TOKEN_KEY)
+    }
+}
+
+/// Copy the request id of `metadata` into the response metadata.
+pub fn echo_request_id(metadata: &Metadata, res: &mut Response) {
+    if let Some(id) = metadata::get(metadata, REQUEST_ID_KEY) {
+        let mut kv = KeyValue::new();
+        kv.set_key(REQUEST_ID_KEY.to_string());
+        kv.set_value(id.to_string());
+        res.mut_metadata().push(kv);
+    }
+}
+
+pub trait MethodHandler {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()>;
+}
+
+/// A service registered as a whole with [`ServerBuilder::register`]. The code
+/// generated for a proto service implements it on `<Service>Service`.
+pub trait Service {
+    /// The fully qualified name of the service, e.g. `grpc.Health`.
+    fn name(&self) -> &str;
+
+    /// The handlers of its methods, by method path.
+    fn methods(&self) -> HashMap<String, Box<dyn MethodHandler + Send + Sync>>;
+
+    /// The hashes of its methods, by method path, see
+    /// [`ServerBuilder::register_method_hashes`]. None by default.
+    fn method_hashes(&self) -> &[(&str, u64)] {
+        &[]
+    }
+}
+
+struct WithMiddleware {
+    inner: Arc<dyn Service + Send + Sync>,
+    middleware: Middleware,
+}
+
+impl Service for WithMiddleware {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn methods(&self) -> HashMap<String, Box<dyn MethodHandler + Send + Sync>> {
+        wrap_service(self.inner.methods(), "", Some(self.middleware.clone()))
+    }
+
+    fn method_hashes(&self) -> &[(&str, u64)] {
+        self.inner.method_hashes()
+    }
+}
+
+/// Route every call to `service` through `middleware`.
+pub fn with_middleware(
+    service: Arc<dyn Service + Send + Sync>,
+    middleware: Middleware,
+) -> Arc<dyn Service + Send + Sync> {
+    Arc::new(WithMiddleware {
+        inner: service,
+        middleware,
+    })
+}
+
+/// Wraps every call to a method: gets the method path, the call and the
+/// handler it should eventually run.
+pub type Middleware =
+    Arc<dyn Fn(&str, TtrpcContext, Request, &dyn MethodHandler) -> Result<()> + Send + Sync>;
+
+struct Wrapped {
+    path: String,
+    inner: Box<dyn MethodHandler + Send + Sync>,
+    middleware: Middleware,
+}
+
+impl MethodHandler for Wrapped {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        (self.middleware)(&self.path, ctx, req, self.inner.as_ref())
+    }
+}
+
+/// Prefix the service name of every method in `methods` with `prefix`
+/// and route each call through `middleware`, if any.
+///
+/// This lets one server expose several instances of the same service,
+/// e.g. one per sandbox: with a prefix of `"sandbox1/"` clients call the
+/// service `sandbox1/<package>.<Service>`.
+pub fn wrap_service(
+    methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
+    prefix: &str,
+    middleware: Option<Middleware>,
+) -> HashMap<String, Box<dyn MethodHandler + Send + Sync>> {
+    methods
+        .into_iter()
+        .map(|(path, inner)| {
+            let path = format!("/{}{}", prefix, path.trim_start_matches('/'));
+            let handler: Box<dyn MethodHandler + Send + Sync> = match middleware {
+                Some(ref m) => Box::new(Wrapped {
+                    path: path.clone(),
+                    inner,
+                    middleware: m.clone(),
+                }),
+                None => inner,
+            };
+            (path, handler)
+        })
+        .collect()
+}
+
+pub fn response_to_channel(
+    stream_id: u32,
     res: Response,
     tx: Sender<(MessageHeader, Vec<u8>)>,
 ) -> Result<()> {
-    let mut buf = Vec::with_capacity(res.compute_size() as usize);
-    let mut s = CodedOutputStream::vec(&mut buf);
-    res.write_to(&mut s).map_err(err_to_Others!(e, ""))?;
-    s.flush().map_err(err_to_Others!(e, ""))?;
+    let (mh, buf) = encode_response(stream_id, &res)?;
+    tx.send((mh, buf)).map_err(err_to_Others!(e, ""))?;
+
+    Ok(())
+}
+
+fn encode_response(stream_id: u32, res: &Response) -> Result<(MessageHeader, Vec<u8>)> {
+    let buf = proto::encode_response(res)?;
 
     let mh = MessageHeader {
         length: buf.len() as u32,
@@ -588,41 +4113,469 @@ pub fn response_to_channel(
         type_: MESSAGE_TYPE_RESPONSE,
         flags: 0,
     };
-    tx.send((mh, buf)).map_err(err_to_Others!(e, ""))?;
 
-    Ok(())
+    Ok((mh, buf))
 }
 
 #[macro_export]
 macro_rules! request_handler {
-    ($class: ident, $ctx: ident, $req: ident, $server: ident, $req_type: ident, $req_fn: ident) => {
+    ($class: ident, $ctx: ident, $req: ident, $($server: ident)::+, $req_type: ident, $req_fn: ident) => {
         let mut s = CodedInputStream::from_bytes(&$req.payload);
-        let mut req = super::$server::$req_type::new();
-        req.merge_from(&mut s)
-            .map_err(::ttrpc::Err_to_Others!(e, ""))?;
-
-        let mut res = ::ttrpc::Response::new();
-        match $class.service.$req_fn(&$ctx, req) {
-            Ok(rep) => {
-                res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string()));
-                res.payload.reserve(rep.compute_size() as usize);
-                let mut s = CodedOutputStream::vec(&mut res.payload);
-                rep.write_to(&mut s)
-                    .map_err(::ttrpc::Err_to_Others!(e, ""))?;
-                s.flush().map_err(::ttrpc::Err_to_Others!(e, ""))?;
-            }
-            Err(x) => match x {
-                ::ttrpc::Error::RpcStatus(s) => {
-                    res.set_status(s);
-                }
-                _ => {
-                    res.set_status(::ttrpc::get_status(
-                        ::ttrpc::Code::UNKNOWN,
-                        format!("{:?}", x),
-                    ));
+        let mut req = super::$($server)::+::$req_type::new();
+        if let Err(e) = req.merge_from(&mut s) {
+            return $ctx.undecodable(e.to_string());
+        }
+
+        let result = $class.service.$req_fn(&$ctx, req);
+        if $ctx.sink().wants_reply() {
+            let mut res = ::ttrpc::Response::new();
+            match result {
+                Ok(rep) => {
+                    res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string()));
+                    res.payload.reserve(rep.compute_size() as usize);
+                    let mut s = CodedOutputStream::vec(&mut res.payload);
+                    rep.write_to(&mut s)
+                        .map_err(::ttrpc::Err_to_Others!(e, ""))?;
+                    s.flush().map_err(::ttrpc::Err_to_Others!(e, ""))?;
+                }
+                Err(x) => res.set_status(x.to_status()),
+            }
+            $ctx.sink().send(res)?
+        }
+    };
+}
+
+/// Like `request_handler!`, but decoding the request into a message of
+/// `$class.pool`, a [`MessagePool`](crate::message_pool::MessagePool), and
+/// giving it back before the response is sent.
+#[macro_export]
+macro_rules! pooled_request_handler {
+    ($class: ident, $ctx: ident, $req: ident, $req_fn: ident) => {
+        let req = match $class.pool.decode(&$req.payload) {
+            Ok(req) => req,
+            Err(e) => return $ctx.undecodable(e.to_string()),
+        };
+
+        let result = $class.service.$req_fn(&$ctx, &req);
+        drop(req);
+        if $ctx.sink().wants_reply() {
+            let mut res = ::ttrpc::Response::new();
+            match result {
+                Ok(rep) => {
+                    res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string()));
+                    res.payload.reserve(rep.compute_size() as usize);
+                    let mut s = CodedOutputStream::vec(&mut res.payload);
+                    rep.write_to(&mut s)
+                        .map_err(::ttrpc::Err_to_Others!(e, ""))?;
+                    s.flush().map_err(::ttrpc::Err_to_Others!(e, ""))?;
+                }
+                Err(x) => res.set_status(x.to_status()),
+            }
+            $ctx.sink().send(res)?
+        }
+    };
+}
+
+/// Like `request_handler!`, for methods the client streams its messages
+/// to, see [`TtrpcContext::request_stream`].
+#[macro_export]
+macro_rules! client_streaming_handler {
+    ($class: ident, $ctx: ident, $req: ident, $req_fn: ident) => {
+        // the messages follow the request, which carries none
+        drop($req);
+        let stream = match $ctx.request_stream() {
+            Ok(stream) => stream,
+            Err(x) => {
+                if $ctx.sink().wants_reply() {
+                    let mut res = ::ttrpc::Response::new();
+                    res.set_status(x.to_status());
+                    $ctx.sink().send(res)?
+                }
+                return Ok(());
+            }
+        };
+
+        let result = $class.service.$req_fn(&$ctx, stream);
+        if $ctx.sink().wants_reply() {
+            let mut res = ::ttrpc::Response::new();
+            match result {
+                Ok(rep) => {
+                    res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string()));
+                    res.payload.reserve(rep.compute_size() as usize);
+                    let mut s = CodedOutputStream::vec(&mut res.payload);
+                    rep.write_to(&mut s)
+                        .map_err(::ttrpc::Err_to_Others!(e, ""))?;
+                    s.flush().map_err(::ttrpc::Err_to_Others!(e, ""))?;
+                }
+                Err(x) => res.set_status(x.to_status()),
+            }
+            $ctx.sink().send(res)?
+        }
+    };
+}
+
+/// Like `request_handler!`, for methods streaming their messages back,
+/// see [`TtrpcContext::stream_sink`]. The response carries no payload.
+#[macro_export]
+macro_rules! server_streaming_handler {
+    ($class: ident, $ctx: ident, $req: ident, $($server: ident)::+, $req_type: ident, $req_fn: ident) => {
+        let mut s = CodedInputStream::from_bytes(&$req.payload);
+        let mut req = super::$($server)::+::$req_type::new();
+        if let Err(e) = req.merge_from(&mut s) {
+            return $ctx.undecodable(e.to_string());
+        }
+
+        let result = $class.service.$req_fn(&$ctx, req, $ctx.stream_sink());
+        if $ctx.sink().wants_reply() {
+            let mut res = ::ttrpc::Response::new();
+            match result {
+                Ok(()) => res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string())),
+                Err(x) => res.set_status(x.to_status()),
+            }
+            $ctx.sink().send(res)?
+        }
+    };
+}
+
+/// Like `request_handler!`, for methods the client and the method stream
+/// messages to each other through.
+#[macro_export]
+macro_rules! duplex_streaming_handler {
+    ($class: ident, $ctx: ident, $req: ident, $req_fn: ident) => {
+        // the messages follow the request, which carries none
+        drop($req);
+        let stream = match $ctx.request_stream() {
+            Ok(stream) => stream,
+            Err(x) => {
+                if $ctx.sink().wants_reply() {
+                    let mut res = ::ttrpc::Response::new();
+                    res.set_status(x.to_status());
+                    $ctx.sink().send(res)?
                 }
-            },
+                return Ok(());
+            }
+        };
+
+        let result = $class.service.$req_fn(&$ctx, stream, $ctx.stream_sink());
+        if $ctx.sink().wants_reply() {
+            let mut res = ::ttrpc::Response::new();
+            match result {
+                Ok(()) => res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string())),
+                Err(x) => res.set_status(x.to_status()),
+            }
+            $ctx.sink().send(res)?
         }
-        ::ttrpc::response_to_channel($ctx.mh.stream_id, res, $ctx.res_tx)?
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::{read_message, write_message, write_message_header};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::IntoRawFd;
+
+    // the largest frame the listeners of these tests take
+    const MAX: usize = 64;
+
+    /// A server taking frames of up to `MAX` bytes, and a client
+    /// connected to it. Any stream socket does for the server, and
+    /// loopback TCP needs no socket file.
+    fn serve(policy: DecodeErrorPolicy) -> (Server, TcpStream) {
+        serve_with(Server::builder().set_decode_error_policy(policy))
+    }
+
+    /// Like [`serve`], for a server set up further.
+    fn serve_with(builder: ServerBuilder) -> (Server, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ListenerConfig::new().max_message_size(MAX);
+        let mut server = builder
+            .add_listener_with(listener.into_raw_fd(), config)
+            .build()
+            .unwrap();
+        server.start().unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (server, stream)
+    }
+
+    fn header(type_: u8, stream_id: u32, length: usize) -> MessageHeader {
+        MessageHeader {
+            length: length as u32,
+            stream_id,
+            type_,
+            flags: 0,
+        }
+    }
+
+    fn request(stream: &TcpStream, stream_id: u32, buf: Vec<u8>) {
+        let mh = header(MESSAGE_TYPE_REQUEST, stream_id, buf.len());
+        write_message(stream.as_raw_fd(), mh, buf).unwrap();
+    }
+
+    /// The next response the server sent, skipping other frames.
+    fn response(stream: &TcpStream) -> (u32, Response) {
+        loop {
+            let (mh, buf) = read_message(stream.as_raw_fd()).unwrap();
+            if mh.type_ == MESSAGE_TYPE_RESPONSE {
+                return (mh.stream_id, Response::parse_from_bytes(&buf).unwrap());
+            }
+        }
+    }
+
+    /// Check the connection is still served, by calling a method the
+    /// server does not have on `stream_id`.
+    fn still_served(stream: &TcpStream, stream_id: u32) {
+        let mut req = Request::new();
+        req.set_service("x".to_string());
+        req.set_method("y".to_string());
+        request(stream, stream_id, req.write_to_bytes().unwrap());
+        assert_eq!(response(stream).0, stream_id);
+    }
+
+    #[test]
+    fn test_decode_error_oversized() {
+        let (server, stream) = serve(DecodeErrorPolicy::Respond);
+
+        request(&stream, 1, vec![0; MAX + 1]);
+        let (stream_id, res) = response(&stream);
+        assert_eq!(stream_id, 1);
+        assert_eq!(res.get_status().get_code(), Code::INVALID_ARGUMENT);
+        still_served(&stream, 3);
+
+        let debug = server.debug_handle();
+        assert_eq!(debug.decode_errors(DecodeErrorKind::Oversized), 1);
+        assert_eq!(debug.decode_errors(DecodeErrorKind::Length), 0);
+        drop(stream);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_decode_error_length() {
+        let (server, stream) = serve(DecodeErrorPolicy::Close);
+
+        // only the header, as the server closes before reading a body
+        let mh = header(MESSAGE_TYPE_REQUEST, 1, MESSAGE_LENGTH_MAX + 1);
+        write_message_header(stream.as_raw_fd(), mh).unwrap();
+        loop {
+            match read_message(stream.as_raw_fd()) {
+                Ok((mh, _)) => assert_ne!(mh.type_, MESSAGE_TYPE_RESPONSE),
+                Err(Error::Socket(e)) => {
+                    assert_eq!(e, SOCK_DICONNECTED);
+                    break;
+                }
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        }
+
+        let debug = server.debug_handle();
+        assert_eq!(debug.decode_errors(DecodeErrorKind::Length), 1);
+        assert_eq!(debug.decode_errors(DecodeErrorKind::Oversized), 0);
+        drop(stream);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_decode_error_batch() {
+        let (server, stream) = serve(DecodeErrorPolicy::Respond);
+
+        // a frame header claiming more than the batch holds
+        let mut buf = vec![0; proto::MESSAGE_HEADER_LENGTH + 4];
+        proto::encode_message_header(&header(MESSAGE_TYPE_REQUEST, 1, 32), &mut buf);
+        let mh = header(MESSAGE_TYPE_BATCH, 0, buf.len());
+        write_message(stream.as_raw_fd(), mh, buf).unwrap();
+        // batch frames are never answered, so the next response is this
+        still_served(&stream, 3);
+
+        let debug = server.debug_handle();
+        assert_eq!(debug.decode_errors(DecodeErrorKind::Batch), 1);
+        drop(stream);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_decode_error_envelope() {
+        let (server, stream) = serve(DecodeErrorPolicy::Respond);
+
+        // a varint which never ends is no Request
+        request(&stream, 1, vec![0xff; 8]);
+        let (stream_id, res) = response(&stream);
+        assert_eq!(stream_id, 1);
+        assert_eq!(res.get_status().get_code(), Code::INVALID_ARGUMENT);
+        still_served(&stream, 3);
+
+        let debug = server.debug_handle();
+        assert_eq!(debug.decode_errors(DecodeErrorKind::Envelope), 1);
+        drop(stream);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_header_filter() {
+        let builder = Server::builder().set_header_filter(|mh| {
+            if mh.length > 16 {
+                return Err(get_rpc_status(
+                    Code::RESOURCE_EXHAUSTED,
+                    "too long".to_string(),
+                ));
+            }
+            Ok(())
+        });
+        let (server, stream) = serve_with(builder);
+
+        // no Request, but it is refused before anything tries to decode it
+        request(&stream, 1, vec![0xff; 32]);
+        let (stream_id, res) = response(&stream);
+        assert_eq!(stream_id, 1);
+        assert_eq!(res.get_status().get_code(), Code::RESOURCE_EXHAUSTED);
+        still_served(&stream, 3);
+
+        // refused on its header as well, and never answered
+        request(&stream, 0, vec![0xff; 8]);
+        still_served(&stream, 5);
+
+        let debug = server.debug_handle();
+        assert_eq!(debug.decode_errors(DecodeErrorKind::Envelope), 0);
+        assert_eq!(debug.decode_errors(DecodeErrorKind::ZeroStream), 1);
+        drop(stream);
+        server.shutdown().unwrap();
+    }
+
+    /// Whether the server sends GOAWAY on shutdown to a client which said
+    /// hello, or not.
+    fn goaway_sent(hello: bool) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::builder()
+            .add_listener(listener.into_raw_fd())
+            .build()
+            .unwrap();
+        server.start().unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        if hello {
+            let (mh, buf) = proto::hello_frame();
+            write_message(stream.as_raw_fd(), mh, buf).unwrap();
+        }
+        // the hello is handled by now
+        still_served(&stream, 1);
+
+        let shutdown = thread::spawn(move || server.shutdown());
+        let mut sent = false;
+        while let Ok((mh, _)) = read_message(stream.as_raw_fd()) {
+            sent |= mh.type_ == MESSAGE_TYPE_GOAWAY;
+        }
+        shutdown.join().unwrap().unwrap();
+        sent
+    }
+
+    #[test]
+    fn test_goaway() {
+        assert!(goaway_sent(true));
+        // older clients would take it for a response to a stream never used
+        assert!(!goaway_sent(false));
+    }
+
+    /// Answers once told to.
+    struct HeldMethod(Mutex<Receiver<()>>);
+
+    impl MethodHandler for HeldMethod {
+        fn handler(&self, ctx: TtrpcContext, _: Request) -> Result<()> {
+            self.0.lock().unwrap().recv().unwrap_or(());
+            ctx.sink().send(Response::new())
+        }
+    }
+
+    #[test]
+    fn test_handle_quiesce() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (release, held) = channel();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert(
+            "/x/Held".to_string(),
+            Box::new(HeldMethod(Mutex::new(held))),
+        );
+        let handle = Server::builder()
+            .add_listener(listener.into_raw_fd())
+            .register_service(methods)
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut req = Request::new();
+        req.set_service("x".to_string());
+        req.set_method("Held".to_string());
+        request(&stream, 1, req.write_to_bytes().unwrap());
+        let debug = handle.debug_handle().unwrap();
+        while debug.requests().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // waits for the held request, without holding up the other handles
+        let quiescing = handle.clone();
+        let quiesce = thread::spawn(move || quiescing.quiesce(Duration::from_secs(5)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!quiesce.is_finished());
+        assert!(handle.listen_addresses().is_ok());
+        assert_eq!(handle.quiesce_status().unwrap().len(), 1);
+
+        release.send(()).unwrap();
+        quiesce.join().unwrap().unwrap();
+        assert_eq!(response(&stream).0, 1);
+        handle.resume().unwrap();
+        drop(stream);
+        handle.shutdown().unwrap();
+        assert!(!handle.is_running());
+        assert!(handle.quiesce(Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_hand_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handle = Server::builder()
+            .add_listener(listener.into_raw_fd())
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        assert_eq!(handle.listen_addresses().unwrap().len(), 1);
+
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let receiver = thread::spawn(move || handoff::receive_listeners(theirs.as_raw_fd()));
+        handle.hand_off(ours.as_raw_fd()).unwrap();
+        let received = receiver.join().unwrap().unwrap();
+        assert_eq!(received.len(), 1);
+        // the fds of the listeners handed off may be reused by now
+        assert!(handle.listen_addresses().unwrap().is_empty());
+        assert!(handle.hand_off(ours.as_raw_fd()).is_err());
+
+        for fd in received {
+            close(fd).unwrap();
+        }
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_bind() {
+        assert!(Server::bind("bogus://addr").is_err());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .build()
+            .unwrap();
+        assert_eq!(server.listen_addresses().unwrap().len(), 1);
+    }
+}
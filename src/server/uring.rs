@@ -0,0 +1,1384 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The io_uring backend of the server, see [`Server::start_uring`].
+//!
+//! One thread accepts, reads and writes for every connection through an
+//! io_uring, decoding what it reads with [`ServerConnection`]. Handlers
+//! run on a pool of threads shared by all connections, and queue their
+//! replies on their connection like with the threaded backend, waking
+//! the ring thread through an eventfd.
+
+use rustix::event::{eventfd, EventfdFlags};
+use rustix::io::Errno;
+use rustix::io_uring::{
+    io_uring_cqe, io_uring_enter, io_uring_params, io_uring_ptr, io_uring_setup, io_uring_sqe,
+    io_uring_user_data, IoringEnterFlags, IoringFeatureFlags, IoringOp, IORING_OFF_CQ_RING,
+    IORING_OFF_SQES, IORING_OFF_SQ_RING,
+};
+use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
+use rustix::net::{SendFlags, SocketFlags};
+use std::ffi::c_void;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::ptr::null_mut;
+use std::sync::atomic::AtomicU32;
+
+use super::*;
+use crate::fair::FairQueue;
+use crate::proto::{encode_frame, ServerConnection, ServerEvent, MESSAGE_HEADER_LENGTH};
+use crate::ttrpc::Status;
+
+const RING_ENTRIES: u32 = 1024;
+const RECV_BUFFER_SIZE: usize = 64 << 10;
+// how many bytes of requests a connection has handled per turn on the
+// pool, see FairQueue
+const FAIR_QUANTUM: usize = 4 << 10;
+// how often the deadlines of deferred replies are checked
+const TICK: Duration = Duration::from_millis(50);
+// how long reads and writes get to finish once their sockets are shut down
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a completion is for. The `user_data` of an entry is the op in
+/// the low bits and the id of its listener or connection above.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Accept,
+    Recv,
+    Send,
+    Wake,
+    Quit,
+    Tick,
+}
+
+impl Op {
+    fn token(self, id: u64) -> u64 {
+        (id << 3) | self as u64
+    }
+
+    fn from_token(token: u64) -> (Op, u64) {
+        let op = match token & 7 {
+            0 => Op::Accept,
+            1 => Op::Recv,
+            2 => Op::Send,
+            3 => Op::Wake,
+            4 => Op::Quit,
+            _ => Op::Tick,
+        };
+        (op, token >> 3)
+    }
+}
+
+#[repr(C)]
+struct KernelTimespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+/// Part of a ring mapped into memory.
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, offset: u64) -> Result<Mapping> {
+        let ptr = unsafe {
+            mmap(
+                null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED | MapFlags::POPULATE,
+                fd,
+                offset,
+            )
+        }
+        .map_err(|e| Error::Others(format!("failed to map io_uring: {}", e)))?;
+        Ok(Mapping { ptr, len })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { (self.ptr as *mut u8).add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len) }.unwrap_or(());
+    }
+}
+
+/// An io_uring, driven from a single thread.
+struct Ring {
+    fd: OwnedFd,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut io_uring_sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const io_uring_cqe,
+    // what the pointers above point into
+    _maps: Vec<Mapping>,
+}
+
+impl Ring {
+    fn new(entries: u32) -> Result<Ring> {
+        let mut p = io_uring_params::default();
+        let fd = unsafe { io_uring_setup(entries, &mut p) }
+            .map_err(|e| Error::Others(format!("io_uring_setup failed: {}", e)))?;
+        let sq_len = p.sq_off.array as usize + p.sq_entries as usize * size_of::<u32>();
+        let cq_len = p.cq_off.cqes as usize + p.cq_entries as usize * size_of::<io_uring_cqe>();
+        let mut maps = Vec::new();
+        if p.features.contains(IoringFeatureFlags::SINGLE_MMAP) {
+            maps.push(Mapping::new(&fd, sq_len.max(cq_len), IORING_OFF_SQ_RING)?);
+        } else {
+            maps.push(Mapping::new(&fd, sq_len, IORING_OFF_SQ_RING)?);
+            maps.push(Mapping::new(&fd, cq_len, IORING_OFF_CQ_RING)?);
+        }
+        let sqes_len = p.sq_entries as usize * size_of::<io_uring_sqe>();
+        let sqes = Mapping::new(&fd, sqes_len, IORING_OFF_SQES)?;
+
+        let (sq, cq) = (&maps[0], &maps[maps.len() - 1]);
+        let ring = Ring {
+            sq_head: sq.at(p.sq_off.head),
+            sq_tail: sq.at(p.sq_off.tail),
+            sq_mask: unsafe { *sq.at::<u32>(p.sq_off.ring_mask) },
+            sq_entries: p.sq_entries,
+            sq_array: sq.at(p.sq_off.array),
+            sqes: sqes.ptr as *mut io_uring_sqe,
+            cq_head: cq.at(p.cq_off.head),
+            cq_tail: cq.at(p.cq_off.tail),
+            cq_mask: unsafe { *cq.at::<u32>(p.cq_off.ring_mask) },
+            cqes: cq.at(p.cq_off.cqes),
+            fd,
+            _maps: Vec::new(),
+        };
+        maps.push(sqes);
+        Ok(Ring {
+            _maps: maps,
+            ..ring
+        })
+    }
+
+    fn queued(&self) -> u32 {
+        let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+        tail.wrapping_sub(unsafe { (*self.sq_head).load(Ordering::Acquire) })
+    }
+
+    /// Queue `sqe`, submitting the queued entries first if the queue is
+    /// full.
+    fn push(&mut self, sqe: io_uring_sqe) -> Result<()> {
+        if self.queued() == self.sq_entries {
+            self.enter(0)?;
+            if self.queued() == self.sq_entries {
+                return Err(Error::Others("io_uring submission queue full".to_string()));
+            }
+        }
+        let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+        let i = tail & self.sq_mask;
+        unsafe {
+            *self.sqes.add(i as usize) = sqe;
+            *self.sq_array.add(i as usize) = i;
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Submit the queued entries, and wait for `wait` completions.
+    fn enter(&mut self, wait: u32) -> Result<()> {
+        let flags = if wait > 0 {
+            IoringEnterFlags::GETEVENTS
+        } else {
+            IoringEnterFlags::empty()
+        };
+        match unsafe { io_uring_enter(&self.fd, self.queued(), wait, flags) } {
+            Ok(_) => Ok(()),
+            // interrupted, or the completions are to be reaped first
+            Err(Errno::INTR) | Err(Errno::BUSY) | Err(Errno::AGAIN) => Ok(()),
+            Err(e) => Err(Error::Others(format!("io_uring_enter failed: {}", e))),
+        }
+    }
+
+    /// Take the completions, as `(user_data, res)`.
+    fn reap(&mut self, out: &mut Vec<(u64, i32)>) {
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            let mut i = head;
+            while i != tail {
+                let cqe = &*self.cqes.add((i & self.cq_mask) as usize);
+                out.push((cqe.user_data.u64_(), cqe.res));
+                i = i.wrapping_add(1);
+            }
+            (*self.cq_head).store(tail, Ordering::Release);
+        }
+    }
+}
+
+fn sqe(op: IoringOp, fd: RawFd, token: u64) -> io_uring_sqe {
+    io_uring_sqe {
+        opcode: op,
+        fd,
+        user_data: io_uring_user_data::from_u64(token),
+        ..Default::default()
+    }
+}
+
+fn sqe_buf(op: IoringOp, fd: RawFd, token: u64, buf: *const u8, len: usize) -> io_uring_sqe {
+    let mut sqe = sqe(op, fd, token);
+    sqe.addr_or_splice_off_in.addr = io_uring_ptr::new(buf as *mut c_void);
+    sqe.len.len = len as u32;
+    sqe
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// What the ring thread shares with every connection.
+struct Shared {
+    methods: Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
+    jobs: Arc<FairQueue<Job>>,
+    waker: Waker,
+    reply_grace: Duration,
+    abandoned: Arc<AtomicUsize>,
+    panic_handler: Option<PanicHandler>,
+}
+
+// the workers exit once the jobs queued ran
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.jobs.close();
+    }
+}
+
+struct Conn {
+    // the key of its jobs on the pool
+    id: u64,
+    fd: RawFd,
+    conf: ConnectionConfig,
+    proto: ServerConnection,
+    recv_buf: Box<[u8]>,
+    // the bytes of the send in flight, and those queued after them
+    sending: Vec<u8>,
+    sent: usize,
+    out: Vec<u8>,
+    in_recv: bool,
+    in_send: bool,
+    // since when a frame is read in part, and the send in flight made no
+    // progress
+    reading_since: Option<Instant>,
+    sending_since: Option<Instant>,
+    // failed: closes without waiting for the replies owed
+    broken: bool,
+    // until when a failed connection writes the responses already queued
+    flush_by: Option<Instant>,
+    // shut down: waits for its reads and writes to finish to be dropped
+    closed: bool,
+    res_tx: Sender<(MessageHeader, Vec<u8>)>,
+    res_rx: Receiver<(MessageHeader, Vec<u8>)>,
+    pending: Arc<PendingReplies>,
+    state: Arc<ConnectionState>,
+    identity: Option<Arc<Vec<u8>>>,
+    fd_open: Arc<RwLock<bool>>,
+    // handlers running, notifications included
+    in_flight: Arc<AtomicUsize>,
+    // a handler failed, which closes the connection
+    failed: Arc<AtomicBool>,
+    connected: Instant,
+}
+
+impl Conn {
+    fn new(id: u64, fd: RawFd, conf: ConnectionConfig) -> Conn {
+        info!(target: EVENT_TARGET, "connection_accepted fd={}", fd);
+        let conn_ref = ConnectionRef {
+            fd: unsafe { BorrowedFd::borrow_raw(fd) },
+        };
+        let state = Arc::new(ConnectionState {
+            data: conf.on_connect.as_ref().and_then(|f| f(&conn_ref)),
+            close_reason: Mutex::new(None),
+            read_closed: AtomicBool::new(false),
+            client_info: Mutex::new(None),
+            served: Arc::new(AtomicU64::new(0)),
+            served_total: conf.served.clone(),
+            listener: conf.listener.clone(),
+            torn_writes: conf.torn_writes.clone(),
+            gate: conf.gate.clone(),
+            drain: Arc::default(),
+            timeouts: conf.policy.timeouts.clone(),
+            streams: Arc::default(),
+        });
+        let (res_tx, res_rx) = channel();
+        Conn {
+            id,
+            fd,
+            proto: ServerConnection::new(conf.policy.max_message_size),
+            conf,
+            recv_buf: vec![0u8; RECV_BUFFER_SIZE].into_boxed_slice(),
+            sending: Vec::new(),
+            sent: 0,
+            out: Vec::new(),
+            in_recv: false,
+            in_send: false,
+            reading_since: None,
+            sending_since: None,
+            broken: false,
+            flush_by: None,
+            closed: false,
+            pending: Arc::new(PendingReplies::new(res_tx.clone())),
+            res_tx,
+            res_rx,
+            state,
+            identity: None,
+            fd_open: Arc::new(RwLock::new(true)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            failed: Arc::new(AtomicBool::new(false)),
+            connected: Instant::now(),
+        }
+    }
+
+    fn read_closed(&self) -> bool {
+        self.state.read_closed.load(Ordering::SeqCst)
+    }
+
+    /// Whether nothing is owed to the peer anymore.
+    fn idle(&self) -> bool {
+        self.pending.is_empty()
+            && self.in_flight.load(Ordering::SeqCst) == 0
+            && self.out.is_empty()
+            && !self.in_send
+    }
+
+    /// Close the connection if reading a frame or writing replies to it
+    /// ran out of time.
+    fn check_io(&mut self) {
+        let timeouts = &self.conf.policy.timeouts;
+        let read = self
+            .reading_since
+            .is_some_and(|t| timeouts.passed(Phase::Read, t));
+        let write = self
+            .sending_since
+            .is_some_and(|t| timeouts.passed(Phase::Write, t));
+        let phase = match (read, write) {
+            (true, _) => Phase::Read,
+            (_, true) => Phase::Write,
+            _ => return,
+        };
+        timeouts.ran_out(self.fd, phase, None);
+        self.reading_since = None;
+        self.sending_since = None;
+        let reason = CloseReason::Timeout(phase);
+        match phase {
+            Phase::Read => self.fail_reading(reason),
+            _ => self.fail(reason),
+        }
+    }
+
+    /// Stop reading and writing, e.g. after an error.
+    fn fail(&mut self, reason: CloseReason) {
+        self.state.closing(reason);
+        self.state.read_closed.store(true, Ordering::SeqCst);
+        self.broken = true;
+        self.out.clear();
+        socket::shutdown(self.fd, Shutdown::Both).unwrap_or(());
+    }
+
+    /// Stop reading, e.g. after an error reading or a handler failing, but
+    /// write the responses already queued before closing, for up to the
+    /// flush timeout.
+    fn fail_reading(&mut self, reason: CloseReason) {
+        self.state.closing(reason);
+        if self.broken {
+            return;
+        }
+        self.state.read_closed.store(true, Ordering::SeqCst);
+        self.broken = true;
+        self.flush_by = Some(Instant::now() + self.conf.flush_timeout);
+        socket::shutdown(self.fd, Shutdown::Read).unwrap_or(());
+    }
+
+    /// Whether a failed connection is done writing what it can.
+    fn flushed(&self) -> bool {
+        if self.out.is_empty() && !self.in_send {
+            return true;
+        }
+        if self.flush_by.is_some_and(|t| Instant::now() < t) {
+            return false;
+        }
+        let dropped = self.out.len() + self.sending.len() - self.sent;
+        if dropped > 0 && self.flush_by.is_some() {
+            warn!(
+                target: EVENT_TARGET,
+                "responses_dropped fd={} bytes={}",
+                self.fd,
+                dropped
+            );
+        }
+        true
+    }
+
+    /// Handle what the bytes received so far decode to.
+    fn receive(&mut self, shared: &Shared, n: usize) {
+        self.proto.receive(&self.recv_buf[..n]);
+        loop {
+            match self.proto.poll_event() {
+                Ok(Some(event)) => {
+                    if let Err(e) = self.event(shared, event) {
+                        debug!("serving request get error {:?}", e);
+                        self.fail_reading(CloseReason::Error(e.to_status().message));
+                        return;
+                    }
+                }
+                Ok(None) => {
+                    if !self.proto.mid_frame() {
+                        self.reading_since = None;
+                    } else if self.reading_since.is_none() {
+                        self.reading_since = Some(Instant::now());
+                    }
+                    return;
+                }
+                Err(e) => {
+                    // the frame cannot be answered, its stream being unknown, so
+                    // close rather than leave its client waiting
+                    let message = e.to_status().message;
+                    warn!(
+                        target: EVENT_TARGET,
+                        "decode_error fd={} kind={:?} stream=0 method=- error={}",
+                        self.fd,
+                        DecodeErrorKind::Oversized,
+                        message
+                    );
+                    self.fail_reading(CloseReason::ProtocolError(format!(
+                        "undecodable request: {}",
+                        message
+                    )));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn event(&mut self, shared: &Shared, event: ServerEvent) -> Result<()> {
+        match event {
+            ServerEvent::Request {
+                stream_id,
+                request,
+                wants_reply,
+                streaming: true,
+                ..
+            } => {
+                let path = self
+                    .conf
+                    .policy
+                    .paths
+                    .get(&request.service, &request.method);
+                debug!("refusing streaming call of {} on fd {}", path, self.fd);
+                if !wants_reply {
+                    return Ok(());
+                }
+                let message = format!("{}: streaming calls are not served by io_uring", path);
+                let mut res = Response::new();
+                res.set_status(get_status(Code::UNIMPLEMENTED, message));
+                response_to_channel(stream_id, res, self.res_tx.clone())
+            }
+            ServerEvent::Request {
+                stream_id,
+                request,
+                wants_reply,
+                wants_progress,
+                extensions,
+                ..
+            } => self.request(
+                shared,
+                stream_id,
+                request,
+                wants_reply,
+                wants_progress,
+                extensions,
+            ),
+            ServerEvent::Undecodable {
+                stream_id,
+                wants_reply,
+                message,
+            } => {
+                let e = DecodeError {
+                    kind: DecodeErrorKind::Envelope,
+                    connection: self.fd,
+                    stream_id,
+                    method: None,
+                    message,
+                };
+                self.reject(stream_id, wants_reply, e)
+            }
+            ServerEvent::Data { stream_id, .. } => {
+                debug!("dropping data for stream {} on fd {}", stream_id, self.fd);
+                Ok(())
+            }
+            ServerEvent::Cancel(stream_id) => {
+                debug!("client cancelled stream {} on fd {}", stream_id, self.fd);
+                self.pending.cancel(stream_id);
+                Ok(())
+            }
+            ServerEvent::Hello(info) => {
+                info!(
+                    target: EVENT_TARGET,
+                    "client_hello fd={} version={} capabilities={}",
+                    self.fd,
+                    info.version,
+                    info.capabilities.join(",")
+                );
+                let answer = self.conf.policy.hello_methods(
+                    self.fd,
+                    &shared.methods,
+                    self.identity.as_ref().map(|id| id.as_slice()),
+                    &info.methods,
+                );
+                *self.state.client_info.lock().unwrap() = Some(Arc::new(info));
+                let max_streams = self.conf.policy.max_streams();
+                self.out
+                    .extend(self.proto.hello_limited(&answer, max_streams));
+                Ok(())
+            }
+            ServerEvent::Identity(id) => {
+                if self.identity.is_none() {
+                    self.identity = Some(Arc::new(id));
+                } else {
+                    debug!("ignoring identity sent again on fd {}", self.fd);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Answer a request which did not decode, unless the connection is to
+    /// be closed over it.
+    fn reject(&self, stream_id: u32, wants_reply: bool, e: DecodeError) -> Result<()> {
+        let answer = self.conf.policy.undecodable(&self.state, &e)?;
+        if !answer || !wants_reply {
+            return Ok(());
+        }
+        let mut res = Response::new();
+        res.set_status(get_status(Code::INVALID_ARGUMENT, e.message));
+        response_to_channel(stream_id, res, self.res_tx.clone())
+    }
+
+    fn request(
+        &mut self,
+        shared: &Shared,
+        stream_id: u32,
+        req: Request,
+        wants_reply: bool,
+        wants_progress: bool,
+        extensions: Extensions,
+    ) -> Result<()> {
+        let fd = self.fd;
+        let cost = MESSAGE_HEADER_LENGTH + req.payload.len();
+        let policy = &self.conf.policy;
+        let journal = &self.conf.journal;
+        let no_reply = !wants_reply;
+        let mut metadata = metadata::from_pairs(req.get_metadata());
+        if metadata::get(&metadata, REQUEST_ID_KEY).is_none() {
+            metadata.insert(REQUEST_ID_KEY.to_string(), vec![metadata::new_request_id()]);
+        }
+
+        let path = policy.paths.get(&req.service, &req.method);
+        if let Some(j) = journal.as_ref() {
+            j.request(fd, stream_id, &path, &req.payload);
+        }
+        let reply = |status: Status| {
+            if no_reply {
+                return Ok(());
+            }
+            let mut res = Response::new();
+            res.set_status(status);
+            echo_request_id(&metadata, &mut res);
+            if let Some(j) = journal.as_ref() {
+                j.response(
+                    fd,
+                    stream_id,
+                    res.get_status().code,
+                    res.compute_size() as usize,
+                );
+            }
+            response_to_channel(stream_id, res, self.res_tx.clone())
+        };
+
+        let denied = policy.acl.as_ref().is_some_and(|acl| {
+            let caller = Caller {
+                fd,
+                listener: self.state.listener.as_deref(),
+                identity: self.identity.as_ref().map(|id| id.as_slice()),
+                metadata: &metadata,
+            };
+            !acl.permits(&caller, &path)
+        });
+        if denied {
+            info!(target: EVENT_TARGET, "acl_denied fd={} method={}", fd, path);
+            let message = format!("{} is not allowed", path);
+            return reply(get_status(Code::PERMISSION_DENIED, message));
+        }
+        let identity = self.identity.as_ref().map(|id| id.as_slice());
+        let routed = policy.tenants.route(identity, &path);
+        if (routed.is_none() && !shared.methods.contains_key(&*path))
+            || !policy.serves(&req.service)
+        {
+            if no_reply {
+                debug!("dropping notification for {}, which does not exist", path);
+                return Ok(());
+            }
+            let message = format!("{} does not exist", path);
+            return reply(get_status(Code::INVALID_ARGUMENT, message));
+        }
+        let checked = match policy.check(&path, &req.payload) {
+            Ok(checked) => checked,
+            Err(message) => {
+                let e = DecodeError {
+                    kind: DecodeErrorKind::Payload,
+                    connection: fd,
+                    stream_id,
+                    method: Some(path.to_string()),
+                    message,
+                };
+                return self.reject(stream_id, wants_reply, e);
+            }
+        };
+        if let Err(e) = checked {
+            if no_reply {
+                debug!("dropping notification for {}: {:?}", path, e);
+                return Ok(());
+            }
+            return reply(e.to_status());
+        }
+
+        let running = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        let in_flight = InFlight(&self.in_flight);
+        if policy.max_in_flight > 0
+            && running > policy.max_in_flight
+            && !policy.priority.contains(&*path)
+        {
+            if no_reply {
+                debug!("dropping notification for {}, too many requests", path);
+                return Ok(());
+            }
+            let message = "too many concurrent requests".to_string();
+            return reply(get_status(Code::RESOURCE_EXHAUSTED, message));
+        }
+        // counted until the handler returns
+        std::mem::forget(in_flight);
+        trace!(
+            target: EVENT_TARGET,
+            "requests_in_flight fd={} count={}",
+            fd,
+            running
+        );
+
+        let cancelled = if no_reply {
+            Arc::new(AtomicBool::new(false))
+        } else {
+            self.pending
+                .begin(stream_id, req.timeout_nano, &path, req.payload.len())
+        };
+        let sink = ResponseSink {
+            inner: Arc::new(SinkInner {
+                stream_id,
+                no_reply,
+                progress_ok: wants_progress,
+                request_id: metadata::get(&metadata, REQUEST_ID_KEY).map(|s| s.to_string()),
+                pending: self.pending.clone(),
+                journal: journal.clone().map(|j| (j, fd)),
+                direct: None,
+                wake: Some(shared.waker.clone()),
+                cancelled: cancelled.clone(),
+                on_complete: Mutex::new(Vec::new()),
+                error_detail: policy.error_detail,
+            }),
+        };
+        let mut flags = 0;
+        if no_reply {
+            flags |= FLAG_NO_REPLY;
+        }
+        if wants_progress {
+            flags |= FLAG_PROGRESS_OK;
+        }
+        #[allow(deprecated)]
+        let ctx = TtrpcContext {
+            fd,
+            mh: MessageHeader {
+                length: req.payload.len() as u32,
+                stream_id,
+                type_: MESSAGE_TYPE_REQUEST,
+                flags,
+            },
+            res_tx: self.res_tx.clone(),
+            metadata,
+            fd_open: self.fd_open.clone(),
+            sink: sink.clone(),
+            identity: self.identity.clone(),
+            client_info: self.state.client_info.lock().unwrap().clone(),
+            connection_data: self.state.data.clone(),
+            cancelled: cancelled.clone(),
+            path: path.clone(),
+            policy: policy.clone(),
+            state: self.state.clone(),
+            extensions,
+            inbound: Mutex::new(None),
+        };
+
+        let methods = shared.methods.clone();
+        let panic_handler = shared.panic_handler.clone();
+        let waker = shared.waker.clone();
+        let (pending, state, journal) = (
+            self.pending.clone(),
+            self.state.clone(),
+            self.conf.journal.clone(),
+        );
+        let (running, failed, slow) = (
+            self.in_flight.clone(),
+            self.failed.clone(),
+            policy.slow_handler,
+        );
+        let (timeouts, traces, queued) = (
+            policy.timeouts.clone(),
+            policy.traces.clone(),
+            Instant::now(),
+        );
+        let job = move || {
+            let _in_flight = InFlight(&running);
+            if cancelled.load(Ordering::SeqCst) {
+                debug!("skipping {} cancelled before it started", path);
+                return;
+            }
+            if timeouts.passed(Phase::Queue, queued) {
+                timeouts.ran_out(fd, Phase::Queue, Some(&path));
+                if sink.is_pending() {
+                    let mut res = Response::new();
+                    res.set_status(get_status(
+                        Code::UNAVAILABLE,
+                        format!("{} waited too long to be handled", path),
+                    ));
+                    sink.send(res).unwrap_or(());
+                }
+                drop(sink);
+                waker();
+                return;
+            }
+            if let Some(t) = timeouts.get(Phase::Handler).filter(|_| !no_reply) {
+                pending.limit(stream_id, t);
+            }
+            let method = match routed.as_deref() {
+                Some(m) => m,
+                None => &*methods[&*path],
+            };
+            let traced = traces
+                .sampled(fd, &path)
+                .then(|| TracedCall::start(fd, &path, &req, &sink, queued));
+            state.served.fetch_add(1, Ordering::Relaxed);
+            state.served_total.fetch_add(1, Ordering::Relaxed);
+            let started = Instant::now();
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| method.handler(ctx, req))) {
+                Ok(result) => result,
+                Err(e) => {
+                    report_panic(fd, Some(&path), &panic_handler, e);
+                    if let Some(j) = journal.as_ref() {
+                        j.log();
+                    }
+                    let mut res = Response::new();
+                    res.set_status(get_status(
+                        Code::INTERNAL,
+                        format!("{} handler panicked", path),
+                    ));
+                    if sink.is_pending() {
+                        sink.send(res)
+                    } else {
+                        Ok(())
+                    }
+                }
+            };
+            if let Some(t) = traced.as_ref() {
+                t.returned(&result);
+            }
+            let elapsed = started.elapsed();
+            if slow.is_some_and(|t| elapsed >= t) {
+                warn!(
+                    target: EVENT_TARGET,
+                    "slow_handler fd={} method={} elapsed_us={}",
+                    fd,
+                    path,
+                    elapsed.as_micros()
+                );
+            }
+            match result {
+                Ok(()) => {
+                    pending.defer(sink.stream_id());
+                }
+                Err(e) => {
+                    debug!("method handle {} get error {:?}", path, e);
+                    state.closing(CloseReason::Error(e.to_status().message));
+                    failed.store(true, Ordering::SeqCst);
+                }
+            }
+            drop(sink);
+            waker();
+        };
+        if !shared.jobs.push(self.id, cost, Box::new(job)) {
+            return Err(Error::ServerShutdown("handler pool stopped".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Queue the frames handlers sent, and report whether the connection
+    /// is done with.
+    fn flush(&mut self) -> bool {
+        while let Ok((mut mh, buf)) = self.res_rx.try_recv() {
+            if mh.type_ == MESSAGE_TYPE_RESPONSE {
+                mh.flags |= FLAG_BATCH_OK;
+            }
+            if !self.closed {
+                self.out.extend(encode_frame(mh, &buf));
+            }
+        }
+        if self.failed.swap(false, Ordering::SeqCst) {
+            self.fail_reading(CloseReason::Error("handler failed".to_string()));
+        }
+        if !self.read_closed() {
+            return false;
+        }
+        // half closed, unless the peer turns out to be gone altogether
+        (self.broken && self.flushed())
+            || self.idle()
+            || (self.out.is_empty() && peer_hung_up(self.fd))
+    }
+}
+
+struct Backend {
+    ring: Ring,
+    shared: Shared,
+    listeners: Vec<Option<(RawFd, ConnectionConfig)>>,
+    accepting: Arc<AtomicBool>,
+    conns: HashMap<u64, Conn>,
+    next_id: u64,
+    wake_fd: OwnedFd,
+    monitor_fd: RawFd,
+    // keeps monitor_fd open
+    control: Arc<Control>,
+    quit: Arc<AtomicBool>,
+    // read into by the kernel until the ring is gone
+    wake_buf: &'static mut [u64; 2],
+    tick: Box<KernelTimespec>,
+    // accepts, reads and writes in flight
+    io_ops: usize,
+}
+
+impl Backend {
+    fn push(&mut self, sqe: io_uring_sqe) {
+        if let Err(e) = self.ring.push(sqe) {
+            error!("failed to queue io_uring entry: {:?}", e);
+        }
+    }
+
+    fn arm_accept(&mut self, i: usize) {
+        if let Some((fd, _)) = self.listeners[i] {
+            let mut sqe = sqe(IoringOp::Accept, fd, Op::Accept.token(i as u64));
+            sqe.op_flags.accept_flags = SocketFlags::CLOEXEC;
+            self.push(sqe);
+            self.io_ops += 1;
+        }
+    }
+
+    fn arm_recv(&mut self, id: u64) {
+        let conn = match self.conns.get_mut(&id) {
+            Some(c) if !c.in_recv && !c.read_closed() => c,
+            _ => return,
+        };
+        conn.in_recv = true;
+        let (buf, len) = (conn.recv_buf.as_ptr(), conn.recv_buf.len());
+        let sqe = sqe_buf(IoringOp::Recv, conn.fd, Op::Recv.token(id), buf, len);
+        self.push(sqe);
+        self.io_ops += 1;
+    }
+
+    fn arm_send(&mut self, id: u64) {
+        let conn = match self.conns.get_mut(&id) {
+            Some(c) if !c.in_send && !c.out.is_empty() => c,
+            _ => return,
+        };
+        conn.in_send = true;
+        conn.sending_since = Some(Instant::now());
+        conn.sending = std::mem::take(&mut conn.out);
+        conn.sent = 0;
+        self.send_rest(id);
+    }
+
+    fn send_rest(&mut self, id: u64) {
+        let conn = &self.conns[&id];
+        let rest = &conn.sending[conn.sent..];
+        let mut sqe = sqe_buf(
+            IoringOp::Send,
+            conn.fd,
+            Op::Send.token(id),
+            rest.as_ptr(),
+            rest.len(),
+        );
+        sqe.op_flags.send_flags = SendFlags::NOSIGNAL;
+        self.push(sqe);
+        self.io_ops += 1;
+    }
+
+    fn arm_wake(&mut self) {
+        let buf = self.wake_buf[0..1].as_ptr() as *const u8;
+        let mut sqe = sqe_buf(
+            IoringOp::Read,
+            self.wake_fd.as_raw_fd(),
+            Op::Wake.token(0),
+            buf,
+            8,
+        );
+        sqe.off_or_addr2.off = u64::MAX;
+        self.push(sqe);
+    }
+
+    fn arm_quit(&mut self) {
+        let buf = self.wake_buf[1..2].as_ptr() as *const u8;
+        let mut sqe = sqe_buf(IoringOp::Read, self.monitor_fd, Op::Quit.token(0), buf, 8);
+        sqe.off_or_addr2.off = u64::MAX;
+        self.push(sqe);
+    }
+
+    fn arm_tick(&mut self) {
+        let ts = &*self.tick as *const KernelTimespec as *const u8;
+        let sqe = sqe_buf(IoringOp::Timeout, -1, Op::Tick.token(0), ts, 1);
+        self.push(sqe);
+    }
+
+    fn add(&mut self, fd: RawFd, conf: ConnectionConfig) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.conns.insert(id, Conn::new(id, fd, conf));
+        self.arm_recv(id);
+        id
+    }
+
+    fn accepted(&mut self, i: usize, res: i32) {
+        self.io_ops -= 1;
+        let quitting = self.quit.load(Ordering::SeqCst);
+        if res < 0 {
+            let e = Errno::from_raw_os_error(-res);
+            let transient = [Errno::INTR, Errno::AGAIN, Errno::CONNABORTED, Errno::MFILE];
+            if quitting || !self.accepting.load(Ordering::SeqCst) || !transient.contains(&e) {
+                if !quitting {
+                    warn!("stopped accepting on listener {}: {}", i, e);
+                }
+                if let Some((fd, _)) = self.listeners[i].take() {
+                    self.control.close_listener(fd);
+                }
+                return;
+            }
+            if e == Errno::MFILE {
+                // let connections close before trying again
+                thread::sleep(TICK);
+            }
+            self.arm_accept(i);
+            return;
+        }
+        let fd = res as RawFd;
+        let (listener, conf) = match self.listeners[i].as_ref() {
+            Some((l, c)) => (*l, c.clone()),
+            None => {
+                close(fd).unwrap_or(());
+                return;
+            }
+        };
+        if quitting {
+            close(fd).unwrap_or(());
+            return;
+        }
+        self.arm_accept(i);
+        if let Some(authorize) = conf.authorize.as_ref() {
+            let conn_ref = ConnectionRef {
+                fd: unsafe { BorrowedFd::borrow_raw(fd) },
+            };
+            if let Err(e) = authorize(&conn_ref) {
+                info!(
+                    target: EVENT_TARGET,
+                    "connection_refused fd={} listener={} reason={}",
+                    fd,
+                    listener,
+                    e.to_status().message
+                );
+                close(fd).unwrap_or(());
+                return;
+            }
+        }
+        let id = self.add(fd, conf);
+        self.settle(id);
+    }
+
+    fn received(&mut self, id: u64, res: i32) {
+        self.io_ops -= 1;
+        let conn = match self.conns.get_mut(&id) {
+            Some(c) => c,
+            None => return,
+        };
+        conn.in_recv = false;
+        if conn.closed {
+            self.settle(id);
+            return;
+        }
+        match res {
+            0 => {
+                // The peer may only have shut down its write side and
+                // still wait for replies: stop reading, and close the
+                // connection once they are sent.
+                conn.state.closing(CloseReason::PeerClosed);
+                conn.state.read_closed.store(true, Ordering::SeqCst);
+            }
+            n if n > 0 => conn.receive(&self.shared, n as usize),
+            e if e == -Errno::INTR.raw_os_error() || e == -Errno::AGAIN.raw_os_error() => {}
+            e => {
+                let e = Errno::from_raw_os_error(-e);
+                debug!("reading from connection {} failed: {}", conn.fd, e);
+                conn.fail_reading(CloseReason::Error(e.to_string()));
+            }
+        }
+        self.arm_recv(id);
+        self.settle(id);
+    }
+
+    fn sent(&mut self, id: u64, res: i32) {
+        self.io_ops -= 1;
+        let conn = match self.conns.get_mut(&id) {
+            Some(c) => c,
+            None => return,
+        };
+        if res < 0 && res != -Errno::INTR.raw_os_error() && res != -Errno::AGAIN.raw_os_error() {
+            conn.in_send = false;
+            if !conn.closed {
+                let e = Errno::from_raw_os_error(-res);
+                info!("write_message got {}", e);
+                conn.fail(CloseReason::WriteError(e.to_string()));
+            }
+            self.settle(id);
+            return;
+        }
+        if res > 0 {
+            conn.sending_since = Some(Instant::now());
+        }
+        conn.sent += res.max(0) as usize;
+        if conn.sent < conn.sending.len() && !conn.closed {
+            self.send_rest(id);
+            return;
+        }
+        conn.in_send = false;
+        conn.sending_since = None;
+        conn.sending.clear();
+        self.settle(id);
+    }
+
+    /// Send what the connection has queued, and drop it once it is done
+    /// with.
+    fn settle(&mut self, id: u64) {
+        let conn = match self.conns.get_mut(&id) {
+            Some(c) => c,
+            None => return,
+        };
+        if !conn.closed && conn.flush() {
+            self.close(id, None);
+        }
+        let conn = match self.conns.get_mut(&id) {
+            Some(c) => c,
+            None => return,
+        };
+        if conn.closed {
+            if !conn.in_recv && !conn.in_send {
+                self.conns.remove(&id);
+            }
+            return;
+        }
+        self.arm_send(id);
+    }
+
+    /// Close the connection, recording `reason` unless one is known. Its
+    /// buffers are kept until the reads and writes in flight finish.
+    fn close(&mut self, id: u64, reason: Option<CloseReason>) {
+        let conn = match self.conns.get_mut(&id) {
+            Some(c) if !c.closed => c,
+            _ => return,
+        };
+        conn.closed = true;
+        self.shared.jobs.forget(id);
+        let fd = conn.fd;
+        let abandoned = conn.pending.drain(Duration::from_secs(0));
+        if abandoned > 0 {
+            warn!("connection closed with {} replies still pending", abandoned);
+            self.shared.abandoned.fetch_add(abandoned, Ordering::SeqCst);
+        }
+        // wait for handlers inside with_connection() to finish with the fd
+        *conn.fd_open.write().unwrap() = false;
+
+        let reason = match reason {
+            Some(r) => r,
+            None => {
+                let r = conn.state.close_reason.lock().unwrap().take();
+                r.unwrap_or(CloseReason::PeerClosed)
+            }
+        };
+        if let Some(f) = conn.conf.on_disconnect.as_ref() {
+            let conn_ref = ConnectionRef {
+                fd: unsafe { BorrowedFd::borrow_raw(fd) },
+            };
+            let disconnect = Disconnect {
+                reason: reason.clone(),
+                duration: conn.connected.elapsed(),
+                data: conn.state.data.clone(),
+            };
+            f(&conn_ref, &disconnect);
+        }
+        // ends the reads and writes in flight, which hold on to the socket
+        socket::shutdown(fd, Shutdown::Both).unwrap_or(());
+        close(fd).unwrap_or(());
+        info!(
+            target: EVENT_TARGET,
+            "connection_closed fd={} abandoned={} reason={}",
+            fd,
+            abandoned,
+            reason
+        );
+        if !conn.in_recv && !conn.in_send {
+            self.conns.remove(&id);
+        }
+    }
+
+    fn run(mut self, attached: Vec<RawFd>, attached_conf: ConnectionConfig) {
+        for i in 0..self.listeners.len() {
+            self.arm_accept(i);
+        }
+        self.arm_wake();
+        self.arm_quit();
+        self.arm_tick();
+        for fd in attached {
+            let id = self.add(fd, attached_conf.clone());
+            self.settle(id);
+        }
+
+        let mut done = Vec::new();
+        let mut stop_at = None;
+        loop {
+            if let Err(e) = self.ring.enter(1) {
+                error!("io_uring backend failed: {:?}", e);
+                break;
+            }
+            self.ring.reap(&mut done);
+            let mut woken = false;
+            for (token, res) in done.drain(..) {
+                let (op, id) = Op::from_token(token);
+                match op {
+                    Op::Accept => self.accepted(id as usize, res),
+                    Op::Recv => self.received(id, res),
+                    Op::Send => self.sent(id, res),
+                    Op::Wake => {
+                        woken = true;
+                        self.arm_wake();
+                    }
+                    Op::Quit => {
+                        if res > 0 && !self.quit.load(Ordering::SeqCst) {
+                            self.arm_quit();
+                        }
+                        woken = true;
+                    }
+                    Op::Tick => {
+                        for conn in self.conns.values_mut() {
+                            let (fd, policy) = (conn.fd, &conn.conf.policy);
+                            conn.pending.expire(|path| {
+                                policy.timeouts.ran_out(fd, Phase::Handler, Some(path))
+                            });
+                            if !conn.closed {
+                                conn.check_io();
+                            }
+                        }
+                        woken = true;
+                        self.arm_tick();
+                    }
+                }
+            }
+            if woken {
+                if !self.accepting.load(Ordering::SeqCst) {
+                    // handed over to another process: ends the accepts
+                    for (fd, _) in self.listeners.iter().flatten() {
+                        socket::shutdown(*fd, Shutdown::Both).unwrap_or(());
+                    }
+                }
+                let ids: Vec<u64> = self.conns.keys().copied().collect();
+                for id in ids {
+                    self.settle(id);
+                }
+            }
+
+            if self.quit.load(Ordering::SeqCst) && stop_at.is_none() {
+                // tell the clients not to send more calls before waiting
+                // for the replies still pending
+                stop_at = Some(Instant::now() + self.shared.reply_grace);
+                for (fd, _) in self.listeners.iter().flatten() {
+                    socket::shutdown(*fd, Shutdown::Both).unwrap_or(());
+                }
+                let ids: Vec<u64> = self.conns.keys().copied().collect();
+                for id in ids {
+                    let conn = self.conns.get_mut(&id).unwrap();
+                    if !conn.closed {
+                        let goaway = conn
+                            .proto
+                            .goaway(self.shared.reply_grace, "server shutdown");
+                        conn.out.extend(goaway);
+                        conn.state.read_closed.store(true, Ordering::SeqCst);
+                        socket::shutdown(conn.fd, Shutdown::Read).unwrap_or(());
+                    }
+                    self.settle(id);
+                }
+            }
+            if let Some(at) = stop_at {
+                let busy = self.conns.values().any(|c| !c.closed && !c.idle());
+                if !busy || Instant::now() >= at {
+                    break;
+                }
+            }
+        }
+
+        let ids: Vec<u64> = self.conns.keys().copied().collect();
+        for id in ids {
+            self.close(id, Some(CloseReason::ServerShutdown));
+        }
+        for (fd, _) in self.listeners.iter().flatten() {
+            socket::shutdown(*fd, Shutdown::Both).unwrap_or(());
+        }
+        // the kernel may still use the buffers of what is in flight
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        while self.io_ops > 0 && Instant::now() < deadline {
+            if self.ring.enter(1).is_err() {
+                break;
+            }
+            self.ring.reap(&mut done);
+            for (token, res) in done.drain(..) {
+                match Op::from_token(token) {
+                    (Op::Accept, id) => self.accepted(id as usize, res),
+                    (Op::Recv, id) => self.received(id, res),
+                    (Op::Send, id) => self.sent(id, res),
+                    _ => {}
+                }
+            }
+        }
+        if self.io_ops > 0 {
+            warn!("io_uring backend left {} operations behind", self.io_ops);
+            std::mem::forget(std::mem::take(&mut self.conns));
+        }
+        for (fd, _) in self.listeners.iter().flatten() {
+            self.control.close_listener(*fd);
+        }
+        info!("ttrpc server stopped");
+    }
+}
+
+impl Server {
+    /// Start the server like [`Server::start`], serving every connection
+    /// from one thread through an io_uring instead of with threads of its
+    /// own. Handlers run on a pool of [`ServerBuilder::set_thread_count_max`]
+    /// threads shared by all connections, so blocking handlers should
+    /// defer their replies, see [`ResponseSink`]. The connections waiting
+    /// for the pool take turns on it by the size of their requests, so a
+    /// busy one does not hold up the others. Needs the `uring` feature and
+    /// Linux 5.6 or later.
+    ///
+    /// Inline methods run on the pool like the others, oversized frames
+    /// close their connection, the connections are not listed by
+    /// [`Server::debug_handle`], and [`ServerBuilder::set_idle_timeout`] is not
+    /// honoured.
+    pub fn start_uring(&mut self) -> Result<()> {
+        self.check_config()?;
+
+        let listeners = self.config.listeners.clone();
+        let attached = std::mem::take(&mut self.config.attached);
+        let (conf, listener_confs) = self.connection_configs();
+        listen_all(&listeners)?;
+        *self.control.listeners.lock().unwrap() = listeners.clone();
+
+        let wake_fd = eventfd(0, EventfdFlags::CLOEXEC | EventfdFlags::NONBLOCK)
+            .map_err(|e| Error::Others(format!("failed to create eventfd: {}", e)))?;
+        let wake_raw = wake_fd.as_raw_fd();
+        let waker: Waker = Arc::new(move || {
+            write(wake_raw, &1u64.to_ne_bytes()).unwrap_or(0);
+        });
+
+        let jobs = Arc::new(FairQueue::<Job>::new(FAIR_QUANTUM));
+        for i in 0..self.config.thread_count_max {
+            let (jobs, scheduling) = (jobs.clone(), self.config.scheduling.clone());
+            let ph = self.config.panic_handler.clone();
+            spawn_guarded(format!("uring_worker-{}", i), -1, ph, move || {
+                if let Some(Err(e)) = scheduling.as_ref().map(|s| s.apply()) {
+                    warn!("failed to set scheduling of method handler: {:?}", e);
+                }
+                while let Some(job) = jobs.pop() {
+                    job();
+                }
+            });
+        }
+
+        let shared = Shared {
+            methods: self.config.methods.clone(),
+            jobs,
+            waker,
+            reply_grace: self.config.reply_grace,
+            abandoned: self.control.abandoned.clone(),
+            panic_handler: self.config.panic_handler.clone(),
+        };
+        let backend = Backend {
+            ring: Ring::new(RING_ENTRIES)?,
+            shared,
+            listeners: listeners
+                .iter()
+                .map(|fd| Some((*fd, listener_confs.get(fd).unwrap_or(&conf).clone())))
+                .collect(),
+            accepting: self.control.accepting.clone(),
+            conns: HashMap::new(),
+            next_id: 0,
+            wake_fd,
+            monitor_fd: self.control.monitor_fd.0,
+            control: self.control.clone(),
+            quit: self.control.quit.clone(),
+            wake_buf: Box::leak(Box::new([0; 2])),
+            tick: Box::new(KernelTimespec {
+                tv_sec: 0,
+                tv_nsec: TICK.as_nanos() as i64,
+            }),
+            io_ops: 0,
+        };
+        let loop_fd = listeners
+            .first()
+            .copied()
+            .unwrap_or(self.control.monitor_fd.0);
+        let ph = self.config.panic_handler.clone();
+        let backend = SendBackend(backend);
+        let handler = spawn_guarded("uring_loop".into(), loop_fd, ph, move || {
+            let backend = backend;
+            backend.0.run(attached, conf)
+        });
+        self.handler = Some(handler);
+
+        Ok(())
+    }
+}
+
+/// Moves the backend to its thread, which is the only one using the ring.
+struct SendBackend(Backend);
+
+unsafe impl Send for SendBackend {}
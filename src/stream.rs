@@ -0,0 +1,611 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming calls, where the client streams messages to the method, the
+//! method streams messages back ahead of its response, or both. The
+//! messages travel in [`MESSAGE_TYPE_DATA`] frames on the stream of the
+//! call.
+//!
+//! On the server, a method gets the messages of the client from
+//! [`TtrpcContext::request_stream`] and streams its own through
+//! [`TtrpcContext::stream_sink`]. Each open stream keeps a handler thread
+//! busy, so [`ServerBuilder::set_thread_count_max`] bounds how many are served
+//! at once, and streaming methods must not be inline. The io_uring
+//! backend and the async server serve no streaming calls, and answer
+//! them with `UNIMPLEMENTED`.
+//!
+//! On the client, [`Client::client_streaming`],
+//! [`Client::server_streaming`] and [`Client::duplex_streaming`] start
+//! them. Messages are queued as they arrive on either side, without flow
+//! control.
+//!
+//! [`copy_to_stream`] streams what it reads, e.g. the stdout of a
+//! container, as messages of either side, pacing them by the frames the
+//! connection has yet to write.
+//!
+//! [`MESSAGE_TYPE_DATA`]: crate::MESSAGE_TYPE_DATA
+//! [`TtrpcContext::request_stream`]: crate::TtrpcContext::request_stream
+//! [`TtrpcContext::stream_sink`]: crate::TtrpcContext::stream_sink
+//! [`ServerBuilder::set_thread_count_max`]: crate::ServerBuilder::set_thread_count_max
+//! [`Client::client_streaming`]: crate::Client::client_streaming
+//! [`Client::server_streaming`]: crate::Client::server_streaming
+//! [`Client::duplex_streaming`]: crate::Client::duplex_streaming
+
+use protobuf::{CodedInputStream, Message};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::client::ClientStream;
+use crate::error::{errno_to_code, get_rpc_status, Error, Result};
+use crate::proto::{encode_message, MessageHeader, FLAG_NO_DATA, FLAG_REMOTE_CLOSED};
+use crate::server::ResponseSink;
+use crate::ttrpc::Code;
+
+/// What a connection hands the handler of a stream.
+enum Inbound {
+    Data(Vec<u8>),
+    /// The client sent its last message.
+    Closed,
+}
+
+#[derive(Default)]
+struct Entry {
+    // None once the client closed the stream
+    tx: Option<Sender<Inbound>>,
+    // until the handler takes it
+    rx: Option<Receiver<Inbound>>,
+}
+
+/// The streams the client has open on a connection, by stream id. Their
+/// messages are routed as the frames are read, so they arrive in order.
+#[derive(Default)]
+pub(crate) struct InboundStreams {
+    open: Mutex<HashMap<u32, Entry>>,
+}
+
+impl InboundStreams {
+    /// Open `stream_id`, whose request was just read.
+    pub(crate) fn open(&self, stream_id: u32) {
+        let (tx, rx) = channel();
+        let entry = Entry {
+            tx: Some(tx),
+            rx: Some(rx),
+        };
+        self.open.lock().unwrap().insert(stream_id, entry);
+    }
+
+    /// Hand the data frame `mh` to its stream.
+    pub(crate) fn deliver(&self, mh: &MessageHeader, buf: Vec<u8>) {
+        let mut open = self.open.lock().unwrap();
+        let tx = match open.get_mut(&mh.stream_id).and_then(|e| e.tx.as_ref()) {
+            Some(tx) => tx,
+            None => {
+                debug!(
+                    "dropping data for stream {}, which is not open",
+                    mh.stream_id
+                );
+                return;
+            }
+        };
+        // the handler may be done with the stream already
+        if mh.flags & FLAG_NO_DATA == 0 {
+            tx.send(Inbound::Data(buf)).unwrap_or(());
+        }
+        if mh.flags & FLAG_REMOTE_CLOSED != 0 {
+            tx.send(Inbound::Closed).unwrap_or(());
+            if let Some(entry) = open.get_mut(&mh.stream_id) {
+                entry.tx = None;
+            }
+        }
+    }
+
+    /// Take the messages of `stream_id` for its handler. The stream is
+    /// forgotten once they are dropped, or if there are none.
+    pub(crate) fn take(self: &Arc<Self>, stream_id: u32) -> Option<StreamInbound> {
+        let rx = self
+            .open
+            .lock()
+            .unwrap()
+            .get_mut(&stream_id)
+            .and_then(|e| e.rx.take());
+        let inbound = StreamInbound {
+            rx: rx?,
+            streams: self.clone(),
+            stream_id,
+            closed: false,
+        };
+        Some(inbound)
+    }
+
+    /// End `stream_id` before the client closed it, e.g. once cancelled.
+    pub(crate) fn close(&self, stream_id: u32) {
+        self.open.lock().unwrap().remove(&stream_id);
+    }
+
+    /// End every stream, once nothing more is read from the connection.
+    pub(crate) fn close_all(&self) {
+        self.open.lock().unwrap().clear();
+    }
+}
+
+/// The messages of one stream, as read from the connection.
+pub(crate) struct StreamInbound {
+    rx: Receiver<Inbound>,
+    streams: Arc<InboundStreams>,
+    stream_id: u32,
+    closed: bool,
+}
+
+impl StreamInbound {
+    fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.closed {
+            return Ok(None);
+        }
+        match self.rx.recv() {
+            Ok(Inbound::Data(buf)) => Ok(Some(buf)),
+            Ok(Inbound::Closed) => {
+                self.closed = true;
+                Ok(None)
+            }
+            Err(_) => {
+                self.closed = true;
+                Err(get_rpc_status(
+                    Code::CANCELLED,
+                    format!(
+                        "stream {} ended before the client closed it",
+                        self.stream_id
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+impl Drop for StreamInbound {
+    fn drop(&mut self) {
+        self.streams.close(self.stream_id);
+    }
+}
+
+/// The messages a client streams to a method, see
+/// [`TtrpcContext::request_stream`](crate::TtrpcContext::request_stream).
+pub struct RequestStream<T> {
+    inbound: StreamInbound,
+    _message: PhantomData<T>,
+}
+
+impl<T: Message> RequestStream<T> {
+    pub(crate) fn new(inbound: StreamInbound) -> RequestStream<T> {
+        RequestStream {
+            inbound,
+            _message: PhantomData,
+        }
+    }
+
+    /// Wait for the next message, `None` once the client closed the
+    /// stream. Fails once if the connection closed or the call was
+    /// cancelled first, and with `INVALID_ARGUMENT` for a message which
+    /// does not decode.
+    pub fn recv(&mut self) -> Result<Option<T>> {
+        let buf = match self.inbound.recv()? {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+        let mut s = CodedInputStream::from_bytes(&buf);
+        let mut m = T::new();
+        m.merge_from(&mut s)
+            .map_err(err_to_RpcStatus!(Code::INVALID_ARGUMENT, e, ""))?;
+        Ok(Some(m))
+    }
+}
+
+impl<T: Message> Iterator for RequestStream<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        self.recv().transpose()
+    }
+}
+
+/// Streams the messages of a method to the client ahead of its response,
+/// see [`TtrpcContext::stream_sink`](crate::TtrpcContext::stream_sink).
+pub struct StreamSink<T> {
+    sink: ResponseSink,
+    _message: PhantomData<T>,
+}
+
+impl<T: Message> StreamSink<T> {
+    pub(crate) fn new(sink: ResponseSink) -> StreamSink<T> {
+        StreamSink {
+            sink,
+            _message: PhantomData,
+        }
+    }
+
+    /// Send `msg` to the client. Fails with
+    /// [`Error::Cancelled`](crate::Error::Cancelled) once the
+    /// client gave up on the call, and once the call was answered, e.g.
+    /// as its deadline passed.
+    pub fn send(&self, msg: &T) -> Result<()> {
+        self.sink.data(encode_message(msg)?)
+    }
+}
+
+fn decode<M: Message>(buf: &[u8]) -> Result<M> {
+    let mut s = CodedInputStream::from_bytes(buf);
+    let mut m = M::new();
+    m.merge_from(&mut s)
+        .map_err(err_to_Others!(e, "Unpack get error "))?;
+    Ok(m)
+}
+
+/// Streams messages to a method, then gets its response, see
+/// [`Client::client_streaming`](crate::Client::client_streaming).
+///
+/// Dropping it before the response arrived cancels the call.
+pub struct ClientStreamSender<Q, P> {
+    stream: ClientStream,
+    _messages: PhantomData<(Q, P)>,
+}
+
+impl<Q: Message, P: Message> ClientStreamSender<Q, P> {
+    pub(crate) fn new(stream: ClientStream) -> ClientStreamSender<Q, P> {
+        ClientStreamSender {
+            stream,
+            _messages: PhantomData,
+        }
+    }
+
+    /// Queue `msg` for the server.
+    pub fn send(&self, msg: &Q) -> Result<()> {
+        self.stream.send(encode_message(msg)?)
+    }
+
+    /// Tell the server no more messages follow, ahead of
+    /// [`close_and_recv`](ClientStreamSender::close_and_recv).
+    pub fn close_send(&mut self) -> Result<()> {
+        self.stream.close_send()
+    }
+
+    /// Close the stream, then wait for the response.
+    pub fn close_and_recv(mut self) -> Result<P> {
+        self.stream.close_send()?;
+        let res = self.stream.response()?;
+        decode(&res.payload)
+    }
+}
+
+/// The messages a method streams back, see
+/// [`Client::server_streaming`](crate::Client::server_streaming).
+///
+/// Dropping it before the response arrived cancels the call.
+pub struct ServerStreamReceiver<P> {
+    stream: ClientStream,
+    _message: PhantomData<P>,
+}
+
+impl<P: Message> ServerStreamReceiver<P> {
+    pub(crate) fn new(stream: ClientStream) -> ServerStreamReceiver<P> {
+        ServerStreamReceiver {
+            stream,
+            _message: PhantomData,
+        }
+    }
+
+    /// Wait for the next message, `None` once the method answered. Fails
+    /// once with the status of an answer other than `OK`.
+    pub fn recv(&mut self) -> Result<Option<P>> {
+        match self.stream.recv()? {
+            Some(buf) => decode(&buf).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<P: Message> Iterator for ServerStreamReceiver<P> {
+    type Item = Result<P>;
+
+    fn next(&mut self) -> Option<Result<P>> {
+        self.recv().transpose()
+    }
+}
+
+/// Both ends of a call where the client and the method stream messages
+/// to each other, see
+/// [`Client::duplex_streaming`](crate::Client::duplex_streaming).
+///
+/// Dropping it before the response arrived cancels the call.
+pub struct DuplexStream<Q, P> {
+    stream: ClientStream,
+    _messages: PhantomData<(Q, P)>,
+}
+
+impl<Q: Message, P: Message> DuplexStream<Q, P> {
+    pub(crate) fn new(stream: ClientStream) -> DuplexStream<Q, P> {
+        DuplexStream {
+            stream,
+            _messages: PhantomData,
+        }
+    }
+
+    /// Queue `msg` for the server.
+    pub fn send(&self, msg: &Q) -> Result<()> {
+        self.stream.send(encode_message(msg)?)
+    }
+
+    /// Tell the server no more messages follow.
+    pub fn close_send(&mut self) -> Result<()> {
+        self.stream.close_send()
+    }
+
+    /// Wait for the next message, like [`ServerStreamReceiver::recv`].
+    pub fn recv(&mut self) -> Result<Option<P>> {
+        match self.stream.recv()? {
+            Some(buf) => decode(&buf).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+// Defaults of `CopyOptions`.
+const COPY_CHUNK_SIZE: usize = 32 * 1024;
+const COPY_WINDOW: usize = 16;
+
+// How often `copy_to_stream` checks whether the connection caught up.
+const BACKLOG_POLL: Duration = Duration::from_millis(1);
+
+/// How [`copy_to_stream`] cuts what it reads into messages and paces them.
+#[derive(Clone, Copy, Debug)]
+pub struct CopyOptions {
+    chunk_size: usize,
+    window: usize,
+}
+
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions {
+            chunk_size: COPY_CHUNK_SIZE,
+            window: COPY_WINDOW,
+        }
+    }
+}
+
+impl CopyOptions {
+    pub fn new() -> CopyOptions {
+        CopyOptions::default()
+    }
+
+    /// Put at most `bytes` in each message, 32 KiB by default. What a
+    /// read returns is sent as it is, rather than waiting for more.
+    pub fn chunk_size(mut self, bytes: usize) -> CopyOptions {
+        self.chunk_size = bytes.max(1);
+        self
+    }
+
+    /// Hold back the next message while `frames` or more are queued on
+    /// the connection and not written yet, 16 by default.
+    pub fn window(mut self, frames: usize) -> CopyOptions {
+        self.window = frames.max(1);
+        self
+    }
+}
+
+/// The sending half of a streaming call, on either side, which
+/// [`copy_to_stream`] writes to.
+pub trait StreamSender<T> {
+    /// Queue `msg` for the peer.
+    fn send(&self, msg: &T) -> Result<()>;
+
+    /// Number of frames queued on the connection and not written yet. It
+    /// is 0 once the call ended, so the next `send` fails.
+    fn backlog(&self) -> usize;
+
+    /// Tell the peer no more messages follow.
+    fn close_send(&mut self) -> Result<()>;
+}
+
+impl<T: Message> StreamSender<T> for StreamSink<T> {
+    fn send(&self, msg: &T) -> Result<()> {
+        StreamSink::send(self, msg)
+    }
+
+    fn backlog(&self) -> usize {
+        self.sink.data_backlog()
+    }
+
+    /// The response of the method ends its stream, so this does nothing.
+    fn close_send(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<Q: Message, P: Message> StreamSender<Q> for ClientStreamSender<Q, P> {
+    fn send(&self, msg: &Q) -> Result<()> {
+        ClientStreamSender::send(self, msg)
+    }
+
+    fn backlog(&self) -> usize {
+        self.stream.backlog()
+    }
+
+    fn close_send(&mut self) -> Result<()> {
+        ClientStreamSender::close_send(self)
+    }
+}
+
+impl<Q: Message, P: Message> StreamSender<Q> for DuplexStream<Q, P> {
+    fn send(&self, msg: &Q) -> Result<()> {
+        DuplexStream::send(self, msg)
+    }
+
+    fn backlog(&self) -> usize {
+        self.stream.backlog()
+    }
+
+    fn close_send(&mut self) -> Result<()> {
+        DuplexStream::close_send(self)
+    }
+}
+
+/// Stream what `reader` returns until its end to `stream`, each chunk
+/// wrapped into a message by `wrap`, then tell the peer no more messages
+/// follow. Returns the number of bytes streamed.
+///
+/// A read error fails the copy with the status code matching its errno,
+/// so a method can return it as is; the stream is left open then, and
+/// dropping it cancels a client's call. Fails as soon as sending does,
+/// e.g. once the call was cancelled.
+///
+/// ```no_run
+/// use std::fs::File;
+/// use ttrpc::diagnostics::EchoResponse;
+/// use ttrpc::stream::{copy_to_stream, CopyOptions, StreamSink};
+///
+/// fn stream_log(mut sink: StreamSink<EchoResponse>) -> ttrpc::Result<u64> {
+///     let log = File::open("/run/app/stdout.log")
+///         .map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+///     copy_to_stream(log, &mut sink, CopyOptions::new(), |data| {
+///         let mut m = EchoResponse::new();
+///         m.set_payload(data);
+///         m
+///     })
+/// }
+/// ```
+pub fn copy_to_stream<R, T, S, F>(
+    mut reader: R,
+    stream: &mut S,
+    opts: CopyOptions,
+    mut wrap: F,
+) -> Result<u64>
+where
+    R: Read,
+    S: StreamSender<T>,
+    F: FnMut(Vec<u8>) -> T,
+{
+    let mut copied = 0;
+    loop {
+        let mut buf = vec![0; opts.chunk_size];
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let code = e.raw_os_error().map_or(Code::UNKNOWN, errno_to_code);
+                return Err(get_rpc_status(code, format!("read failed: {}", e)));
+            }
+        };
+        buf.truncate(n);
+        while stream.backlog() >= opts.window {
+            thread::sleep(BACKLOG_POLL);
+        }
+        stream.send(&wrap(buf))?;
+        copied += n as u64;
+    }
+    stream.close_send()?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::Client;
+    use crate::diagnostics::EchoResponse;
+    use crate::error::get_status;
+    use crate::server::{MethodHandler, Server, TtrpcContext};
+    use crate::ttrpc::{Request, Response};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::IntoRawFd;
+
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        }
+    }
+
+    /// Streams its data back in chunks of 4 bytes, or fails reading it
+    /// if there is none.
+    struct CopyMethod(Option<Vec<u8>>);
+
+    impl MethodHandler for CopyMethod {
+        fn handler(&self, ctx: TtrpcContext, _: Request) -> Result<()> {
+            let reader: Box<dyn Read> = match self.0.as_ref() {
+                Some(data) => Box::new(io::Cursor::new(data.clone())),
+                None => Box::new(Failing),
+            };
+            let mut sink = ctx.stream_sink::<EchoResponse>();
+            let opts = CopyOptions::new().chunk_size(4).window(1);
+            let result = copy_to_stream(reader, &mut sink, opts, |data| {
+                let mut m = EchoResponse::new();
+                m.set_payload(data);
+                m
+            });
+            let mut res = Response::new();
+            match result {
+                Ok(_) => res.set_status(get_status(Code::OK, "".to_string())),
+                Err(e) => res.set_status(e.to_status()),
+            }
+            ctx.sink().send(res)
+        }
+    }
+
+    fn call(data: Option<Vec<u8>>) -> (Server, ServerStreamReceiver<EchoResponse>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Logs/Copy".to_string(), Box::new(CopyMethod(data)));
+        let mut server = Server::builder()
+            .add_listener(listener.into_raw_fd())
+            .register_service(methods)
+            .build()
+            .unwrap();
+        server.start().unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let client = Client::new(stream.into_raw_fd());
+        let mut req = Request::new();
+        req.set_service("test.Logs".to_string());
+        req.set_method("Copy".to_string());
+        (server, client.server_streaming(req).unwrap())
+    }
+
+    #[test]
+    fn test_copy_to_stream() {
+        let data = b"container stdout\n".to_vec();
+        let (server, rx) = call(Some(data.clone()));
+
+        let chunks = rx.map(|m| m.unwrap().take_payload()).collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|c| c.len() <= 4));
+        assert_eq!(chunks.concat(), data);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_copy_to_stream_read_error() {
+        let (server, mut rx) = call(None);
+
+        match rx.recv() {
+            Err(Error::RpcStatus(s)) => assert_eq!(s.get_code(), Code::PERMISSION_DENIED),
+            r => panic!("expected the read error, got {:?}", r.map(|_| ())),
+        }
+        server.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright (c) 2020 Ant Financial
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Synchronization primitives of the connection state types in
+//! [`pending`](crate::pending) and [`pool`](crate::pool), and of the
+//! shared job queue in `fair`.
+//!
+//! They are imported from here rather than `std` so the state types can be
+//! built against `loom`, which provides the same API, to explore their
+//! interleavings: with `RUSTFLAGS="--cfg loom"`, the models at the end of
+//! this file run them on loom's primitives. `Arc` stays std's, as
+//! the state types share their handles with code built on std's.
+
+pub(crate) use std::sync::Arc;
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::sync::{Condvar, Mutex};
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Condvar, Mutex};
+
+#[cfg(all(test, loom))]
+mod test {
+    use std::sync::mpsc::{channel, sync_channel};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use loom::thread;
+
+    use crate::fair::FairQueue;
+    use crate::pending::PendingReplies;
+    use crate::pool::WorkerPool;
+
+    #[test]
+    fn test_loom_cancel_before_or_after_begin() {
+        loom::model(|| {
+            let (tx, _rx) = channel();
+            let pending = Arc::new(PendingReplies::new(tx));
+            let canceller = {
+                let pending = pending.clone();
+                thread::spawn(move || pending.cancel(1))
+            };
+            let cancelled = pending.begin(1, 0, "/test.Echo/Echo", 0);
+            canceller.join().unwrap();
+
+            // the handler learns of the cancellation either way
+            assert!(cancelled.load(std::sync::atomic::Ordering::SeqCst));
+            assert!(!pending.is_pending(1));
+        });
+    }
+
+    #[test]
+    fn test_loom_drain_woken_by_finish() {
+        loom::model(|| {
+            let (tx, _rx) = channel();
+            let pending = Arc::new(PendingReplies::new(tx));
+            pending.begin(1, 0, "/test.Echo/Echo", 0);
+            let handler = {
+                let pending = pending.clone();
+                thread::spawn(move || assert!(pending.finish(1)))
+            };
+            // loom does not time out, so a missed wake-up deadlocks
+            assert_eq!(pending.drain(Duration::from_secs(1)), 0);
+            handler.join().unwrap();
+            assert!(pending.sender().is_err());
+        });
+    }
+
+    #[test]
+    fn test_loom_worker_pool_max() {
+        loom::model(|| {
+            let (control_tx, _control_rx) = sync_channel(1);
+            let pool = Arc::new(WorkerPool::new(1, 0, 1, control_tx));
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let pool = pool.clone();
+                    thread::spawn(move || {
+                        let _worker = pool.spawned();
+                        pool.enter()
+                    })
+                })
+                .collect();
+            let entered = threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .filter(|entered| *entered)
+                .count();
+
+            // no more than `max` wait at once, whatever the interleaving
+            assert_eq!(entered, 1);
+            assert_eq!(pool.waiting(), 1);
+            assert_eq!(pool.threads(), 0);
+        });
+    }
+
+    #[test]
+    fn test_loom_fair_queue_close() {
+        loom::model(|| {
+            let queue = Arc::new(FairQueue::new(1));
+            let worker = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    let mut jobs = Vec::new();
+                    while let Some(job) = queue.pop() {
+                        jobs.push(job);
+                    }
+                    jobs
+                })
+            };
+            assert!(queue.push(1, 1, 1));
+            assert!(queue.push(2, 1, 2));
+            queue.close();
+            // the jobs queued before closing still run
+            assert_eq!(worker.join().unwrap(), vec![1, 2]);
+        });
+    }
+}
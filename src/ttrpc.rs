@@ -1,11 +1,12 @@
-// This file is generated by rust-protobuf 2.14.0. Do not edit
+// This file is generated by rust-protobuf 2.28.0. Do not edit
 // @generated
 
 // https://github.com/rust-lang/rust-clippy/issues/702
 #![allow(unknown_lints)]
 #![allow(clippy::all)]
 
-#![cfg_attr(rustfmt, rustfmt_skip)]
+#![allow(unused_attributes)]
+#![cfg_attr(rustfmt, rustfmt::skip)]
 
 #![allow(box_pointers)]
 #![allow(dead_code)]
@@ -14,17 +15,13 @@
 #![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
 #![allow(trivial_casts)]
-#![allow(unsafe_code)]
 #![allow(unused_imports)]
 #![allow(unused_results)]
 //! Generated file from `ttrpc.proto`
 
-use protobuf::Message as Message_imported_for_functions;
-use protobuf::ProtobufEnum as ProtobufEnum_imported_for_functions;
-
 /// Generated files are compatible only with the same version
 /// of protobuf runtime.
-// const _PROTOBUF_VERSION_CHECK: () = ::protobuf::VERSION_2_14_0;
+// const _PROTOBUF_VERSION_CHECK: () = ::protobuf::VERSION_2_28_0;
 
 #[derive(PartialEq,Clone,Default)]
 pub struct Request {
@@ -33,6 +30,7 @@ pub struct Request {
     pub method: ::std::string::String,
     pub payload: ::std::vec::Vec<u8>,
     pub timeout_nano: i64,
+    pub metadata: ::protobuf::RepeatedField<KeyValue>,
     // special fields
     pub unknown_fields: ::protobuf::UnknownFields,
     pub cached_size: ::protobuf::CachedSize,
@@ -141,10 +139,40 @@ impl Request {
     pub fn set_timeout_nano(&mut self, v: i64) {
         self.timeout_nano = v;
     }
+
+    // repeated .grpc.KeyValue metadata = 5;
+
+
+    pub fn get_metadata(&self) -> &[KeyValue] {
+        &self.metadata
+    }
+    pub fn clear_metadata(&mut self) {
+        self.metadata.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_metadata(&mut self, v: ::protobuf::RepeatedField<KeyValue>) {
+        self.metadata = v;
+    }
+
+    // Mutable pointer to the field.
+    pub fn mut_metadata(&mut self) -> &mut ::protobuf::RepeatedField<KeyValue> {
+        &mut self.metadata
+    }
+
+    // Take field
+    pub fn take_metadata(&mut self) -> ::protobuf::RepeatedField<KeyValue> {
+        ::std::mem::replace(&mut self.metadata, ::protobuf::RepeatedField::new())
+    }
 }
 
 impl ::protobuf::Message for Request {
     fn is_initialized(&self) -> bool {
+        for v in &self.metadata {
+            if !v.is_initialized() {
+                return false;
+            }
+        };
         true
     }
 
@@ -168,6 +196,9 @@ impl ::protobuf::Message for Request {
                     let tmp = is.read_int64()?;
                     self.timeout_nano = tmp;
                 },
+                5 => {
+                    ::protobuf::rt::read_repeated_message_into(wire_type, is, &mut self.metadata)?;
+                },
                 _ => {
                     ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
                 },
@@ -192,6 +223,10 @@ impl ::protobuf::Message for Request {
         if self.timeout_nano != 0 {
             my_size += ::protobuf::rt::value_size(4, self.timeout_nano, ::protobuf::wire_format::WireTypeVarint);
         }
+        for value in &self.metadata {
+            let len = value.compute_size();
+            my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
+        };
         my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
         self.cached_size.set(my_size);
         my_size
@@ -210,6 +245,11 @@ impl ::protobuf::Message for Request {
         if self.timeout_nano != 0 {
             os.write_int64(4, self.timeout_nano)?;
         }
+        for v in &self.metadata {
+            os.write_tag(5, ::protobuf::wire_format::WireTypeLengthDelimited)?;
+            os.write_raw_varint32(v.get_cached_size())?;
+            v.write_to_with_cached_sizes(os)?;
+        };
         os.write_unknown_fields(self.get_unknown_fields())?;
         ::std::result::Result::Ok(())
     }
@@ -232,7 +272,7 @@ impl ::protobuf::Message for Request {
     fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
         self as &mut dyn (::std::any::Any)
     }
-    fn into_any(self: Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
         self
     }
 
@@ -245,44 +285,45 @@ impl ::protobuf::Message for Request {
     }
 
     fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
-                    "service",
-                    |m: &Request| { &m.service },
-                    |m: &mut Request| { &mut m.service },
-                ));
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
-                    "method",
-                    |m: &Request| { &m.method },
-                    |m: &mut Request| { &mut m.method },
-                ));
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
-                    "payload",
-                    |m: &Request| { &m.payload },
-                    |m: &mut Request| { &mut m.payload },
-                ));
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
-                    "timeout_nano",
-                    |m: &Request| { &m.timeout_nano },
-                    |m: &mut Request| { &mut m.timeout_nano },
-                ));
-                ::protobuf::reflect::MessageDescriptor::new_pb_name::<Request>(
-                    "Request",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "service",
+                |m: &Request| { &m.service },
+                |m: &mut Request| { &mut m.service },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "method",
+                |m: &Request| { &m.method },
+                |m: &mut Request| { &mut m.method },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
+                "payload",
+                |m: &Request| { &m.payload },
+                |m: &mut Request| { &mut m.payload },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt64>(
+                "timeout_nano",
+                |m: &Request| { &m.timeout_nano },
+                |m: &mut Request| { &mut m.timeout_nano },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_repeated_field_accessor::<_, ::protobuf::types::ProtobufTypeMessage<KeyValue>>(
+                "metadata",
+                |m: &Request| { &m.metadata },
+                |m: &mut Request| { &mut m.metadata },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<Request>(
+                "Request",
+                fields,
+                file_descriptor_proto()
+            )
+        })
     }
 
     fn default_instance() -> &'static Request {
-        static mut instance: ::protobuf::lazy::Lazy<Request> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            instance.get(Request::new)
-        }
+        static instance: ::protobuf::rt::LazyV2<Request> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(Request::new)
     }
 }
 
@@ -292,6 +333,7 @@ impl ::protobuf::Clear for Request {
         self.method.clear();
         self.payload.clear();
         self.timeout_nano = 0;
+        self.metadata.clear();
         self.unknown_fields.clear();
     }
 }
@@ -308,6 +350,207 @@ impl ::protobuf::reflect::ProtobufValue for Request {
     }
 }
 
+#[derive(PartialEq,Clone,Default)]
+pub struct KeyValue {
+    // message fields
+    pub key: ::std::string::String,
+    pub value: ::std::string::String,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a KeyValue {
+    fn default() -> &'a KeyValue {
+        <KeyValue as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl KeyValue {
+    pub fn new() -> KeyValue {
+        ::std::default::Default::default()
+    }
+
+    // string key = 1;
+
+
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+    pub fn clear_key(&mut self) {
+        self.key.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_key(&mut self, v: ::std::string::String) {
+        self.key = v;
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_key(&mut self) -> &mut ::std::string::String {
+        &mut self.key
+    }
+
+    // Take field
+    pub fn take_key(&mut self) -> ::std::string::String {
+        ::std::mem::replace(&mut self.key, ::std::string::String::new())
+    }
+
+    // string value = 2;
+
+
+    pub fn get_value(&self) -> &str {
+        &self.value
+    }
+    pub fn clear_value(&mut self) {
+        self.value.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_value(&mut self, v: ::std::string::String) {
+        self.value = v;
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_value(&mut self) -> &mut ::std::string::String {
+        &mut self.value
+    }
+
+    // Take field
+    pub fn take_value(&mut self) -> ::std::string::String {
+        ::std::mem::replace(&mut self.value, ::std::string::String::new())
+    }
+}
+
+impl ::protobuf::Message for KeyValue {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    ::protobuf::rt::read_singular_proto3_string_into(wire_type, is, &mut self.key)?;
+                },
+                2 => {
+                    ::protobuf::rt::read_singular_proto3_string_into(wire_type, is, &mut self.value)?;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if !self.key.is_empty() {
+            my_size += ::protobuf::rt::string_size(1, &self.key);
+        }
+        if !self.value.is_empty() {
+            my_size += ::protobuf::rt::string_size(2, &self.value);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if !self.key.is_empty() {
+            os.write_string(1, &self.key)?;
+        }
+        if !self.value.is_empty() {
+            os.write_string(2, &self.value)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> KeyValue {
+        KeyValue::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "key",
+                |m: &KeyValue| { &m.key },
+                |m: &mut KeyValue| { &mut m.key },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "value",
+                |m: &KeyValue| { &m.value },
+                |m: &mut KeyValue| { &mut m.value },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<KeyValue>(
+                "KeyValue",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static KeyValue {
+        static instance: ::protobuf::rt::LazyV2<KeyValue> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(KeyValue::new)
+    }
+}
+
+impl ::protobuf::Clear for KeyValue {
+    fn clear(&mut self) {
+        self.key.clear();
+        self.value.clear();
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for KeyValue {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for KeyValue {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
 #[derive(PartialEq,Clone,Default)]
 pub struct Any {
     // message fields
@@ -449,7 +692,7 @@ impl ::protobuf::Message for Any {
     fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
         self as &mut dyn (::std::any::Any)
     }
-    fn into_any(self: Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
         self
     }
 
@@ -462,34 +705,30 @@ impl ::protobuf::Message for Any {
     }
 
     fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
-                    "type_url",
-                    |m: &Any| { &m.type_url },
-                    |m: &mut Any| { &mut m.type_url },
-                ));
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
-                    "value",
-                    |m: &Any| { &m.value },
-                    |m: &mut Any| { &mut m.value },
-                ));
-                ::protobuf::reflect::MessageDescriptor::new_pb_name::<Any>(
-                    "Any",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "type_url",
+                |m: &Any| { &m.type_url },
+                |m: &mut Any| { &mut m.type_url },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
+                "value",
+                |m: &Any| { &m.value },
+                |m: &mut Any| { &mut m.value },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<Any>(
+                "Any",
+                fields,
+                file_descriptor_proto()
+            )
+        })
     }
 
     fn default_instance() -> &'static Any {
-        static mut instance: ::protobuf::lazy::Lazy<Any> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            instance.get(Any::new)
-        }
+        static instance: ::protobuf::rt::LazyV2<Any> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(Any::new)
     }
 }
 
@@ -654,7 +893,7 @@ impl ::protobuf::Message for Status {
 
     fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
         if self.code != Code::OK {
-            os.write_enum(1, self.code.value())?;
+            os.write_enum(1, ::protobuf::ProtobufEnum::value(&self.code))?;
         }
         if !self.message.is_empty() {
             os.write_string(2, &self.message)?;
@@ -686,7 +925,7 @@ impl ::protobuf::Message for Status {
     fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
         self as &mut dyn (::std::any::Any)
     }
-    fn into_any(self: Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
         self
     }
 
@@ -699,39 +938,35 @@ impl ::protobuf::Message for Status {
     }
 
     fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeEnum<Code>>(
-                    "code",
-                    |m: &Status| { &m.code },
-                    |m: &mut Status| { &mut m.code },
-                ));
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
-                    "message",
-                    |m: &Status| { &m.message },
-                    |m: &mut Status| { &mut m.message },
-                ));
-                fields.push(::protobuf::reflect::accessor::make_repeated_field_accessor::<_, ::protobuf::types::ProtobufTypeMessage<Any>>(
-                    "details",
-                    |m: &Status| { &m.details },
-                    |m: &mut Status| { &mut m.details },
-                ));
-                ::protobuf::reflect::MessageDescriptor::new_pb_name::<Status>(
-                    "Status",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeEnum<Code>>(
+                "code",
+                |m: &Status| { &m.code },
+                |m: &mut Status| { &mut m.code },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeString>(
+                "message",
+                |m: &Status| { &m.message },
+                |m: &mut Status| { &mut m.message },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_repeated_field_accessor::<_, ::protobuf::types::ProtobufTypeMessage<Any>>(
+                "details",
+                |m: &Status| { &m.details },
+                |m: &mut Status| { &mut m.details },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<Status>(
+                "Status",
+                fields,
+                file_descriptor_proto()
+            )
+        })
     }
 
     fn default_instance() -> &'static Status {
-        static mut instance: ::protobuf::lazy::Lazy<Status> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            instance.get(Status::new)
-        }
+        static instance: ::protobuf::rt::LazyV2<Status> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(Status::new)
     }
 }
 
@@ -761,6 +996,7 @@ pub struct Response {
     // message fields
     pub status: ::protobuf::SingularPtrField<Status>,
     pub payload: ::std::vec::Vec<u8>,
+    pub metadata: ::protobuf::RepeatedField<KeyValue>,
     // special fields
     pub unknown_fields: ::protobuf::UnknownFields,
     pub cached_size: ::protobuf::CachedSize,
@@ -781,7 +1017,7 @@ impl Response {
 
 
     pub fn get_status(&self) -> &Status {
-        self.status.as_ref().unwrap_or_else(|| Status::default_instance())
+        self.status.as_ref().unwrap_or_else(|| <Status as ::protobuf::Message>::default_instance())
     }
     pub fn clear_status(&mut self) {
         self.status.clear();
@@ -835,6 +1071,31 @@ impl Response {
     pub fn take_payload(&mut self) -> ::std::vec::Vec<u8> {
         ::std::mem::replace(&mut self.payload, ::std::vec::Vec::new())
     }
+
+    // repeated .grpc.KeyValue metadata = 3;
+
+
+    pub fn get_metadata(&self) -> &[KeyValue] {
+        &self.metadata
+    }
+    pub fn clear_metadata(&mut self) {
+        self.metadata.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_metadata(&mut self, v: ::protobuf::RepeatedField<KeyValue>) {
+        self.metadata = v;
+    }
+
+    // Mutable pointer to the field.
+    pub fn mut_metadata(&mut self) -> &mut ::protobuf::RepeatedField<KeyValue> {
+        &mut self.metadata
+    }
+
+    // Take field
+    pub fn take_metadata(&mut self) -> ::protobuf::RepeatedField<KeyValue> {
+        ::std::mem::replace(&mut self.metadata, ::protobuf::RepeatedField::new())
+    }
 }
 
 impl ::protobuf::Message for Response {
@@ -844,6 +1105,11 @@ impl ::protobuf::Message for Response {
                 return false;
             }
         };
+        for v in &self.metadata {
+            if !v.is_initialized() {
+                return false;
+            }
+        };
         true
     }
 
@@ -857,6 +1123,9 @@ impl ::protobuf::Message for Response {
                 2 => {
                     ::protobuf::rt::read_singular_proto3_bytes_into(wire_type, is, &mut self.payload)?;
                 },
+                3 => {
+                    ::protobuf::rt::read_repeated_message_into(wire_type, is, &mut self.metadata)?;
+                },
                 _ => {
                     ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
                 },
@@ -876,6 +1145,10 @@ impl ::protobuf::Message for Response {
         if !self.payload.is_empty() {
             my_size += ::protobuf::rt::bytes_size(2, &self.payload);
         }
+        for value in &self.metadata {
+            let len = value.compute_size();
+            my_size += 1 + ::protobuf::rt::compute_raw_varint32_size(len) + len;
+        };
         my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
         self.cached_size.set(my_size);
         my_size
@@ -890,6 +1163,11 @@ impl ::protobuf::Message for Response {
         if !self.payload.is_empty() {
             os.write_bytes(2, &self.payload)?;
         }
+        for v in &self.metadata {
+            os.write_tag(3, ::protobuf::wire_format::WireTypeLengthDelimited)?;
+            os.write_raw_varint32(v.get_cached_size())?;
+            v.write_to_with_cached_sizes(os)?;
+        };
         os.write_unknown_fields(self.get_unknown_fields())?;
         ::std::result::Result::Ok(())
     }
@@ -912,7 +1190,7 @@ impl ::protobuf::Message for Response {
     fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
         self as &mut dyn (::std::any::Any)
     }
-    fn into_any(self: Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
         self
     }
 
@@ -925,34 +1203,35 @@ impl ::protobuf::Message for Response {
     }
 
     fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::MessageDescriptor> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            descriptor.get(|| {
-                let mut fields = ::std::vec::Vec::new();
-                fields.push(::protobuf::reflect::accessor::make_singular_ptr_field_accessor::<_, ::protobuf::types::ProtobufTypeMessage<Status>>(
-                    "status",
-                    |m: &Response| { &m.status },
-                    |m: &mut Response| { &mut m.status },
-                ));
-                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
-                    "payload",
-                    |m: &Response| { &m.payload },
-                    |m: &mut Response| { &mut m.payload },
-                ));
-                ::protobuf::reflect::MessageDescriptor::new_pb_name::<Response>(
-                    "Response",
-                    fields,
-                    file_descriptor_proto()
-                )
-            })
-        }
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_singular_ptr_field_accessor::<_, ::protobuf::types::ProtobufTypeMessage<Status>>(
+                "status",
+                |m: &Response| { &m.status },
+                |m: &mut Response| { &mut m.status },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
+                "payload",
+                |m: &Response| { &m.payload },
+                |m: &mut Response| { &mut m.payload },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_repeated_field_accessor::<_, ::protobuf::types::ProtobufTypeMessage<KeyValue>>(
+                "metadata",
+                |m: &Response| { &m.metadata },
+                |m: &mut Response| { &mut m.metadata },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<Response>(
+                "Response",
+                fields,
+                file_descriptor_proto()
+            )
+        })
     }
 
     fn default_instance() -> &'static Response {
-        static mut instance: ::protobuf::lazy::Lazy<Response> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            instance.get(Response::new)
-        }
+        static instance: ::protobuf::rt::LazyV2<Response> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(Response::new)
     }
 }
 
@@ -960,6 +1239,7 @@ impl ::protobuf::Clear for Response {
     fn clear(&mut self) {
         self.status.clear();
         self.payload.clear();
+        self.metadata.clear();
         self.unknown_fields.clear();
     }
 }
@@ -1049,12 +1329,10 @@ impl ::protobuf::ProtobufEnum for Code {
     }
 
     fn enum_descriptor_static() -> &'static ::protobuf::reflect::EnumDescriptor {
-        static mut descriptor: ::protobuf::lazy::Lazy<::protobuf::reflect::EnumDescriptor> = ::protobuf::lazy::Lazy::INIT;
-        unsafe {
-            descriptor.get(|| {
-                ::protobuf::reflect::EnumDescriptor::new_pb_name::<Code>("Code", file_descriptor_proto())
-            })
-        }
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::EnumDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            ::protobuf::reflect::EnumDescriptor::new_pb_name::<Code>("Code", file_descriptor_proto())
+        })
     }
 }
 
@@ -1069,41 +1347,44 @@ impl ::std::default::Default for Code {
 
 impl ::protobuf::reflect::ProtobufValue for Code {
     fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
-        ::protobuf::reflect::ReflectValueRef::Enum(self.descriptor())
+        ::protobuf::reflect::ReflectValueRef::Enum(::protobuf::ProtobufEnum::descriptor(self))
     }
 }
 
 static file_descriptor_proto_data: &'static [u8] = b"\
-    \n\x0bttrpc.proto\x12\x04grpc\"[\n\x07Request\x12\x11\n\x07service\x18\
-    \x01\x20\x01(\tB\0\x12\x10\n\x06method\x18\x02\x20\x01(\tB\0\x12\x11\n\
-    \x07payload\x18\x03\x20\x01(\x0cB\0\x12\x16\n\x0ctimeout_nano\x18\x04\
-    \x20\x01(\x03B\0:\0\",\n\x03Any\x12\x12\n\x08type_url\x18\x01\x20\x01(\t\
-    B\0\x12\x0f\n\x05value\x18\x02\x20\x01(\x0cB\0:\0\"W\n\x06Status\x12\x1a\
-    \n\x04code\x18\x01\x20\x01(\x0e2\n.grpc.CodeB\0\x12\x11\n\x07message\x18\
-    \x02\x20\x01(\tB\0\x12\x1c\n\x07details\x18\x03\x20\x03(\x0b2\t.grpc.Any\
-    B\0:\0\"?\n\x08Response\x12\x1e\n\x06status\x18\x01\x20\x01(\x0b2\x0c.gr\
-    pc.StatusB\0\x12\x11\n\x07payload\x18\x02\x20\x01(\x0cB\0:\0*\xb9\x02\n\
-    \x04Code\x12\x06\n\x02OK\x10\0\x12\r\n\tCANCELLED\x10\x01\x12\x0b\n\x07U\
-    NKNOWN\x10\x02\x12\x14\n\x10INVALID_ARGUMENT\x10\x03\x12\x15\n\x11DEADLI\
-    NE_EXCEEDED\x10\x04\x12\r\n\tNOT_FOUND\x10\x05\x12\x12\n\x0eALREADY_EXIS\
-    TS\x10\x06\x12\x15\n\x11PERMISSION_DENIED\x10\x07\x12\x13\n\x0fUNAUTHENT\
-    ICATED\x10\x10\x12\x16\n\x12RESOURCE_EXHAUSTED\x10\x08\x12\x17\n\x13FAIL\
-    ED_PRECONDITION\x10\t\x12\x0b\n\x07ABORTED\x10\n\x12\x10\n\x0cOUT_OF_RAN\
-    GE\x10\x0b\x12\x11\n\rUNIMPLEMENTED\x10\x0c\x12\x0c\n\x08INTERNAL\x10\r\
-    \x12\x0f\n\x0bUNAVAILABLE\x10\x0e\x12\r\n\tDATA_LOSS\x10\x0f\x1a\0B\0b\
-    \x06proto3\
+    \n\x0bttrpc.proto\x12\x04grpc\"\xb0\x01\n\x07Request\x12\x1a\n\x07servic\
+    e\x18\x01\x20\x01(\tR\x07serviceB\0\x12\x18\n\x06method\x18\x02\x20\x01(\
+    \tR\x06methodB\0\x12\x1a\n\x07payload\x18\x03\x20\x01(\x0cR\x07payloadB\
+    \0\x12#\n\x0ctimeout_nano\x18\x04\x20\x01(\x03R\x0btimeoutNanoB\0\x12,\n\
+    \x08metadata\x18\x05\x20\x03(\x0b2\x0e.grpc.KeyValueR\x08metadataB\0:\0\
+    \"8\n\x08KeyValue\x12\x12\n\x03key\x18\x01\x20\x01(\tR\x03keyB\0\x12\x16\
+    \n\x05value\x18\x02\x20\x01(\tR\x05valueB\0:\0\"<\n\x03Any\x12\x1b\n\x08\
+    type_url\x18\x01\x20\x01(\tR\x07typeUrlB\0\x12\x16\n\x05value\x18\x02\
+    \x20\x01(\x0cR\x05valueB\0:\0\"o\n\x06Status\x12\x20\n\x04code\x18\x01\
+    \x20\x01(\x0e2\n.grpc.CodeR\x04codeB\0\x12\x1a\n\x07message\x18\x02\x20\
+    \x01(\tR\x07messageB\0\x12%\n\x07details\x18\x03\x20\x03(\x0b2\t.grpc.An\
+    yR\x07detailsB\0:\0\"~\n\x08Response\x12&\n\x06status\x18\x01\x20\x01(\
+    \x0b2\x0c.grpc.StatusR\x06statusB\0\x12\x1a\n\x07payload\x18\x02\x20\x01\
+    (\x0cR\x07payloadB\0\x12,\n\x08metadata\x18\x03\x20\x03(\x0b2\x0e.grpc.K\
+    eyValueR\x08metadataB\0:\0*\xb9\x02\n\x04Code\x12\x06\n\x02OK\x10\0\x12\
+    \r\n\tCANCELLED\x10\x01\x12\x0b\n\x07UNKNOWN\x10\x02\x12\x14\n\x10INVALI\
+    D_ARGUMENT\x10\x03\x12\x15\n\x11DEADLINE_EXCEEDED\x10\x04\x12\r\n\tNOT_F\
+    OUND\x10\x05\x12\x12\n\x0eALREADY_EXISTS\x10\x06\x12\x15\n\x11PERMISSION\
+    _DENIED\x10\x07\x12\x13\n\x0fUNAUTHENTICATED\x10\x10\x12\x16\n\x12RESOUR\
+    CE_EXHAUSTED\x10\x08\x12\x17\n\x13FAILED_PRECONDITION\x10\t\x12\x0b\n\
+    \x07ABORTED\x10\n\x12\x10\n\x0cOUT_OF_RANGE\x10\x0b\x12\x11\n\rUNIMPLEME\
+    NTED\x10\x0c\x12\x0c\n\x08INTERNAL\x10\r\x12\x0f\n\x0bUNAVAILABLE\x10\
+    \x0e\x12\r\n\tDATA_LOSS\x10\x0f\x1a\0B\0b\x06proto3\
 ";
 
-static mut file_descriptor_proto_lazy: ::protobuf::lazy::Lazy<::protobuf::descriptor::FileDescriptorProto> = ::protobuf::lazy::Lazy::INIT;
+static file_descriptor_proto_lazy: ::protobuf::rt::LazyV2<::protobuf::descriptor::FileDescriptorProto> = ::protobuf::rt::LazyV2::INIT;
 
 fn parse_descriptor_proto() -> ::protobuf::descriptor::FileDescriptorProto {
-    ::protobuf::parse_from_bytes(file_descriptor_proto_data).unwrap()
+    ::protobuf::Message::parse_from_bytes(file_descriptor_proto_data).unwrap()
 }
 
 pub fn file_descriptor_proto() -> &'static ::protobuf::descriptor::FileDescriptorProto {
-    unsafe {
-        file_descriptor_proto_lazy.get(|| {
-            parse_descriptor_proto()
-        })
-    }
+    file_descriptor_proto_lazy.get(|| {
+        parse_descriptor_proto()
+    })
 }